@@ -0,0 +1,593 @@
+use std::{collections::HashMap, error::Error};
+
+use super::{
+    export::{export, ExportOptions},
+    import::{self, Imported},
+};
+use crate::{
+    data::{
+        args::{CliArgs, ConfigArgs, ConvoCommand},
+        project::Project,
+    },
+    effect::{
+        self,
+        conversation::{DialogueSegment, Script, TurnMeta, ROOT},
+        encryption::Cipher,
+        search_index::SearchIndex,
+        LongMemEffect, LongMemQueryOpt, LongMemSaveInp, QueryWindow, ShortMemEffect,
+    },
+    model::{HistoryBackend, SourceType},
+    utils::{check_name, format_time, snippet},
+};
+
+pub async fn run(
+    command: &ConvoCommand,
+    args: &CliArgs,
+    config_args: &ConfigArgs,
+) -> Result<(), Box<dyn Error>> {
+    let convo_dir = args.convo_dir(config_args)?;
+    let backend = args.history_backend(config_args);
+    let cipher = args.history_cipher(config_args, &convo_dir)?;
+    let history = |name: &str| history_for(&backend, &convo_dir, name, cipher.as_ref());
+
+    match command {
+        ConvoCommand::Current => {
+            let project = Project::find(&std::env::current_dir()?)?;
+            println!("{}", args.convo(config_args, project.as_ref())?);
+        }
+        ConvoCommand::List => {
+            let mut convos = vec![];
+            for name in effect::conversations(&backend, &convo_dir)? {
+                let script = history(&name)?.load().await;
+                let updated = script
+                    .as_ref()
+                    .ok()
+                    .and_then(|script| script.dialogue.last())
+                    .map(|segment| segment.created_at);
+                convos.push((updated, name, script));
+            }
+            convos.sort_by_key(|convo| std::cmp::Reverse(convo.0));
+
+            for (updated, name, script) in convos {
+                match script {
+                    Ok(script) => println!(
+                        "{}\t{} turns\t{}",
+                        name,
+                        script.turns().len(),
+                        updated.as_ref().map(format_time).unwrap_or_default()
+                    ),
+                    Err(e) => println!("{}\tunreadable: {}", name, e),
+                }
+            }
+        }
+        ConvoCommand::Show { name } => {
+            let script = load(history(name)?.as_ref(), name).await?;
+            print_turns(&script, 0);
+        }
+        ConvoCommand::Tail { name, turns } => {
+            let script = load(history(name)?.as_ref(), name).await?;
+            print_turns(&script, script.turns().len().saturating_sub(*turns));
+        }
+        ConvoCommand::Rm { name, memory } => {
+            let convo = history(name)?;
+            if !convo.exists().await? {
+                return Err(format!("No conversation named '{}'", name).into());
+            }
+            if *memory {
+                long_term_memory(args, config_args)?
+                    .delete_category(name)
+                    .await?;
+                eprintln!("Deleted long term memories in '{}'", name);
+            }
+            convo.delete().await?;
+            eprintln!("Deleted conversation '{}'", name);
+        }
+        ConvoCommand::Mv { from, to } => {
+            // renaming does not need a memory store, its memories are only moved when there is one
+            let memory = long_term_memory(args, config_args).ok();
+            rename(
+                history(from)?.as_ref(),
+                history(to)?.as_ref(),
+                memory.as_deref(),
+                from,
+                to,
+            )
+            .await?;
+        }
+        ConvoCommand::Fork { from, to, turns } => {
+            let script = load(history(from)?.as_ref(), from).await?.linear(*turns);
+            let turns = script.turns().len();
+            create(history(to)?.as_ref(), to, script).await?;
+            eprintln!("Forked {} turns of '{}' into '{}'", turns, from, to);
+        }
+        ConvoCommand::Clear { name } => {
+            let convo = history(name)?;
+            let script = load(convo.as_ref(), name).await?;
+            convo
+                .replace(Script {
+                    dialogue: vec![],
+                    summary: None,
+                    head: None,
+                    ..script
+                })
+                .await?;
+        }
+        ConvoCommand::Branches { name } => {
+            let script = load(history(name)?.as_ref(), name).await?;
+            for leaf in script.leaves() {
+                let branch = script.branch_to(Some(&leaf.id));
+                let turns = effect::conversation::turns(&branch);
+                let question = turns
+                    .last()
+                    .and_then(|turn| turn.iter().find(|segment| segment.role == "user"))
+                    .map(|segment| snippet(&segment.content))
+                    .unwrap_or_default();
+                // the next turn follows a segment on this branch
+                let active = script
+                    .head()
+                    .is_some_and(|head| branch.iter().any(|segment| segment.id == head));
+                println!(
+                    "{} {}\t{} turns\t{}",
+                    if active { "*" } else { " " },
+                    leaf.id,
+                    turns.len(),
+                    question
+                );
+            }
+        }
+        ConvoCommand::Export {
+            name,
+            format,
+            output,
+            system,
+            since,
+            until,
+        } => {
+            let script = load(history(name)?.as_ref(), name).await?;
+            let options = ExportOptions {
+                format: *format,
+                system: system.then(|| args.system_message(config_args, script.persona.as_deref())),
+                since: *since,
+                until: *until,
+            };
+            let exported = export(name, &script, &options)?;
+            match output {
+                Some(path) => std::fs::write(path, exported)?,
+                None => print!("{}", exported),
+            }
+        }
+        ConvoCommand::Import {
+            file,
+            format,
+            memory,
+            batch_size,
+        } => {
+            let contents = std::fs::read_to_string(file)?;
+            let stem = std::path::Path::new(file)
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .unwrap_or("imported");
+            let imported = import::parse(*format, &contents, stem)?;
+            let context = match memory {
+                true => Some(long_term_memory(args, config_args)?),
+                false => None,
+            };
+
+            for Imported { name, mut script } in imported {
+                let convo = history(&name)?;
+                if convo.exists().await? {
+                    eprintln!("Skipping '{}', a conversation with that name exists", name);
+                    continue;
+                }
+                if let Some(context) = &context {
+                    let mut ids = Vec::with_capacity(script.dialogue.len());
+                    for batch in script.dialogue.chunks((*batch_size).max(1)) {
+                        let batch: Vec<LongMemSaveInp> = batch
+                            .iter()
+                            .map(|segment| LongMemSaveInp {
+                                text: segment.content.clone(),
+                                author: segment.role.clone(),
+                                created_at: segment.created_at,
+                                source_type: SourceType::Import,
+                                source: None,
+                            })
+                            .collect();
+                        ids.extend(context.save(&batch, &name).await?);
+                    }
+                    if ids.len() != script.dialogue.len() {
+                        return Err(format!(
+                            "Expected {} memories to be saved, {} were",
+                            script.dialogue.len(),
+                            ids.len()
+                        )
+                        .into());
+                    }
+                    // link the segments to their memories, like chatting does
+                    for (segment, id) in script.dialogue.iter_mut().zip(ids) {
+                        segment.memory_id = Some(id);
+                    }
+                }
+                let turns = script.turns().len();
+                convo.replace(script).await?;
+                eprintln!("Imported '{}' ({} turns)", name, turns);
+            }
+        }
+        ConvoCommand::Checkout { name, id } => {
+            let convo = history(name)?;
+            load(convo.as_ref(), name).await?;
+            convo
+                .checkout(Some(id.as_str()).filter(|id| *id != ROOT))
+                .await?;
+            eprintln!("The next turn of '{}' will follow {}", name, id);
+        }
+        ConvoCommand::Encrypt { names } | ConvoCommand::Decrypt { names } => {
+            let cipher = cipher
+                .as_ref()
+                .ok_or("Set encryption in config.yaml to encrypt or decrypt conversations")?;
+            let encrypt = matches!(command, ConvoCommand::Encrypt { .. });
+            let names = if names.is_empty() {
+                effect::conversations(&backend, &convo_dir)?
+            } else {
+                names.clone()
+            };
+            let index = SearchIndex::for_conversations(&convo_dir);
+
+            for name in names {
+                let convo = history_for(&backend, &convo_dir, &name, Some(cipher))?;
+                let script = load(convo.as_ref(), &name).await?;
+                history_for(&backend, &convo_dir, &name, encrypt.then_some(cipher))?
+                    .replace(script)
+                    .await?;
+                if encrypt {
+                    // the index holds the text of every message
+                    index.remove(&name).await?;
+                    eprintln!("Encrypted '{}'", name);
+                } else {
+                    eprintln!("Decrypted '{}'", name);
+                }
+            }
+        }
+        ConvoCommand::Migrate { from, to, names } => {
+            let from = from.unwrap_or(backend);
+            if &from == to {
+                return Err("Conversations are already stored by that backend".into());
+            }
+            let names = if names.is_empty() {
+                effect::conversations(&from, &convo_dir)?
+            } else {
+                names.clone()
+            };
+
+            for name in names {
+                let convo = history_for(&from, &convo_dir, &name, cipher.as_ref())?;
+                let script = load(convo.as_ref(), &name).await?;
+                let target = history_for(to, &convo_dir, &name, cipher.as_ref())?;
+                if target.exists().await? {
+                    eprintln!("Skipping '{}', it already exists in {:?}", name, to);
+                    continue;
+                }
+                target.replace(script).await?;
+                eprintln!("Copied '{}'", name);
+            }
+            eprintln!(
+                "Set history_backend in config.yaml to use the copies. The originals have been kept"
+            );
+        }
+    }
+    Ok(())
+}
+
+fn history_for(
+    backend: &HistoryBackend,
+    convo_dir: &str,
+    name: &str,
+    cipher: Option<&Cipher>,
+) -> Result<Box<dyn ShortMemEffect>, Box<dyn Error>> {
+    check_name("conversation", name)?;
+    Ok(effect::history(backend, convo_dir, name, cipher))
+}
+
+fn long_term_memory(
+    args: &CliArgs,
+    config_args: &ConfigArgs,
+) -> Result<Box<dyn LongMemEffect>, Box<dyn Error>> {
+    Ok(effect::context(&args.memory_store(config_args)?, 0, None))
+}
+
+async fn load(convo: &dyn ShortMemEffect, name: &str) -> Result<Script, Box<dyn Error>> {
+    if !convo.exists().await? {
+        return Err(format!("No conversation named '{}'", name).into());
+    }
+    convo.load().await
+}
+
+/// Renames a conversation, moving its long term memories to the new name when the store can be
+/// reached. The rename goes ahead without them otherwise
+async fn rename(
+    from_convo: &dyn ShortMemEffect,
+    to_convo: &dyn ShortMemEffect,
+    memory: Option<&dyn LongMemEffect>,
+    from: &str,
+    to: &str,
+) -> Result<(), Box<dyn Error>> {
+    let mut script = load(from_convo, from).await?;
+    if to_convo.exists().await? {
+        return Err(format!("A conversation named '{}' already exists", to).into());
+    }
+
+    if let Some(memory) = memory {
+        match move_memories(memory, from, to).await {
+            Ok(moved) => {
+                for segment in &mut script.dialogue {
+                    if let Some(id) = segment.memory_id.as_ref().and_then(|id| moved.get(id)) {
+                        segment.memory_id = Some(id.clone());
+                    }
+                }
+                if !moved.is_empty() {
+                    eprintln!("Moved {} long term memories to '{}'", moved.len(), to);
+                }
+            }
+            Err(e) => eprintln!(
+                "Could not move the long term memories of '{}' to '{}': {}",
+                from, to, e
+            ),
+        }
+    }
+
+    create(to_convo, to, script).await?;
+    from_convo.delete().await
+}
+
+/// Saves the memories of one category under another, then deletes the originals. Returns the new
+/// id of each moved memory by its old one
+async fn move_memories(
+    memory: &dyn LongMemEffect,
+    from: &str,
+    to: &str,
+) -> Result<HashMap<String, String>, Box<dyn Error>> {
+    let options = LongMemQueryOpt {
+        category: from.to_string(),
+        query_window: QueryWindow {
+            min: None,
+            max: None,
+        },
+        author: None,
+        source_type: None,
+    };
+    let memories = memory.list(&options, usize::MAX).await?;
+    if memories.is_empty() {
+        return Ok(HashMap::new());
+    }
+
+    let input: Vec<LongMemSaveInp> = memories
+        .iter()
+        .map(|memory| LongMemSaveInp {
+            text: memory.text.clone(),
+            author: memory.author.clone(),
+            created_at: memory.created_at,
+            source_type: memory.source_type,
+            source: memory.source.clone(),
+        })
+        .collect();
+    let ids = memory.save(&input, to).await?;
+    if ids.len() != memories.len() {
+        return Err(format!(
+            "Expected {} memories to be saved, {} were",
+            memories.len(),
+            ids.len()
+        )
+        .into());
+    }
+    memory.delete_category(from).await?;
+    Ok(memories
+        .into_iter()
+        .map(|memory| memory.id)
+        .zip(ids)
+        .collect())
+}
+
+async fn create(
+    convo: &dyn ShortMemEffect,
+    name: &str,
+    script: Script,
+) -> Result<(), Box<dyn Error>> {
+    if convo.exists().await? {
+        return Err(format!("A conversation named '{}' already exists", name).into());
+    }
+    convo.replace(script).await
+}
+
+fn print_turns(script: &Script, skip: usize) {
+    if let Some(persona) = &script.persona {
+        println!("persona: {}\n", persona);
+    }
+    for (i, turn) in script.turns().iter().enumerate().skip(skip) {
+        println!("--- turn {} ---", i + 1);
+        for DialogueSegment {
+            id,
+            role,
+            content,
+            created_at,
+            meta,
+            memory_id,
+            ..
+        } in turn.iter()
+        {
+            println!(
+                "[{} {}{}] {}:\n{}\n",
+                id,
+                format_time(created_at),
+                memory_id
+                    .as_ref()
+                    .map(|memory_id| format!(" memory {}", memory_id))
+                    .unwrap_or_default(),
+                role,
+                content
+            );
+            if let Some(meta) = meta {
+                println!("({})\n", describe(meta));
+            }
+        }
+    }
+}
+
+/// One line summary of how a response was produced
+fn describe(meta: &TurnMeta) -> String {
+    let mut parts = vec![
+        format!("{} {}", meta.provider, meta.model),
+        format!("temperature {}", meta.temperature),
+    ];
+    if let Some(persona) = &meta.persona {
+        parts.push(format!("as {}", persona));
+    }
+    if let Some(usage) = &meta.usage {
+        parts.push(format!(
+            "{} prompt + {} completion tokens",
+            usage.prompt_tokens, usage.completion_tokens
+        ));
+    }
+    if let Some(latency) = meta.latency_ms {
+        parts.push(format!("{:.1}s", latency as f64 / 1000.0));
+    }
+    if let Some(finish_reason) = &meta.finish_reason {
+        parts.push(format!("finished: {}", finish_reason));
+    }
+    if !meta.inputs.is_empty() {
+        let names: Vec<&str> = meta
+            .inputs
+            .iter()
+            .map(|input| input.name.as_str())
+            .collect();
+        parts.push(format!("inputs: {}", names.join(", ")));
+    }
+    if !meta.memories.is_empty() {
+        parts.push(format!("{} memories", meta.memories.len()));
+    }
+    parts.join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use chrono::Utc;
+    use tempfile::tempdir;
+
+    use crate::{
+        data::model::{MemoryStore, SourceType},
+        effect::{
+            self, bm25_memory::Bm25Memory, conversation::YamlHistory, search_index::SearchIndex,
+            LongMemEffect, LongMemQueryOpt, LongMemSaveInp, QueryWindow, ShortMemEffect,
+            ShortMemInput,
+        },
+    };
+
+    use super::rename;
+
+    fn history(dir: &Path, name: &str) -> YamlHistory {
+        YamlHistory::new(&dir.join(name).to_string_lossy())
+    }
+
+    async fn save(convo: &YamlHistory, memory_id: Option<String>) {
+        convo
+            .save_history(&[ShortMemInput {
+                id: None,
+                author: "user".to_string(),
+                content: "the vpn needs a token".to_string(),
+                meta: None,
+                memory_id,
+            }])
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn renames_without_a_memory_store() {
+        let dir = tempdir().unwrap();
+        save(&history(dir.path(), "a"), None).await;
+        rename(
+            &history(dir.path(), "a"),
+            &history(dir.path(), "b"),
+            None,
+            "a",
+            "b",
+        )
+        .await
+        .unwrap();
+        assert!(!history(dir.path(), "a").exists().await.unwrap());
+        assert_eq!(
+            1,
+            history(dir.path(), "b")
+                .load()
+                .await
+                .unwrap()
+                .dialogue
+                .len()
+        );
+
+        // a store that cannot be reached does not stop the rename
+        let unreachable = effect::context(
+            &MemoryStore::RetrievalPlugin {
+                url: "http://127.0.0.1:9".to_string(),
+                bearer: "token".to_string(),
+            },
+            0,
+            None,
+        );
+        rename(
+            &history(dir.path(), "b"),
+            &history(dir.path(), "c"),
+            Some(unreachable.as_ref()),
+            "b",
+            "c",
+        )
+        .await
+        .unwrap();
+        assert!(history(dir.path(), "c").exists().await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn renaming_moves_memories_to_the_new_name() {
+        let dir = tempdir().unwrap();
+        let memory = Bm25Memory::new(SearchIndex::new(dir.path().join("bm25.sqlite3")), 5);
+        let ids = memory
+            .save(
+                &[LongMemSaveInp {
+                    text: "the vpn needs a token".to_string(),
+                    author: "user".to_string(),
+                    created_at: Utc::now(),
+                    source_type: SourceType::Chat,
+                    source: None,
+                }],
+                "a",
+            )
+            .await
+            .unwrap();
+        save(&history(dir.path(), "a"), ids.first().cloned()).await;
+
+        rename(
+            &history(dir.path(), "a"),
+            &history(dir.path(), "b"),
+            Some(&memory),
+            "a",
+            "b",
+        )
+        .await
+        .unwrap();
+
+        let list = |category: &str| LongMemQueryOpt {
+            category: category.to_string(),
+            query_window: QueryWindow {
+                min: None,
+                max: None,
+            },
+            author: None,
+            source_type: None,
+        };
+        assert!(memory.list(&list("a"), 5).await.unwrap().is_empty());
+        let moved = memory.list(&list("b"), 5).await.unwrap();
+        assert_eq!(1, moved.len());
+        let script = history(dir.path(), "b").load().await.unwrap();
+        assert_eq!(Some(moved[0].id.clone()), script.dialogue[0].memory_id);
+    }
+}
@@ -0,0 +1,362 @@
+use std::error::Error;
+
+use chrono::{Local, NaiveDate};
+use serde::Serialize;
+
+use crate::{
+    data::args::ExportFormat,
+    effect::conversation::{DialogueSegment, Script},
+};
+
+pub struct ExportOptions {
+    pub format: ExportFormat,
+    /// System message to start with
+    pub system: Option<String>,
+    pub since: Option<NaiveDate>,
+    pub until: Option<NaiveDate>,
+}
+
+#[derive(Serialize)]
+struct JsonExport<'a> {
+    name: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    persona: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    system: Option<&'a str>,
+    dialogue: Vec<&'a DialogueSegment>,
+}
+
+#[derive(Serialize)]
+struct FineTuningMessage<'a> {
+    role: &'a str,
+    content: &'a str,
+}
+
+#[derive(Serialize)]
+struct FineTuningExample<'a> {
+    messages: Vec<FineTuningMessage<'a>>,
+}
+
+/// Renders the turns of the active branch in the date range, numbered from the start of the
+/// conversation
+pub fn export(
+    name: &str,
+    script: &Script,
+    options: &ExportOptions,
+) -> Result<String, Box<dyn Error>> {
+    let turns: Vec<(usize, Vec<&DialogueSegment>)> = script
+        .turns()
+        .into_iter()
+        .enumerate()
+        .map(|(i, turn)| (i + 1, turn))
+        .filter(|(_, turn)| {
+            let date = turn[0].created_at.with_timezone(&Local).date_naive();
+            options.since.map(|since| date >= since).unwrap_or(true)
+                && options.until.map(|until| date <= until).unwrap_or(true)
+        })
+        .collect();
+    if turns.is_empty() {
+        return Err(format!("No turns of '{}' to export", name).into());
+    }
+    let system = options.system.as_deref();
+
+    Ok(match options.format {
+        ExportFormat::Md => markdown(name, script.persona.as_deref(), system, &turns),
+        ExportFormat::Html => html(name, script.persona.as_deref(), system, &turns),
+        ExportFormat::Json => {
+            let export = JsonExport {
+                name,
+                persona: script.persona.as_deref(),
+                system,
+                dialogue: turns.into_iter().flat_map(|(_, turn)| turn).collect(),
+            };
+            format!("{}\n", serde_json::to_string_pretty(&export)?)
+        }
+        ExportFormat::OpenaiJsonl => {
+            let messages = system
+                .map(|system| FineTuningMessage {
+                    role: "system",
+                    content: system,
+                })
+                .into_iter()
+                .chain(turns.iter().flat_map(|(_, turn)| {
+                    turn.iter().map(|segment| FineTuningMessage {
+                        role: &segment.role,
+                        content: &segment.content,
+                    })
+                }))
+                .collect();
+            format!(
+                "{}\n",
+                serde_json::to_string(&FineTuningExample { messages })?
+            )
+        }
+    })
+}
+
+fn heading(number: usize, turn: &[&DialogueSegment]) -> String {
+    format!(
+        "Turn {} · {}",
+        number,
+        turn[0]
+            .created_at
+            .with_timezone(&Local)
+            .format("%Y-%m-%d %H:%M")
+    )
+}
+
+fn speaker(segment: &DialogueSegment) -> String {
+    let mut role = segment.role.clone();
+    if let Some(first) = role.get_mut(..1) {
+        first.make_ascii_uppercase();
+    }
+    match &segment.meta {
+        Some(meta) => format!("{} · {}", role, meta.model),
+        None => role,
+    }
+}
+
+fn markdown(
+    name: &str,
+    persona: Option<&str>,
+    system: Option<&str>,
+    turns: &[(usize, Vec<&DialogueSegment>)],
+) -> String {
+    let mut md = format!("# {}\n\n", name);
+    if let Some(persona) = persona {
+        md.push_str(&format!("Persona: {}\n\n", persona));
+    }
+    if let Some(system) = system {
+        md.push_str(&format!("**System**\n\n{}\n\n", close_fences(system)));
+    }
+    for (number, turn) in turns {
+        md.push_str(&format!("## {}\n\n", heading(*number, turn)));
+        for segment in turn {
+            md.push_str(&format!(
+                "**{}**\n\n{}\n\n",
+                speaker(segment),
+                close_fences(&segment.content)
+            ));
+        }
+    }
+    md.trim_end().to_string() + "\n"
+}
+
+/// Closes a code block left open at the end of a message so it does not swallow the rest of the
+/// transcript
+fn close_fences(content: &str) -> String {
+    let fences = content
+        .lines()
+        .filter(|line| line.trim_start().starts_with("```"))
+        .count();
+    let content = content.trim_end();
+    if fences % 2 == 1 {
+        format!("{}\n```", content)
+    } else {
+        content.to_string()
+    }
+}
+
+fn html(
+    name: &str,
+    persona: Option<&str>,
+    system: Option<&str>,
+    turns: &[(usize, Vec<&DialogueSegment>)],
+) -> String {
+    let mut body = format!("<h1>{}</h1>\n", escape(name));
+    if let Some(persona) = persona {
+        body.push_str(&format!(
+            "<p class=\"persona\">Persona: {}</p>\n",
+            escape(persona)
+        ));
+    }
+    if let Some(system) = system {
+        body.push_str(&format!(
+            "<div class=\"message system\">\n<h3>System</h3>\n{}</div>\n",
+            html_blocks(system)
+        ));
+    }
+    for (number, turn) in turns {
+        body.push_str(&format!(
+            "<section>\n<h2>{}</h2>\n",
+            escape(&heading(*number, turn))
+        ));
+        for segment in turn {
+            body.push_str(&format!(
+                "<div class=\"message {}\">\n<h3>{}</h3>\n{}</div>\n",
+                escape(&segment.role),
+                escape(&speaker(segment)),
+                html_blocks(&segment.content)
+            ));
+        }
+        body.push_str("</section>\n");
+    }
+
+    format!(
+        "<!DOCTYPE html>
+<html>
+<head>
+<meta charset=\"utf-8\">
+<title>{}</title>
+<style>
+body {{ font-family: sans-serif; max-width: 50em; margin: 2em auto; line-height: 1.5; }}
+.message {{ border-left: 3px solid #ccc; padding-left: 1em; margin: 1em 0; }}
+.assistant {{ border-color: #4a8; }}
+.system {{ border-color: #888; color: #555; }}
+pre {{ background: #f4f4f4; padding: 0.75em; overflow-x: auto; }}
+</style>
+</head>
+<body>
+{}</body>
+</html>
+",
+        escape(name),
+        body
+    )
+}
+
+/// Paragraphs and fenced code blocks of a message as html
+fn html_blocks(content: &str) -> String {
+    let mut html = String::new();
+    let mut paragraph: Vec<&str> = vec![];
+    // language and lines of the code block being read
+    let mut code: Option<(&str, Vec<&str>)> = None;
+
+    let flush_paragraph = |html: &mut String, paragraph: &mut Vec<&str>| {
+        if !paragraph.is_empty() {
+            let lines: Vec<String> = paragraph.iter().map(|line| escape(line)).collect();
+            html.push_str(&format!("<p>{}</p>\n", lines.join("<br>\n")));
+            paragraph.clear();
+        }
+    };
+    let flush_code = |html: &mut String, language: &str, lines: &[&str]| {
+        let class = if language.is_empty() {
+            String::new()
+        } else {
+            format!(" class=\"language-{}\"", escape(language))
+        };
+        html.push_str(&format!(
+            "<pre><code{}>{}</code></pre>\n",
+            class,
+            escape(&lines.join("\n"))
+        ));
+    };
+
+    for line in content.lines() {
+        let fence = line.trim_start().strip_prefix("```");
+        match (&mut code, fence) {
+            (Some((language, lines)), Some(_)) => {
+                flush_code(&mut html, language, lines);
+                code = None;
+            }
+            (Some((_, lines)), None) => lines.push(line),
+            (None, Some(language)) => {
+                flush_paragraph(&mut html, &mut paragraph);
+                code = Some((language.trim(), vec![]));
+            }
+            (None, None) if line.trim().is_empty() => flush_paragraph(&mut html, &mut paragraph),
+            (None, None) => paragraph.push(line),
+        }
+    }
+    if let Some((language, lines)) = &code {
+        flush_code(&mut html, language, lines);
+    }
+    flush_paragraph(&mut html, &mut paragraph);
+    html
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Local, NaiveDate, TimeZone, Utc};
+
+    use crate::{
+        data::args::ExportFormat,
+        effect::conversation::{DialogueSegment, Script},
+    };
+
+    use super::{export, ExportOptions};
+
+    const ANSWER: &str =
+        "Use a loop:\n\n```rust\nfor i in 0..3 {\n    println!(\"{}\", i < 2);\n}\n```";
+
+    fn script() -> Script {
+        let segment = |role: &str, content: &str, day: u32| DialogueSegment {
+            id: String::new(),
+            parent: None,
+            role: role.to_string(),
+            content: content.to_string(),
+            // the filter compares local dates
+            created_at: Local
+                .with_ymd_and_hms(2023, 5, day, 12, 0, 0)
+                .unwrap()
+                .with_timezone(&Utc),
+            meta: None,
+            memory_id: None,
+        };
+        Script {
+            dialogue: vec![
+                segment("user", "How do I count?", 1),
+                segment("assistant", ANSWER, 1),
+                segment("user", "Thanks", 3),
+                segment("assistant", "No problem", 3),
+            ],
+            ..Default::default()
+        }
+        .normalise()
+    }
+
+    fn options(format: ExportFormat) -> ExportOptions {
+        ExportOptions {
+            format,
+            system: None,
+            since: None,
+            until: None,
+        }
+    }
+
+    #[test]
+    fn markdown_keeps_code_blocks() {
+        let md = export("counting", &script(), &options(ExportFormat::Md)).unwrap();
+        assert!(md.starts_with("# counting\n"));
+        assert!(md.contains(ANSWER));
+        assert!(md.contains("## Turn 2"));
+    }
+
+    #[test]
+    fn html_escapes_text_and_renders_code() {
+        let html = export("counting", &script(), &options(ExportFormat::Html)).unwrap();
+        assert!(html.contains(
+            "<pre><code class=\"language-rust\">for i in 0..3 {\n    println!(&quot;{}&quot;, i &lt; 2);\n}</code></pre>"
+        ));
+        assert!(html.contains("<p>Use a loop:</p>"));
+    }
+
+    #[test]
+    fn fine_tuning_data_filters_turns_by_date() {
+        let options = ExportOptions {
+            system: Some("You count.".to_string()),
+            since: NaiveDate::from_ymd_opt(2023, 5, 2),
+            ..options(ExportFormat::OpenaiJsonl)
+        };
+        let jsonl = export("counting", &script(), &options).unwrap();
+        assert_eq!(
+            "{\"messages\":[{\"role\":\"system\",\"content\":\"You count.\"},\
+            {\"role\":\"user\",\"content\":\"Thanks\"},\
+            {\"role\":\"assistant\",\"content\":\"No problem\"}]}\n",
+            jsonl
+        );
+
+        let options = ExportOptions {
+            until: NaiveDate::from_ymd_opt(2023, 4, 30),
+            ..options
+        };
+        assert!(export("counting", &script(), &options).is_err());
+    }
+}
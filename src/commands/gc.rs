@@ -0,0 +1,88 @@
+use std::error::Error;
+
+use chrono::Local;
+
+use crate::{
+    data::args::{CliArgs, ConfigArgs},
+    effect::{self, retention},
+    utils::{check_name, snippet},
+};
+
+pub async fn run(
+    names: &[String],
+    dry_run: bool,
+    memory: bool,
+    args: &CliArgs,
+    config_args: &ConfigArgs,
+) -> Result<(), Box<dyn Error>> {
+    let retention = config_args
+        .retention
+        .clone()
+        .ok_or("Set limits under retention in config.yaml to remove old turns")?;
+    let convo_dir = args.convo_dir(config_args)?;
+    let backend = args.history_backend(config_args);
+    let cipher = args.history_cipher(config_args, &convo_dir)?;
+    let context = match memory {
+        true => Some(effect::context(&args.memory_store(config_args)?, 3, None)),
+        false => None,
+    };
+    let names = if names.is_empty() {
+        effect::conversations(&backend, &convo_dir)?
+    } else {
+        names.to_vec()
+    };
+
+    for name in names {
+        let policy = retention.policy(&name);
+        if policy.is_empty() {
+            continue;
+        }
+        check_name("conversation", &name)?;
+        let history = effect::history(&backend, &convo_dir, &name, cipher.as_ref());
+        if !history.exists().await? {
+            return Err(format!("No conversation named '{}'", name).into());
+        }
+        let pruned = retention::prune(
+            history.as_ref(),
+            context.as_deref(),
+            &name,
+            &policy,
+            dry_run,
+        )
+        .await?;
+        if pruned.turns.is_empty() {
+            continue;
+        }
+
+        if dry_run {
+            for turn in &pruned.turns {
+                let question = turn
+                    .iter()
+                    .find(|segment| segment.role == "user")
+                    .unwrap_or(&turn[0]);
+                println!(
+                    "{}\t{}\t{}\t{}",
+                    name,
+                    question.id,
+                    question
+                        .created_at
+                        .with_timezone(&Local)
+                        .format("%Y-%m-%d %H:%M"),
+                    snippet(&question.content)
+                );
+            }
+        }
+        eprintln!(
+            "{} {} turns ({} bytes){} from '{}'",
+            if dry_run { "Would remove" } else { "Removed" },
+            pruned.turns.len(),
+            pruned.bytes,
+            match memory {
+                true => format!(" and {} long term memories", pruned.memories.len()),
+                false => String::new(),
+            },
+            name
+        );
+    }
+    Ok(())
+}
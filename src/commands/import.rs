@@ -0,0 +1,290 @@
+use std::{collections::HashSet, error::Error};
+
+use chrono::{DateTime, TimeZone, Utc};
+use serde_json::Value;
+
+use crate::{
+    data::{
+        args::ImportFormat,
+        dtos::{ChatGptConversationDTO, ChatGptMessageDTO},
+    },
+    effect::conversation::{DialogueSegment, Script},
+};
+
+/// A conversation read from another tool's export
+pub struct Imported {
+    pub name: String,
+    pub script: Script,
+}
+
+/// Reads the conversations in an export. `file_stem` names conversations when the export does
+/// not
+pub fn parse(
+    format: ImportFormat,
+    contents: &str,
+    file_stem: &str,
+) -> Result<Vec<Imported>, Box<dyn Error>> {
+    let now = Utc::now();
+    let imported = match format {
+        ImportFormat::Chatgpt => {
+            let conversations: Vec<ChatGptConversationDTO> = serde_json::from_str(contents)?;
+            conversations
+                .iter()
+                .enumerate()
+                .map(|(i, conversation)| chatgpt(conversation, i, now))
+                .collect()
+        }
+        ImportFormat::Messages => {
+            let value: Value = serde_json::from_str(contents)?;
+            let name = value
+                .get("name")
+                .and_then(Value::as_str)
+                .unwrap_or(file_stem);
+            vec![Imported {
+                name: slug(name),
+                script: messages(&value, now)?,
+            }]
+        }
+        ImportFormat::OpenaiJsonl => contents
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty())
+            .map(|(i, line)| {
+                Ok(Imported {
+                    name: slug(&format!("{}-{}", file_stem, i + 1)),
+                    script: messages(&serde_json::from_str(line)?, now)?,
+                })
+            })
+            .collect::<Result<Vec<Imported>, Box<dyn Error>>>()?,
+    };
+
+    Ok(imported
+        .into_iter()
+        .filter(|imported| !imported.script.dialogue.is_empty())
+        .collect())
+}
+
+fn segment(role: &str, content: String, created_at: DateTime<Utc>) -> DialogueSegment {
+    DialogueSegment {
+        id: String::new(),
+        parent: None,
+        role: role.to_string(),
+        content,
+        created_at,
+        meta: None,
+        memory_id: None,
+    }
+}
+
+/// Only the user's messages and the responses to them are kept, system prompts and tool calls
+/// are not part of a conversation's history
+fn is_dialogue(role: &str) -> bool {
+    role == "user" || role == "assistant"
+}
+
+/// Flattens the conversation to the branch ending at its current node
+fn chatgpt(conversation: &ChatGptConversationDTO, index: usize, now: DateTime<Utc>) -> Imported {
+    let mapping = &conversation.mapping;
+    // exports from before current_node was added end at the latest message
+    let current = conversation.current_node.clone().or_else(|| {
+        mapping
+            .iter()
+            .filter_map(|(id, node)| Some((id, node.message.as_ref()?.create_time?)))
+            .max_by(|a, b| a.1.total_cmp(&b.1))
+            .map(|(id, _)| id.clone())
+    });
+
+    let mut branch = vec![];
+    let mut seen = HashSet::new();
+    let mut next = current;
+    while let Some(node) = next.as_ref().and_then(|id| mapping.get(id)) {
+        if !seen.insert(next.clone()) {
+            break;
+        }
+        branch.extend(node.message.as_ref());
+        next = node.parent.clone();
+    }
+    branch.reverse();
+
+    let dialogue = branch
+        .into_iter()
+        .filter(|message| is_dialogue(&message.author.role) && !is_hidden(message))
+        .filter_map(|message| {
+            let text = chatgpt_text(message)?;
+            let created_at = message
+                .create_time
+                .and_then(|time| Utc.timestamp_millis_opt((time * 1000.0) as i64).single())
+                .unwrap_or(now);
+            Some(segment(&message.author.role, text, created_at))
+        })
+        .collect();
+
+    let id = conversation
+        .conversation_id
+        .as_ref()
+        .or(conversation.id.as_ref())
+        .map(|id| id.chars().take(8).collect::<String>())
+        .unwrap_or_else(|| (index + 1).to_string());
+    let title = conversation
+        .title
+        .as_deref()
+        .filter(|title| !title.trim().is_empty())
+        .unwrap_or("chatgpt");
+
+    Imported {
+        name: slug(&format!("{}-{}", title, id)),
+        script: Script {
+            dialogue,
+            ..Default::default()
+        }
+        .normalise(),
+    }
+}
+
+fn is_hidden(message: &ChatGptMessageDTO) -> bool {
+    message
+        .metadata
+        .as_ref()
+        .and_then(|metadata| metadata.get("is_visually_hidden_from_conversation"))
+        .and_then(Value::as_bool)
+        .unwrap_or(false)
+}
+
+fn chatgpt_text(message: &ChatGptMessageDTO) -> Option<String> {
+    let content = message.content.as_ref()?;
+    let text = match &content.text {
+        Some(text) => text.clone(),
+        None => content
+            .parts
+            .iter()
+            .filter_map(Value::as_str)
+            .collect::<Vec<&str>>()
+            .join("\n"),
+    };
+    Some(text).filter(|text| !text.trim().is_empty())
+}
+
+/// Messages from a list, or from the `messages` or `dialogue` list of an object
+fn messages(value: &Value, now: DateTime<Utc>) -> Result<Script, Box<dyn Error>> {
+    let list = value
+        .as_array()
+        .or_else(|| value.get("messages").and_then(Value::as_array))
+        .or_else(|| value.get("dialogue").and_then(Value::as_array))
+        .ok_or("Expected a list of messages, or an object with a messages list")?;
+
+    let dialogue = list
+        .iter()
+        .filter_map(|message| {
+            let role = message.get("role")?.as_str()?;
+            let text = match message.get("content")? {
+                Value::String(text) => text.clone(),
+                // content parts of multimodal messages
+                Value::Array(parts) => parts
+                    .iter()
+                    .filter_map(|part| part.get("text").and_then(Value::as_str))
+                    .collect::<Vec<&str>>()
+                    .join("\n"),
+                _ => return None,
+            };
+            let created_at = message
+                .get("created_at")
+                .and_then(Value::as_str)
+                .and_then(|time| DateTime::parse_from_rfc3339(time).ok())
+                .map(|time| time.with_timezone(&Utc))
+                .unwrap_or(now);
+            Some(segment(role, text, created_at))
+        })
+        .filter(|segment| is_dialogue(&segment.role) && !segment.content.trim().is_empty())
+        .collect();
+
+    Ok(Script {
+        dialogue,
+        ..Default::default()
+    }
+    .normalise())
+}
+
+/// A conversation name made of lowercase letters, digits and dashes
+fn slug(name: &str) -> String {
+    let slug = name
+        .to_lowercase()
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<&str>>()
+        .join("-");
+    slug.chars()
+        .take(60)
+        .collect::<String>()
+        .trim_end_matches('-')
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::data::args::ImportFormat;
+
+    use super::parse;
+
+    fn contents(imported: &super::Imported) -> Vec<(&str, &str)> {
+        imported
+            .script
+            .dialogue
+            .iter()
+            .map(|segment| (segment.role.as_str(), segment.content.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn chatgpt_conversations_keep_their_current_branch() {
+        let export = r#"[{
+            "title": "Rust: lifetimes?",
+            "conversation_id": "0f3a9c2e-aaaa-bbbb",
+            "current_node": "c",
+            "mapping": {
+                "root": {"message": null, "parent": null, "children": ["s"]},
+                "s": {"message": {"author": {"role": "system"}, "content": {"content_type": "text", "parts": [""]}}, "parent": "root"},
+                "u": {"message": {"author": {"role": "user"}, "create_time": 1690000000.5, "content": {"content_type": "text", "parts": ["What is 'a?"]}}, "parent": "s"},
+                "b": {"message": {"author": {"role": "assistant"}, "content": {"content_type": "text", "parts": ["An old answer"]}}, "parent": "u"},
+                "c": {"message": {"author": {"role": "assistant"}, "content": {"content_type": "text", "parts": ["A lifetime"]}}, "parent": "u"}
+            }
+        }]"#;
+
+        let imported = parse(ImportFormat::Chatgpt, export, "conversations").unwrap();
+        assert_eq!(1, imported.len());
+        assert_eq!("rust-lifetimes-0f3a9c2e", imported[0].name);
+        assert_eq!(
+            vec![("user", "What is 'a?"), ("assistant", "A lifetime")],
+            contents(&imported[0])
+        );
+        assert_eq!(
+            1690000000,
+            imported[0].script.dialogue[0].created_at.timestamp()
+        );
+    }
+
+    #[test]
+    fn message_lists_skip_system_prompts() {
+        let chat = r#"[
+            {"role": "system", "content": "You are a shell assistant"},
+            {"role": "user", "content": "list files"},
+            {"role": "assistant", "content": "ls -la"}
+        ]"#;
+        let imported = parse(ImportFormat::Messages, chat, "Shell Chat").unwrap();
+        assert_eq!("shell-chat", imported[0].name);
+        assert_eq!(
+            vec![("user", "list files"), ("assistant", "ls -la")],
+            contents(&imported[0])
+        );
+
+        let jsonl = "{\"messages\":[{\"role\":\"user\",\"content\":\"hi\"}]}\n\n\
+            {\"messages\":[{\"role\":\"user\",\"content\":\"bye\"}]}\n";
+        let imported = parse(ImportFormat::OpenaiJsonl, jsonl, "dataset").unwrap();
+        assert_eq!(
+            vec!["dataset-1", "dataset-3"],
+            imported
+                .iter()
+                .map(|imported| imported.name.as_str())
+                .collect::<Vec<&str>>()
+        );
+    }
+}
@@ -0,0 +1,372 @@
+use std::{
+    collections::BTreeMap,
+    error::Error,
+    path::{Path, PathBuf},
+};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    data::{
+        args::{CliArgs, ConfigArgs, IngestArgs},
+        chunks::chunk,
+        inputs::{glob_root, is_glob, read_files},
+        model::SourceType,
+    },
+    effect::{self, LongMemEffect, LongMemSaveInp, MemorySource},
+    utils,
+};
+
+/// Author of the memories of ingested files
+pub const DOCUMENT_AUTHOR: &str = "document";
+
+/// What was saved for each ingested file of a category, by the file's canonical path
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Manifest {
+    pub files: BTreeMap<PathBuf, IngestedFile>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IngestedFile {
+    pub sha256: String,
+    pub ids: Vec<String>,
+}
+
+impl Manifest {
+    fn load(path: &Path) -> Result<Manifest, Box<dyn Error>> {
+        match path.exists() {
+            true => utils::deserialise_from_file(&path.to_string_lossy())
+                .map_err(|e| format!("Could not read '{}': {}", path.display(), e).into()),
+            false => Ok(Manifest::default()),
+        }
+    }
+
+    fn save(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        Ok(utils::write_atomic(
+            path,
+            serde_yaml::to_string(self)?.as_bytes(),
+        )?)
+    }
+}
+
+#[derive(Debug)]
+pub struct IngestOptions {
+    pub chunk_size: usize,
+    pub overlap: usize,
+    pub batch_size: usize,
+    pub max_file_size: u64,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Ingested {
+    pub files: usize,
+    pub chunks: usize,
+    pub unchanged: usize,
+    pub removed: usize,
+}
+
+/// A file waiting to be saved with the next batch
+struct Pending {
+    path: PathBuf,
+    sha256: String,
+    chunks: Vec<LongMemSaveInp>,
+}
+
+pub async fn run(
+    ingest: &IngestArgs,
+    args: &CliArgs,
+    config_args: &ConfigArgs,
+) -> Result<(), Box<dyn Error>> {
+    // the category names the manifest file
+    utils::check_name("category", &ingest.category)?;
+    let context = effect::context(&args.memory_store(config_args)?, 0, None);
+    let manifest = Path::new(&args.memory_dir(config_args)?)
+        .join("ingested")
+        .join(format!("{}.yaml", ingest.category));
+    let ingested = ingest_paths(
+        context.as_ref(),
+        &manifest,
+        &ingest.paths,
+        &ingest.category,
+        &IngestOptions {
+            chunk_size: ingest.chunk_size,
+            overlap: ingest.overlap,
+            batch_size: ingest.batch_size,
+            max_file_size: args
+                .max_file_size
+                .unwrap_or(config_args.max_file_size.unwrap_or(256 * 1024)),
+        },
+    )
+    .await?;
+    eprintln!(
+        "Ingested {} files ({} chunks) into '{}', {} unchanged, {} removed",
+        ingested.files, ingested.chunks, ingest.category, ingested.unchanged, ingested.removed
+    );
+    Ok(())
+}
+
+/// Saves the chunks of the files, directories and globs to the category. Files are skipped when
+/// unchanged since they were last ingested, the memories of changed files are replaced and
+/// those of deleted files removed. The manifest records what was saved for each file
+pub async fn ingest_paths(
+    context: &dyn LongMemEffect,
+    manifest_path: &Path,
+    paths: &[String],
+    category: &str,
+    options: &IngestOptions,
+) -> Result<Ingested, Box<dyn Error>> {
+    let mut manifest = Manifest::load(manifest_path)?;
+    let mut ingested = Ingested::default();
+
+    let mut roots = vec![];
+    let mut patterns = vec![];
+    for path in paths {
+        let path = path.trim_end_matches('/');
+        if is_glob(path) && !Path::new(path).exists() {
+            roots.push(absolute(&glob_root(path))?);
+            patterns.push(path.to_string());
+        } else if Path::new(path).is_dir() {
+            roots.push(absolute(Path::new(path))?);
+            patterns.push(format!("{}/**/*", path));
+        } else {
+            roots.push(absolute(Path::new(path))?);
+            if Path::new(path).exists() {
+                patterns.push(path.to_string());
+            }
+        }
+    }
+
+    let mut pending: Vec<Pending> = vec![];
+    for input in read_files(&patterns, options.max_file_size)? {
+        let path = absolute(Path::new(&input.name))?;
+        let sha256 = format!("{:x}", Sha256::digest(input.content.as_bytes()));
+        if let Some(file) = manifest.files.get(&path) {
+            if file.sha256 == sha256 {
+                ingested.unchanged += 1;
+                continue;
+            }
+            forget(context, &mut manifest, &path).await?;
+        }
+
+        let modified: DateTime<Utc> = std::fs::metadata(&path)?.modified()?.into();
+        let source_path = path.to_string_lossy().to_string();
+        let chunks: Vec<LongMemSaveInp> =
+            chunk(&path, &input.content, options.chunk_size, options.overlap)
+                .into_iter()
+                .map(|chunk| LongMemSaveInp {
+                    text: chunk.text,
+                    author: DOCUMENT_AUTHOR.to_string(),
+                    created_at: modified,
+                    source_type: SourceType::Document,
+                    source: Some(MemorySource {
+                        path: source_path.clone(),
+                        offset: chunk.offset,
+                    }),
+                })
+                .collect();
+        eprintln!("{}: {} chunks", input.name, chunks.len());
+        ingested.files += 1;
+        ingested.chunks += chunks.len();
+        pending.push(Pending {
+            path,
+            sha256,
+            chunks,
+        });
+
+        if pending.iter().map(|file| file.chunks.len()).sum::<usize>() >= options.batch_size {
+            save(
+                context,
+                &mut manifest,
+                manifest_path,
+                category,
+                options.batch_size,
+                &mut pending,
+            )
+            .await?;
+        }
+    }
+    save(
+        context,
+        &mut manifest,
+        manifest_path,
+        category,
+        options.batch_size,
+        &mut pending,
+    )
+    .await?;
+
+    let deleted: Vec<PathBuf> = manifest
+        .files
+        .keys()
+        .filter(|path| roots.iter().any(|root| path.starts_with(root)) && !path.exists())
+        .cloned()
+        .collect();
+    for path in deleted {
+        eprintln!("{}: removed", path.display());
+        forget(context, &mut manifest, &path).await?;
+        ingested.removed += 1;
+    }
+    manifest.save(manifest_path)?;
+    Ok(ingested)
+}
+
+/// Saves the pending files in batches, recording the ids of their memories in the manifest
+async fn save(
+    context: &dyn LongMemEffect,
+    manifest: &mut Manifest,
+    manifest_path: &Path,
+    category: &str,
+    batch_size: usize,
+    pending: &mut Vec<Pending>,
+) -> Result<(), Box<dyn Error>> {
+    let counts: Vec<usize> = pending.iter().map(|file| file.chunks.len()).collect();
+    let chunks: Vec<LongMemSaveInp> = pending
+        .iter_mut()
+        .flat_map(|file| std::mem::take(&mut file.chunks))
+        .collect();
+    let mut ids = vec![];
+    for batch in chunks.chunks(batch_size.max(1)) {
+        ids.extend(context.save(batch, category).await?);
+    }
+    if ids.len() != chunks.len() {
+        return Err(format!(
+            "Expected {} memories to be saved, {} were",
+            chunks.len(),
+            ids.len()
+        )
+        .into());
+    }
+
+    let mut ids = ids.into_iter();
+    for (file, count) in pending.drain(..).zip(counts) {
+        manifest.files.insert(
+            file.path,
+            IngestedFile {
+                sha256: file.sha256,
+                ids: ids.by_ref().take(count).collect(),
+            },
+        );
+    }
+    manifest.save(manifest_path)
+}
+
+/// Deletes the memories saved for the file
+async fn forget(
+    context: &dyn LongMemEffect,
+    manifest: &mut Manifest,
+    path: &Path,
+) -> Result<(), Box<dyn Error>> {
+    if let Some(file) = manifest.files.remove(path) {
+        for id in &file.ids {
+            context.delete(id).await?;
+        }
+    }
+    Ok(())
+}
+
+/// The path from the root, without `.` or `..`, as files may be ingested from anywhere
+fn absolute(path: &Path) -> Result<PathBuf, Box<dyn Error>> {
+    Ok(match path.canonicalize() {
+        Ok(path) => path,
+        Err(_) => std::env::current_dir()?.join(path),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use crate::effect::{
+        bm25_memory::Bm25Memory, search_index::SearchIndex, LongMemEffect, LongMemQueryOpt,
+        QueryWindow,
+    };
+
+    use super::{ingest_paths, IngestOptions, Ingested, Manifest};
+
+    #[tokio::test]
+    async fn reingests_changed_files_and_removes_deleted_ones() {
+        let dir = tempdir().unwrap();
+        let docs = dir.path().join("docs");
+        std::fs::create_dir_all(&docs).unwrap();
+        std::fs::write(docs.join("a.md"), "# Deploy\n\nRun make deploy.\n").unwrap();
+        std::fs::write(docs.join("b.md"), "# Lunch\n\nLunch is at noon.\n").unwrap();
+        let memory = Bm25Memory::new(SearchIndex::new(dir.path().join("bm25.sqlite3")), 10);
+        let manifest = dir.path().join("ingested").join("docs.yaml");
+        let options = IngestOptions {
+            chunk_size: 1500,
+            overlap: 200,
+            batch_size: 1,
+            max_file_size: 1024,
+        };
+        let paths = vec![docs.to_string_lossy().to_string()];
+        let ingest = || ingest_paths(&memory, &manifest, &paths, "docs", &options);
+        let texts = |query: &'static str| async {
+            memory
+                .query(
+                    query,
+                    &[LongMemQueryOpt {
+                        category: "docs".to_string(),
+                        query_window: QueryWindow {
+                            min: None,
+                            max: None,
+                        },
+                        author: None,
+                        source_type: None,
+                    }],
+                )
+                .await
+                .unwrap()
+                .into_iter()
+                .map(|output| {
+                    let source = output.source.unwrap();
+                    assert_eq!(0, source.offset);
+                    output.text
+                })
+                .collect::<Vec<String>>()
+        };
+
+        let first = ingest().await.unwrap();
+        assert_eq!(
+            Ingested {
+                files: 2,
+                chunks: 2,
+                unchanged: 0,
+                removed: 0
+            },
+            first
+        );
+        assert_eq!(
+            vec!["# Deploy\n\nRun make deploy.\n"],
+            texts("deploy").await
+        );
+
+        std::fs::write(docs.join("a.md"), "# Deploy\n\nRun make release.\n").unwrap();
+        std::fs::remove_file(docs.join("b.md")).unwrap();
+        let second = ingest().await.unwrap();
+        assert_eq!(
+            Ingested {
+                files: 1,
+                chunks: 1,
+                unchanged: 0,
+                removed: 1
+            },
+            second
+        );
+        assert_eq!(
+            vec!["# Deploy\n\nRun make release.\n"],
+            texts("deploy").await
+        );
+        assert!(texts("lunch").await.is_empty());
+
+        let third = ingest().await.unwrap();
+        assert_eq!(1, third.unchanged);
+        let saved: Manifest =
+            crate::utils::deserialise_from_file(&manifest.to_string_lossy()).unwrap();
+        assert_eq!(1, saved.files.len());
+    }
+}
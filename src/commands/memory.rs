@@ -0,0 +1,142 @@
+use std::error::Error;
+
+use chrono::Utc;
+
+use crate::{
+    data::{
+        args::{CliArgs, ConfigArgs, MemoryCommand},
+        model::SourceType,
+    },
+    effect::{self, outbox::Outbox, LongMemOutput, LongMemQueryOpt, LongMemSaveInp, QueryWindow},
+    utils::{format_time, snippet},
+};
+
+const ALL_TIME: QueryWindow = QueryWindow {
+    min: None,
+    max: None,
+};
+
+pub async fn run(
+    command: &MemoryCommand,
+    args: &CliArgs,
+    config_args: &ConfigArgs,
+) -> Result<(), Box<dyn Error>> {
+    let store = args.memory_store(config_args)?;
+
+    match command {
+        MemoryCommand::List {
+            category,
+            limit,
+            filter,
+        } => {
+            let context = effect::context(&store, 0, None);
+            let options = LongMemQueryOpt::filtered(category, ALL_TIME, &filter.filter());
+            for memory in context.list(&options, *limit).await? {
+                println!(
+                    "{}\t{}\t{}\t{}\t{}",
+                    memory.id,
+                    format_time(&memory.created_at),
+                    memory.author,
+                    memory.source_type.as_str(),
+                    snippet(&memory.text)
+                );
+            }
+        }
+        MemoryCommand::Search {
+            query,
+            category,
+            limit,
+            filter,
+        } => {
+            // ranked as they would be for a request
+            let context = effect::context(&store, *limit as u32, config_args.rerank.as_ref());
+            let options = [LongMemQueryOpt::filtered(
+                category,
+                ALL_TIME,
+                &filter.filter(),
+            )];
+            for memory in context.query(&query.join(" "), &options).await? {
+                println!(
+                    "{}\t{:.3}\t{}\t{}\t{}",
+                    memory.id,
+                    memory.score,
+                    format_time(&memory.created_at),
+                    memory.author,
+                    snippet(&memory.text)
+                );
+            }
+        }
+        MemoryCommand::Add { text, category } => {
+            let context = effect::context(&store, 0, None);
+            let note = LongMemSaveInp {
+                text: text.clone(),
+                author: "user".to_string(),
+                created_at: Utc::now(),
+                source_type: SourceType::Note,
+                source: None,
+            };
+            for id in context.save(&[note], category).await? {
+                println!("{}", id);
+            }
+        }
+        MemoryCommand::Show { id } => {
+            let context = effect::context(&store, 0, None);
+            let memory = context
+                .get(id)
+                .await?
+                .ok_or(format!("No memory with id '{}'", id))?;
+            print_memory(&memory);
+        }
+        MemoryCommand::Rm { ids } => {
+            let context = effect::context(&store, 0, None);
+            for id in ids {
+                if context.get(id).await?.is_none() {
+                    return Err(format!("No memory with id '{}'", id).into());
+                }
+                context.delete(id).await?;
+                eprintln!("Deleted memory {}", id);
+            }
+        }
+        MemoryCommand::Purge { category } => {
+            let context = effect::context(&store, 0, None);
+            context.delete_category(category).await?;
+            eprintln!("Deleted the memories in '{}'", category);
+        }
+        MemoryCommand::Flush => {
+            let context = effect::context(&store, 0, None);
+            let convo_dir = args.convo_dir(config_args)?;
+            let backend = args.history_backend(config_args);
+            let cipher = args.history_cipher(config_args, &convo_dir)?;
+            let history = |name: &str| effect::history(&backend, &convo_dir, name, cipher.as_ref());
+            let flushed = Outbox::new(args.outbox(config_args)?.into())
+                .flush(context.as_ref(), &history, true)
+                .await?;
+            for failure in &flushed.failed {
+                eprintln!("Could not save the memories queued for {}", failure);
+            }
+            for unlinked in &flushed.unlinked {
+                eprintln!(
+                    "Could not record the saved memories in conversation {}",
+                    unlinked
+                );
+            }
+            eprintln!(
+                "Saved {} queued writes ({} memories), {} still queued",
+                flushed.saved, flushed.memories, flushed.waiting
+            );
+        }
+    }
+    Ok(())
+}
+
+fn print_memory(memory: &LongMemOutput) {
+    println!("id: {}", memory.id);
+    println!("category: {}", memory.category);
+    println!("author: {}", memory.author);
+    println!("type: {}", memory.source_type.as_str());
+    println!("created_at: {}", format_time(&memory.created_at));
+    if let Some(source) = &memory.source {
+        println!("source: {}", source.to_url());
+    }
+    println!("\n{}", memory.text);
+}
@@ -0,0 +1,9 @@
+pub mod convo;
+mod export;
+pub mod gc;
+mod import;
+pub mod ingest;
+pub mod memory;
+pub mod personas;
+pub mod search;
+pub mod templates;
@@ -0,0 +1,21 @@
+use std::{collections::HashMap, error::Error};
+
+use crate::data::persona::Persona;
+
+pub fn run(personas: &HashMap<String, Persona>) -> Result<(), Box<dyn Error>> {
+    if personas.is_empty() {
+        eprintln!("No personas configured, add them under 'personas' in config.yaml");
+    }
+
+    let mut names: Vec<&String> = personas.keys().collect();
+    names.sort();
+    for name in names {
+        let persona = &personas[name];
+        let model = persona.model.clone().unwrap_or("-".to_string());
+        match &persona.description {
+            Some(description) => println!("{}\t{}\t{}", name, model, description),
+            None => println!("{}\t{}", name, model),
+        }
+    }
+    Ok(())
+}
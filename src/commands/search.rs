@@ -0,0 +1,149 @@
+use std::{collections::HashSet, error::Error};
+
+use chrono::Local;
+
+use crate::{
+    data::args::{CliArgs, ConfigArgs, SearchArgs},
+    effect::{
+        self,
+        search_index::{terms, SearchFilter},
+    },
+    utils::start_of,
+};
+
+/// Characters of a message shown around the words that matched
+const SNIPPET_WIDTH: usize = 160;
+
+pub async fn run(
+    search: &SearchArgs,
+    args: &CliArgs,
+    config_args: &ConfigArgs,
+) -> Result<(), Box<dyn Error>> {
+    if config_args.encryption.is_some() {
+        return Err("Encrypted conversations are not added to the search index".into());
+    }
+    let convo_dir = args.convo_dir(config_args)?;
+    let backend = args.history_backend(config_args);
+    let index = effect::search_index(&backend, &convo_dir, search.reindex).await?;
+
+    let filter = SearchFilter {
+        collections: search.convo.clone(),
+        author: search.role.map(|role| role.as_str().to_string()),
+        source_type: None,
+        since: search.since.and_then(start_of),
+        // the whole of the last day is included
+        until: search
+            .until
+            .and_then(|until| until.succ_opt())
+            .and_then(start_of),
+    };
+    let query = search.query.join(" ");
+    let hits = index.search(&query, filter, search.limit).await?;
+    if hits.is_empty() {
+        eprintln!("No messages found for '{}'", query);
+    }
+
+    let query: HashSet<String> = terms(&query).into_iter().collect();
+    for hit in hits {
+        println!(
+            "{} {} [{}] {} ({:.2})\n    {}\n",
+            hit.collection,
+            hit.document.key,
+            hit.document
+                .created_at
+                .with_timezone(&Local)
+                .format("%Y-%m-%d %H:%M"),
+            hit.document.author,
+            hit.score,
+            snippet(&hit.document.text, &query)
+        );
+    }
+    Ok(())
+}
+
+/// The part of the text with the most of the query's words in it, on one line
+fn snippet(text: &str, query: &HashSet<String>) -> String {
+    let chars: Vec<char> = text
+        .split_whitespace()
+        .collect::<Vec<&str>>()
+        .join(" ")
+        .chars()
+        .collect();
+    if chars.len() <= SNIPPET_WIDTH {
+        return chars.into_iter().collect();
+    }
+
+    // start and lowercased text of each word
+    let mut words: Vec<(usize, String)> = vec![];
+    let mut start = None;
+    for (i, c) in chars.iter().chain([' '].iter()).enumerate() {
+        match (start, c.is_alphanumeric() || *c == '_') {
+            (None, true) => start = Some(i),
+            (Some(from), false) => {
+                words.push((
+                    from,
+                    chars[from..i].iter().collect::<String>().to_lowercase(),
+                ));
+                start = None;
+            }
+            _ => (),
+        }
+    }
+    let matches: Vec<&(usize, String)> = words
+        .iter()
+        .filter(|(_, word)| query.contains(word))
+        .collect();
+
+    let best = matches
+        .iter()
+        .map(|(from, _)| {
+            let matched: HashSet<&String> = matches
+                .iter()
+                .filter(|(at, _)| at >= from && *at < from + SNIPPET_WIDTH)
+                .map(|(_, word)| word)
+                .collect();
+            (*from, matched.len())
+        })
+        // the earliest of the best windows
+        .max_by(|a, b| a.1.cmp(&b.1).then(b.0.cmp(&a.0)))
+        .map(|(from, _)| from)
+        .unwrap_or(0);
+
+    // a little of what comes before the first match, starting at a word
+    let from = words
+        .iter()
+        .map(|(at, _)| *at)
+        .filter(|at| *at + SNIPPET_WIDTH / 4 >= best && *at <= best)
+        .min()
+        .unwrap_or(best);
+    let to = (from + SNIPPET_WIDTH).min(chars.len());
+    format!(
+        "{}{}{}",
+        if from > 0 { "…" } else { "" },
+        chars[from..to].iter().collect::<String>().trim(),
+        if to < chars.len() { "…" } else { "" }
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::snippet;
+
+    #[test]
+    fn snippets_show_the_words_that_matched() {
+        let query: HashSet<String> = ["cancellation", "tokio"].map(String::from).into();
+        let text = format!(
+            "{}\n\nIn tokio, cancellation happens when a future is dropped. {}",
+            "Some unrelated introduction. ".repeat(10),
+            "More detail follows. ".repeat(10)
+        );
+        let shown = snippet(&text, &query);
+        assert!(shown.starts_with('…') && shown.ends_with('…'));
+        assert!(shown.contains("In tokio, cancellation happens"));
+        assert!(!shown.contains('\n'));
+
+        assert_eq!("short answer", snippet("short \n answer", &query));
+    }
+}
@@ -0,0 +1,39 @@
+use std::error::Error;
+
+use crate::data::{args::TemplatesCommand, template::Template};
+
+pub fn run(command: &TemplatesCommand, templates_dir: &str) -> Result<(), Box<dyn Error>> {
+    match command {
+        TemplatesCommand::List => {
+            let templates = Template::list(templates_dir)?;
+            if templates.is_empty() {
+                eprintln!("No templates found in {}", templates_dir);
+            }
+            for template in templates {
+                match template.defaults.description {
+                    Some(description) => println!("{}\t{}", template.name, description),
+                    None => println!("{}", template.name),
+                }
+            }
+        }
+        TemplatesCommand::Show { name } => {
+            let template = Template::load(templates_dir, name)?;
+            let defaults = &template.defaults;
+            let settings = [
+                ("model", defaults.model.clone()),
+                ("temp", defaults.temp.map(|temp| temp.to_string())),
+                ("act_as", defaults.act_as.clone()),
+            ];
+            for (setting, value) in settings {
+                if let Some(value) = value {
+                    println!("{}: {}", setting, value);
+                }
+            }
+            for (var, value) in &defaults.vars {
+                println!("var {}: {}", var, value);
+            }
+            println!("---\n{}", template.body);
+        }
+    }
+    Ok(())
+}
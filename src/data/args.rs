@@ -1,20 +1,26 @@
-use std::{
-    env,
-    error::Error,
-    io::{self, BufRead, Write},
-    path::PathBuf,
-    process::exit,
-};
+use std::{collections::HashMap, env, error::Error, path::Path, process::exit};
 
-use clap::{command, Parser};
+use chrono::{Duration, NaiveDate};
+use clap::{Args, Parser, Subcommand, ValueEnum};
 use log::debug;
 
 use crate::{
-    utils::{self, file_exists},
-    DEFAULT_CONVO,
+    effect::{self, encryption::Cipher},
+    utils, DEFAULT_CONVO,
 };
 
-use super::model::{Algo, ChatData, Config, EditData, Memory, Mode, Model, Output, Prompt};
+use super::{
+    inputs::{get_stdin, read_files},
+    model::{
+        Algo, ChatData, Config, ConvoScope, EditData, EmbedderConfig, EmbedderProvider,
+        EncryptionConfig, HistoryBackend, Memory, MemoryBackend, MemoryFilter, MemoryStore, Mode,
+        Model, NamedInput, Output, Prompt, RerankConfig, RetentionConfig, SourceType,
+    },
+    persona::Persona,
+    pipeline::Pipeline,
+    project::{scoped_convo, Project},
+    template::Template,
+};
 
 fn default_act_as() -> String {
     "You are a helpful AI assistant that will give responses in a computer terminal".to_string()
@@ -25,9 +31,19 @@ fn default_act_as() -> String {
 #[command(version = "0.1.0")]
 #[command(about = "CLI wrapper around chat-gpt cli")]
 #[command(long_about=None)]
+#[command(subcommand_negates_reqs = true)]
 pub struct CliArgs {
-    /// The prompt to give to the model
-    pub prompt: String,
+    /// The prompt to give to the model. Available to templates as {{prompt}}
+    #[arg(required_unless_present = "template")]
+    pub prompt: Option<String>,
+
+    /// Name of a prompt template in the templates directory
+    #[arg(short = 'T', long)]
+    pub template: Option<String>,
+
+    /// Directory containing prompt templates. Defaults to $HOME/.config/hey_gpt/templates
+    #[arg(long)]
+    pub templates_dir: Option<String>,
 
     /// Chat model - default: gpt-3.5-turbo
     #[arg(short, long)]
@@ -73,6 +89,11 @@ pub struct CliArgs {
     #[arg(long)]
     pub convo: Option<String>,
 
+    /// Conversation used when none is named by --convo or a .hey-gpt.yaml. Defaults to none, one
+    /// conversation shared everywhere
+    #[arg(long, value_enum)]
+    pub convo_scope: Option<ConvoScope>,
+
     /// Length of short term memory to extract. Defaults to 3
     #[arg(long)]
     pub convo_length: Option<usize>,
@@ -82,10 +103,32 @@ pub struct CliArgs {
     #[arg(long)]
     pub convo_dir: Option<String>,
 
+    /// Keep a running summary of turns older than the conversation length and include it in
+    /// requests
+    #[arg(long)]
+    pub summarise: bool,
+
+    /// Model used to summarise older turns. Defaults to gpt-3.5-turbo
+    #[arg(long)]
+    pub summary_model: Option<String>,
+
+    /// Replace turn N of the conversation with this prompt. The new answer starts a branch and
+    /// the original turn is kept on its own branch
+    #[arg(long, value_name = "N")]
+    pub edit_turn: Option<usize>,
+
+    /// How conversation history is stored. Defaults to yaml
+    #[arg(long, value_enum)]
+    pub history_backend: Option<HistoryBackend>,
+
     /// Add custom message for system
     #[arg(long)]
     pub act_as: Option<String>,
 
+    /// Name of a persona from the config file. Continuing a conversation uses its last persona
+    #[arg(long = "as")]
+    pub persona: Option<String>,
+
     /// The number of items to retrieve from vector database when using long term memory. Defaults
     /// to 3
     #[arg(long)]
@@ -100,17 +143,461 @@ pub struct CliArgs {
     #[arg(long)]
     pub memory: bool,
 
+    /// Where long term memories are kept. Defaults to retrieval-plugin
+    #[arg(long, value_enum)]
+    pub memory_backend: Option<MemoryBackend>,
+
+    #[command(flatten)]
+    pub memory_filter: MemoryFilterArgs,
+
+    /// Print the long term memories added to the request to stderr before the answer
+    #[arg(long)]
+    pub show_context: bool,
+
+    /// Number the long term memories added to the request and ask for them to be cited, such as
+    /// [m3]. The sources of the citations are printed to stderr after the answer
+    #[arg(long)]
+    pub cite: bool,
+
     /// Print debug output
     #[arg(long)]
     pub debug: bool,
 
-    /// Edit request, data must be supplied through std-in or --data argument
+    #[command(flatten)]
+    pub inputs: InputArgs,
+
+    /// Files larger than this many bytes are not attached. Defaults to 262144
+    #[arg(long)]
+    pub max_file_size: Option<u64>,
+
+    /// Edit request, data must be supplied through std-in, --file or --data argument
     #[arg(long, short)]
     pub edit: bool,
 
     #[arg(long)]
     /// Url of context retrieval api - defaults to http://localhost:5000
     pub retrieval_plugin_url: Option<String>,
+
+    #[command(subcommand)]
+    pub command: Option<CliCommand>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum CliCommand {
+    /// Generate a shell command from a description, then choose to run, edit, copy or cancel it
+    Cmd {
+        /// Description of the command to generate
+        prompt: String,
+
+        /// Break the generated command down into its parts
+        #[arg(long)]
+        explain: bool,
+
+        /// Store the output of the command in the conversation history
+        #[arg(long)]
+        feed_output: bool,
+
+        #[command(flatten)]
+        inputs: InputArgs,
+    },
+    /// Run a multi-stage prompt pipeline defined in a yaml file
+    Run {
+        /// Path to the pipeline file
+        pipeline: String,
+
+        /// Directory in which a directory of stage outputs is saved for each run. Defaults to
+        /// $HOME/.config/hey_gpt/runs
+        #[arg(long)]
+        runs_dir: Option<String>,
+
+        #[command(flatten)]
+        inputs: InputArgs,
+    },
+    /// Manage stored conversations
+    Convo {
+        #[command(subcommand)]
+        command: ConvoCommand,
+    },
+    /// Inspect and delete long term memories
+    Memory {
+        #[command(subcommand)]
+        command: MemoryCommand,
+    },
+    /// Search every stored conversation for turns about something
+    Search(SearchArgs),
+    /// Remove the turns of conversations past the limits set under retention in config.yaml
+    Gc {
+        /// Conversations to prune, all of them when none are given
+        names: Vec<String>,
+
+        /// List what would be removed without removing it
+        #[arg(long)]
+        dry_run: bool,
+
+        /// Also delete the long term memories of the removed turns
+        #[arg(long)]
+        memory: bool,
+    },
+    /// Split files into chunks and save them as long term memories, replacing those of files
+    /// that changed since and removing those of deleted files
+    Ingest(IngestArgs),
+    /// List the personas in the config file
+    Personas,
+    /// Manage prompt templates
+    Templates {
+        #[command(subcommand)]
+        command: TemplatesCommand,
+    },
+}
+
+/// What to send along with the prompt, taken by the prompt itself, cmd and run
+#[derive(Args, Debug)]
+pub struct InputArgs {
+    /// Attach a file to the prompt. Accepts globs, which skip files ignored by .gitignore.
+    /// Can be repeated
+    #[arg(short, long = "file")]
+    pub files: Vec<String>,
+
+    /// Set a template variable, e.g. --var lang=rust
+    #[arg(long = "var", value_parser = parse_var)]
+    pub vars: Vec<(String, String)>,
+}
+
+/// Which long term memories to use
+#[derive(Args, Debug)]
+pub struct MemoryFilterArgs {
+    /// Only use memories saved from this date onwards, as YYYY-MM-DD
+    #[arg(long)]
+    pub since: Option<NaiveDate>,
+
+    /// Only use memories saved up to and including this date, as YYYY-MM-DD
+    #[arg(long)]
+    pub until: Option<NaiveDate>,
+
+    /// Only use memories written by
+    #[arg(long, value_enum)]
+    pub author: Option<Role>,
+
+    /// Only use memories saved from
+    #[arg(long, value_enum)]
+    pub source: Option<SourceType>,
+}
+
+impl MemoryFilterArgs {
+    pub fn filter(&self) -> MemoryFilter {
+        MemoryFilter {
+            since: self.since.and_then(utils::start_of),
+            // the whole of the last day is included
+            until: self
+                .until
+                .and_then(|until| until.succ_opt())
+                .and_then(utils::start_of)
+                .map(|end| end - Duration::milliseconds(1)),
+            author: self.author.map(|role| role.as_str().to_string()),
+            source_type: self.source,
+        }
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct SearchArgs {
+    /// Words to search for
+    #[arg(required = true)]
+    pub query: Vec<String>,
+
+    /// Only search these conversations
+    #[arg(long)]
+    pub convo: Vec<String>,
+
+    /// Only search messages written by
+    #[arg(long, value_enum)]
+    pub role: Option<Role>,
+
+    /// Only search messages from this date onwards, as YYYY-MM-DD
+    #[arg(long)]
+    pub since: Option<NaiveDate>,
+
+    /// Only search messages up to and including this date, as YYYY-MM-DD
+    #[arg(long)]
+    pub until: Option<NaiveDate>,
+
+    /// Number of results to show
+    #[arg(long, default_value_t = 10)]
+    pub limit: usize,
+
+    /// Index every conversation again, for changes made outside of hey-gpt
+    #[arg(long)]
+    pub reindex: bool,
+}
+
+#[derive(Args, Debug)]
+pub struct IngestArgs {
+    /// Files, directories or globs to ingest
+    #[arg(required = true)]
+    pub paths: Vec<String>,
+
+    /// Memory category to save the chunks in, named like a conversation
+    #[arg(long)]
+    pub category: String,
+
+    /// Largest chunk in bytes
+    #[arg(long, default_value_t = 1500)]
+    pub chunk_size: usize,
+
+    /// Bytes at the end of a chunk repeated at the start of the next
+    #[arg(long, default_value_t = 200)]
+    pub overlap: usize,
+
+    /// Number of chunks saved at once
+    #[arg(long, default_value_t = 50)]
+    pub batch_size: usize,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum TemplatesCommand {
+    /// List available templates
+    List,
+    /// Print a template
+    Show {
+        /// Name of the template
+        name: String,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum MemoryCommand {
+    /// List the newest memories of a category
+    List {
+        /// Category of the memories, the conversation they were saved in
+        #[arg(long)]
+        category: String,
+
+        /// Number of memories to list
+        #[arg(long, default_value_t = 20)]
+        limit: usize,
+
+        #[command(flatten)]
+        filter: MemoryFilterArgs,
+    },
+    /// Find the memories of a category closest to a query, as they would be for a request
+    Search {
+        /// Words to search for
+        #[arg(required = true)]
+        query: Vec<String>,
+
+        /// Category to search
+        #[arg(long)]
+        category: String,
+
+        /// Number of memories to show
+        #[arg(long, default_value_t = 5)]
+        limit: usize,
+
+        #[command(flatten)]
+        filter: MemoryFilterArgs,
+    },
+    /// Save a note as a memory
+    Add {
+        /// Text of the note
+        text: String,
+
+        /// Category to save it in
+        #[arg(long)]
+        category: String,
+    },
+    /// Print a memory with its metadata
+    Show {
+        /// Id of the memory
+        id: String,
+    },
+    /// Delete memories
+    Rm {
+        /// Ids of the memories
+        #[arg(required = true)]
+        ids: Vec<String>,
+    },
+    /// Delete every memory of a category
+    Purge {
+        /// Category of the memories
+        #[arg(long)]
+        category: String,
+    },
+    /// Save the memories queued while the memory store could not be reached
+    Flush,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum ConvoCommand {
+    /// List conversations with their number of turns and last update
+    List,
+    /// Print the name of the conversation used here when none is given
+    Current,
+    /// Print a conversation
+    Show {
+        /// Name of the conversation
+        name: String,
+    },
+    /// Delete a conversation
+    Rm {
+        /// Name of the conversation
+        name: String,
+
+        /// Also delete long term memories saved in the conversation's category
+        #[arg(long)]
+        memory: bool,
+    },
+    /// Rename a conversation, moving its long term memories to the new name
+    Mv {
+        /// Name of the conversation
+        from: String,
+        /// New name of the conversation
+        to: String,
+    },
+    /// Copy a conversation under a new name
+    Fork {
+        /// Name of the conversation to copy
+        from: String,
+        /// Name of the new conversation
+        to: String,
+
+        /// Only copy up to and including this turn
+        #[arg(long)]
+        turns: Option<usize>,
+    },
+    /// Remove all turns from a conversation
+    Clear {
+        /// Name of the conversation
+        name: String,
+    },
+    /// Copy conversations from one history backend to another
+    Migrate {
+        /// Backend to copy from. Defaults to the configured backend
+        #[arg(long, value_enum)]
+        from: Option<HistoryBackend>,
+
+        /// Backend to copy to
+        #[arg(long, value_enum)]
+        to: HistoryBackend,
+
+        /// Conversations to copy. Defaults to all of them
+        names: Vec<String>,
+    },
+    /// Print the last turns of a conversation
+    Tail {
+        /// Name of the conversation
+        name: String,
+
+        /// Number of turns to print
+        #[arg(short = 'n', long, default_value_t = 3)]
+        turns: usize,
+    },
+    /// List the branches of a conversation, marking the active one
+    Branches {
+        /// Name of the conversation
+        name: String,
+    },
+    /// Write the active branch of a conversation as a transcript or fine-tuning data
+    Export {
+        /// Name of the conversation
+        name: String,
+
+        #[arg(long, value_enum, default_value_t = ExportFormat::Md)]
+        format: ExportFormat,
+
+        /// File to write to. Defaults to stdout
+        #[arg(short, long)]
+        output: Option<String>,
+
+        /// Start with the system message of the conversation's persona, or --act-as
+        #[arg(long)]
+        system: bool,
+
+        /// Only export turns from this date (YYYY-MM-DD)
+        #[arg(long)]
+        since: Option<NaiveDate>,
+
+        /// Only export turns up to and including this date (YYYY-MM-DD)
+        #[arg(long)]
+        until: Option<NaiveDate>,
+    },
+    /// Create conversations from another tool's export
+    Import {
+        /// File to import
+        file: String,
+
+        #[arg(long, value_enum, default_value_t = ImportFormat::Chatgpt)]
+        format: ImportFormat,
+
+        /// Also save the imported turns to long term memory, under each conversation's name
+        #[arg(long)]
+        memory: bool,
+
+        /// Number of messages saved to long term memory per request
+        #[arg(long, default_value_t = 50)]
+        batch_size: usize,
+    },
+    /// Encrypt stored conversations with the key set under encryption in config.yaml
+    Encrypt {
+        /// Conversations to encrypt, all of them when none are given
+        names: Vec<String>,
+    },
+    /// Store encrypted conversations in plain text again, before encryption is removed from
+    /// config.yaml
+    Decrypt {
+        /// Conversations to decrypt, all of them when none are given
+        names: Vec<String>,
+    },
+    /// Continue a conversation from a segment, the end of a branch by default
+    Checkout {
+        /// Name of the conversation
+        name: String,
+        /// Id of the segment the next turn follows, `root` to start from the beginning
+        id: String,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum ExportFormat {
+    /// Markdown transcript
+    Md,
+    /// Standalone html page
+    Html,
+    /// The turns with their metadata
+    Json,
+    /// A line of chat fine-tuning data
+    OpenaiJsonl,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum ImportFormat {
+    /// conversations.json from a ChatGPT data export, each conversation keeps its active branch
+    Chatgpt,
+    /// A json list of messages with a role and content, or an object with a `messages` or
+    /// `dialogue` list, such as a shell-gpt chat or `convo export --format json`
+    Messages,
+    /// Chat fine-tuning data, one conversation per line
+    OpenaiJsonl,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum Role {
+    User,
+    Assistant,
+}
+
+impl Role {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::User => "user",
+            Role::Assistant => "assistant",
+        }
+    }
+}
+
+fn parse_var(var: &str) -> Result<(String, String), String> {
+    var.split_once('=')
+        .map(|(key, value)| (key.trim().to_string(), value.to_string()))
+        .ok_or_else(|| format!("Expected a variable in the form key=value, got '{}'", var))
 }
 
 #[derive(serde::Serialize, serde::Deserialize, Debug, Default)]
@@ -124,101 +611,357 @@ pub struct ConfigArgs {
     pub retrieval_api_bearer_env: Option<String>,
     pub retrieval_api_bearer: Option<String>,
     pub convo: Option<String>,
+    pub convo_scope: Option<ConvoScope>,
     pub convo_length: Option<usize>,
     pub convo_dir: Option<String>,
+    pub history_backend: Option<HistoryBackend>,
+    pub encryption: Option<EncryptionConfig>,
+    pub retention: Option<RetentionConfig>,
+    pub summarise: Option<bool>,
+    pub summary_model: Option<String>,
     pub act_as: Option<String>,
     pub top_k: Option<u32>,
     pub memories: Option<Vec<String>>,
+    pub memory_backend: Option<MemoryBackend>,
+    pub memory_dir: Option<String>,
+    pub embedder: Option<EmbedderConfig>,
+    pub rerank: Option<RerankConfig>,
+    pub show_context: Option<bool>,
+    pub cite: Option<bool>,
     pub retrieval_plugin_url: Option<String>,
+    pub templates_dir: Option<String>,
+    pub max_file_size: Option<u64>,
+    pub runs_dir: Option<String>,
+    pub personas: Option<HashMap<String, Persona>>,
 }
 
-fn get_stdin() -> String {
-    if !atty::is(atty::Stream::Stdin) {
-        let stdin = io::stdin();
-        let mut buffer = String::new();
-        for line in stdin.lock().lines().flatten() {
-            buffer.push_str(line.as_str());
-        }
+impl ConfigArgs {
+    pub fn load() -> Result<ConfigArgs, Box<dyn Error>> {
+        let home = env::var("HOME")?;
+        let paths = vec![
+            format!("{}/.config/hey_gpt/config.yaml", home),
+            format!("{}/hey_gpt/config.yaml", home),
+        ];
+
+        debug!(
+            "Looking for configuration files in the following order: {:#?}",
+            paths
+        );
 
-        buffer
-    } else {
-        Default::default()
+        // a config that can't be read is an error rather than ignored, it may hold settings such
+        // as encryption that must not be silently dropped
+        match paths.iter().find(|path| utils::file_exists(path)) {
+            Some(existing_file_path) => utils::deserialise_from_file(existing_file_path)
+                .map_err(|e| format!("Could not read '{}': {}", existing_file_path, e).into()),
+            None => Ok(ConfigArgs::default()),
+        }
     }
 }
 
 impl CliArgs {
-    pub fn into_domain(self) -> Result<Model, Box<dyn Error>> {
-        // this probably belongs elsewhere
-        let config_args = {
-            let home = env::var("HOME")?;
-            let paths = vec![
-                format!("{}/.config/hey_gpt/config.yaml", home),
-                format!("{}/hey_gpt/config.yaml", home),
-            ];
+    pub fn templates_dir(&self, config_args: &ConfigArgs) -> Result<String, Box<dyn Error>> {
+        let home = env::var("HOME")?;
+        Ok(self
+            .templates_dir
+            .clone()
+            .or(config_args.templates_dir.clone())
+            .map(|dir| dir.replace("$HOME", &home))
+            .unwrap_or(format!("{}/.config/hey_gpt/templates", home)))
+    }
 
-            debug!(
-                "Looking for configuration files in the following order: {:#?}",
-                paths
-            );
+    pub fn convo_dir(&self, config_args: &ConfigArgs) -> Result<String, Box<dyn Error>> {
+        let home = env::var("HOME")?;
+        self.convo_dir
+            .clone()
+            .or(config_args.convo_dir.clone())
+            .map(|dir| dir.replace("$HOME", &home))
+            .ok_or_else(|| "Directory for storing conversation should be set".into())
+    }
 
-            paths
-                .iter()
-                .find(|path| utils::file_exists(path))
-                .map(|existing_file_path| {
-                    utils::deserialise_from_file::<ConfigArgs>(existing_file_path)
-                        .unwrap_or_default()
-                })
-                .unwrap_or_default()
-        };
+    /// The conversation to use: the one named by --convo, the project, the configured scope or
+    /// config.yaml, in that order
+    pub fn convo(
+        &self,
+        config_args: &ConfigArgs,
+        project: Option<&Project>,
+    ) -> Result<String, Box<dyn Error>> {
+        let scope = self
+            .convo_scope
+            .or(config_args.convo_scope)
+            .unwrap_or_default();
+        let convo = self
+            .convo
+            .clone()
+            .or_else(|| project.and_then(|project| project.convo.clone()))
+            .or_else(|| {
+                env::current_dir()
+                    .ok()
+                    .and_then(|dir| scoped_convo(scope, &dir))
+            })
+            .or(config_args.convo.clone())
+            .unwrap_or(DEFAULT_CONVO.to_string());
+        utils::check_name("conversation", &convo)?;
+        Ok(convo)
+    }
 
-        debug!("Found the following configuration: {:#?}", config_args);
+    /// System message used for a conversation with the persona, when not overridden by a template
+    pub fn system_message(&self, config_args: &ConfigArgs, persona: Option<&str>) -> String {
+        let personas = config_args.personas.clone().unwrap_or_default();
+        self.act_as
+            .clone()
+            .or_else(|| {
+                persona
+                    .and_then(|name| personas.get(name))
+                    .map(|persona| persona.system_message())
+            })
+            .or(config_args.act_as.clone())
+            .unwrap_or_else(default_act_as)
+    }
 
-        debug!("Building model");
-        let open_ai_token = self.open_ai_token.unwrap_or_else(|| {
-            config_args.open_ai_token.unwrap_or_else(|| {
-                match std::env::var(
-                    self.open_ai_token_env.unwrap_or(
+    pub fn history_backend(&self, config_args: &ConfigArgs) -> HistoryBackend {
+        self.history_backend
+            .or(config_args.history_backend)
+            .unwrap_or_default()
+    }
+
+    /// Cipher for the conversations in the directory when encryption is configured
+    pub fn history_cipher(
+        &self,
+        config_args: &ConfigArgs,
+        convo_dir: &str,
+    ) -> Result<Option<Cipher>, Box<dyn Error>> {
+        config_args
+            .encryption
+            .as_ref()
+            .map(|encryption| Cipher::load(encryption, convo_dir))
+            .transpose()
+    }
+
+    pub fn retrieval_plugin_url(&self, config_args: &ConfigArgs) -> String {
+        self.retrieval_plugin_url
+            .clone()
+            .or(config_args.retrieval_plugin_url.clone())
+            .unwrap_or("http://localhost:5000".to_string())
+    }
+
+    pub fn retrieval_api_bearer(&self, config_args: &ConfigArgs) -> Option<String> {
+        self.retrieval_api_bearer
+            .clone()
+            .or(config_args.retrieval_api_bearer.clone())
+            .or_else(|| {
+                std::env::var(
+                    self.retrieval_api_bearer_env.clone().unwrap_or(
+                        config_args
+                            .retrieval_api_bearer_env
+                            .clone()
+                            .unwrap_or("RETRIEVAL_API_BEARER".to_string()),
+                    ),
+                )
+                .ok()
+            })
+    }
+
+    pub fn open_ai_token(&self, config_args: &ConfigArgs) -> Option<String> {
+        self.open_ai_token
+            .clone()
+            .or(config_args.open_ai_token.clone())
+            .or_else(|| {
+                std::env::var(
+                    self.open_ai_token_env.clone().unwrap_or(
                         config_args
                             .open_ai_token_env
+                            .clone()
                             .unwrap_or("OPENAI_KEY".to_string()),
                     ),
-                ) {
-                    Ok(token) => token,
-                    _ => panic!(
-                    "Could not find openai token in environment and it was not provided by user"
-                ),
-                }
+                )
+                .ok()
             })
+    }
+
+    /// Directory of the local memory stores. Defaults to $HOME/.config/hey_gpt/memory
+    pub fn memory_dir(&self, config_args: &ConfigArgs) -> Result<String, Box<dyn Error>> {
+        let home = env::var("HOME")?;
+        Ok(config_args
+            .memory_dir
+            .clone()
+            .map(|dir| dir.replace("$HOME", &home))
+            .unwrap_or(format!("{}/.config/hey_gpt/memory", home)))
+    }
+
+    /// Database of the memories queued while the store could not be reached, in memory_dir
+    pub fn outbox(&self, config_args: &ConfigArgs) -> Result<String, Box<dyn Error>> {
+        Ok(Path::new(&self.memory_dir(config_args)?)
+            .join("outbox.sqlite3")
+            .to_string_lossy()
+            .to_string())
+    }
+
+    /// The configured long term memory store. Local stores are kept in memory_dir
+    pub fn memory_store(&self, config_args: &ConfigArgs) -> Result<MemoryStore, Box<dyn Error>> {
+        let backend = self
+            .memory_backend
+            .or(config_args.memory_backend)
+            .unwrap_or_default();
+        let memory_dir = self.memory_dir(config_args)?;
+        let in_memory_dir = |file: &str| {
+            Path::new(&memory_dir)
+                .join(file)
+                .to_string_lossy()
+                .to_string()
+        };
+        Ok(match backend {
+            MemoryBackend::RetrievalPlugin => MemoryStore::RetrievalPlugin {
+                url: self.retrieval_plugin_url(config_args),
+                bearer: self.retrieval_api_bearer(config_args).ok_or(
+                    "Could not find ai context token in environment and it was not provided by user",
+                )?,
+            },
+            MemoryBackend::Local => {
+                let embedder = config_args.embedder.clone().unwrap_or_default();
+                let api_key = match embedder.provider {
+                    EmbedderProvider::Openai => Some(self.open_ai_token(config_args).ok_or(
+                        "Could not find openai token in environment for the embedder and it was not provided by user",
+                    )?),
+                    EmbedderProvider::OpenaiCompatible => {
+                        if embedder.url.is_none() {
+                            return Err(
+                                "Set the url of the embeddings server under embedder in config.yaml"
+                                    .into(),
+                            );
+                        }
+                        embedder
+                            .api_key_env
+                            .as_ref()
+                            .and_then(|var| env::var(var).ok())
+                    }
+                    EmbedderProvider::Hashing => None,
+                };
+                MemoryStore::Local {
+                    path: in_memory_dir("vectors.sqlite3"),
+                    embedder,
+                    api_key,
+                }
+            }
+            MemoryBackend::Bm25 => MemoryStore::Bm25 {
+                path: in_memory_dir("bm25.sqlite3"),
+            },
+        })
+    }
+
+    pub async fn into_domain(self) -> Result<Model, Box<dyn Error>> {
+        let config_args = ConfigArgs::load()?;
+
+        debug!("Found the following configuration: {:#?}", config_args);
+
+        let project = Project::find(&env::current_dir()?)?;
+        debug!("Project: {:#?}", project);
+
+        let templates_dir = self.templates_dir(&config_args)?;
+        let convo_dir = self.convo_dir(&config_args)?;
+        let cipher = self.history_cipher(&config_args, &convo_dir)?;
+        let convo = self.convo(&config_args, project.as_ref())?;
+        debug!("Conversation: {}", convo);
+        let history_backend = self.history_backend(&config_args);
+        let store = match self.memory {
+            true => self.memory_store(&config_args)?,
+            false => MemoryStore::default(),
+        };
+        let outbox = self.outbox(&config_args)?;
+
+        let template = self
+            .template
+            .as_ref()
+            .map(|name| Template::load(&templates_dir, name))
+            .transpose()?;
+
+        debug!("Template: {:#?}", template);
+        let template_defaults = template
+            .as_ref()
+            .map(|template| template.defaults.clone())
+            .unwrap_or_default();
+
+        debug!("Building model");
+        let open_ai_token = self.open_ai_token(&config_args).unwrap_or_else(|| {
+            panic!("Could not find openai token in environment and it was not provided by user")
         });
 
         let user_wants_memory = self.memory;
 
-        // only required if user wants persistant memory
-        let context_token = if user_wants_memory {
-            self.retrieval_api_bearer.unwrap_or_else(|| {
-                config_args.retrieval_api_bearer
-                    .unwrap_or_else(|| match std::env::var(self.retrieval_api_bearer_env.unwrap_or(config_args.retrieval_api_bearer_env.unwrap_or("RETRIEVAL_API_BEARER".to_string()))) {
-                        Ok(token) => token,
-                        _ => panic!(
-                            "Could not find ai context token in environment and it was not provided by user"
-                        ),
-                    })
-            })
-        } else {
-            Default::default()
+        let pipeline = match &self.command {
+            Some(CliCommand::Run { pipeline, .. }) => {
+                Some(Pipeline::load(pipeline, &templates_dir)?)
+            }
+            _ => None,
+        };
+
+        debug!("Pipeline: {:#?}", pipeline);
+
+        let runs_dir = {
+            let home = env::var("HOME")?;
+            match &self.command {
+                Some(CliCommand::Run {
+                    runs_dir: Some(runs_dir),
+                    ..
+                }) => Some(runs_dir.clone()),
+                _ => config_args.runs_dir.clone(),
+            }
+            .map(|dir| dir.replace("$HOME", &home))
+            .unwrap_or(format!("{}/.config/hey_gpt/runs", home))
+        };
+
+        // inputs can be given both before and after the cmd and run subcommands
+        let command_inputs = match &self.command {
+            Some(CliCommand::Cmd { inputs, .. }) | Some(CliCommand::Run { inputs, .. }) => {
+                Some(inputs)
+            }
+            _ => None,
+        };
+        let mut files = self.inputs.files.clone();
+        let mut vars = self.inputs.vars.clone();
+        if let Some(inputs) = command_inputs {
+            files.extend(inputs.files.iter().cloned());
+            vars.extend(inputs.vars.iter().cloned());
+        }
+
+        let (user_prompt, shell_command) = match self.command {
+            Some(CliCommand::Cmd {
+                prompt,
+                explain,
+                feed_output,
+                ..
+            }) => (prompt, Some((explain, feed_output))),
+            // clap ensures a prompt is given unless using a template
+            _ => (self.prompt.unwrap_or_default(), None),
         };
 
         let mode = {
-            // for now favour data argument over stdin by default
-            let stdin = get_stdin();
+            // for now favour data argument over stdin and files by default
+            let inputs = {
+                let mut inputs: Vec<NamedInput> = get_stdin().into_iter().collect();
+                inputs.append(&mut read_files(
+                    &files,
+                    self.max_file_size
+                        .unwrap_or(config_args.max_file_size.unwrap_or(256 * 1024)),
+                )?);
+                inputs
+            };
             let data_prompt = self.data_prompt.clone();
 
-            if self.edit {
-                let edit_data = match (data_prompt, stdin.is_empty()) {
+            if let Some(pipeline) = pipeline {
+                Mode::Pipeline(pipeline, inputs)
+            } else if shell_command.is_some() {
+                let chat_data = match (data_prompt, inputs.is_empty()) {
+                    (Some(data), true) | (Some(data), false) => ChatData::DataFromPrompt(data),
+                    (None, false) => ChatData::DataFromInputs(inputs),
+                    _ => ChatData::NoAdditionalData,
+                };
+                Mode::Shell(chat_data)
+            } else if self.edit {
+                let edit_data = match (data_prompt, inputs.is_empty()) {
                     (Some(data_prompt), true) | (Some(data_prompt), false) => {
                         EditData::DataFromPrompt(data_prompt)
                     }
-                    (None, false) => EditData::DataFromStdIn(stdin),
+                    (None, false) => EditData::DataFromInputs(inputs),
                     _ => {
                         eprintln!("Data must be provided for edit mode");
                         exit(1);
@@ -226,9 +969,9 @@ impl CliArgs {
                 };
                 Mode::Edit(edit_data)
             } else {
-                let chat_data = match (data_prompt, stdin.is_empty()) {
+                let chat_data = match (data_prompt, inputs.is_empty()) {
                     (Some(data), true) | (Some(data), false) => ChatData::DataFromPrompt(data),
-                    (None, false) => ChatData::DataFromStdIn(stdin),
+                    (None, false) => ChatData::DataFromInputs(inputs),
                     _ => ChatData::NoAdditionalData,
                 };
                 Mode::Chat(chat_data)
@@ -237,112 +980,148 @@ impl CliArgs {
 
         debug!("Mode: {:#?}", mode);
 
+        if let Err(e) = std::fs::create_dir_all(&convo_dir) {
+            panic!(
+                "Could not create conversation directory at '{}': {}",
+                convo_dir, e
+            )
+        }
+
+        let pinned_persona = self
+            .persona
+            .clone()
+            .or_else(|| project.as_ref().and_then(|project| project.persona.clone()));
+        let persona_name = match pinned_persona.clone() {
+            Some(name) => Some(name),
+            None => {
+                effect::history(&history_backend, &convo_dir, &convo, cipher.as_ref())
+                    .get_persona()
+                    .await?
+            }
+        };
+        let personas = config_args.personas.clone().unwrap_or_default();
+        let persona = match &persona_name {
+            Some(name) if pinned_persona.is_some() => Some(Persona::find(&personas, name)?.clone()),
+            // the persona stored in the conversation may since have been removed from config
+            Some(name) => match Persona::find(&personas, name) {
+                Ok(persona) => Some(persona.clone()),
+                Err(e) => {
+                    eprintln!("{}", e);
+                    None
+                }
+            },
+            None => None,
+        };
+
+        debug!("Persona: {:#?}", persona);
+        let persona_name = persona.as_ref().and(persona_name);
+        let persona = persona.unwrap_or_default();
+
         let algo = Algo {
             chat_model: self.chat_model.unwrap_or(
-                config_args
-                    .chat_model
-                    .unwrap_or("gpt-3.5-turbo".to_string()),
+                persona.model.clone().unwrap_or(
+                    template_defaults.model.unwrap_or(
+                        config_args
+                            .chat_model
+                            .unwrap_or("gpt-3.5-turbo".to_string()),
+                    ),
+                ),
             ),
             edit_model: self.edit_model.unwrap_or(
                 config_args
                     .edit_model
                     .unwrap_or("text-davinci-edit-001".to_string()),
             ),
-            temp: self.temp.unwrap_or(config_args.temp.unwrap_or(0.7)),
+            temp: self.temp.unwrap_or(
+                persona.temp.unwrap_or(
+                    template_defaults
+                        .temp
+                        .unwrap_or(config_args.temp.unwrap_or(0.7)),
+                ),
+            ),
             max_tokens: self.max_tokens.map_or_else(|| config_args.max_tokens, Some),
         };
 
         debug!("Algo: {:#?}", algo);
 
         let config = Config {
-            debug: self.debug,
             preview_data_generation: self.data_prompt.map(|_| !self.no_preview).unwrap_or(false),
-            context_url: self.retrieval_plugin_url.unwrap_or_else(|| {
-                if user_wants_memory {
-                    config_args
-                        .retrieval_plugin_url
-                        .unwrap_or("http://localhost:5000".to_string())
-                } else {
-                    String::new()
-                }
-            }),
+            shell: env::var("SHELL").unwrap_or("/bin/sh".to_string()),
+            explain_command: shell_command.map(|(explain, _)| explain).unwrap_or(false),
+            feed_command_output: shell_command
+                .map(|(_, feed_output)| feed_output)
+                .unwrap_or(false),
+            runs_dir,
         };
 
         debug!("Config: {:#?}", config);
 
         let output = Output {
-            failure_msg: None,
             chat_results: None,
-            edit_results: None,
+            command_output: None,
+            turn_meta: None,
         };
 
         debug!("Output: {:#?}", output);
 
         let prompt = Prompt {
             generated_data: None,
-            prompt: self.prompt,
+            prompt: user_prompt,
             final_chat_prompt: None,
-            act_as: self
-                .act_as
-                .unwrap_or(config_args.act_as.unwrap_or(default_act_as())),
+            act_as: self.act_as.unwrap_or_else(|| {
+                if persona_name.is_some() {
+                    persona.system_message()
+                } else {
+                    template_defaults
+                        .act_as
+                        .unwrap_or(config_args.act_as.unwrap_or(default_act_as()))
+                }
+            }),
+            template,
+            template_vars: vars.into_iter().collect(),
+            persona: persona_name,
         };
 
         debug!("Prompt: {:#?}", prompt);
 
-        let convo_file_path: PathBuf = vec![
-            self.convo_dir
-                .clone()
-                .map(|dir| {
-                    dir.replace(
-                        "$HOME",
-                        env::var("HOME")
-                            .expect("Could not find HOME environment variable")
-                            .as_str(),
-                    )
-                })
-                .unwrap_or_else(|| {
-                    config_args
-                        .convo_dir
-                        .clone()
-                        .expect("Directory for storing conversation should be set")
-                }),
-            self.convo.clone().unwrap_or(
-                config_args
-                    .convo
-                    .clone()
-                    .unwrap_or(DEFAULT_CONVO.to_string()),
-            ),
-        ]
-        .into_iter()
-        .collect();
-
-        if !file_exists(convo_file_path.to_str().unwrap()) {
-            match std::fs::File::create(&convo_file_path) {
-                Ok(mut file) => {
-                    let str = "dialogue: ";
-                    file.write_all(str.as_bytes()).unwrap();
-                }
-                Err(e) => panic!(
-                    "Could not create conversation file at '{}': {}",
-                    convo_file_path.to_str().unwrap(),
-                    e
-                ),
-            }
-        }
-
+        let retention = config_args
+            .retention
+            .as_ref()
+            .filter(|retention| retention.on_run)
+            .map(|retention| retention.policy(&convo))
+            .filter(|policy| !policy.is_empty());
         let memory = Memory {
             top_k: self.top_k.unwrap_or(config_args.top_k.unwrap_or(3)),
             memories: self
                 .memories
-                .unwrap_or(config_args.memories.unwrap_or(vec![])),
+                .or(project.and_then(|project| project.memories))
+                .unwrap_or(
+                    persona
+                        .memories
+                        .unwrap_or(config_args.memories.unwrap_or(vec![])),
+                ),
             enabled: user_wants_memory,
-            convo: self
-                .convo
-                .unwrap_or(config_args.convo.unwrap_or(DEFAULT_CONVO.to_string())),
+            convo,
             convo_len: self
                 .convo_length
                 .unwrap_or(config_args.convo_length.unwrap_or(3)),
-            convo_path: convo_file_path.to_str().unwrap().to_owned(),
+            convo_dir,
+            history_backend,
+            filter: self.memory_filter.filter(),
+            outbox,
+            show_context: self.show_context || config_args.show_context.unwrap_or(false),
+            cite: self.cite || config_args.cite.unwrap_or(false),
+            summarise: self.summarise || config_args.summarise.unwrap_or(false),
+            summary_model: self.summary_model.unwrap_or(
+                config_args
+                    .summary_model
+                    .unwrap_or("gpt-3.5-turbo".to_string()),
+            ),
+            edit_turn: self.edit_turn,
+            cipher,
+            retention,
+            store,
+            rerank: config_args.rerank,
         };
 
         debug!("Memory: {:#?}", memory);
@@ -355,7 +1134,6 @@ impl CliArgs {
             prompt,
             memory,
             open_ai_token,
-            context_token,
         })
     }
 }
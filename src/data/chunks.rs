@@ -0,0 +1,281 @@
+use std::path::Path;
+
+const MARKDOWN_EXTENSIONS: &[&str] = &["md", "markdown", "mdx", "rst", "adoc"];
+const CODE_EXTENSIONS: &[&str] = &[
+    "rs", "py", "js", "jsx", "ts", "tsx", "go", "java", "kt", "scala", "c", "h", "cc", "cpp",
+    "hpp", "cs", "rb", "php", "swift", "ex", "exs", "hs", "ml", "lua", "sh", "bash", "zsh",
+];
+/// Words that may come before the keyword of a definition
+const MODIFIERS: &[&str] = &[
+    "pub",
+    "async",
+    "unsafe",
+    "extern",
+    "export",
+    "default",
+    "static",
+    "abstract",
+    "final",
+    "public",
+    "private",
+    "protected",
+    "internal",
+    "override",
+    "open",
+    "sealed",
+    "data",
+    "inline",
+];
+/// Keywords starting a definition worth a chunk of its own
+const DEFINITIONS: &[&str] = &[
+    "fn",
+    "def",
+    "class",
+    "impl",
+    "struct",
+    "enum",
+    "trait",
+    "mod",
+    "interface",
+    "func",
+    "function",
+    "module",
+    "object",
+    "macro_rules!",
+];
+
+/// Part of a file, starting `offset` bytes into it
+#[derive(Debug, Clone, PartialEq)]
+pub struct Chunk {
+    pub offset: usize,
+    pub text: String,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Kind {
+    Markdown,
+    Code,
+    Text,
+}
+
+/// How good a place to split is, better places are greater
+#[derive(Clone, Copy, PartialEq, PartialOrd)]
+enum Split {
+    Line,
+    Paragraph,
+    Section,
+}
+
+/// Splits the text of the file into chunks of at most `size` bytes, each starting `overlap`
+/// bytes before the end of the one before it. Chunks are split before headings in markdown and
+/// before definitions in code when they can be, otherwise between paragraphs, then lines
+pub fn chunk(path: &Path, text: &str, size: usize, overlap: usize) -> Vec<Chunk> {
+    let size = size.max(1);
+    let overlap = overlap.min(size / 2);
+    let splits = splits(kind(path), text);
+
+    let mut chunks = vec![];
+    let mut start = 0;
+    while start < text.len() {
+        let end = match text.len() - start <= size {
+            true => text.len(),
+            false => split_before(text, &splits, start, size),
+        };
+        if !text[start..end].trim().is_empty() {
+            chunks.push(Chunk {
+                offset: start,
+                text: text[start..end].to_string(),
+            });
+        }
+        if end == text.len() {
+            break;
+        }
+        start = overlap_start(text, &splits, start, end, overlap);
+    }
+    chunks
+}
+
+fn kind(path: &Path) -> Kind {
+    let extension = path
+        .extension()
+        .map(|extension| extension.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    if MARKDOWN_EXTENSIONS.contains(&extension.as_str()) {
+        Kind::Markdown
+    } else if CODE_EXTENSIONS.contains(&extension.as_str()) {
+        Kind::Code
+    } else {
+        Kind::Text
+    }
+}
+
+/// Each line start after the first, with how good a place it is to split
+fn splits(kind: Kind, text: &str) -> Vec<(usize, Split)> {
+    let mut lines = vec![];
+    let mut offset = 0;
+    for line in text.split_inclusive('\n') {
+        lines.push((offset, line));
+        offset += line.len();
+    }
+
+    let blank = |index: usize| lines[index].1.trim().is_empty();
+    let mut in_fence = false;
+    let mut splits = vec![];
+    for (index, &(offset, line)) in lines.iter().enumerate().skip(1) {
+        let section = match kind {
+            Kind::Markdown => {
+                if line.trim_start().starts_with("```") {
+                    in_fence = !in_fence;
+                }
+                !in_fence && line.starts_with('#')
+            }
+            Kind::Code => blank(index - 1) && is_definition(line),
+            Kind::Text => false,
+        };
+        let split = if section {
+            Split::Section
+        } else if blank(index - 1) && !blank(index) {
+            Split::Paragraph
+        } else {
+            Split::Line
+        };
+        splits.push((offset, split));
+    }
+
+    // the comments and attributes of a definition stay with it
+    if kind == Kind::Code {
+        for index in 1..lines.len() {
+            if !is_annotation(lines[index].1) || !blank(index - 1) {
+                continue;
+            }
+            let mut last = index;
+            while last + 1 < lines.len() && is_annotation(lines[last + 1].1) {
+                last += 1;
+            }
+            if last + 1 < lines.len() && is_definition(lines[last + 1].1) {
+                splits[index - 1].1 = Split::Section;
+            }
+        }
+    }
+    splits
+}
+
+fn is_annotation(line: &str) -> bool {
+    let line = line.trim_start();
+    ["//", "#[", "#!", "@", "/*", "*", "\"\"\"", "#"]
+        .iter()
+        .any(|prefix| line.starts_with(prefix))
+}
+
+fn is_definition(line: &str) -> bool {
+    line.split_whitespace()
+        .map(|word| word.split('(').next().unwrap_or_default())
+        .find(|word| !word.is_empty() && !MODIFIERS.contains(word))
+        .is_some_and(|word| DEFINITIONS.contains(&word))
+}
+
+/// The end of the chunk starting at `start`: the best place to split in the last half of it,
+/// the latest of those equally good, or `size` bytes in when there is none
+fn split_before(text: &str, splits: &[(usize, Split)], start: usize, size: usize) -> usize {
+    let limit = start + size;
+    splits
+        .iter()
+        .filter(|(offset, _)| *offset > start + size / 2 && *offset <= limit)
+        .max_by(|a, b| a.1.partial_cmp(&b.1).unwrap().then(a.0.cmp(&b.0)))
+        .map(|(offset, _)| *offset)
+        .unwrap_or_else(|| floor_char_boundary(text, limit).max(start + 1))
+}
+
+/// Where the chunk after the one from `start` to `end` starts: the first line start in the
+/// last `overlap` bytes of it, or the end when there is none
+fn overlap_start(
+    text: &str,
+    splits: &[(usize, Split)],
+    start: usize,
+    end: usize,
+    overlap: usize,
+) -> usize {
+    let from = end.saturating_sub(overlap).max(start + 1);
+    splits
+        .iter()
+        .map(|(offset, _)| *offset)
+        .find(|offset| *offset >= from && *offset <= end)
+        .unwrap_or(match splits.iter().any(|(offset, _)| *offset == end) {
+            true => end,
+            // cut mid line, so the overlap is cut too
+            false => floor_char_boundary(text, from).max(start + 1).min(end),
+        })
+}
+
+fn floor_char_boundary(text: &str, index: usize) -> usize {
+    (0..=index.min(text.len()))
+        .rev()
+        .find(|index| text.is_char_boundary(*index))
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::chunk;
+
+    #[test]
+    fn markdown_splits_before_headings_outside_code() {
+        let text = "# Install\n\nRun the installer.\n\n```sh\n# not a heading\nmake\n```\n\n# Usage\n\nCall it.\n";
+        let chunks = chunk(Path::new("README.md"), text, 70, 0);
+        let texts: Vec<&str> = chunks.iter().map(|chunk| chunk.text.as_str()).collect();
+        assert_eq!(
+            vec![
+                "# Install\n\nRun the installer.\n\n```sh\n# not a heading\nmake\n```\n\n",
+                "# Usage\n\nCall it.\n"
+            ],
+            texts
+        );
+        assert_eq!(text.find("# Usage").unwrap(), chunks[1].offset);
+    }
+
+    #[test]
+    fn code_splits_before_definitions_with_their_comments() {
+        let text = "use std::io;\n\nfn one() {\n    let a = 1;\n}\n\n/// Two\n#[inline]\npub fn two() {\n    let b = 2;\n}\n";
+        let chunks = chunk(Path::new("lib.rs"), text, 70, 0);
+        let texts: Vec<&str> = chunks.iter().map(|chunk| chunk.text.as_str()).collect();
+        assert_eq!(
+            vec![
+                "use std::io;\n\nfn one() {\n    let a = 1;\n}\n\n",
+                "/// Two\n#[inline]\npub fn two() {\n    let b = 2;\n}\n"
+            ],
+            texts
+        );
+    }
+
+    #[test]
+    fn chunks_overlap_and_cover_the_text() {
+        let text: String = (0..40).map(|n| format!("line {} ü\n", n)).collect();
+        let chunks = chunk(Path::new("notes.txt"), &text, 100, 30);
+
+        assert!(chunks.len() > 1);
+        assert_eq!(0, chunks[0].offset);
+        for pair in chunks.windows(2) {
+            let end = pair[0].offset + pair[0].text.len();
+            assert!(pair[1].offset < end && end - pair[1].offset <= 30);
+            assert!(text[pair[1].offset..].starts_with("line"));
+        }
+        for chunk in &chunks {
+            assert!(chunk.text.len() <= 100);
+            assert_eq!(
+                &text[chunk.offset..chunk.offset + chunk.text.len()],
+                chunk.text
+            );
+        }
+        let last = chunks.last().unwrap();
+        assert_eq!(text.len(), last.offset + last.text.len());
+
+        let long_line = "ü".repeat(100);
+        let chunks = chunk(Path::new("notes.txt"), &long_line, 51, 0);
+        assert_eq!(
+            long_line,
+            chunks.iter().map(|c| c.text.clone()).collect::<String>()
+        );
+    }
+}
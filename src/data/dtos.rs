@@ -1,3 +1,5 @@
+use std::collections::HashMap;
+
 use serde::{Deserialize, Serialize};
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -23,6 +25,13 @@ pub struct ChatRequestDTO {
     pub temperature: f32,
     pub max_tokens: Option<i32>,
     pub stream: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stream_options: Option<StreamOptionsDTO>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct StreamOptionsDTO {
+    pub include_usage: bool,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -32,29 +41,6 @@ pub struct UsageDTO {
     pub total_tokens: i32,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-pub struct ChatResponseMsgDTO {
-    pub content: String,
-    pub role: String,
-}
-
-#[derive(Serialize, Deserialize, Debug)]
-pub struct ChatChoiceDTO {
-    pub finish_reason: String,
-    pub index: i32,
-    pub message: ChatResponseMsgDTO,
-}
-
-#[derive(Serialize, Deserialize, Debug)]
-pub struct ChatResponseDTO {
-    pub choices: Vec<ChatChoiceDTO>,
-    pub created: i32,
-    pub id: String,
-    pub model: String,
-    pub object: String,
-    pub usage: UsageDTO,
-}
-
 #[derive(Serialize, Deserialize, Debug)]
 pub struct StreamChatResponseMsgDTO {
     pub content: Option<String>,
@@ -75,6 +61,8 @@ pub struct StreamChatResponseDTO {
     pub id: String,
     pub model: String,
     pub object: String,
+    /// Only sent, in a final chunk with no choices, when usage is requested in the stream options
+    pub usage: Option<UsageDTO>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -97,6 +85,8 @@ pub struct UpsertMetadataDTO {
     pub source_id: String,
     pub source: String,
     pub author: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -118,16 +108,20 @@ pub struct RetreivalUpsertWrapperDTO {
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct RetrievalDeleteDTO {
-    pub ids: Vec<String>,
+    pub ids: Option<Vec<String>>,
+    pub filter: Option<RetrievalFilterDTO>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct RetrievalFilterDTO {
     pub source_id: Option<String>,
-    pub source: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
     pub author: Option<String>,
     pub start_date: Option<String>,
     pub end_date: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub document_id: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -171,3 +165,59 @@ pub struct SearchResultDTO {
 pub struct ResultWrapperDTO {
     pub results: Vec<SearchResultDTO>,
 }
+
+#[derive(Serialize, Debug)]
+pub struct EmbeddingRequestDTO {
+    pub model: String,
+    pub input: Vec<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct EmbeddingDTO {
+    pub index: usize,
+    pub embedding: Vec<f32>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct EmbeddingResponseDTO {
+    pub data: Vec<EmbeddingDTO>,
+}
+
+/// A conversation in the `conversations.json` of a ChatGPT data export. Its messages form a tree
+/// in which `current_node` is the last message of the branch shown in the app
+#[derive(Deserialize, Debug)]
+pub struct ChatGptConversationDTO {
+    pub title: Option<String>,
+    pub id: Option<String>,
+    pub conversation_id: Option<String>,
+    pub current_node: Option<String>,
+    #[serde(default)]
+    pub mapping: HashMap<String, ChatGptNodeDTO>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ChatGptNodeDTO {
+    pub message: Option<ChatGptMessageDTO>,
+    pub parent: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ChatGptMessageDTO {
+    pub author: ChatGptAuthorDTO,
+    pub create_time: Option<f64>,
+    pub content: Option<ChatGptContentDTO>,
+    pub metadata: Option<serde_json::Value>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ChatGptAuthorDTO {
+    pub role: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ChatGptContentDTO {
+    /// Text, or objects such as images in multimodal messages
+    #[serde(default)]
+    pub parts: Vec<serde_json::Value>,
+    pub text: Option<String>,
+}
@@ -0,0 +1,322 @@
+use std::{
+    collections::HashMap,
+    error::Error,
+    fmt::Display,
+    io::{self, Read},
+    path::{Path, PathBuf},
+};
+
+use globset::GlobBuilder;
+use ignore::WalkBuilder;
+use log::debug;
+use sha2::{Digest, Sha256};
+
+use crate::effect::conversation::InputMeta;
+
+use super::model::NamedInput;
+
+pub const STDIN_INPUT_NAME: &str = "stdin";
+
+/// Number of bytes inspected for NUL bytes when deciding whether a file is binary
+const BINARY_SNIFF_LEN: usize = 8000;
+
+#[derive(Debug)]
+pub struct InputError(pub String);
+
+impl Display for InputError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+impl Error for InputError {}
+
+pub fn get_stdin() -> Option<NamedInput> {
+    if !atty::is(atty::Stream::Stdin) {
+        let mut buffer = String::new();
+        match io::stdin().lock().read_to_string(&mut buffer) {
+            Ok(_) if !buffer.is_empty() => Some(NamedInput {
+                name: STDIN_INPUT_NAME.to_string(),
+                content: buffer,
+            }),
+            Ok(_) => None,
+            Err(e) => {
+                eprintln!("Could not read from stdin: {}", e);
+                None
+            }
+        }
+    } else {
+        None
+    }
+}
+
+pub fn is_glob(pattern: &str) -> bool {
+    pattern.contains(['*', '?', '[', '{'])
+}
+
+/// The directory to start walking from: the path components before the first containing a
+/// glob character
+pub fn glob_root(pattern: &str) -> PathBuf {
+    let root: PathBuf = Path::new(pattern)
+        .components()
+        .take_while(|component| !is_glob(&component.as_os_str().to_string_lossy()))
+        .collect();
+
+    if root.as_os_str().is_empty() {
+        PathBuf::from(".")
+    } else {
+        root
+    }
+}
+
+/// Expands a glob, skipping anything excluded by .gitignore files or hidden
+fn expand_glob(pattern: &str) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+    let pattern = pattern.trim_start_matches("./");
+    let matcher = GlobBuilder::new(pattern)
+        .literal_separator(true)
+        .build()?
+        .compile_matcher();
+    let root = glob_root(pattern);
+    debug!("Expanding glob '{}' from {:?}", pattern, root);
+
+    let mut paths: Vec<PathBuf> = WalkBuilder::new(&root)
+        .require_git(false)
+        .build()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().map(|ft| ft.is_file()).unwrap_or(false))
+        .map(|entry| {
+            entry
+                .path()
+                .strip_prefix("./")
+                .unwrap_or(entry.path())
+                .to_path_buf()
+        })
+        .filter(|path| matcher.is_match(path))
+        .collect();
+
+    paths.sort();
+    Ok(paths)
+}
+
+/// Reads a file as text, returning None if it looks binary
+fn read_text_file(path: &Path) -> Result<Option<String>, Box<dyn Error>> {
+    let bytes = std::fs::read(path)?;
+    if bytes.iter().take(BINARY_SNIFF_LEN).any(|byte| *byte == 0) {
+        return Ok(None);
+    }
+    Ok(String::from_utf8(bytes).ok())
+}
+
+/// Reads each path or glob into a named input. Binary files and files larger than
+/// `max_file_size` bytes are skipped with a warning; globs that match nothing are an error.
+pub fn read_files(
+    patterns: &[String],
+    max_file_size: u64,
+) -> Result<Vec<NamedInput>, Box<dyn Error>> {
+    let mut inputs: Vec<NamedInput> = vec![];
+
+    for pattern in patterns {
+        let paths = if is_glob(pattern) && !Path::new(pattern).exists() {
+            expand_glob(pattern)?
+        } else {
+            vec![PathBuf::from(pattern)]
+        };
+
+        if paths.is_empty() {
+            return Err(Box::new(InputError(format!(
+                "No files matched '{}'",
+                pattern
+            ))));
+        }
+
+        for path in paths {
+            let name = path.to_string_lossy().to_string();
+            if inputs.iter().any(|input| input.name == name) {
+                continue;
+            }
+
+            let size = std::fs::metadata(&path)
+                .map_err(|e| InputError(format!("Could not read '{}': {}", name, e)))?
+                .len();
+            if size > max_file_size {
+                eprintln!(
+                    "Skipping '{}': {} bytes is over the {} byte limit",
+                    name, size, max_file_size
+                );
+                continue;
+            }
+
+            match read_text_file(&path)? {
+                Some(content) => inputs.push(NamedInput { name, content }),
+                None => eprintln!("Skipping '{}': file is not text", name),
+            }
+        }
+    }
+
+    Ok(inputs)
+}
+
+/// Joins inputs into a single string. Piped input on its own is used as is, otherwise each input
+/// is headed by its name so files are always labelled with their path.
+pub fn format_inputs(inputs: &[NamedInput]) -> String {
+    match inputs {
+        [input] if input.name == STDIN_INPUT_NAME => input.content.clone(),
+        _ => inputs
+            .iter()
+            .map(|NamedInput { name, content }| {
+                format!("==> {} <==\n{}", name, content.trim_end_matches('\n'))
+            })
+            .collect::<Vec<String>>()
+            .join("\n\n"),
+    }
+}
+
+/// Template variables for the inputs: `{{stdin}}` for piped input and `{{files}}` for
+/// attached files
+pub fn input_vars(inputs: &[NamedInput]) -> HashMap<String, String> {
+    let (std_in, files): (Vec<NamedInput>, Vec<NamedInput>) = inputs
+        .iter()
+        .cloned()
+        .partition(|input| input.name == STDIN_INPUT_NAME);
+
+    let mut vars = HashMap::new();
+    if let Some(std_in) = std_in.first() {
+        vars.insert("stdin".to_string(), std_in.content.clone());
+    }
+    if !files.is_empty() {
+        vars.insert("files".to_string(), format_inputs(&files));
+    }
+    vars
+}
+
+/// Names and hashes of the inputs, recorded with the response to them
+pub fn input_meta(inputs: &[NamedInput]) -> Vec<InputMeta> {
+    inputs
+        .iter()
+        .map(|NamedInput { name, content }| InputMeta {
+            name: name.clone(),
+            sha256: format!("{:x}", Sha256::digest(content.as_bytes())),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::Path};
+
+    use tempfile::TempDir;
+
+    use super::{format_inputs, glob_root, input_meta, read_files};
+    use crate::data::model::NamedInput;
+
+    fn setup_dir() -> TempDir {
+        let dir = TempDir::new().unwrap();
+        let root = dir.path();
+        fs::create_dir_all(root.join("src/nested")).unwrap();
+        fs::create_dir_all(root.join("target")).unwrap();
+        fs::write(root.join(".gitignore"), "target/\n").unwrap();
+        fs::write(
+            root.join("src/main.rs"),
+            "fn main() {\n    println!(\"hi\");\n}\n",
+        )
+        .unwrap();
+        fs::write(root.join("src/nested/lib.rs"), "pub mod a;\n").unwrap();
+        fs::write(root.join("src/data.bin"), [0u8, 1, 2, 3]).unwrap();
+        fs::write(root.join("target/out.rs"), "// generated\n").unwrap();
+        dir
+    }
+
+    fn pattern(root: &Path, pattern: &str) -> String {
+        format!("{}/{}", root.to_string_lossy(), pattern)
+    }
+
+    #[test]
+    fn finds_glob_root() {
+        assert_eq!(Path::new("src"), glob_root("src/**/*.rs"));
+        assert_eq!(Path::new("."), glob_root("*.rs"));
+    }
+
+    #[test]
+    fn reads_file_preserving_newlines() {
+        let dir = setup_dir();
+        let inputs = read_files(&[pattern(dir.path(), "src/main.rs")], 1024).unwrap();
+        assert_eq!(1, inputs.len());
+        assert_eq!("fn main() {\n    println!(\"hi\");\n}\n", inputs[0].content);
+    }
+
+    #[test]
+    fn expands_globs_respecting_gitignore() {
+        let dir = setup_dir();
+        let inputs = read_files(&[pattern(dir.path(), "**/*.rs")], 1024).unwrap();
+        let names: Vec<String> = inputs
+            .iter()
+            .map(|input| {
+                Path::new(&input.name)
+                    .strip_prefix(dir.path())
+                    .unwrap()
+                    .to_string_lossy()
+                    .to_string()
+            })
+            .collect();
+        assert_eq!(vec!["src/main.rs", "src/nested/lib.rs"], names);
+    }
+
+    #[test]
+    fn skips_binary_and_large_files() {
+        let dir = setup_dir();
+        let inputs = read_files(&[pattern(dir.path(), "src/*")], 1024).unwrap();
+        assert_eq!(1, inputs.len());
+        assert!(inputs[0].name.ends_with("src/main.rs"));
+
+        let inputs = read_files(&[pattern(dir.path(), "src/main.rs")], 10).unwrap();
+        assert!(inputs.is_empty());
+    }
+
+    #[test]
+    fn errors_when_glob_matches_nothing() {
+        let dir = setup_dir();
+        assert!(read_files(&[pattern(dir.path(), "**/*.py")], 1024).is_err());
+    }
+
+    #[test]
+    fn formats_inputs_with_headers() {
+        let single = vec![NamedInput {
+            name: "stdin".to_string(),
+            content: "a\nb\n".to_string(),
+        }];
+        assert_eq!("a\nb\n", format_inputs(&single));
+
+        let file = vec![NamedInput {
+            name: "a.rs".to_string(),
+            content: "a\n".to_string(),
+        }];
+        assert_eq!("==> a.rs <==\na", format_inputs(&file));
+
+        let multiple = vec![
+            NamedInput {
+                name: "a.rs".to_string(),
+                content: "a\n".to_string(),
+            },
+            NamedInput {
+                name: "b.rs".to_string(),
+                content: "b".to_string(),
+            },
+        ];
+        assert_eq!(
+            "==> a.rs <==\na\n\n==> b.rs <==\nb",
+            format_inputs(&multiple)
+        );
+    }
+
+    #[test]
+    fn hashes_inputs() {
+        let meta = input_meta(&[NamedInput {
+            name: "stdin".to_string(),
+            content: "abc".to_string(),
+        }]);
+        assert_eq!("stdin", meta[0].name);
+        assert_eq!(
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad",
+            meta[0].sha256
+        );
+    }
+}
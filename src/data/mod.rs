@@ -1,3 +1,9 @@
 pub mod args;
+pub mod chunks;
 pub mod dtos;
+pub mod inputs;
 pub mod model;
+pub mod persona;
+pub mod pipeline;
+pub mod project;
+pub mod template;
@@ -1,4 +1,16 @@
-use std::fmt::Display;
+use std::{collections::HashMap, fmt::Display};
+
+use chrono::{DateTime, Utc};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+use crate::effect::{conversation::TurnMeta, encryption::Cipher};
+
+use super::{
+    inputs::input_vars,
+    pipeline::Pipeline,
+    template::{Template, TemplateError},
+};
 
 #[derive(Debug, Clone, Default)]
 pub struct Algo {
@@ -8,16 +20,23 @@ pub struct Algo {
     pub max_tokens: Option<i32>,
 }
 
+/// Data attached to a request from stdin or a file
+#[derive(Debug, Clone, PartialEq)]
+pub struct NamedInput {
+    pub name: String,
+    pub content: String,
+}
+
 #[derive(Debug, Clone)]
 pub enum EditData {
     DataFromPrompt(String),
-    DataFromStdIn(String),
+    DataFromInputs(Vec<NamedInput>),
 }
 
 #[derive(Debug, Clone)]
 pub enum ChatData {
     DataFromPrompt(String),
-    DataFromStdIn(String),
+    DataFromInputs(Vec<NamedInput>),
     NoAdditionalData,
 }
 
@@ -25,6 +44,8 @@ pub enum ChatData {
 pub enum Mode {
     Chat(ChatData),
     Edit(EditData),
+    Shell(ChatData),
+    Pipeline(Pipeline, Vec<NamedInput>),
 }
 
 #[derive(Debug, Clone, Default)]
@@ -34,21 +55,262 @@ pub struct Memory {
     pub convo: String,
     pub convo_len: usize,
     pub enabled: bool,
-    pub convo_path: String,
+    pub convo_dir: String,
+    pub history_backend: HistoryBackend,
+    /// Fold turns that fall out of the conversation window into a running summary
+    pub summarise: bool,
+    pub summary_model: String,
+    /// Ask this turn of the active branch again, starting a new branch from the turn before it
+    pub edit_turn: Option<usize>,
+    /// Encrypts conversation history when configured
+    pub cipher: Option<Cipher>,
+    /// Limits applied to the conversation after each run
+    pub retention: Option<RetentionPolicy>,
+    pub store: MemoryStore,
+    /// Re-ranks long term memories when configured
+    pub rerank: Option<RerankConfig>,
+    /// Which long term memories are added to requests
+    pub filter: MemoryFilter,
+    /// Database of the memories waiting to be saved to the store
+    pub outbox: String,
+    /// Print the memories added to a request
+    pub show_context: bool,
+    /// Ask for the memories added to a request to be cited by number
+    pub cite: bool,
+}
+
+/// Where long term memories are kept
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum MemoryBackend {
+    /// The chatgpt retrieval plugin at --retrieval-plugin-url
+    #[default]
+    RetrievalPlugin,
+    /// A vector index in memory_dir, embedded by the configured embedder
+    Local,
+    /// A BM25 index of the words of memories in memory_dir, which needs no embeddings
+    Bm25,
+}
+
+/// The long term memory store to use, with what is needed to reach it
+#[derive(Debug, Clone)]
+pub enum MemoryStore {
+    RetrievalPlugin {
+        url: String,
+        bearer: String,
+    },
+    Local {
+        path: String,
+        embedder: EmbedderConfig,
+        api_key: Option<String>,
+    },
+    Bm25 {
+        path: String,
+    },
+}
+
+impl Default for MemoryStore {
+    fn default() -> Self {
+        MemoryStore::RetrievalPlugin {
+            url: String::new(),
+            bearer: String::new(),
+        }
+    }
+}
+
+/// How text is turned into vectors for the local memory store
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct EmbedderConfig {
+    #[serde(default)]
+    pub provider: EmbedderProvider,
+    /// Embedding model. Defaults to text-embedding-3-small for openai
+    pub model: Option<String>,
+    /// Base url of an OpenAI compatible server, such as http://localhost:11434/v1
+    pub url: Option<String>,
+    /// Environment variable holding the server's api key, if it needs one
+    pub api_key_env: Option<String>,
+    /// Length of hashing embeddings. Defaults to 512
+    pub dimensions: Option<usize>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EmbedderProvider {
+    /// The OpenAI embeddings api, with the OpenAI token
+    #[default]
+    Openai,
+    /// A server with the same embeddings api
+    OpenaiCompatible,
+    /// Hashes of the words of the text, which needs no model or network, but only matches
+    /// shared words
+    Hashing,
+}
+
+/// Which conversation is used when none is named
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum ConvoScope {
+    /// One conversation shared everywhere
+    #[default]
+    None,
+    /// A conversation per git repository, or per directory outside of one
+    Git,
+    /// A conversation per working directory
+    Directory,
+    /// A conversation per terminal session
+    Tty,
+}
+
+/// How conversation history is stored in the conversation directory
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum HistoryBackend {
+    /// A yaml file per conversation
+    #[default]
+    Yaml,
+    /// An append-only `<name>.jsonl` file per conversation
+    Jsonl,
+    /// A sqlite database shared by all conversations
+    Sqlite,
+}
+
+/// Where the key that encrypts conversation history comes from, the first of them that is set
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct EncryptionConfig {
+    /// File holding a 32 byte key, raw or base64 encoded
+    pub key_file: Option<String>,
+    /// Command that prints the passphrase on its first line, such as `pass show hey-gpt`
+    pub passphrase_command: Option<String>,
+    /// Environment variable holding the passphrase
+    pub passphrase_env: Option<String>,
+}
+
+/// Limits on how much of a conversation is kept. The oldest turns past any of them are removed
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct RetentionPolicy {
+    pub max_age_days: Option<u32>,
+    pub max_turns: Option<usize>,
+    /// Total size of the messages
+    pub max_bytes: Option<usize>,
+}
+
+impl RetentionPolicy {
+    pub fn is_empty(&self) -> bool {
+        self == &Self::default()
+    }
+
+    /// This policy with any limits it does not set taken from `other`
+    pub fn or(&self, other: &RetentionPolicy) -> RetentionPolicy {
+        RetentionPolicy {
+            max_age_days: self.max_age_days.or(other.max_age_days),
+            max_turns: self.max_turns.or(other.max_turns),
+            max_bytes: self.max_bytes.or(other.max_bytes),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct RetentionConfig {
+    /// Limits for every conversation
+    #[serde(flatten)]
+    pub policy: RetentionPolicy,
+    /// Apply the limits to the conversation after each run, not only with `hey-gpt gc`
+    #[serde(default)]
+    pub on_run: bool,
+    /// Limits for particular conversations, overriding those for every conversation
+    #[serde(default)]
+    pub conversations: HashMap<String, RetentionPolicy>,
+}
+
+impl RetentionConfig {
+    pub fn policy(&self, convo: &str) -> RetentionPolicy {
+        match self.conversations.get(convo) {
+            Some(policy) => policy.or(&self.policy),
+            None => self.policy.clone(),
+        }
+    }
+}
+
+/// What a long term memory was saved from
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum SourceType {
+    /// A turn of a conversation
+    #[default]
+    Chat,
+    /// A chunk of a file added by hey-gpt ingest
+    Document,
+    /// A turn of a conversation imported from another tool
+    Import,
+    /// Added by hand with hey-gpt memory add
+    Note,
+}
+
+/// Source saved with every memory before source types were, all of them from conversations
+pub const LEGACY_SOURCE: &str = "email";
+
+impl SourceType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SourceType::Chat => "chat",
+            SourceType::Document => "document",
+            SourceType::Import => "import",
+            SourceType::Note => "note",
+        }
+    }
+
+    /// The stored source, memories saved without one or with the legacy one are from chats
+    pub fn from_stored(source: Option<&str>) -> Self {
+        match source {
+            Some("document") => SourceType::Document,
+            Some("import") => SourceType::Import,
+            Some("note") => SourceType::Note,
+            _ => SourceType::Chat,
+        }
+    }
+}
+
+/// Limits on the long term memories added to a request
+#[derive(Debug, Clone, Default)]
+pub struct MemoryFilter {
+    pub since: Option<DateTime<Utc>>,
+    /// Latest time a memory may have been saved, inclusive
+    pub until: Option<DateTime<Utc>>,
+    pub author: Option<String>,
+    pub source_type: Option<SourceType>,
+}
+
+/// How memories from long term memory are re-ranked before they are added to a request
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct RerankConfig {
+    /// Number of memories to re-rank. Defaults to four times top_k
+    pub candidates: Option<u32>,
+    /// Share of the score from the words a memory has in common with the query, the rest is
+    /// from the memory store. Defaults to 0.3
+    pub lexical_weight: Option<f64>,
+    /// Days after which a memory's score is halved. Memories do not decay by default
+    pub half_life_days: Option<f64>,
+    /// Memories scoring less are dropped. Defaults to 0
+    pub min_score: Option<f64>,
+    /// Trade off between relevance, at 1, and diversity, at 0. Defaults to 0.7
+    pub mmr_lambda: Option<f64>,
 }
 
 #[derive(Debug, Clone, Default)]
 pub struct Output {
-    pub failure_msg: Option<String>,
     pub chat_results: Option<Vec<String>>,
-    pub edit_results: Option<Vec<String>>,
+    pub command_output: Option<String>,
+    /// How the chat response was produced, saved with it to the conversation
+    pub turn_meta: Option<TurnMeta>,
 }
 
 #[derive(Debug, Clone, Default)]
 pub struct Config {
-    pub debug: bool,
     pub preview_data_generation: bool,
-    pub context_url: String,
+    pub shell: String,
+    pub explain_command: bool,
+    pub feed_command_output: bool,
+    pub runs_dir: String,
 }
 
 // prompt could maybe be simplified
@@ -58,6 +320,36 @@ pub struct Prompt {
     pub prompt: String,
     pub final_chat_prompt: Option<String>,
     pub act_as: String,
+    pub template: Option<Template>,
+    pub template_vars: HashMap<String, String>,
+    /// Name of the persona in use, saved to the conversation
+    pub persona: Option<String>,
+}
+
+impl Prompt {
+    /// Renders the template, if there is one, into the prompt. The prompt argument and any
+    /// additional data are available to the template as `{{prompt}}`, `{{stdin}}`, `{{files}}`
+    /// and `{{data}}`
+    pub fn render(self, inputs: &[NamedInput]) -> Result<Prompt, TemplateError> {
+        match &self.template {
+            Some(template) => {
+                let mut vars = self.template_vars.clone();
+                if !self.prompt.is_empty() {
+                    vars.insert("prompt".to_string(), self.prompt.clone());
+                }
+                vars.extend(input_vars(inputs));
+                if let Some(data) = &self.generated_data {
+                    vars.insert("data".to_string(), data.clone());
+                }
+
+                Ok(Prompt {
+                    prompt: template.render(&vars)?,
+                    ..self
+                })
+            }
+            None => Ok(self),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Default)]
@@ -69,10 +361,48 @@ pub struct Model {
     pub prompt: Prompt,
     pub memory: Memory,
     pub open_ai_token: String,
-    pub context_token: String,
 }
 
 impl Model {
+    /// Renders the prompt template. Data used by the template is removed from chat and shell
+    /// requests so that it is not also appended to the prompt.
+    pub fn with_rendered_prompt(self) -> Result<Model, TemplateError> {
+        let consumes_data = match &self.prompt.template {
+            Some(template) => ["stdin", "files", "data"]
+                .iter()
+                .any(|var| template.uses(var)),
+            None => return Ok(self),
+        };
+
+        let inputs = match &self.mode {
+            Mode::Chat(ChatData::DataFromInputs(inputs))
+            | Mode::Edit(EditData::DataFromInputs(inputs))
+            | Mode::Shell(ChatData::DataFromInputs(inputs)) => inputs.clone(),
+            _ => vec![],
+        };
+        let prompt = self.prompt.render(&inputs)?;
+
+        Ok(match self.mode {
+            Mode::Chat(_) if consumes_data => Model {
+                mode: Mode::Chat(ChatData::NoAdditionalData),
+                prompt: Prompt {
+                    generated_data: None,
+                    ..prompt
+                },
+                ..self
+            },
+            Mode::Shell(_) if consumes_data => Model {
+                mode: Mode::Shell(ChatData::NoAdditionalData),
+                prompt: Prompt {
+                    generated_data: None,
+                    ..prompt
+                },
+                ..self
+            },
+            _ => Model { prompt, ..self },
+        })
+    }
+
     pub fn with_chat_prompt(self, prompt: String) -> Model {
         Model {
             prompt: Prompt {
@@ -92,10 +422,20 @@ impl Model {
             ..self
         }
     }
-    pub fn with_edit_response(self, response: Vec<String>) -> Model {
+    pub fn with_turn_meta(self, turn_meta: TurnMeta) -> Model {
+        Model {
+            output: Output {
+                turn_meta: Some(turn_meta),
+                ..self.output
+            },
+            ..self
+        }
+    }
+
+    pub fn with_command_output(self, command_output: String) -> Model {
         Model {
             output: Output {
-                edit_results: Some(response),
+                command_output: Some(command_output),
                 ..self.output
             },
             ..self
@@ -0,0 +1,87 @@
+use std::{collections::HashMap, error::Error, fmt::Display};
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug)]
+pub struct PersonaError(pub String);
+
+impl Display for PersonaError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+impl Error for PersonaError {}
+
+/// A named system prompt with its own defaults, configured under `personas` in config.yaml
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Persona {
+    pub description: Option<String>,
+    pub system: String,
+    pub model: Option<String>,
+    pub temp: Option<f32>,
+    /// Memory categories to query by default
+    pub memories: Option<Vec<String>>,
+    /// Hint on how responses should be formatted, added to the system message
+    pub format: Option<String>,
+}
+
+impl Persona {
+    pub fn find<'a>(
+        personas: &'a HashMap<String, Persona>,
+        name: &str,
+    ) -> Result<&'a Persona, PersonaError> {
+        personas.get(name).ok_or_else(|| {
+            let mut names: Vec<&String> = personas.keys().collect();
+            names.sort();
+            PersonaError(format!(
+                "Unknown persona '{}'. Available personas: {}",
+                name,
+                names
+                    .iter()
+                    .map(|name| name.as_str())
+                    .collect::<Vec<&str>>()
+                    .join(", ")
+            ))
+        })
+    }
+
+    pub fn system_message(&self) -> String {
+        match &self.format {
+            Some(format) => format!("{}\n{}", self.system.trim_end(), format),
+            None => self.system.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::Persona;
+
+    #[test]
+    fn system_message_includes_format_hint() {
+        let persona = Persona {
+            system: "You review code.\n".to_string(),
+            format: Some("Respond with a bulleted list.".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            "You review code.\nRespond with a bulleted list.",
+            persona.system_message()
+        );
+    }
+
+    #[test]
+    fn find_lists_available_personas_when_missing() {
+        let personas = HashMap::from([
+            ("reviewer".to_string(), Persona::default()),
+            ("editor".to_string(), Persona::default()),
+        ]);
+        assert!(Persona::find(&personas, "reviewer").is_ok());
+        assert_eq!(
+            "Unknown persona 'tester'. Available personas: editor, reviewer",
+            Persona::find(&personas, "tester").unwrap_err().0
+        );
+    }
+}
@@ -0,0 +1,231 @@
+use std::{collections::HashMap, error::Error, fmt::Display, path::Path};
+
+use serde::{Deserialize, Serialize};
+
+use crate::utils;
+
+use super::template::Template;
+
+#[derive(Debug)]
+pub struct PipelineError(pub String);
+
+impl Display for PipelineError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+impl Error for PipelineError {}
+
+/// A single request in a pipeline. The prompt can reference the output of earlier stages by
+/// their name, e.g. `{{outline}}`, and `{{item}}` when fanning out over an earlier stage
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Stage {
+    pub name: String,
+    #[serde(default)]
+    pub prompt: String,
+    /// Name of a template to use as the prompt, its front-matter is used for unset settings
+    pub template: Option<String>,
+    pub act_as: Option<String>,
+    pub model: Option<String>,
+    pub temp: Option<f32>,
+    /// Ask the user to accept or edit the output before continuing
+    #[serde(default)]
+    pub preview: bool,
+    /// Name of an earlier stage whose output is split into list items, running this stage
+    /// once for each
+    pub for_each: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Pipeline {
+    pub name: Option<String>,
+    #[serde(default)]
+    pub vars: HashMap<String, String>,
+    pub stages: Vec<Stage>,
+}
+
+impl Pipeline {
+    /// Loads a pipeline, resolving stage templates and checking names and stage references. It
+    /// is named after its file when no name is given
+    pub fn load(file_path: &str, templates_dir: &str) -> Result<Pipeline, Box<dyn Error>> {
+        let pipeline: Pipeline = utils::deserialise_from_file(file_path)?;
+        let name = pipeline.name.clone().or(Path::new(file_path)
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().to_string()));
+
+        let stages = pipeline
+            .stages
+            .into_iter()
+            .map(|stage| match &stage.template {
+                Some(template_name) => {
+                    let template = Template::load(templates_dir, template_name)?;
+                    Ok(Stage {
+                        prompt: template.body,
+                        act_as: stage.act_as.or(template.defaults.act_as),
+                        model: stage.model.or(template.defaults.model),
+                        temp: stage.temp.or(template.defaults.temp),
+                        ..stage
+                    })
+                }
+                None => Ok(stage),
+            })
+            .collect::<Result<Vec<Stage>, Box<dyn Error>>>()?;
+
+        let pipeline = Pipeline {
+            name,
+            stages,
+            ..pipeline
+        };
+        pipeline.validate()?;
+        Ok(pipeline)
+    }
+
+    fn validate(&self) -> Result<(), PipelineError> {
+        if self.stages.is_empty() {
+            return Err(PipelineError("Pipeline has no stages".to_string()));
+        }
+        // names are used for the directories and files of a run's outputs
+        if let Some(name) = &self.name {
+            utils::check_name("pipeline", name).map_err(PipelineError)?;
+        }
+
+        for (i, stage) in self.stages.iter().enumerate() {
+            let earlier = &self.stages[..i];
+            if stage.name.is_empty() {
+                return Err(PipelineError(format!("Stage {} has no name", i + 1)));
+            }
+            utils::check_name("stage", &stage.name).map_err(PipelineError)?;
+            if earlier.iter().any(|s| s.name == stage.name) {
+                return Err(PipelineError(format!(
+                    "Stage name '{}' is used more than once",
+                    stage.name
+                )));
+            }
+            if stage.prompt.trim().is_empty() {
+                return Err(PipelineError(format!(
+                    "Stage '{}' needs a prompt or template",
+                    stage.name
+                )));
+            }
+            if let Some(for_each) = &stage.for_each {
+                if !earlier.iter().any(|s| &s.name == for_each) {
+                    return Err(PipelineError(format!(
+                        "Stage '{}' fans out over '{}' which is not an earlier stage",
+                        stage.name, for_each
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Splits model output into list items. Bulleted or numbered lines are used if there are any,
+/// otherwise each non-empty line is an item
+pub fn split_list_items(output: &str) -> Vec<String> {
+    let lines: Vec<&str> = output
+        .lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty())
+        .collect();
+
+    let list_items: Vec<String> = lines
+        .iter()
+        .filter_map(|line| strip_list_marker(line))
+        .map(|item| item.to_string())
+        .collect();
+
+    if list_items.is_empty() {
+        lines.into_iter().map(|line| line.to_string()).collect()
+    } else {
+        list_items
+    }
+}
+
+fn strip_list_marker(line: &str) -> Option<&str> {
+    if let Some(item) = line
+        .strip_prefix("- ")
+        .or(line.strip_prefix("* "))
+        .or(line.strip_prefix("+ "))
+    {
+        return Some(item.trim());
+    }
+
+    let digits = line.chars().take_while(|c| c.is_ascii_digit()).count();
+    if digits > 0 {
+        let rest = &line[digits..];
+        return rest
+            .strip_prefix(". ")
+            .or(rest.strip_prefix(") "))
+            .map(|item| item.trim());
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{split_list_items, Pipeline, Stage};
+
+    fn stage(name: &str, for_each: Option<&str>) -> Stage {
+        Stage {
+            name: name.to_string(),
+            prompt: "prompt".to_string(),
+            for_each: for_each.map(|s| s.to_string()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn splits_bulleted_and_numbered_items() {
+        let output = "Here are some ideas:\n- first\n* second\n\n1. third\n12) fourth\nThanks";
+        assert_eq!(
+            vec!["first", "second", "third", "fourth"],
+            split_list_items(output)
+        );
+    }
+
+    #[test]
+    fn splits_plain_lines() {
+        assert_eq!(vec!["a", "b"], split_list_items("a\n\n  b  \n"));
+    }
+
+    #[test]
+    fn validates_fan_out_references_earlier_stage() {
+        let pipeline = Pipeline {
+            stages: vec![stage("a", None), stage("b", Some("a"))],
+            ..Default::default()
+        };
+        assert!(pipeline.validate().is_ok());
+
+        let pipeline = Pipeline {
+            stages: vec![stage("a", Some("b")), stage("b", None)],
+            ..Default::default()
+        };
+        assert!(pipeline.validate().is_err());
+    }
+
+    #[test]
+    fn validates_unique_names() {
+        let pipeline = Pipeline {
+            stages: vec![stage("a", None), stage("a", None)],
+            ..Default::default()
+        };
+        assert!(pipeline.validate().is_err());
+    }
+
+    #[test]
+    fn validates_names_stay_in_the_run_directory() {
+        let pipeline = Pipeline {
+            stages: vec![stage("../outline", None)],
+            ..Default::default()
+        };
+        assert!(pipeline.validate().is_err());
+
+        let pipeline = Pipeline {
+            name: Some("../../.ssh".to_string()),
+            stages: vec![stage("a", None)],
+            ..Default::default()
+        };
+        assert!(pipeline.validate().is_err());
+    }
+}
@@ -0,0 +1,158 @@
+use std::{
+    error::Error,
+    fmt::Display,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::utils;
+
+use super::model::ConvoScope;
+
+/// Settings for the directory it is in and those below it
+pub const PROJECT_FILE: &str = ".hey-gpt.yaml";
+
+#[derive(Debug)]
+pub struct ProjectError(pub String);
+
+impl Display for ProjectError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+impl Error for ProjectError {}
+
+/// Conversation, persona and memory categories pinned for a project by a `.hey-gpt.yaml`
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Project {
+    pub convo: Option<String>,
+    pub persona: Option<String>,
+    pub memories: Option<Vec<String>>,
+}
+
+impl Project {
+    /// The nearest project file in the directory or its parents
+    pub fn find(dir: &Path) -> Result<Option<Project>, Box<dyn Error>> {
+        match dir
+            .ancestors()
+            .map(|dir| dir.join(PROJECT_FILE))
+            .find(|path| path.is_file())
+        {
+            Some(path) => {
+                let project: Project = utils::deserialise_from_file(&path.to_string_lossy())
+                    .map_err(|e| {
+                        ProjectError(format!("Could not read '{}': {}", path.display(), e))
+                    })?;
+                if let Some(convo) = &project.convo {
+                    utils::check_name("conversation", convo)
+                        .map_err(|e| ProjectError(format!("{} in '{}'", e, path.display())))?;
+                }
+                Ok(Some(project))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+/// Name of the conversation for the scope, from the working directory or terminal. `None` when
+/// the scope is `none` or there is no terminal
+pub fn scoped_convo(scope: ConvoScope, dir: &Path) -> Option<String> {
+    match scope {
+        ConvoScope::None => None,
+        ConvoScope::Git => Some(path_convo(&git_root(dir).unwrap_or(dir.to_path_buf()))),
+        ConvoScope::Directory => Some(path_convo(dir)),
+        ConvoScope::Tty => tty_convo(),
+    }
+}
+
+/// The top of the repository the directory is in, a worktree's `.git` is a file
+fn git_root(dir: &Path) -> Option<PathBuf> {
+    dir.ancestors()
+        .find(|dir| dir.join(".git").exists())
+        .map(Path::to_path_buf)
+}
+
+/// The directory's name, to recognise the conversation by, and a hash of its path to tell apart
+/// directories with the same name
+fn path_convo(dir: &Path) -> String {
+    let name: String = dir
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or("root".to_string())
+        .chars()
+        .map(|c| match c.is_alphanumeric() || c == '-' || c == '_' {
+            true => c,
+            false => '_',
+        })
+        .collect();
+    let hash = format!("{:x}", Sha256::digest(dir.to_string_lossy().as_bytes()));
+    format!("{}-{}", name, &hash[..8])
+}
+
+/// The controlling terminal with its session, as terminal devices are reused by later sessions
+fn tty_convo() -> Option<String> {
+    let output = Command::new("ps")
+        .args(["-o", "tty=,sess=", "-p", &std::process::id().to_string()])
+        .output()
+        .ok()?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let mut fields = stdout.split_whitespace();
+    let tty = fields.next().filter(|tty| !tty.starts_with('?'))?;
+    let session = fields.next().unwrap_or_default();
+    Some(format!("tty-{}-{}", tty.replace('/', ""), session))
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use crate::data::model::ConvoScope;
+
+    use super::{scoped_convo, Project, PROJECT_FILE};
+
+    #[test]
+    fn nearest_project_file_applies() {
+        let dir = tempdir().unwrap();
+        let nested = dir.path().join("src").join("bin");
+        std::fs::create_dir_all(&nested).unwrap();
+        assert!(Project::find(&nested).unwrap().is_none());
+
+        std::fs::write(
+            dir.path().join(PROJECT_FILE),
+            "convo: hey-gpt\nmemories: [docs]\n",
+        )
+        .unwrap();
+        let project = Project::find(&nested).unwrap().unwrap();
+        assert_eq!(Some("hey-gpt".to_string()), project.convo);
+        assert_eq!(Some(vec!["docs".to_string()]), project.memories);
+        assert_eq!(None, project.persona);
+
+        std::fs::write(nested.join(PROJECT_FILE), "persona: [").unwrap();
+        assert!(Project::find(&nested).is_err());
+
+        std::fs::write(nested.join(PROJECT_FILE), "convo: ../../.bashrc\n").unwrap();
+        assert!(Project::find(&nested).is_err());
+    }
+
+    #[test]
+    fn git_scope_shares_a_conversation_across_the_repository() {
+        let dir = tempdir().unwrap();
+        let repo = dir.path().join("my repo");
+        let nested = repo.join("src");
+        std::fs::create_dir_all(repo.join(".git")).unwrap();
+        std::fs::create_dir_all(&nested).unwrap();
+
+        let convo = scoped_convo(ConvoScope::Git, &nested).unwrap();
+        assert!(convo.starts_with("my_repo-"));
+        assert_eq!(Some(convo.clone()), scoped_convo(ConvoScope::Git, &repo));
+        assert_eq!(Some(convo), scoped_convo(ConvoScope::Directory, &repo));
+        assert_ne!(
+            scoped_convo(ConvoScope::Directory, &repo),
+            scoped_convo(ConvoScope::Directory, &nested)
+        );
+        assert_eq!(None, scoped_convo(ConvoScope::None, &repo));
+    }
+}
@@ -0,0 +1,194 @@
+use std::{collections::HashMap, error::Error, fmt::Display, path::Path};
+
+use serde::Deserialize;
+
+const FRONT_MATTER_DELIMITER: &str = "---";
+
+#[derive(Debug)]
+pub struct TemplateError(pub String);
+
+impl Display for TemplateError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+impl Error for TemplateError {}
+
+/// Values set in the front-matter of a template, used unless overridden by command arguments
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct TemplateDefaults {
+    pub description: Option<String>,
+    pub model: Option<String>,
+    pub temp: Option<f32>,
+    pub act_as: Option<String>,
+    #[serde(default)]
+    pub vars: HashMap<String, String>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Template {
+    pub name: String,
+    pub defaults: TemplateDefaults,
+    pub body: String,
+}
+
+impl Template {
+    /// Parses a template with optional yaml front-matter between `---` lines
+    pub fn parse(name: &str, contents: &str) -> Result<Template, Box<dyn Error>> {
+        let (defaults, body) = match contents.strip_prefix(FRONT_MATTER_DELIMITER) {
+            Some(rest) => {
+                let end = rest
+                    .find(&format!("\n{}", FRONT_MATTER_DELIMITER))
+                    .ok_or_else(|| {
+                        TemplateError(format!("Front-matter of template '{}' is not closed", name))
+                    })?;
+                let front_matter = &rest[..end];
+                let body = &rest[end + FRONT_MATTER_DELIMITER.len() + 1..];
+                let defaults: Option<TemplateDefaults> = serde_yaml::from_str(front_matter)?;
+                (
+                    defaults.unwrap_or_default(),
+                    body.strip_prefix('\n').unwrap_or(body),
+                )
+            }
+            None => (TemplateDefaults::default(), contents),
+        };
+
+        Ok(Template {
+            name: name.to_string(),
+            defaults,
+            body: body.trim_end().to_string(),
+        })
+    }
+
+    /// Loads every template in the directory, named by their file stem
+    pub fn list(templates_dir: &str) -> Result<Vec<Template>, Box<dyn Error>> {
+        if !Path::new(templates_dir).is_dir() {
+            return Ok(vec![]);
+        }
+
+        let mut templates = std::fs::read_dir(templates_dir)?
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.is_file())
+            .map(|path| {
+                let name = path
+                    .file_stem()
+                    .map(|stem| stem.to_string_lossy().to_string())
+                    .unwrap_or_default();
+                let contents = std::fs::read_to_string(&path)?;
+                Template::parse(&name, &contents)
+            })
+            .collect::<Result<Vec<Template>, Box<dyn Error>>>()?;
+
+        templates.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(templates)
+    }
+
+    pub fn load(templates_dir: &str, name: &str) -> Result<Template, Box<dyn Error>> {
+        Template::list(templates_dir)?
+            .into_iter()
+            .find(|template| template.name == name)
+            .ok_or_else(|| {
+                Box::new(TemplateError(format!(
+                    "Could not find template '{}' in {}",
+                    name, templates_dir
+                ))) as Box<dyn Error>
+            })
+    }
+
+    /// True if the template contains a `{{var}}` placeholder for the variable
+    pub fn uses(&self, var: &str) -> bool {
+        placeholders(&self.body).any(|(_, name)| name == var)
+    }
+
+    /// Replaces `{{var}}` placeholders with the given variables, falling back to the defaults
+    /// in the front-matter. Fails if any placeholder has no value.
+    pub fn render(&self, vars: &HashMap<String, String>) -> Result<String, TemplateError> {
+        let mut rendered = String::new();
+        let mut missing: Vec<&str> = vec![];
+        let mut last_end = 0;
+
+        for ((start, end), name) in placeholders(&self.body) {
+            rendered.push_str(&self.body[last_end..start]);
+            match vars.get(name).or(self.defaults.vars.get(name)) {
+                Some(value) => rendered.push_str(value),
+                None => missing.push(name),
+            }
+            last_end = end;
+        }
+        rendered.push_str(&self.body[last_end..]);
+
+        if missing.is_empty() {
+            Ok(rendered)
+        } else {
+            Err(TemplateError(format!(
+                "Template '{}' is missing values for: {}",
+                self.name,
+                missing.join(", ")
+            )))
+        }
+    }
+}
+
+/// Iterates the byte range and trimmed name of each `{{name}}` placeholder
+fn placeholders(body: &str) -> impl Iterator<Item = ((usize, usize), &str)> {
+    let mut position = 0;
+    std::iter::from_fn(move || {
+        let start = position + body[position..].find("{{")?;
+        let end = start + body[start..].find("}}")? + 2;
+        position = end;
+        Some(((start, end), body[start + 2..end - 2].trim()))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::Template;
+
+    #[test]
+    fn parses_front_matter() {
+        let contents = "---\nmodel: gpt-4\ntemp: 0.2\nact_as: A reviewer\n---\nReview {{lang}}";
+        let template = Template::parse("review", contents).unwrap();
+        assert_eq!(Some("gpt-4".to_string()), template.defaults.model);
+        assert_eq!(Some(0.2), template.defaults.temp);
+        assert_eq!(Some("A reviewer".to_string()), template.defaults.act_as);
+        assert_eq!("Review {{lang}}", template.body);
+    }
+
+    #[test]
+    fn parses_template_without_front_matter() {
+        let template = Template::parse("plain", "Write tests for {{stdin}}\n").unwrap();
+        assert_eq!(None, template.defaults.model);
+        assert_eq!("Write tests for {{stdin}}", template.body);
+    }
+
+    #[test]
+    fn renders_vars_and_defaults() {
+        let contents = "---\nvars:\n  lang: python\n  focus: security\n---\nReview this {{ lang }} diff for {{focus}} issues:\n{{stdin}}";
+        let template = Template::parse("review", contents).unwrap();
+        let vars = HashMap::from([
+            ("lang".to_string(), "rust".to_string()),
+            ("stdin".to_string(), "fn main() {}".to_string()),
+        ]);
+        assert_eq!(
+            "Review this rust diff for security issues:\nfn main() {}",
+            template.render(&vars).unwrap()
+        );
+    }
+
+    #[test]
+    fn render_fails_on_missing_vars() {
+        let template = Template::parse("review", "{{a}} and {{b}}").unwrap();
+        let vars = HashMap::from([("a".to_string(), "1".to_string())]);
+        let error = template.render(&vars).unwrap_err();
+        assert_eq!("Template 'review' is missing values for: b", error.0);
+    }
+
+    #[test]
+    fn finds_used_placeholders() {
+        let template = Template::parse("t", "Summarise {{ stdin }}").unwrap();
+        assert!(template.uses("stdin"));
+        assert!(!template.uses("prompt"));
+    }
+}
@@ -0,0 +1,278 @@
+use std::error::Error;
+
+use async_trait::async_trait;
+use chrono::Duration;
+
+use super::{
+    conversation::new_id,
+    search_index::{Document, SearchFilter, SearchIndex},
+    LongMemEffect, LongMemOutput, LongMemQueryOpt, LongMemSaveInp,
+};
+
+/// Long term memory in a search index, with a collection per category. Memories are found by
+/// the words they share with the query, ranked with BM25, so no embedding model is needed
+pub struct Bm25Memory {
+    index: SearchIndex,
+    top_k: u32,
+}
+
+impl Bm25Memory {
+    pub fn new(index: SearchIndex, top_k: u32) -> Self {
+        Self { index, top_k }
+    }
+}
+
+#[async_trait]
+impl LongMemEffect for Bm25Memory {
+    async fn save(
+        &self,
+        input: &[LongMemSaveInp],
+        category: &str,
+    ) -> Result<Vec<String>, Box<dyn Error>> {
+        let documents: Vec<Document> = input
+            .iter()
+            .map(|input| Document {
+                key: new_id(),
+                author: input.author.clone(),
+                created_at: input.created_at,
+                text: input.text.clone(),
+                source_type: input.source_type,
+                source: input.source.clone(),
+            })
+            .collect();
+        let ids = documents
+            .iter()
+            .map(|document| document.key.clone())
+            .collect();
+        self.index.add(category, documents).await?;
+        Ok(ids)
+    }
+
+    async fn query(
+        &self,
+        query: &str,
+        query_opts: &[LongMemQueryOpt],
+    ) -> Result<Vec<LongMemOutput>, Box<dyn Error>> {
+        let mut hits = vec![];
+        for opt in query_opts {
+            hits.extend(
+                self.index
+                    .search(query, filter(opt), self.top_k as usize)
+                    .await?,
+            );
+        }
+
+        hits.sort_by(|a, b| b.relevance.total_cmp(&a.relevance));
+        Ok(hits
+            .into_iter()
+            .take(self.top_k as usize)
+            .map(|hit| output(hit.collection, hit.document, hit.relevance))
+            .collect())
+    }
+
+    async fn list(
+        &self,
+        query_opt: &LongMemQueryOpt,
+        limit: usize,
+    ) -> Result<Vec<LongMemOutput>, Box<dyn Error>> {
+        Ok(self
+            .index
+            .documents(filter(query_opt), limit)
+            .await?
+            .into_iter()
+            .map(|(collection, document)| output(collection, document, 1.0))
+            .collect())
+    }
+
+    async fn get(&self, id: &str) -> Result<Option<LongMemOutput>, Box<dyn Error>> {
+        Ok(self
+            .index
+            .document(id)
+            .await?
+            .map(|(collection, document)| output(collection, document, 1.0)))
+    }
+
+    async fn delete(&self, id: &str) -> Result<(), Box<dyn Error>> {
+        Ok(self.index.remove_document(id).await?)
+    }
+
+    async fn delete_category(&self, category: &str) -> Result<(), Box<dyn Error>> {
+        Ok(self.index.remove(category).await?)
+    }
+}
+
+fn filter(query_opt: &LongMemQueryOpt) -> SearchFilter {
+    SearchFilter {
+        collections: vec![query_opt.category.clone()],
+        author: query_opt.author.clone(),
+        source_type: query_opt.source_type,
+        since: query_opt.query_window.min,
+        // the window includes its end, the filter does not
+        until: query_opt
+            .query_window
+            .max
+            .map(|max| max + Duration::milliseconds(1)),
+    }
+}
+
+fn output(collection: String, document: Document, score: f64) -> LongMemOutput {
+    LongMemOutput {
+        id: document.key,
+        text: document.text,
+        created_at: document.created_at,
+        author: document.author,
+        category: collection,
+        score,
+        source_type: document.source_type,
+        source: document.source,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, Utc};
+    use tempfile::tempdir;
+
+    use crate::{
+        data::model::SourceType,
+        effect::{
+            search_index::SearchIndex, LongMemEffect, LongMemQueryOpt, LongMemSaveInp, QueryWindow,
+        },
+    };
+
+    use super::Bm25Memory;
+
+    #[tokio::test]
+    async fn finds_memories_sharing_words_in_the_category_and_window() {
+        let dir = tempdir().unwrap();
+        let memory = Bm25Memory::new(SearchIndex::new(dir.path().join("bm25.sqlite3")), 3);
+        let now = Utc::now();
+        let save = |text: &str, days_ago: i64| LongMemSaveInp {
+            text: text.to_string(),
+            author: "user".to_string(),
+            created_at: now - Duration::days(days_ago),
+            source_type: SourceType::Chat,
+            source: None,
+        };
+        let ids = memory
+            .save(
+                &[
+                    save("postgres runs on port 5433 here", 3),
+                    save("the cat is called postgres", 2),
+                    save("lunch is at noon", 1),
+                ],
+                "work",
+            )
+            .await
+            .unwrap();
+        memory
+            .save(&[save("postgres port is 5432", 0)], "other")
+            .await
+            .unwrap();
+
+        let window = |category: &str, max| LongMemQueryOpt {
+            category: category.to_string(),
+            query_window: QueryWindow { min: None, max },
+            author: None,
+            source_type: None,
+        };
+        let found = memory
+            .query("which port does postgres use", &[window("work", None)])
+            .await
+            .unwrap();
+        let texts: Vec<&str> = found.iter().map(|output| output.text.as_str()).collect();
+        assert_eq!(
+            vec![
+                "postgres runs on port 5433 here",
+                "the cat is called postgres"
+            ],
+            texts
+        );
+        assert!(found[0].score > found[1].score);
+        assert!(found
+            .iter()
+            .all(|output| output.score > 0.0 && output.score < 1.0));
+        assert_eq!("work", found[0].category);
+
+        let older = memory
+            .query("postgres", &[window("work", Some(now - Duration::days(3)))])
+            .await
+            .unwrap();
+        assert_eq!(
+            vec![ids[0].clone()],
+            older.into_iter().map(|o| o.id).collect::<Vec<_>>()
+        );
+
+        let listed = memory.list(&window("work", None), 2).await.unwrap();
+        assert_eq!(
+            vec!["lunch is at noon", "the cat is called postgres"],
+            listed.iter().map(|o| o.text.as_str()).collect::<Vec<_>>()
+        );
+        let shown = memory.get(&ids[0]).await.unwrap().unwrap();
+        assert_eq!(
+            ("postgres runs on port 5433 here", "work"),
+            (shown.text.as_str(), shown.category.as_str())
+        );
+        assert!(memory.get("missing").await.unwrap().is_none());
+
+        memory.delete(&ids[0]).await.unwrap();
+        memory.delete_category("other").await.unwrap();
+        let left = memory
+            .query("port", &[window("work", None), window("other", None)])
+            .await
+            .unwrap();
+        assert!(left.is_empty());
+    }
+
+    #[tokio::test]
+    async fn filters_memories_by_author_and_source_type() {
+        let dir = tempdir().unwrap();
+        let memory = Bm25Memory::new(SearchIndex::new(dir.path().join("bm25.sqlite3")), 5);
+        let save = |text: &str, author: &str, source_type| LongMemSaveInp {
+            text: text.to_string(),
+            author: author.to_string(),
+            created_at: Utc::now(),
+            source_type,
+            source: None,
+        };
+        memory
+            .save(
+                &[
+                    save("the vpn needs a token", "user", SourceType::Chat),
+                    save("use the vpn from home", "assistant", SourceType::Chat),
+                    save("vpn tokens expire monthly", "user", SourceType::Note),
+                ],
+                "work",
+            )
+            .await
+            .unwrap();
+
+        let filtered = |author: Option<&str>, source_type| LongMemQueryOpt {
+            category: "work".to_string(),
+            query_window: QueryWindow {
+                min: None,
+                max: None,
+            },
+            author: author.map(str::to_string),
+            source_type,
+        };
+        let texts = |outputs: Vec<crate::effect::LongMemOutput>| {
+            outputs
+                .into_iter()
+                .map(|output| output.text)
+                .collect::<Vec<String>>()
+        };
+        let notes = memory
+            .query("vpn", &[filtered(None, Some(SourceType::Note))])
+            .await
+            .unwrap();
+        assert_eq!(vec!["vpn tokens expire monthly"], texts(notes));
+        let user_chats = memory
+            .list(&filtered(Some("user"), Some(SourceType::Chat)), 5)
+            .await
+            .unwrap();
+        assert_eq!(vec!["the vpn needs a token"], texts(user_chats));
+        let assistant = memory.list(&filtered(Some("assistant"), None), 5).await;
+        assert_eq!(vec!["use the vpn from home"], texts(assistant.unwrap()));
+    }
+}
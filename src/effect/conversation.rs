@@ -1,4 +1,11 @@
-use std::error::Error;
+use std::{
+    collections::{HashMap, HashSet},
+    error::Error,
+    fmt::Display,
+    fs::{File, OpenOptions},
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
 
 use async_trait::async_trait;
 use chrono::{DateTime, Utc};
@@ -6,20 +13,279 @@ use serde::{Deserialize, Serialize};
 
 use crate::utils;
 
-use super::{ShortMemEffect, ShortMemInput, ShortMemOutput};
+use super::{ScriptEdit, ShortMemEffect, ShortMemInput, ShortMemOutput};
 
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
-struct DialogueSegment {
-    role: String,
-    content: String,
-    created_at: DateTime<Utc>,
+/// Value of the branch pointer when the next turn starts a new branch from the start of the
+/// conversation
+pub const ROOT: &str = "root";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DialogueSegment {
+    /// Segments saved before ids were added are given their position in the conversation
+    #[serde(default)]
+    pub id: String,
+    /// The segment this one follows, none for the first segment of a branch
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parent: Option<String>,
+    pub role: String,
+    pub content: String,
+    pub created_at: DateTime<Utc>,
+    /// How a response was produced. Only set on responses saved since it was added
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub meta: Option<TurnMeta>,
+    /// Id of the long term memory saved for the segment, deleted along with it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub memory_id: Option<String>,
+}
+
+/// Settings and results of the request that produced a response, kept so a conversation can be
+/// audited and reproduced
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct TurnMeta {
+    pub provider: String,
+    pub model: String,
+    pub temperature: f32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<i32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub usage: Option<TokenUsage>,
+    /// Milliseconds from sending the request to the end of the response
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub latency_ms: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub finish_reason: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub persona: Option<String>,
+    /// Files and stdin attached to the prompt
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub inputs: Vec<InputMeta>,
+    /// Ids of the long term memories included in the request
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub memories: Vec<String>,
+    /// Output of running the suggested command, kept with --feed-output
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub command_output: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct TokenUsage {
+    pub prompt_tokens: u32,
+    pub completion_tokens: u32,
+    pub total_tokens: u32,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct InputMeta {
+    pub name: String,
+    pub sha256: String,
+}
+
+/// A conversation, stored as a tree of segments so that earlier turns can be asked again without
+/// losing the original answers
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Script {
+    #[serde(default)]
+    pub dialogue: Vec<DialogueSegment>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub persona: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub summary: Option<Summary>,
+    /// Id of the segment the next turn follows. The latest segment when not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub head: Option<String>,
+}
+
+/// Running summary of the start of a conversation
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Summary {
+    pub text: String,
+    /// Number of segments from the start of the active branch folded into the summary
+    pub covered: usize,
+    /// Id of the last segment folded into the summary
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub through: Option<String>,
 }
 
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
-struct Script {
-    dialogue: Vec<DialogueSegment>,
+/// Random id for a segment, also given to memories in the local stores
+pub fn new_id() -> String {
+    uuid::Uuid::new_v4().simple().to_string()
 }
 
+/// Splits segments into turns. A turn starts at each user message, along with any segments
+/// before the first one
+pub fn turns<'a>(segments: &[&'a DialogueSegment]) -> Vec<Vec<&'a DialogueSegment>> {
+    let mut turns: Vec<Vec<&DialogueSegment>> = vec![];
+    for segment in segments {
+        match turns.last_mut() {
+            Some(turn) if segment.role != "user" => turn.push(segment),
+            _ => turns.push(vec![segment]),
+        }
+    }
+    turns
+}
+
+impl Script {
+    /// Gives segments saved before ids were added an id from their position, following the
+    /// segment before them
+    pub fn normalise(mut self) -> Self {
+        let mut previous: Option<String> = None;
+        for (i, segment) in self.dialogue.iter_mut().enumerate() {
+            if segment.id.is_empty() {
+                segment.id = i.to_string();
+                segment.parent = previous.clone();
+            }
+            previous = Some(segment.id.clone());
+        }
+        self
+    }
+
+    /// Id of the segment the next turn follows, none when starting from the root
+    pub fn head(&self) -> Option<&str> {
+        match self.head.as_deref() {
+            Some(ROOT) => None,
+            Some(head) => Some(head),
+            None => self.dialogue.last().map(|segment| segment.id.as_str()),
+        }
+    }
+
+    /// Segments from the start of the conversation up to and including `id`
+    pub fn branch_to(&self, id: Option<&str>) -> Vec<&DialogueSegment> {
+        let by_id: HashMap<&str, &DialogueSegment> = self
+            .dialogue
+            .iter()
+            .map(|segment| (segment.id.as_str(), segment))
+            .collect();
+
+        let mut branch = vec![];
+        let mut next = id;
+        while let Some(segment) = next.and_then(|id| by_id.get(id)) {
+            // guards against a cycle in a hand edited file
+            if branch.len() == self.dialogue.len() {
+                break;
+            }
+            branch.push(*segment);
+            next = segment.parent.as_deref();
+        }
+        branch.reverse();
+        branch
+    }
+
+    /// The active branch
+    pub fn branch(&self) -> Vec<&DialogueSegment> {
+        self.branch_to(self.head())
+    }
+
+    /// The last segment of every branch
+    pub fn leaves(&self) -> Vec<&DialogueSegment> {
+        let parents: HashSet<&str> = self
+            .dialogue
+            .iter()
+            .filter_map(|segment| segment.parent.as_deref())
+            .collect();
+        self.dialogue
+            .iter()
+            .filter(|segment| !parents.contains(segment.id.as_str()))
+            .collect()
+    }
+
+    /// Turns of the active branch
+    pub fn turns(&self) -> Vec<Vec<&DialogueSegment>> {
+        turns(&self.branch())
+    }
+
+    /// Adds segments to the end of the active branch
+    pub fn append(&mut self, segments: Vec<DialogueSegment>) {
+        let mut parent = self.head().map(|head| head.to_string());
+        for segment in segments {
+            let id = segment.id.clone();
+            self.dialogue.push(DialogueSegment {
+                parent: parent.take(),
+                ..segment
+            });
+            parent = Some(id);
+        }
+        self.head = None;
+    }
+
+    /// Moves the branch pointer so the next turn follows the segment, or starts a new branch
+    /// from the start of the conversation
+    pub fn checkout(&mut self, id: Option<&str>) -> Result<(), HistoryError> {
+        match id {
+            Some(id) if !self.dialogue.iter().any(|segment| segment.id == id) => {
+                Err(HistoryError(format!("No segment with id '{}'", id)))
+            }
+            Some(id) => {
+                self.head = Some(id.to_string());
+                Ok(())
+            }
+            None => {
+                self.head = Some(ROOT.to_string());
+                Ok(())
+            }
+        }
+    }
+
+    /// Id of the segment that `turn`, counting from 1, of the active branch follows
+    pub fn turn_parent(&self, turn: usize) -> Result<Option<String>, HistoryError> {
+        let turns = self.turns();
+        match turn.checked_sub(1).and_then(|i| turns.get(i)) {
+            Some(segments) => Ok(segments[0].parent.clone()),
+            None => Err(HistoryError(format!(
+                "Turn {} does not exist, the conversation has {} turns",
+                turn,
+                turns.len()
+            ))),
+        }
+    }
+
+    /// A copy of the first `turns` turns of the active branch, without the other branches
+    pub fn linear(&self, turns: Option<usize>) -> Script {
+        let dialogue: Vec<DialogueSegment> = self
+            .turns()
+            .into_iter()
+            .take(turns.unwrap_or(usize::MAX))
+            .flatten()
+            .cloned()
+            .collect();
+        let summary = self.summary.clone().filter(|summary| {
+            summary.covered > 0
+                && dialogue
+                    .get(summary.covered - 1)
+                    .map(|segment| {
+                        summary
+                            .through
+                            .as_ref()
+                            .is_none_or(|through| *through == segment.id)
+                    })
+                    .unwrap_or(false)
+        });
+        Script {
+            dialogue,
+            persona: self.persona.clone(),
+            summary,
+            head: None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct HistoryError(pub String);
+
+impl Display for HistoryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+impl Error for HistoryError {}
+
+impl From<std::io::Error> for HistoryError {
+    fn from(e: std::io::Error) -> Self {
+        HistoryError(e.to_string())
+    }
+}
+
+/// Conversation history stored in a yaml file. Reads and writes hold an advisory lock on a
+/// hidden `.<name>.lock` file next to the conversation, and writes replace the file atomically
 pub struct YamlHistory {
     convo_path: String,
 }
@@ -30,62 +296,493 @@ impl YamlHistory {
             convo_path: convo_path.to_string(),
         }
     }
+
+    /// Names of the yaml conversations in the directory
+    pub fn list(convo_dir: &str) -> Result<Vec<String>, HistoryError> {
+        let mut names = vec![];
+        for entry in std::fs::read_dir(convo_dir)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().to_string();
+            // lock files, temporary files, quarantined conversations and other backends
+            if entry.file_type()?.is_file() && !name.starts_with('.') && !name.ends_with(".jsonl") {
+                names.push(name);
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    /// Reads, updates and rewrites the script while holding an exclusive lock, returning the
+    /// script from before the update
+    pub async fn update<F>(&self, update: F) -> Result<Script, HistoryError>
+    where
+        F: FnOnce(Script) -> Result<Script, HistoryError> + Send + 'static,
+    {
+        let convo_path = PathBuf::from(&self.convo_path);
+        blocking(move || {
+            let _lock = lock(&convo_path, true)?;
+            let script = read_script(&convo_path)?;
+            let updated = update(script.clone())?;
+            let contents =
+                serde_yaml::to_string(&updated).map_err(|e| HistoryError(e.to_string()))?;
+            utils::write_atomic(&convo_path, contents.as_bytes())?;
+            Ok(script)
+        })
+        .await
+    }
+}
+
+/// Runs blocking file or database access off the async runtime
+pub(super) async fn blocking<T, F>(f: F) -> Result<T, HistoryError>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T, HistoryError> + Send + 'static,
+{
+    tokio::task::spawn_blocking(f)
+        .await
+        .map_err(|e| HistoryError(e.to_string()))?
+}
+
+pub(super) fn lock_path(convo_path: &Path) -> PathBuf {
+    let name = convo_path
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    convo_path.with_file_name(format!(".{}.lock", name))
+}
+
+pub(super) fn lock(convo_path: &Path, exclusive: bool) -> Result<File, HistoryError> {
+    let file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(lock_path(convo_path))?;
+    if exclusive {
+        file.lock()?;
+    } else {
+        file.lock_shared()?;
+    }
+    Ok(file)
+}
+
+/// Reads the script, moving it aside if it cannot be parsed so that the conversation can carry on
+fn read_script(convo_path: &Path) -> Result<Script, HistoryError> {
+    let contents = match std::fs::read_to_string(convo_path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Script::default()),
+        Err(e) if e.kind() == ErrorKind::InvalidData => return quarantine(convo_path, &e),
+        Err(e) => return Err(e.into()),
+    };
+    if contents.trim().is_empty() {
+        return Ok(Script::default());
+    }
+
+    match serde_yaml::from_str::<Script>(&contents) {
+        Ok(script) => Ok(script.normalise()),
+        Err(e) => quarantine(convo_path, &e),
+    }
+}
+
+fn quarantine(convo_path: &Path, error: &dyn Display) -> Result<Script, HistoryError> {
+    let name = convo_path
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let quarantine_path = convo_path.with_file_name(format!(
+        ".{}.corrupt-{}",
+        name,
+        Utc::now().format("%Y%m%dT%H%M%S%.f")
+    ));
+    match std::fs::rename(convo_path, &quarantine_path) {
+        Ok(()) => eprintln!(
+            "Conversation file {} could not be read ({}). It has been moved to {} and the conversation restarted",
+            convo_path.to_string_lossy(),
+            error,
+            quarantine_path.to_string_lossy()
+        ),
+        // another process has already moved it
+        Err(e) if e.kind() == ErrorKind::NotFound => (),
+        Err(e) => return Err(e.into()),
+    }
+    Ok(Script::default())
+}
+
+pub(super) fn segments(input: &[ShortMemInput]) -> Vec<DialogueSegment> {
+    input
+        .iter()
+        .map(
+            |ShortMemInput {
+                 id,
+                 author,
+                 content,
+                 meta,
+                 memory_id,
+             }| DialogueSegment {
+                id: id.clone().unwrap_or_else(new_id),
+                parent: None,
+                role: author.clone(),
+                content: content.clone(),
+                created_at: Utc::now(),
+                meta: meta.clone(),
+                memory_id: memory_id.clone(),
+            },
+        )
+        .collect()
 }
 
 #[async_trait]
 impl ShortMemEffect for YamlHistory {
     async fn save_history(&self, input: &[ShortMemInput]) -> Result<(), Box<dyn Error>> {
-        if utils::file_exists_async(&self.convo_path).await {
-            let script: Script = utils::deserialise_from_file_async(&self.convo_path).await?;
-
-            let mut new_dialogue_segment: Vec<DialogueSegment> = input
-                .iter()
-                .map(|ShortMemInput { author, content }| DialogueSegment {
-                    role: author.clone(),
-                    content: content.clone(),
-                    created_at: Utc::now(),
-                })
-                .collect();
-
-            let mut dialogue = script.dialogue;
-            dialogue.append(&mut new_dialogue_segment);
-            utils::write_to_async(&self.convo_path, &Script { dialogue }).await?;
-        } else {
-            let dialogue: Vec<DialogueSegment> = input
-                .iter()
-                .map(|ShortMemInput { author, content }| DialogueSegment {
-                    role: author.clone(),
-                    content: content.clone(),
-                    created_at: Utc::now(),
-                })
-                .collect();
-            utils::write_to_async(&self.convo_path, &Script { dialogue }).await?;
-        };
+        let new_dialogue_segments = segments(input);
+        self.update(move |mut script| {
+            script.append(new_dialogue_segments);
+            Ok(script)
+        })
+        .await?;
         Ok(())
     }
 
     async fn get_history(&self, len: usize) -> Result<Vec<ShortMemOutput>, Box<dyn Error>> {
-        let script: Script = utils::deserialise_from_file_async(&self.convo_path).await?;
+        let script = self.load().await?;
+        let branch = script.branch();
 
-        let dialogue_window = {
-            let dialogue_len = script.dialogue.len();
-            let difference = dialogue_len.checked_sub(len);
-            &script.dialogue[difference.unwrap_or(0)..dialogue_len]
-        };
+        let dialogue_window = &branch[branch.len().saturating_sub(len)..];
 
         Ok(dialogue_window
             .iter()
-            .map(
-                |DialogueSegment {
-                     role,
-                     content,
-                     created_at,
-                 }| ShortMemOutput {
-                    author: role.to_string(),
-                    content: content.to_string(),
-                    created_at: *created_at,
-                },
-            )
+            .map(|segment| output(segment))
             .collect())
     }
+
+    async fn get_persona(&self) -> Result<Option<String>, Box<dyn Error>> {
+        Ok(self.load().await?.persona)
+    }
+
+    async fn save_persona(&self, persona: &str) -> Result<(), Box<dyn Error>> {
+        let persona = persona.to_string();
+        self.update(move |script| {
+            Ok(Script {
+                persona: Some(persona),
+                ..script
+            })
+        })
+        .await?;
+        Ok(())
+    }
+
+    async fn get_summary(&self) -> Result<Option<Summary>, Box<dyn Error>> {
+        Ok(self.load().await?.summary)
+    }
+
+    async fn save_summary(&self, summary: &Summary) -> Result<(), Box<dyn Error>> {
+        let summary = summary.clone();
+        self.update(move |script| {
+            Ok(Script {
+                summary: Some(summary),
+                ..script
+            })
+        })
+        .await?;
+        Ok(())
+    }
+
+    async fn load(&self) -> Result<Script, Box<dyn Error>> {
+        let convo_path = PathBuf::from(&self.convo_path);
+        Ok(blocking(move || {
+            let _lock = lock(&convo_path, false)?;
+            read_script(&convo_path)
+        })
+        .await?)
+    }
+
+    async fn replace(&self, script: Script) -> Result<(), Box<dyn Error>> {
+        self.update(move |_| Ok(script)).await?;
+        Ok(())
+    }
+
+    async fn edit(&self, edit: ScriptEdit) -> Result<Script, Box<dyn Error>> {
+        Ok(self.update(edit).await?)
+    }
+
+    async fn checkout(&self, id: Option<&str>) -> Result<(), Box<dyn Error>> {
+        let id = id.map(|id| id.to_string());
+        self.update(move |mut script| {
+            script.checkout(id.as_deref())?;
+            Ok(script)
+        })
+        .await?;
+        Ok(())
+    }
+
+    async fn exists(&self) -> Result<bool, Box<dyn Error>> {
+        Ok(utils::file_exists(&self.convo_path))
+    }
+
+    async fn delete(&self) -> Result<(), Box<dyn Error>> {
+        let convo_path = PathBuf::from(&self.convo_path);
+        tokio::fs::remove_file(&convo_path).await?;
+        let _ = tokio::fs::remove_file(lock_path(&convo_path)).await;
+        Ok(())
+    }
+}
+
+pub fn output(segment: &DialogueSegment) -> ShortMemOutput {
+    ShortMemOutput {
+        id: segment.id.to_string(),
+        author: segment.role.to_string(),
+        content: segment.content.to_string(),
+        created_at: segment.created_at,
+        command_output: segment
+            .meta
+            .as_ref()
+            .and_then(|meta| meta.command_output.clone()),
+    }
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use chrono::Utc;
+
+    use crate::effect::{ShortMemEffect, ShortMemInput};
+
+    use super::{DialogueSegment, Script, TurnMeta, YamlHistory};
+
+    fn input(content: &str) -> ShortMemInput {
+        ShortMemInput {
+            id: None,
+            author: "user".to_string(),
+            content: content.to_string(),
+            meta: None,
+            memory_id: None,
+        }
+    }
+
+    async fn contents(history: &dyn ShortMemEffect) -> Vec<String> {
+        history
+            .get_history(5)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|segment| segment.content)
+            .collect()
+    }
+
+    /// What every backend of short term memory should do, run against an empty conversation
+    pub(in crate::effect) async fn check_history(history: &dyn ShortMemEffect) {
+        history
+            .save_history(&[input("one"), input("two")])
+            .await
+            .unwrap();
+
+        // edits return the conversation from before
+        let before = history
+            .edit(Box::new(|mut script| {
+                script.dialogue.retain(|segment| segment.content != "one");
+                Ok(script)
+            }))
+            .await
+            .unwrap();
+        assert_eq!(2, before.dialogue.len());
+        let after = history.load().await.unwrap();
+        assert_eq!(1, after.dialogue.len());
+        assert_eq!("two", after.dialogue[0].content);
+
+        // history follows the checked out branch
+        history.save_history(&[input("three")]).await.unwrap();
+        let first = history.get_history(2).await.unwrap()[0].id.clone();
+        history.save_history(&[input("four")]).await.unwrap();
+        history.checkout(Some(&first)).await.unwrap();
+        history.save_history(&[input("edited")]).await.unwrap();
+        assert_eq!(vec!["two", "edited"], contents(history).await);
+        assert_eq!(2, history.len().await.unwrap());
+        assert_eq!(2, history.load().await.unwrap().leaves().len());
+
+        history.checkout(None).await.unwrap();
+        assert!(contents(history).await.is_empty());
+        assert!(history.checkout(Some("missing")).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn yaml_history_keeps_the_contract() {
+        let dir = tempfile::tempdir().unwrap();
+        check_history(&YamlHistory::new(
+            &dir.path().join("convo").to_string_lossy(),
+        ))
+        .await;
+    }
+
+    fn segment(role: &str, content: &str) -> DialogueSegment {
+        DialogueSegment {
+            id: String::new(),
+            parent: None,
+            role: role.to_string(),
+            content: content.to_string(),
+            created_at: Utc::now(),
+            meta: None,
+            memory_id: None,
+        }
+    }
+
+    fn script(roles: &[&str]) -> Script {
+        Script {
+            dialogue: roles
+                .iter()
+                .enumerate()
+                .map(|(i, role)| segment(role, &i.to_string()))
+                .collect(),
+            ..Default::default()
+        }
+        .normalise()
+    }
+
+    #[test]
+    fn turns_start_at_user_messages() {
+        let script = script(&["user", "assistant", "user", "assistant", "user"]);
+        let turns: Vec<usize> = script.turns().iter().map(|turn| turn.len()).collect();
+        assert_eq!(vec![2, 2, 1], turns);
+    }
+
+    #[tokio::test]
+    async fn files_from_before_metadata_still_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let convo_path = dir.path().join("convo");
+        std::fs::write(
+            &convo_path,
+            "dialogue:\n\
+            - role: user\n  content: hi\n  created_at: 2023-01-01T00:00:00Z\n\
+            - role: assistant\n  content: hello\n  created_at: 2023-01-01T00:00:01Z\n",
+        )
+        .unwrap();
+
+        let history = YamlHistory::new(&convo_path.to_string_lossy());
+        let meta = TurnMeta {
+            provider: "openai".to_string(),
+            model: "gpt-4".to_string(),
+            finish_reason: Some("stop".to_string()),
+            ..Default::default()
+        };
+        history
+            .save_history(&[ShortMemInput {
+                id: None,
+                author: "assistant".to_string(),
+                content: "again".to_string(),
+                meta: Some(meta.clone()),
+                memory_id: Some("m1".to_string()),
+            }])
+            .await
+            .unwrap();
+
+        let script = history.load().await.unwrap();
+        assert_eq!(None, script.dialogue[1].meta);
+        assert_eq!(None, script.dialogue[1].memory_id);
+        assert_eq!(Some("1".to_string()), script.dialogue[2].parent);
+        assert_eq!(Some(meta), script.dialogue[2].meta);
+        assert_eq!(Some("m1".to_string()), script.dialogue[2].memory_id);
+    }
+
+    #[test]
+    fn linear_keeps_whole_turns() {
+        let script = script(&["user", "assistant", "user", "assistant", "user"]);
+        assert_eq!(4, script.linear(Some(2)).dialogue.len());
+        assert_eq!(5, script.linear(Some(5)).dialogue.len());
+    }
+
+    #[test]
+    fn editing_a_turn_branches_from_its_parent() {
+        let mut script = script(&["user", "assistant", "user", "assistant"]);
+        let original_leaf = script.dialogue[3].id.clone();
+
+        let parent = script.turn_parent(2).unwrap();
+        assert_eq!(Some("1".to_string()), parent);
+        script.checkout(parent.as_deref()).unwrap();
+        script.append(vec![
+            segment("user", "rephrased"),
+            segment("assistant", "new"),
+        ]);
+        // ids are normally unique, set them here so the branches can be told apart
+        script.dialogue[4].id = "4".to_string();
+        script.dialogue[5].id = "5".to_string();
+        script.dialogue[5].parent = Some("4".to_string());
+
+        let branch: Vec<&str> = script
+            .branch()
+            .iter()
+            .map(|segment| segment.content.as_str())
+            .collect();
+        assert_eq!(vec!["0", "1", "rephrased", "new"], branch);
+        assert_eq!(2, script.leaves().len());
+
+        script.checkout(Some(&original_leaf)).unwrap();
+        assert_eq!("3", script.branch().last().unwrap().content);
+        assert!(script.checkout(Some("missing")).is_err());
+        assert!(script.turn_parent(3).is_err());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_writers_keep_every_turn() {
+        let dir = tempfile::tempdir().unwrap();
+        let convo_path = dir.path().join("convo").to_string_lossy().to_string();
+
+        let writers: Vec<_> = (0..8)
+            .map(|writer| {
+                let convo_path = convo_path.clone();
+                tokio::spawn(async move {
+                    let history = YamlHistory::new(&convo_path);
+                    for turn in 0..10 {
+                        let input = [
+                            ShortMemInput {
+                                id: None,
+                                author: "user".to_string(),
+                                content: format!("{}-{}", writer, turn),
+                                meta: None,
+                                memory_id: None,
+                            },
+                            ShortMemInput {
+                                id: None,
+                                author: "assistant".to_string(),
+                                content: "ok".to_string(),
+                                meta: None,
+                                memory_id: None,
+                            },
+                        ];
+                        history.save_history(&input).await.unwrap();
+                    }
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.await.unwrap();
+        }
+
+        let script = YamlHistory::new(&convo_path).load().await.unwrap();
+        assert_eq!(160, script.dialogue.len());
+        assert_eq!(80, script.turns().len());
+    }
+
+    #[tokio::test]
+    async fn corrupt_files_are_quarantined() {
+        let dir = tempfile::tempdir().unwrap();
+        let convo_path = dir.path().join("convo");
+        std::fs::write(&convo_path, "dialogue: [ {role: unterminated").unwrap();
+
+        let history = YamlHistory::new(&convo_path.to_string_lossy());
+        assert!(history.get_history(3).await.unwrap().is_empty());
+
+        let quarantined: Vec<String> = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .filter(|name| name.starts_with(".convo.corrupt-"))
+            .collect();
+        assert_eq!(1, quarantined.len());
+
+        let input = [ShortMemInput {
+            id: None,
+            author: "user".to_string(),
+            content: "hello".to_string(),
+            meta: None,
+            memory_id: None,
+        }];
+        history.save_history(&input).await.unwrap();
+        assert_eq!(1, history.load().await.unwrap().dialogue.len());
+    }
 }
@@ -0,0 +1,164 @@
+use std::error::Error;
+
+use async_trait::async_trait;
+use reqwest::Client;
+
+use crate::{
+    data::{
+        dtos::{EmbeddingRequestDTO, EmbeddingResponseDTO},
+        model::{EmbedderConfig, EmbedderProvider},
+    },
+    OPENAI_URL,
+};
+
+use super::search_index::terms;
+
+const DEFAULT_MODEL: &str = "text-embedding-3-small";
+const DEFAULT_DIMENSIONS: usize = 512;
+
+#[async_trait]
+pub trait Embedder: Send + Sync {
+    /// Identifies the model, as vectors from different models cannot be compared
+    fn name(&self) -> String;
+    async fn embed(&self, texts: &[String]) -> Result<Vec<Vec<f32>>, Box<dyn Error>>;
+}
+
+/// Embeddings from the OpenAI api, or a server with the same api
+pub struct ApiEmbedder {
+    client: Client,
+    url: String,
+    api_key: Option<String>,
+    model: String,
+}
+
+impl ApiEmbedder {
+    pub fn new(client: Client, url: String, api_key: Option<String>, model: String) -> Self {
+        Self {
+            client,
+            url: url.trim_end_matches('/').to_string(),
+            api_key,
+            model,
+        }
+    }
+}
+
+#[async_trait]
+impl Embedder for ApiEmbedder {
+    fn name(&self) -> String {
+        format!("{} {}", self.url, self.model)
+    }
+
+    async fn embed(&self, texts: &[String]) -> Result<Vec<Vec<f32>>, Box<dyn Error>> {
+        let mut request =
+            self.client
+                .post(format!("{}/embeddings", self.url))
+                .json(&EmbeddingRequestDTO {
+                    model: self.model.clone(),
+                    input: texts.to_vec(),
+                });
+        if let Some(api_key) = &self.api_key {
+            request = request.bearer_auth(api_key);
+        }
+        let mut response = request
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<EmbeddingResponseDTO>()
+            .await?;
+        if response.data.len() != texts.len() {
+            return Err(format!(
+                "Expected {} embeddings from {}, got {}",
+                texts.len(),
+                self.url,
+                response.data.len()
+            )
+            .into());
+        }
+        response.data.sort_by_key(|embedding| embedding.index);
+        Ok(response
+            .data
+            .into_iter()
+            .map(|embedding| embedding.embedding)
+            .collect())
+    }
+}
+
+/// Adds a signed count of each word of the text to a position picked by its hash. Texts are
+/// only similar when they share words, but nothing has to be downloaded or run
+pub struct HashingEmbedder {
+    dimensions: usize,
+}
+
+impl HashingEmbedder {
+    pub fn new(dimensions: usize) -> Self {
+        Self {
+            dimensions: dimensions.max(1),
+        }
+    }
+
+    fn embed_text(&self, text: &str) -> Vec<f32> {
+        let mut vector = vec![0.0; self.dimensions];
+        for term in terms(text) {
+            let hash = fnv1a(term.as_bytes());
+            let sign = if hash >> 63 == 0 { 1.0 } else { -1.0 };
+            vector[(hash % self.dimensions as u64) as usize] += sign;
+        }
+        vector
+    }
+}
+
+#[async_trait]
+impl Embedder for HashingEmbedder {
+    fn name(&self) -> String {
+        format!("hashing {}", self.dimensions)
+    }
+
+    async fn embed(&self, texts: &[String]) -> Result<Vec<Vec<f32>>, Box<dyn Error>> {
+        Ok(texts.iter().map(|text| self.embed_text(text)).collect())
+    }
+}
+
+/// Stable across platforms and releases, unlike the standard library's hasher, as the vectors
+/// are stored
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// The embedder for the config. An OpenAI compatible server is expected to have a url
+pub fn embedder(config: &EmbedderConfig, api_key: Option<String>) -> Box<dyn Embedder> {
+    match config.provider {
+        EmbedderProvider::Openai | EmbedderProvider::OpenaiCompatible => {
+            Box::new(ApiEmbedder::new(
+                Client::new(),
+                config.url.clone().unwrap_or(OPENAI_URL.to_string()),
+                api_key,
+                config.model.clone().unwrap_or(DEFAULT_MODEL.to_string()),
+            ))
+        }
+        EmbedderProvider::Hashing => Box::new(HashingEmbedder::new(
+            config.dimensions.unwrap_or(DEFAULT_DIMENSIONS),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Embedder, HashingEmbedder};
+
+    #[tokio::test]
+    async fn hashing_embeddings_are_stable() {
+        let embedder = HashingEmbedder::new(64);
+        let texts = [
+            "Rust borrow checker".to_string(),
+            "rust, BORROW checker!".to_string(),
+        ];
+        let vectors = embedder.embed(&texts).await.unwrap();
+
+        assert_eq!(64, vectors[0].len());
+        assert_eq!(vectors[0], vectors[1]);
+        assert_eq!(3.0, vectors[0].iter().map(|x| x.abs()).sum::<f32>());
+        assert_eq!(vectors[0], embedder.embed(&texts[..1]).await.unwrap()[0]);
+    }
+}
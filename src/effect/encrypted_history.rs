@@ -0,0 +1,312 @@
+use std::error::Error;
+
+use async_trait::async_trait;
+
+use super::{
+    conversation::{DialogueSegment, HistoryError, Script, Summary, TurnMeta},
+    encryption::{is_encrypted, Cipher, EncryptionError},
+    ScriptEdit, ShortMemEffect, ShortMemInput, ShortMemOutput,
+};
+
+/// Encrypts the messages, command outputs and summary of a conversation before another backend
+/// stores them. Roles, times, personas and other response metadata are stored as they are.
+/// Without a cipher, conversations are stored in plain text and encrypted ones cannot be read
+pub struct EncryptedHistory {
+    history: Box<dyn ShortMemEffect>,
+    cipher: ConvoCipher,
+}
+
+impl EncryptedHistory {
+    pub fn new(history: Box<dyn ShortMemEffect>, cipher: Option<Cipher>, convo: &str) -> Self {
+        Self {
+            history,
+            cipher: ConvoCipher {
+                cipher,
+                convo: convo.to_string(),
+            },
+        }
+    }
+
+    fn decrypt_outputs(
+        &self,
+        outputs: Vec<ShortMemOutput>,
+    ) -> Result<Vec<ShortMemOutput>, Box<dyn Error>> {
+        Ok(outputs
+            .into_iter()
+            .map(|output| {
+                Ok(ShortMemOutput {
+                    content: self.cipher.decrypt(&output.content)?,
+                    command_output: output
+                        .command_output
+                        .map(|output| self.cipher.decrypt(&output))
+                        .transpose()?,
+                    ..output
+                })
+            })
+            .collect::<Result<Vec<ShortMemOutput>, EncryptionError>>()?)
+    }
+}
+
+/// The cipher, if any, with the conversation its text is bound to. Kept apart from the history
+/// so edits made under the backend's lock can encrypt and decrypt
+#[derive(Clone)]
+struct ConvoCipher {
+    cipher: Option<Cipher>,
+    convo: String,
+}
+
+impl ConvoCipher {
+    fn encrypt(&self, text: &str) -> String {
+        match &self.cipher {
+            Some(cipher) => cipher.encrypt(text, &self.convo),
+            None => text.to_string(),
+        }
+    }
+
+    fn decrypt(&self, text: &str) -> Result<String, EncryptionError> {
+        match &self.cipher {
+            Some(cipher) => cipher.decrypt(text, &self.convo),
+            None if is_encrypted(text) => Err(EncryptionError(format!(
+                "Conversation '{}' is encrypted, set encryption in config.yaml to read it",
+                self.convo
+            ))),
+            None => Ok(text.to_string()),
+        }
+    }
+
+    fn encrypt_meta(&self, meta: Option<TurnMeta>) -> Option<TurnMeta> {
+        meta.map(|meta| TurnMeta {
+            command_output: meta.command_output.map(|output| self.encrypt(&output)),
+            ..meta
+        })
+    }
+
+    fn decrypt_meta(&self, meta: Option<TurnMeta>) -> Result<Option<TurnMeta>, EncryptionError> {
+        meta.map(|meta| {
+            Ok(TurnMeta {
+                command_output: meta
+                    .command_output
+                    .map(|output| self.decrypt(&output))
+                    .transpose()?,
+                ..meta
+            })
+        })
+        .transpose()
+    }
+
+    fn encrypt_script(&self, script: Script) -> Script {
+        let dialogue = script
+            .dialogue
+            .into_iter()
+            .map(|segment| DialogueSegment {
+                content: self.encrypt(&segment.content),
+                meta: self.encrypt_meta(segment.meta),
+                ..segment
+            })
+            .collect();
+        let summary = script.summary.map(|summary| Summary {
+            text: self.encrypt(&summary.text),
+            ..summary
+        });
+        Script {
+            dialogue,
+            summary,
+            ..script
+        }
+    }
+
+    fn decrypt_script(&self, script: Script) -> Result<Script, EncryptionError> {
+        let mut dialogue = Vec::with_capacity(script.dialogue.len());
+        for segment in script.dialogue {
+            let content = self.decrypt(&segment.content)?;
+            let meta = self.decrypt_meta(segment.meta)?;
+            dialogue.push(DialogueSegment {
+                content,
+                meta,
+                ..segment
+            });
+        }
+        let summary = script
+            .summary
+            .map(|summary| self.decrypt_summary(summary))
+            .transpose()?;
+        Ok(Script {
+            dialogue,
+            summary,
+            ..script
+        })
+    }
+
+    fn decrypt_summary(&self, summary: Summary) -> Result<Summary, EncryptionError> {
+        Ok(Summary {
+            text: self.decrypt(&summary.text)?,
+            ..summary
+        })
+    }
+}
+
+#[async_trait]
+impl ShortMemEffect for EncryptedHistory {
+    async fn save_history(&self, input: &[ShortMemInput]) -> Result<(), Box<dyn Error>> {
+        let input: Vec<ShortMemInput> = input
+            .iter()
+            .map(|input| ShortMemInput {
+                id: input.id.clone(),
+                author: input.author.clone(),
+                content: self.cipher.encrypt(&input.content),
+                meta: self.cipher.encrypt_meta(input.meta.clone()),
+                memory_id: input.memory_id.clone(),
+            })
+            .collect();
+        self.history.save_history(&input).await
+    }
+
+    async fn get_history(&self, len: usize) -> Result<Vec<ShortMemOutput>, Box<dyn Error>> {
+        let outputs = self.history.get_history(len).await?;
+        self.decrypt_outputs(outputs)
+    }
+
+    async fn checkout(&self, id: Option<&str>) -> Result<(), Box<dyn Error>> {
+        self.history.checkout(id).await
+    }
+
+    async fn get_persona(&self) -> Result<Option<String>, Box<dyn Error>> {
+        self.history.get_persona().await
+    }
+
+    async fn save_persona(&self, persona: &str) -> Result<(), Box<dyn Error>> {
+        self.history.save_persona(persona).await
+    }
+
+    async fn get_summary(&self) -> Result<Option<Summary>, Box<dyn Error>> {
+        let summary = self.history.get_summary().await?;
+        Ok(summary
+            .map(|summary| self.cipher.decrypt_summary(summary))
+            .transpose()?)
+    }
+
+    async fn save_summary(&self, summary: &Summary) -> Result<(), Box<dyn Error>> {
+        let summary = Summary {
+            text: self.cipher.encrypt(&summary.text),
+            ..summary.clone()
+        };
+        self.history.save_summary(&summary).await
+    }
+
+    async fn load(&self) -> Result<Script, Box<dyn Error>> {
+        let script = self.history.load().await?;
+        Ok(self.cipher.decrypt_script(script)?)
+    }
+
+    async fn replace(&self, script: Script) -> Result<(), Box<dyn Error>> {
+        self.history
+            .replace(self.cipher.encrypt_script(script))
+            .await
+    }
+
+    async fn edit(&self, edit: ScriptEdit) -> Result<Script, Box<dyn Error>> {
+        let cipher = self.cipher.clone();
+        let before = self
+            .history
+            .edit(Box::new(move |script| {
+                let script = cipher
+                    .decrypt_script(script)
+                    .map_err(|e| HistoryError(e.to_string()))?;
+                Ok(cipher.encrypt_script(edit(script)?))
+            }))
+            .await?;
+        Ok(self.cipher.decrypt_script(before)?)
+    }
+
+    async fn exists(&self) -> Result<bool, Box<dyn Error>> {
+        self.history.exists().await
+    }
+
+    async fn delete(&self) -> Result<(), Box<dyn Error>> {
+        self.history.delete().await
+    }
+
+    async fn len(&self) -> Result<usize, Box<dyn Error>> {
+        self.history.len().await
+    }
+
+    async fn get_segments(
+        &self,
+        from: usize,
+        to: usize,
+    ) -> Result<Vec<ShortMemOutput>, Box<dyn Error>> {
+        let outputs = self.history.get_segments(from, to).await?;
+        self.decrypt_outputs(outputs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use crate::effect::{
+        conversation::{Summary, TurnMeta, YamlHistory},
+        encryption::Cipher,
+        ShortMemEffect, ShortMemInput,
+    };
+
+    use super::EncryptedHistory;
+
+    #[tokio::test]
+    async fn stores_messages_encrypted() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("secret").to_string_lossy().to_string();
+        let history = || Box::new(YamlHistory::new(&path));
+        let encrypted = EncryptedHistory::new(history(), Some(Cipher::new([1; 32])), "secret");
+
+        encrypted
+            .save_history(&[
+                ShortMemInput {
+                    id: None,
+                    author: "user".to_string(),
+                    content: "the launch code is 0000".to_string(),
+                    meta: None,
+                    memory_id: None,
+                },
+                ShortMemInput {
+                    id: None,
+                    author: "assistant".to_string(),
+                    content: "cat codes".to_string(),
+                    meta: Some(TurnMeta {
+                        command_output: Some("launch 0000".to_string()),
+                        ..Default::default()
+                    }),
+                    memory_id: None,
+                },
+            ])
+            .await
+            .unwrap();
+        encrypted
+            .save_summary(&Summary {
+                text: "asked about the launch code".to_string(),
+                covered: 1,
+                through: None,
+            })
+            .await
+            .unwrap();
+
+        let stored = std::fs::read_to_string(&path).unwrap();
+        assert!(!stored.contains("launch"));
+        let script = encrypted.load().await.unwrap();
+        assert_eq!("the launch code is 0000", script.dialogue[0].content);
+        assert_eq!("asked about the launch code", script.summary.unwrap().text);
+        assert_eq!(
+            "the launch code is 0000",
+            encrypted.get_history(2).await.unwrap()[0].content
+        );
+        assert_eq!(
+            Some("launch 0000".to_string()),
+            encrypted.get_history(1).await.unwrap()[0].command_output
+        );
+
+        let plain = EncryptedHistory::new(history(), None, "secret");
+        assert!(plain.load().await.is_err());
+        let renamed = EncryptedHistory::new(history(), Some(Cipher::new([1; 32])), "other");
+        assert!(renamed.get_history(1).await.is_err());
+    }
+}
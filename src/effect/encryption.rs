@@ -0,0 +1,250 @@
+use std::{
+    env,
+    error::Error,
+    fmt::{Debug, Display},
+    path::Path,
+    process::Command,
+};
+
+use argon2::Argon2;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chacha20poly1305::{
+    aead::{rand_core::RngCore, Aead, AeadCore, KeyInit, OsRng, Payload},
+    Key, XChaCha20Poly1305, XNonce,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{data::model::EncryptionConfig, utils};
+
+/// Marks text encrypted by a `Cipher`, followed by the base64 of the nonce and ciphertext
+const PREFIX: &str = "hey-gpt-encrypted:v1:";
+/// Salt for passphrases and a check that the key is right, kept with the conversations
+const KEY_INFO: &str = ".encryption.yaml";
+const CHECK: &str = "hey-gpt";
+const NONCE_LEN: usize = 24;
+
+#[derive(Debug)]
+pub struct EncryptionError(pub String);
+
+impl Display for EncryptionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+impl Error for EncryptionError {}
+
+#[derive(Serialize, Deserialize)]
+struct KeyInfo {
+    salt: String,
+    check: String,
+}
+
+/// Authenticated encryption of conversation text with XChaCha20-Poly1305. Each text is bound to
+/// a context, such as the name of its conversation, and will not decrypt under another
+#[derive(Clone)]
+pub struct Cipher {
+    key: Key,
+}
+
+impl Debug for Cipher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Cipher(..)")
+    }
+}
+
+impl Cipher {
+    pub fn new(key: [u8; 32]) -> Self {
+        Self { key: key.into() }
+    }
+
+    /// The cipher for the conversations in a directory, with the key from the configured
+    /// source. Keys from passphrases are derived with Argon2id
+    pub fn load(config: &EncryptionConfig, convo_dir: &str) -> Result<Self, Box<dyn Error>> {
+        let info_path = Path::new(convo_dir).join(KEY_INFO);
+        let info = match utils::file_exists(&info_path.to_string_lossy()) {
+            true => Some(utils::deserialise_from_file::<KeyInfo>(
+                &info_path.to_string_lossy(),
+            )?),
+            false => None,
+        };
+        let salt = match &info {
+            Some(info) => STANDARD.decode(&info.salt)?,
+            None => {
+                let mut salt = vec![0; 16];
+                OsRng.fill_bytes(&mut salt);
+                salt
+            }
+        };
+
+        let mut key = [0; 32];
+        match (
+            &config.key_file,
+            &config.passphrase_command,
+            &config.passphrase_env,
+        ) {
+            (Some(key_file), _, _) => {
+                let home = env::var("HOME")?;
+                let contents = std::fs::read(key_file.replace("$HOME", &home))?;
+                let decoded = match contents.len() {
+                    32 => contents,
+                    _ => STANDARD.decode(String::from_utf8_lossy(&contents).trim())?,
+                };
+                key = decoded.try_into().map_err(|_| {
+                    EncryptionError(format!(
+                        "The key in '{}' should be 32 bytes, raw or base64 encoded",
+                        key_file
+                    ))
+                })?;
+            }
+            (None, Some(command), _) => {
+                let output = Command::new("sh").arg("-c").arg(command).output()?;
+                if !output.status.success() {
+                    return Err(EncryptionError(format!(
+                        "Could not get the passphrase from '{}': {}",
+                        command,
+                        String::from_utf8_lossy(&output.stderr).trim()
+                    ))
+                    .into());
+                }
+                let stdout = String::from_utf8_lossy(&output.stdout);
+                let passphrase = stdout.lines().next().unwrap_or_default();
+                derive(passphrase, &salt, &mut key)?;
+            }
+            (None, None, Some(var)) => {
+                let passphrase = env::var(var).map_err(|_| {
+                    EncryptionError(format!("Passphrase environment variable {} is not set", var))
+                })?;
+                derive(&passphrase, &salt, &mut key)?;
+            }
+            (None, None, None) => {
+                return Err(EncryptionError(
+                    "Set key_file, passphrase_command or passphrase_env under encryption in config.yaml"
+                        .to_string(),
+                )
+                .into())
+            }
+        }
+        let cipher = Self::new(key);
+
+        match info {
+            Some(info) => {
+                if cipher.decrypt(&info.check, CHECK).ok().as_deref() != Some(CHECK) {
+                    return Err(EncryptionError(format!(
+                        "The encryption key is not the one conversations in '{}' were encrypted with",
+                        convo_dir
+                    ))
+                    .into());
+                }
+            }
+            None => {
+                std::fs::create_dir_all(convo_dir)?;
+                let info = KeyInfo {
+                    salt: STANDARD.encode(&salt),
+                    check: cipher.encrypt(CHECK, CHECK),
+                };
+                utils::write_atomic(&info_path, serde_yaml::to_string(&info)?.as_bytes())?;
+            }
+        }
+        Ok(cipher)
+    }
+
+    pub fn encrypt(&self, text: &str, context: &str) -> String {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = XChaCha20Poly1305::new(&self.key)
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: text.as_bytes(),
+                    aad: context.as_bytes(),
+                },
+            )
+            .expect("Encrypting in memory does not fail");
+        let mut sealed = nonce.to_vec();
+        sealed.extend(ciphertext);
+        format!("{}{}", PREFIX, STANDARD.encode(sealed))
+    }
+
+    /// Decrypts text from `encrypt`. Text that was never encrypted is returned as it is
+    pub fn decrypt(&self, text: &str, context: &str) -> Result<String, EncryptionError> {
+        let Some(encoded) = text.strip_prefix(PREFIX) else {
+            return Ok(text.to_string());
+        };
+        let error = || {
+            EncryptionError(
+                "Could not decrypt conversation history, it was changed or encrypted with another key"
+                    .to_string(),
+            )
+        };
+        let sealed = STANDARD.decode(encoded).map_err(|_| error())?;
+        if sealed.len() < NONCE_LEN {
+            return Err(error());
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        let plaintext = XChaCha20Poly1305::new(&self.key)
+            .decrypt(
+                XNonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: context.as_bytes(),
+                },
+            )
+            .map_err(|_| error())?;
+        String::from_utf8(plaintext).map_err(|_| error())
+    }
+}
+
+pub fn is_encrypted(text: &str) -> bool {
+    text.starts_with(PREFIX)
+}
+
+fn derive(passphrase: &str, salt: &[u8], key: &mut [u8; 32]) -> Result<(), EncryptionError> {
+    if passphrase.is_empty() {
+        return Err(EncryptionError("The passphrase is empty".to_string()));
+    }
+    Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), salt, key)
+        .map_err(|e| EncryptionError(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use crate::data::model::EncryptionConfig;
+
+    use super::{is_encrypted, Cipher};
+
+    #[test]
+    fn encrypted_text_only_decrypts_in_its_context() {
+        let cipher = Cipher::new([7; 32]);
+        let sealed = cipher.encrypt("fn secret() {}", "project");
+        assert!(is_encrypted(&sealed));
+        assert!(!sealed.contains("secret"));
+
+        assert_eq!(
+            "fn secret() {}",
+            cipher.decrypt(&sealed, "project").unwrap()
+        );
+        assert!(cipher.decrypt(&sealed, "other").is_err());
+        assert!(Cipher::new([8; 32]).decrypt(&sealed, "project").is_err());
+        assert_eq!("plain", cipher.decrypt("plain", "project").unwrap());
+    }
+
+    #[test]
+    fn passphrases_must_match_the_directory() {
+        let dir = tempdir().unwrap();
+        let convo_dir = dir.path().to_string_lossy().to_string();
+        let config = |command: &str| EncryptionConfig {
+            passphrase_command: Some(command.to_string()),
+            ..Default::default()
+        };
+
+        let first = Cipher::load(&config("echo correct horse"), &convo_dir).unwrap();
+        let again = Cipher::load(&config("echo correct horse"), &convo_dir).unwrap();
+        let sealed = first.encrypt("text", "convo");
+        assert_eq!("text", again.decrypt(&sealed, "convo").unwrap());
+
+        let error = Cipher::load(&config("echo battery staple"), &convo_dir).unwrap_err();
+        assert!(error.to_string().contains("not the one"));
+    }
+}
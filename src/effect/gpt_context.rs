@@ -1,17 +1,20 @@
-use std::error::Error;
+use std::{cmp::Reverse, error::Error};
 
 use async_trait::async_trait;
 use chrono::{DateTime, Utc};
 use log::debug;
 use reqwest::Client;
 
-use crate::data::dtos::{
-    DocumentResultDTO, QueryDTO, ResultWrapperDTO, RetreivalUpsertWrapperDTO, RetrievalDeleteDTO,
-    RetrievalFilterDTO, RetrievalQueryDTO, RetrievalUpsertDTO, UpsertMetadataDTO,
-    UpsertResponseDTO,
+use crate::data::{
+    dtos::{
+        DocumentResultDTO, QueryDTO, ResultWrapperDTO, RetreivalUpsertWrapperDTO,
+        RetrievalDeleteDTO, RetrievalFilterDTO, RetrievalQueryDTO, RetrievalUpsertDTO,
+        UpsertMetadataDTO, UpsertResponseDTO,
+    },
+    model::{SourceType, LEGACY_SOURCE},
 };
 
-use super::{LongMemEffect, LongMemOutput, LongMemQueryOpt, LongMemSaveInp};
+use super::{LongMemEffect, LongMemOutput, LongMemQueryOpt, LongMemSaveInp, MemorySource};
 
 pub struct LongTermGptMemory {
     client: Client,
@@ -29,6 +32,55 @@ impl LongTermGptMemory {
             context_url,
         }
     }
+
+    /// Results of the queries, best first
+    async fn search(&self, queries: Vec<QueryDTO>) -> Result<Vec<LongMemOutput>, Box<dyn Error>> {
+        let query_wrapper = RetrievalQueryDTO { queries };
+
+        let post_result = self
+            .client
+            .post(format!("{}/query", self.context_url))
+            .bearer_auth(&self.bearer_auth) // todo get from env or config
+            .json::<RetrievalQueryDTO>(&query_wrapper)
+            .send()
+            .await?;
+
+        // dbg!(&post_result.text().await);
+        let response = post_result
+            .error_for_status()?
+            .json::<ResultWrapperDTO>()
+            .await?;
+
+        let mut document_results: Vec<DocumentResultDTO> = response
+            .results
+            .into_iter()
+            .flat_map(|search_result| search_result.results)
+            .collect();
+
+        document_results.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap());
+
+        let result: Vec<LongMemOutput> = document_results
+            .into_iter()
+            .map(|bm| LongMemOutput {
+                // results are chunks of the documents that delete takes the ids of
+                id: bm.metadata.document_id.clone().unwrap_or(bm.id.clone()),
+                text: bm.text.clone(),
+                created_at: bm
+                    .metadata
+                    .created_at
+                    .clone()
+                    .map(|ca| ca.parse().unwrap())
+                    .unwrap_or(DateTime::<Utc>::MIN_UTC),
+                author: bm.metadata.author.clone().unwrap_or("".to_string()),
+                category: bm.metadata.source_id.clone().unwrap_or("".to_string()),
+                score: bm.score,
+                source_type: SourceType::from_stored(bm.metadata.source.as_deref()),
+                source: bm.metadata.url.as_deref().and_then(MemorySource::from_url),
+            })
+            .collect();
+
+        Ok(result)
+    }
 }
 
 #[async_trait]
@@ -40,16 +92,25 @@ impl LongMemEffect for LongTermGptMemory {
     ) -> Result<Vec<String>, Box<dyn Error>> {
         let documents: Vec<RetrievalUpsertDTO> = ctx_input
             .iter()
-            .map(|LongMemSaveInp { text, author }| RetrievalUpsertDTO {
-                id: None,
-                metadata: Some(UpsertMetadataDTO {
-                    created_at: chrono::offset::Utc::now().to_rfc3339(),
-                    source_id: category.to_string(),
-                    source: "email".to_string(),
-                    author: author.to_string(),
-                }),
-                text: text.to_string(),
-            })
+            .map(
+                |LongMemSaveInp {
+                     text,
+                     author,
+                     created_at,
+                     source_type,
+                     source,
+                 }| RetrievalUpsertDTO {
+                    id: None,
+                    metadata: Some(UpsertMetadataDTO {
+                        created_at: created_at.to_rfc3339(),
+                        source_id: category.to_string(),
+                        source: source_type.as_str().to_string(),
+                        author: author.to_string(),
+                        url: source.as_ref().map(MemorySource::to_url),
+                    }),
+                    text: text.to_string(),
+                },
+            )
             .collect();
 
         let upsert_wrapper = RetreivalUpsertWrapperDTO { documents };
@@ -77,71 +138,61 @@ impl LongMemEffect for LongTermGptMemory {
     ) -> Result<Vec<LongMemOutput>, Box<dyn Error>> {
         let queries: Vec<QueryDTO> = query_options
             .iter()
-            .map(
-                |LongMemQueryOpt {
-                     category,
-                     query_window,
-                 }| QueryDTO {
-                    query: query.to_string(),
-                    top_k: self.top_k,
-                    filter: Some(RetrievalFilterDTO {
-                        source_id: Some(category.to_string()),
-                        source: "email".to_string(),
-                        author: None,
-                        start_date: query_window.min.map(|min| min.to_rfc3339()),
-                        end_date: query_window.max.map(|max| max.to_rfc3339()),
-                    }),
-                },
-            )
-            .collect();
-        let query_wrapper = RetrievalQueryDTO { queries };
-
-        let post_result = self
-            .client
-            .post(format!("{}/query", self.context_url))
-            .bearer_auth(&self.bearer_auth) // todo get from env or config
-            .json::<RetrievalQueryDTO>(&query_wrapper)
-            .send()
-            .await?;
-
-        // dbg!(&post_result.text().await);
-        let response = post_result
-            .error_for_status()?
-            .json::<ResultWrapperDTO>()
-            .await?;
-
-        let mut document_results: Vec<DocumentResultDTO> = response
-            .results
-            .into_iter()
-            .flat_map(|search_result| search_result.results)
+            .flat_map(filters)
+            .map(|filter| QueryDTO {
+                query: query.to_string(),
+                top_k: self.top_k,
+                filter: Some(filter),
+            })
             .collect();
+        let mut document_results = self.search(queries).await?;
+        document_results.truncate(self.top_k as usize);
+        Ok(document_results)
+    }
 
-        document_results.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap());
-
-        let top_k_best_match = document_results.iter().take(self.top_k as usize);
-
-        let result: Vec<LongMemOutput> = top_k_best_match
+    async fn list(
+        &self,
+        query_opt: &LongMemQueryOpt,
+        limit: usize,
+    ) -> Result<Vec<LongMemOutput>, Box<dyn Error>> {
+        // the plugin can only search, so the category's name stands in for a query
+        let queries = filters(query_opt)
             .into_iter()
-            .map(|bm| LongMemOutput {
-                text: bm.text.clone(),
-                created_at: bm
-                    .metadata
-                    .created_at
-                    .clone()
-                    .map(|ca| ca.parse().unwrap())
-                    .unwrap_or(DateTime::<Utc>::MIN_UTC),
-                author: bm.metadata.author.clone().unwrap_or("".to_string()),
-                category: bm.metadata.source_id.clone().unwrap_or("".to_string()),
+            .map(|filter| QueryDTO {
+                query: query_opt.category.clone(),
+                top_k: limit as u32,
+                filter: Some(filter),
             })
             .collect();
+        let mut memories = self.search(queries).await?;
+        memories.sort_by_key(|memory| Reverse(memory.created_at));
+        memories.truncate(limit);
+        Ok(memories)
+    }
 
-        Ok(result)
+    async fn get(&self, id: &str) -> Result<Option<LongMemOutput>, Box<dyn Error>> {
+        let memories = self
+            .search(vec![QueryDTO {
+                query: id.to_string(),
+                top_k: 1,
+                filter: Some(RetrievalFilterDTO {
+                    source_id: None,
+                    source: None,
+                    author: None,
+                    start_date: None,
+                    end_date: None,
+                    document_id: Some(id.to_string()),
+                }),
+            }])
+            .await?;
+        Ok(memories.into_iter().next())
     }
 
     async fn delete(&self, id: &str) -> Result<(), Box<dyn Error>> {
         debug!("Deleting context with id: {}", id);
         let delete_ids = RetrievalDeleteDTO {
-            ids: vec![id.to_string()],
+            ids: Some(vec![id.to_string()]),
+            filter: None,
         };
 
         let _ = self
@@ -155,6 +206,57 @@ impl LongMemEffect for LongTermGptMemory {
 
         Ok(())
     }
+
+    async fn delete_category(&self, category: &str) -> Result<(), Box<dyn Error>> {
+        debug!("Deleting context in category: {}", category);
+        let delete_filter = RetrievalDeleteDTO {
+            ids: None,
+            filter: Some(RetrievalFilterDTO {
+                source_id: Some(category.to_string()),
+                // every source type, including the legacy one
+                source: None,
+                author: None,
+                start_date: None,
+                end_date: None,
+                document_id: None,
+            }),
+        };
+
+        let _ = self
+            .client
+            .delete(format!("{}/delete", self.context_url))
+            .bearer_auth(&self.bearer_auth)
+            .json(&delete_filter)
+            .send()
+            .await?
+            .error_for_status()?;
+
+        Ok(())
+    }
+}
+
+/// Filters for the query option. Memories saved before there were source types have the legacy
+/// source, so chats are looked for under both
+fn filters(query_opt: &LongMemQueryOpt) -> Vec<RetrievalFilterDTO> {
+    let sources = match query_opt.source_type {
+        Some(SourceType::Chat) => vec![
+            Some(SourceType::Chat.as_str().to_string()),
+            Some(LEGACY_SOURCE.to_string()),
+        ],
+        Some(source_type) => vec![Some(source_type.as_str().to_string())],
+        None => vec![None],
+    };
+    sources
+        .into_iter()
+        .map(|source| RetrievalFilterDTO {
+            source_id: Some(query_opt.category.clone()),
+            source,
+            author: query_opt.author.clone(),
+            start_date: query_opt.query_window.min.map(|min| min.to_rfc3339()),
+            end_date: query_opt.query_window.max.map(|max| max.to_rfc3339()),
+            document_id: None,
+        })
+        .collect()
 }
 
 #[cfg(test)]
@@ -162,8 +264,12 @@ mod tests {
     use chrono::Utc;
     use reqwest::Client;
 
-    use crate::effect::{
-        gpt_context::LongTermGptMemory, LongMemEffect, LongMemQueryOpt, LongMemSaveInp, QueryWindow,
+    use crate::{
+        data::model::SourceType,
+        effect::{
+            gpt_context::LongTermGptMemory, LongMemEffect, LongMemQueryOpt, LongMemSaveInp,
+            QueryWindow,
+        },
     };
 
     #[tokio::test]
@@ -183,6 +289,9 @@ mod tests {
                 &[LongMemSaveInp {
                     text: "This is a new embedding".to_string(),
                     author: "user".to_string(),
+                    created_at: Utc::now(),
+                    source_type: SourceType::Chat,
+                    source: None,
                 }],
                 "a4c80afe-f225-11ed-a05b-0242ac120003",
             )
@@ -196,6 +305,8 @@ mod tests {
                 min: None,
                 max: Some(Utc::now()),
             },
+            author: None,
+            source_type: None,
         };
         let result = effect
             .query("can you summerise our conversation so far?", &[query_opt])
@@ -1,23 +1,30 @@
-use std::fmt::Display;
+use std::sync::{Arc, Mutex};
 use std::{error::Error, pin::Pin};
 
 use crate::data::dtos::{
     ChatRequestDTO, ChatRequestMsgDTO, EditRequestDTO, EditResponseDTO, StreamChatResponseDTO,
+    StreamOptionsDTO,
 };
 use async_trait::async_trait;
-use futures::{stream, Stream, StreamExt};
+use futures::{future, stream, Stream, StreamExt};
 use log::debug;
 use reqwest::Client;
 
-use crate::data::model::Model;
+use crate::data::model::{Algo, Model};
 use crate::{utils::Transpose, COMPLETION_URL, EDIT_URL};
 
-use super::{AiRequestEffect, ChatRequestInput, EditRequestInput};
+use super::{
+    conversation::TokenUsage, AiRequestEffect, ChatRequestInput, ChatResponseMeta, EditRequestInput,
+};
+
+const PROVIDER: &str = "openai";
 
 pub struct GptRequest {
     client: Client,
     auth_token: String,
     model: Model,
+    /// Filled in as the stream of the last chat response is consumed
+    response_meta: Arc<Mutex<ChatResponseMeta>>,
 }
 
 impl GptRequest {
@@ -26,46 +33,31 @@ impl GptRequest {
             client,
             auth_token,
             model,
+            response_meta: Default::default(),
         }
     }
 }
 
-#[derive(Debug)]
-pub struct StreamChatResponseDeserialiseError(pub Vec<String>);
-impl Error for StreamChatResponseDeserialiseError {}
-impl Display for StreamChatResponseDeserialiseError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(
-            f,
-            "Error occured trying to deserialise StreamChatResponseDTO: {:#?} ",
-            self.0
-        )
-    }
-}
-
-fn parse_byte_to_response_dto(str_from_bytes: &str) -> Vec<StreamChatResponseDTO> {
-    let result: Vec<StreamChatResponseDTO> = str_from_bytes
-        .split("\n\n")
-        .filter(|s| !s.is_empty() && str_from_bytes != "{}" && !str_from_bytes.contains("[DONE]"))
-        .filter_map(|str_object| {
-            debug!(
-                "Attempting to convert object from byte stream: {}",
-                str_object
-            );
-            let str_without_prefix = str_object.trim_start_matches("data: ");
-            match serde_json::from_str(str_without_prefix) {
-                Ok(converted) => {
-                    debug!("Success!");
-                    converted
-                }
-                Err(e) => {
-                    debug!("Could not convert: {} due to {}", str_from_bytes, e);
-                    None
-                }
+/// Removes the complete server sent events from the start of the buffer and parses them. An event
+/// split across network chunks stays in the buffer until the rest of it arrives
+fn take_response_dtos(buffer: &mut Vec<u8>) -> Vec<StreamChatResponseDTO> {
+    let mut result = vec![];
+    while let Some(end) = buffer.windows(2).position(|window| window == b"\n\n") {
+        let event: Vec<u8> = buffer.drain(..end + 2).collect();
+        let event = String::from_utf8_lossy(&event);
+        for data in event
+            .lines()
+            .filter_map(|line| line.strip_prefix("data:"))
+            .map(str::trim)
+            .filter(|data| !data.is_empty() && *data != "[DONE]" && *data != "{}")
+        {
+            debug!("Attempting to convert object from byte stream: {}", data);
+            match serde_json::from_str(data) {
+                Ok(converted) => result.push(converted),
+                Err(e) => debug!("Could not convert: {} due to {}", data, e),
             }
-        })
-        .collect();
-
+        }
+    }
     result
 }
 
@@ -74,6 +66,15 @@ impl AiRequestEffect for GptRequest {
     async fn chat_request_stream(
         &self,
         request: &[ChatRequestInput],
+    ) -> Result<Pin<Box<dyn Stream<Item = Vec<String>> + Send + 'static>>, Box<dyn Error>> {
+        self.chat_request_stream_with_algo(request, &self.model.algo)
+            .await
+    }
+
+    async fn chat_request_stream_with_algo(
+        &self,
+        request: &[ChatRequestInput],
+        algo: &Algo,
     ) -> Result<Pin<Box<dyn Stream<Item = Vec<String>> + Send + 'static>>, Box<dyn Error>> {
         let request = ChatRequestDTO {
             messages: request
@@ -83,11 +84,14 @@ impl AiRequestEffect for GptRequest {
                     role: role.to_string(),
                 })
                 .collect(),
-            model: self.model.algo.chat_model.to_string(),
+            model: algo.chat_model.to_string(),
             n: 1,
-            temperature: self.model.algo.temp,
-            max_tokens: self.model.algo.max_tokens,
+            temperature: algo.temp,
+            max_tokens: algo.max_tokens,
             stream: true,
+            stream_options: Some(StreamOptionsDTO {
+                include_usage: true,
+            }),
         };
 
         debug!("Sending chat request with request: {:#?}", request);
@@ -100,26 +104,43 @@ impl AiRequestEffect for GptRequest {
             .send()
             .await?;
 
-        // todo!
+        let response_meta = self.response_meta.clone();
+        *response_meta.lock().unwrap() = ChatResponseMeta {
+            provider: PROVIDER.to_string(),
+            ..Default::default()
+        };
+
         let result = response
             .error_for_status()?
             .bytes_stream()
-            .flat_map(|byte_result| {
-                let str_from_byte = String::from_utf8(
-                    byte_result
-                        .expect("Did not recieve byte from byte string")
-                        .to_vec(),
-                )
-                .expect("Could not convert byte to string when serialising StreamChatResponse");
-                let response_vec = parse_byte_to_response_dto(&str_from_byte);
-                stream::iter(response_vec.into_iter())
+            .scan(vec![], |buffer: &mut Vec<u8>, byte_result| {
+                buffer.extend_from_slice(
+                    &byte_result.expect("Did not recieve byte from byte string"),
+                );
+                future::ready(Some(stream::iter(take_response_dtos(buffer))))
             })
-            .map(|dto: StreamChatResponseDTO| -> Vec<String> {
+            .flatten()
+            .map(move |dto: StreamChatResponseDTO| -> Vec<String> {
+                let mut meta = response_meta.lock().unwrap();
+                if let Some(usage) = &dto.usage {
+                    meta.usage = Some(TokenUsage {
+                        prompt_tokens: usage.prompt_tokens as u32,
+                        completion_tokens: usage.completion_tokens as u32,
+                        total_tokens: usage.total_tokens as u32,
+                    });
+                }
                 dto.choices
                     .into_iter()
-                    .flat_map(|choice| choice.delta.content)
+                    .flat_map(|choice| {
+                        if choice.finish_reason.is_some() {
+                            meta.finish_reason = choice.finish_reason;
+                        }
+                        choice.delta.content
+                    })
                     .collect()
-            });
+            })
+            // the usage chunk has no choices
+            .filter(|content: &Vec<String>| future::ready(!content.is_empty()));
 
         Ok(Box::pin(result))
     }
@@ -169,29 +190,42 @@ impl AiRequestEffect for GptRequest {
 
         Ok(Box::pin(futures::stream::iter(result.transpose())))
     }
+
+    fn response_meta(&self) -> ChatResponseMeta {
+        self.response_meta.lock().unwrap().clone()
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    // use super::parse_byte_to_response_dto;
-    //
-    // #[test]
-    // fn will_parse_empty_object() {
-    //     let input = "{}";
-    //     let result = parse_byte_to_response_dto(input);
-    //     assert!(result.is_err())
-    // }
-    // #[test]
-    // fn will_parse_expected_object() {
-    //     let input = "data: {\"id\":\"chatcmpl-7YK1bd5RqjEmR7W5TQn3hAqoyA0Zy\",\"object\":\"chat.completion.chunk\",\"created\":1688414483,\"model\":\"gpt-3.5-turbo-0613\",\"choices\":[{\"index\":0,\"delta\":{\"content\":\".\"},\"finish_reason\":null}]}\n\n";
-    //     let result = parse_byte_to_response_dto(input);
-    //     assert!(result.is_ok())
-    // }
-    // #[test]
-    // fn will_parse_double_object() {
-    //     let input =  "data: {\"id\":\"chatcmpl-7YK1bd5RqjEmR7W5TQn3hAqoyA0Zy\",\"object\":\"chat.completion.chunk\",\"created\":1688414483,\"model\":\"gpt-3.5-turbo-0613\",\"choices\":[{\"index\":0,\"delta\":{\"role\":\"assistant\",\"content\":\"\"},\"finish_reason\":null}]}\n\ndata: {\"id\":\"chatcmpl-7YK1bd5RqjEmR7W5TQn3hAqoyA0Zy\",\"object\":\"chat.completion.chunk\",\"created\":1688414483,\"model\":\"gpt-3.5-turbo-0613\",\"choices\":[{\"index\":0,\"delta\":{\"content\":\"OK\"},\"finish_reason\":null}]}\n\n";
-    //     let result = parse_byte_to_response_dto(input);
-    //     assert!(result.is_ok())
-    // }
-    //
+    use super::take_response_dtos;
+
+    const FIRST: &str = "data: {\"id\":\"chatcmpl-7YK1bd5RqjEmR7W5TQn3hAqoyA0Zy\",\"object\":\"chat.completion.chunk\",\"created\":1688414483,\"model\":\"gpt-3.5-turbo-0613\",\"choices\":[{\"index\":0,\"delta\":{\"role\":\"assistant\",\"content\":\"\"},\"finish_reason\":null}]}\n\n";
+    const SECOND: &str = "data: {\"id\":\"chatcmpl-7YK1bd5RqjEmR7W5TQn3hAqoyA0Zy\",\"object\":\"chat.completion.chunk\",\"created\":1688414483,\"model\":\"gpt-3.5-turbo-0613\",\"choices\":[{\"index\":0,\"delta\":{},\"finish_reason\":\"stop\"}]}\n\n";
+
+    #[test]
+    fn will_parse_double_object() {
+        let mut buffer = format!("{}{}", FIRST, SECOND).into_bytes();
+        let result = take_response_dtos(&mut buffer);
+        assert_eq!(2, result.len());
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn keeps_events_split_across_chunks() {
+        let (start, end) = FIRST.split_at(40);
+        let mut buffer = start.as_bytes().to_vec();
+        assert!(take_response_dtos(&mut buffer).is_empty());
+
+        buffer.extend_from_slice(end.as_bytes());
+        assert_eq!(1, take_response_dtos(&mut buffer).len());
+    }
+
+    #[test]
+    fn keeps_last_event_sent_with_done() {
+        let mut buffer = format!("{}data: [DONE]\n\n", SECOND).into_bytes();
+        let result = take_response_dtos(&mut buffer);
+        assert_eq!(1, result.len());
+        assert_eq!(Some("stop".to_string()), result[0].choices[0].finish_reason);
+    }
 }
@@ -0,0 +1,154 @@
+use std::error::Error;
+
+use async_trait::async_trait;
+
+use crate::data::model::SourceType;
+
+use super::{
+    conversation::{Script, Summary},
+    search_index::{Document, SearchIndex},
+    ScriptEdit, ShortMemEffect, ShortMemInput, ShortMemOutput,
+};
+
+/// Keeps the search index of a conversation up to date as it is stored by another backend.
+/// Failing to update the index does not fail the change to the conversation
+pub struct IndexedHistory {
+    history: Box<dyn ShortMemEffect>,
+    index: SearchIndex,
+    convo: String,
+}
+
+impl IndexedHistory {
+    pub fn new(history: Box<dyn ShortMemEffect>, index: SearchIndex, convo: &str) -> Self {
+        Self {
+            history,
+            index,
+            convo: convo.to_string(),
+        }
+    }
+
+    async fn index_saved(&self, saved: usize) -> Result<(), Box<dyn Error>> {
+        let indexed = self.index.collections().await?;
+        if indexed.contains(&self.convo) {
+            let documents = self
+                .history
+                .get_history(saved)
+                .await?
+                .into_iter()
+                .map(|output| Document {
+                    key: output.id,
+                    author: output.author,
+                    created_at: output.created_at,
+                    text: output.content,
+                    source_type: SourceType::Chat,
+                    source: None,
+                })
+                .collect();
+            self.index.add(&self.convo, documents).await?;
+        } else {
+            // conversations from before the index are indexed whole the first time they change
+            self.reindex().await?;
+        }
+        Ok(())
+    }
+
+    async fn reindex(&self) -> Result<(), Box<dyn Error>> {
+        let script = self.history.load().await?;
+        self.index.replace(&self.convo, documents(&script)).await?;
+        Ok(())
+    }
+}
+
+pub fn documents(script: &Script) -> Vec<Document> {
+    script
+        .dialogue
+        .iter()
+        .map(|segment| Document {
+            key: segment.id.clone(),
+            author: segment.role.clone(),
+            created_at: segment.created_at,
+            text: segment.content.clone(),
+            source_type: SourceType::Chat,
+            source: None,
+        })
+        .collect()
+}
+
+fn warn(result: Result<(), Box<dyn Error>>) {
+    if let Err(e) = result {
+        eprintln!("Could not update the search index: {}", e);
+    }
+}
+
+#[async_trait]
+impl ShortMemEffect for IndexedHistory {
+    async fn save_history(&self, input: &[ShortMemInput]) -> Result<(), Box<dyn Error>> {
+        self.history.save_history(input).await?;
+        warn(self.index_saved(input.len()).await);
+        Ok(())
+    }
+
+    async fn get_history(&self, len: usize) -> Result<Vec<ShortMemOutput>, Box<dyn Error>> {
+        self.history.get_history(len).await
+    }
+
+    async fn checkout(&self, id: Option<&str>) -> Result<(), Box<dyn Error>> {
+        self.history.checkout(id).await
+    }
+
+    async fn get_persona(&self) -> Result<Option<String>, Box<dyn Error>> {
+        self.history.get_persona().await
+    }
+
+    async fn save_persona(&self, persona: &str) -> Result<(), Box<dyn Error>> {
+        self.history.save_persona(persona).await
+    }
+
+    async fn get_summary(&self) -> Result<Option<Summary>, Box<dyn Error>> {
+        self.history.get_summary().await
+    }
+
+    async fn save_summary(&self, summary: &Summary) -> Result<(), Box<dyn Error>> {
+        self.history.save_summary(summary).await
+    }
+
+    async fn load(&self) -> Result<Script, Box<dyn Error>> {
+        self.history.load().await
+    }
+
+    async fn replace(&self, script: Script) -> Result<(), Box<dyn Error>> {
+        let documents = documents(&script);
+        self.history.replace(script).await?;
+        let indexed = self.index.replace(&self.convo, documents).await;
+        warn(indexed.map_err(|e| e.into()));
+        Ok(())
+    }
+
+    async fn edit(&self, edit: ScriptEdit) -> Result<Script, Box<dyn Error>> {
+        let before = self.history.edit(edit).await?;
+        warn(self.reindex().await);
+        Ok(before)
+    }
+
+    async fn exists(&self) -> Result<bool, Box<dyn Error>> {
+        self.history.exists().await
+    }
+
+    async fn delete(&self) -> Result<(), Box<dyn Error>> {
+        self.history.delete().await?;
+        warn(self.index.remove(&self.convo).await.map_err(|e| e.into()));
+        Ok(())
+    }
+
+    async fn len(&self) -> Result<usize, Box<dyn Error>> {
+        self.history.len().await
+    }
+
+    async fn get_segments(
+        &self,
+        from: usize,
+        to: usize,
+    ) -> Result<Vec<ShortMemOutput>, Box<dyn Error>> {
+        self.history.get_segments(from, to).await
+    }
+}
@@ -0,0 +1,507 @@
+use std::{
+    error::Error,
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, ErrorKind, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+
+use crate::utils;
+
+use super::{
+    conversation::{
+        blocking, lock, lock_path, output, segments, DialogueSegment, HistoryError, Script,
+        Summary, ROOT,
+    },
+    ScriptEdit, ShortMemEffect, ShortMemInput, ShortMemOutput,
+};
+
+const EXTENSION: &str = "jsonl";
+const CHUNK_SIZE: u64 = 8 * 1024;
+
+impl From<serde_json::Error> for HistoryError {
+    fn from(e: serde_json::Error) -> Self {
+        HistoryError(e.to_string())
+    }
+}
+
+/// A line of the conversation file. The persona, summary and branch pointer are appended
+/// whenever they change, the last one wins. A segment moves the branch pointer to itself
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Record {
+    Segment(Box<DialogueSegment>),
+    Persona { persona: String },
+    Summary(Summary),
+    Head { head: String },
+}
+
+/// Conversation history appended to a `<name>.jsonl` file, one record per line. Recent history is
+/// read from the end of the file so long conversations stay quick to continue
+pub struct JsonlHistory {
+    convo_path: PathBuf,
+}
+
+impl JsonlHistory {
+    pub fn new(convo_dir: &str, convo: &str) -> Self {
+        Self {
+            convo_path: Path::new(convo_dir).join(format!("{}.{}", convo, EXTENSION)),
+        }
+    }
+
+    /// Names of the jsonl conversations in the directory
+    pub fn list(convo_dir: &str) -> Result<Vec<String>, HistoryError> {
+        let mut names = vec![];
+        for entry in std::fs::read_dir(convo_dir)? {
+            let path = entry?.path();
+            let is_jsonl = path
+                .extension()
+                .map(|ext| ext == EXTENSION)
+                .unwrap_or(false);
+            let name = path
+                .file_stem()
+                .map(|stem| stem.to_string_lossy().to_string())
+                .unwrap_or_default();
+            if path.is_file() && is_jsonl && !name.starts_with('.') {
+                names.push(name);
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    async fn append(&self, records: Vec<Record>) -> Result<(), HistoryError> {
+        let convo_path = self.convo_path.clone();
+        blocking(move || {
+            let _lock = lock(&convo_path, true)?;
+            append_records(&convo_path, &records)
+        })
+        .await
+    }
+}
+
+fn append_records(convo_path: &Path, records: &[Record]) -> Result<(), HistoryError> {
+    let mut lines = String::new();
+    for record in records {
+        lines.push_str(&serde_json::to_string(record)?);
+        lines.push('\n');
+    }
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(convo_path)?;
+    file.write_all(lines.as_bytes())?;
+    file.sync_data()?;
+    Ok(())
+}
+
+/// Rewrites the file with the script, the caller holds the exclusive lock
+fn write_script(convo_path: &Path, script: Script) -> Result<(), HistoryError> {
+    let mut lines = String::new();
+    if let Some(persona) = script.persona {
+        lines.push_str(&serde_json::to_string(&Record::Persona { persona })?);
+        lines.push('\n');
+    }
+    if let Some(summary) = script.summary {
+        lines.push_str(&serde_json::to_string(&Record::Summary(summary))?);
+        lines.push('\n');
+    }
+    for segment in script.dialogue {
+        lines.push_str(&serde_json::to_string(&Record::Segment(Box::new(segment)))?);
+        lines.push('\n');
+    }
+    if let Some(head) = script.head {
+        lines.push_str(&serde_json::to_string(&Record::Head { head })?);
+        lines.push('\n');
+    }
+    utils::write_atomic(convo_path, lines.as_bytes())?;
+    Ok(())
+}
+
+fn parse_line(convo_path: &Path, line: &[u8]) -> Option<Record> {
+    let line = String::from_utf8_lossy(line);
+    if line.trim().is_empty() {
+        return None;
+    }
+    match serde_json::from_str(&line) {
+        Ok(record) => Some(record),
+        Err(e) => {
+            // most likely a write cut short by a crash
+            eprintln!(
+                "Skipping unreadable line in {}: {}",
+                convo_path.to_string_lossy(),
+                e
+            );
+            None
+        }
+    }
+}
+
+fn read_records(convo_path: &Path) -> Result<Vec<Record>, HistoryError> {
+    let file = match File::open(convo_path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e.into()),
+    };
+    let mut records = vec![];
+    for line in BufReader::new(file).split(b'\n') {
+        records.extend(parse_line(convo_path, &line?));
+    }
+    Ok(records)
+}
+
+/// Reads records from the end of the file backwards until `done` returns true
+fn read_records_back<F>(convo_path: &Path, chunk_size: u64, mut done: F) -> Result<(), HistoryError>
+where
+    F: FnMut(Record) -> bool,
+{
+    let mut file = match File::open(convo_path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut position = file.seek(SeekFrom::End(0))?;
+    // bytes after `position` that have not yet been split into lines
+    let mut unread: Vec<u8> = vec![];
+
+    while position > 0 {
+        let read = chunk_size.min(position);
+        position -= read;
+        file.seek(SeekFrom::Start(position))?;
+
+        let mut chunk = vec![0; read as usize];
+        file.read_exact(&mut chunk)?;
+        chunk.append(&mut unread);
+        unread = chunk;
+
+        while let Some(newline) = unread.iter().rposition(|byte| *byte == b'\n') {
+            let line = unread.split_off(newline + 1);
+            unread.truncate(newline);
+            if let Some(record) = parse_line(convo_path, &line) {
+                if done(record) {
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    if let Some(record) = parse_line(convo_path, &unread) {
+        done(record);
+    }
+    Ok(())
+}
+
+fn script(records: Vec<Record>) -> Script {
+    let mut script = Script::default();
+    for record in records {
+        match record {
+            Record::Segment(segment) => {
+                script.dialogue.push(*segment);
+                script.head = None;
+            }
+            Record::Persona { persona } => script.persona = Some(persona),
+            Record::Summary(summary) => script.summary = Some(summary),
+            Record::Head { head } => script.head = Some(head),
+        }
+    }
+    script.normalise()
+}
+
+/// The segment the next turn follows. Only files with segments saved before ids were added need
+/// to be read in full
+fn head(convo_path: &Path) -> Result<Option<String>, HistoryError> {
+    let mut head = None;
+    let mut legacy = false;
+    read_records_back(convo_path, CHUNK_SIZE, |record| match record {
+        Record::Segment(segment) => {
+            legacy = segment.id.is_empty();
+            head = Some(segment.id);
+            true
+        }
+        Record::Head { head: id } => {
+            head = Some(id).filter(|id| id != ROOT);
+            true
+        }
+        _ => false,
+    })?;
+    if legacy {
+        return Ok(script(read_records(convo_path)?)
+            .head()
+            .map(|head| head.to_string()));
+    }
+    Ok(head)
+}
+
+fn last_persona(convo_path: &Path) -> Result<Option<String>, HistoryError> {
+    let mut persona = None;
+    read_records_back(convo_path, CHUNK_SIZE, |record| match record {
+        Record::Persona { persona: name } => {
+            persona = Some(name);
+            true
+        }
+        _ => false,
+    })?;
+    Ok(persona)
+}
+
+fn last_summary(convo_path: &Path) -> Result<Option<Summary>, HistoryError> {
+    let mut summary = None;
+    read_records_back(convo_path, CHUNK_SIZE, |record| match record {
+        Record::Summary(last) => {
+            summary = Some(last);
+            true
+        }
+        _ => false,
+    })?;
+    Ok(summary)
+}
+
+#[async_trait]
+impl ShortMemEffect for JsonlHistory {
+    async fn save_history(&self, input: &[ShortMemInput]) -> Result<(), Box<dyn Error>> {
+        let dialogue = segments(input);
+        let convo_path = self.convo_path.clone();
+        blocking(move || {
+            let _lock = lock(&convo_path, true)?;
+            let mut parent = head(&convo_path)?;
+            let records: Vec<Record> = dialogue
+                .into_iter()
+                .map(|segment| {
+                    let id = segment.id.clone();
+                    let segment = DialogueSegment {
+                        parent: parent.replace(id),
+                        ..segment
+                    };
+                    Record::Segment(Box::new(segment))
+                })
+                .collect();
+            append_records(&convo_path, &records)
+        })
+        .await?;
+        Ok(())
+    }
+
+    async fn get_history(&self, len: usize) -> Result<Vec<ShortMemOutput>, Box<dyn Error>> {
+        if len == 0 {
+            return Ok(vec![]);
+        }
+        let convo_path = self.convo_path.clone();
+        let dialogue = blocking(move || {
+            let _lock = lock(&convo_path, false)?;
+            let mut dialogue = vec![];
+            // the segment wanted next, outer none until the head is found
+            let mut want: Option<Option<String>> = None;
+            let mut legacy = false;
+            read_records_back(&convo_path, CHUNK_SIZE, |record| {
+                match (record, &want) {
+                    (Record::Head { head }, None) => {
+                        want = Some(Some(head).filter(|head| head != ROOT));
+                    }
+                    (Record::Segment(segment), _) if segment.id.is_empty() => legacy = true,
+                    (Record::Segment(segment), None) => {
+                        want = Some(segment.parent.clone());
+                        dialogue.push(*segment);
+                    }
+                    (Record::Segment(segment), Some(Some(id))) if *id == segment.id => {
+                        want = Some(segment.parent.clone());
+                        dialogue.push(*segment);
+                    }
+                    _ => (),
+                }
+                legacy || dialogue.len() >= len || want == Some(None)
+            })?;
+
+            if legacy {
+                let script = script(read_records(&convo_path)?);
+                let branch = script.branch();
+                return Ok(branch[branch.len().saturating_sub(len)..]
+                    .iter()
+                    .map(|segment| (*segment).clone())
+                    .collect());
+            }
+            dialogue.reverse();
+            Ok(dialogue)
+        })
+        .await?;
+
+        Ok(dialogue.iter().map(output).collect())
+    }
+
+    async fn checkout(&self, id: Option<&str>) -> Result<(), Box<dyn Error>> {
+        let id = id.map(|id| id.to_string());
+        let convo_path = self.convo_path.clone();
+        blocking(move || {
+            let _lock = lock(&convo_path, true)?;
+            let mut script = script(read_records(&convo_path)?);
+            script.checkout(id.as_deref())?;
+            let head = script.head.unwrap_or_else(|| ROOT.to_string());
+            append_records(&convo_path, &[Record::Head { head }])
+        })
+        .await?;
+        Ok(())
+    }
+
+    async fn get_persona(&self) -> Result<Option<String>, Box<dyn Error>> {
+        let convo_path = self.convo_path.clone();
+        Ok(blocking(move || {
+            let _lock = lock(&convo_path, false)?;
+            last_persona(&convo_path)
+        })
+        .await?)
+    }
+
+    async fn save_persona(&self, persona: &str) -> Result<(), Box<dyn Error>> {
+        let convo_path = self.convo_path.clone();
+        let persona = persona.to_string();
+        blocking(move || {
+            let _lock = lock(&convo_path, true)?;
+            if last_persona(&convo_path)?.as_ref() != Some(&persona) {
+                append_records(&convo_path, &[Record::Persona { persona }])?;
+            }
+            Ok(())
+        })
+        .await?;
+        Ok(())
+    }
+
+    async fn get_summary(&self) -> Result<Option<Summary>, Box<dyn Error>> {
+        let convo_path = self.convo_path.clone();
+        Ok(blocking(move || {
+            let _lock = lock(&convo_path, false)?;
+            last_summary(&convo_path)
+        })
+        .await?)
+    }
+
+    async fn save_summary(&self, summary: &Summary) -> Result<(), Box<dyn Error>> {
+        self.append(vec![Record::Summary(summary.clone())]).await?;
+        Ok(())
+    }
+
+    async fn load(&self) -> Result<Script, Box<dyn Error>> {
+        let convo_path = self.convo_path.clone();
+        let records = blocking(move || {
+            let _lock = lock(&convo_path, false)?;
+            read_records(&convo_path)
+        })
+        .await?;
+
+        Ok(script(records))
+    }
+
+    async fn replace(&self, script: Script) -> Result<(), Box<dyn Error>> {
+        let convo_path = self.convo_path.clone();
+        blocking(move || {
+            let _lock = lock(&convo_path, true)?;
+            write_script(&convo_path, script)
+        })
+        .await?;
+        Ok(())
+    }
+
+    async fn edit(&self, edit: ScriptEdit) -> Result<Script, Box<dyn Error>> {
+        let convo_path = self.convo_path.clone();
+        Ok(blocking(move || {
+            let _lock = lock(&convo_path, true)?;
+            let before = script(read_records(&convo_path)?);
+            write_script(&convo_path, edit(before.clone())?)?;
+            Ok(before)
+        })
+        .await?)
+    }
+
+    async fn exists(&self) -> Result<bool, Box<dyn Error>> {
+        Ok(self.convo_path.is_file())
+    }
+
+    async fn delete(&self) -> Result<(), Box<dyn Error>> {
+        tokio::fs::remove_file(&self.convo_path).await?;
+        let _ = tokio::fs::remove_file(lock_path(&self.convo_path)).await;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use crate::effect::{conversation::tests::check_history, ShortMemEffect, ShortMemInput};
+
+    use super::{read_records_back, JsonlHistory, Record};
+
+    fn input(content: &str) -> ShortMemInput {
+        ShortMemInput {
+            id: None,
+            author: "user".to_string(),
+            content: content.to_string(),
+            meta: None,
+            memory_id: None,
+        }
+    }
+
+    #[test]
+    fn reads_back_across_chunks() {
+        let dir = tempfile::tempdir().unwrap();
+        let convo_path = dir.path().join("convo.jsonl");
+        let mut file = std::fs::File::create(&convo_path).unwrap();
+        for i in 0..20 {
+            writeln!(file, r#"{{"type":"persona","persona":"persona-{}"}}"#, i).unwrap();
+        }
+        // a write cut short is skipped
+        write!(file, r#"{{"type":"persona","pers"#).unwrap();
+
+        let mut personas = vec![];
+        read_records_back(&convo_path, 7, |record| {
+            if let Record::Persona { persona } = record {
+                personas.push(persona);
+            }
+            false
+        })
+        .unwrap();
+
+        let expected: Vec<String> = (0..20).rev().map(|i| format!("persona-{}", i)).collect();
+        assert_eq!(expected, personas);
+    }
+
+    #[tokio::test]
+    async fn tail_returns_latest_segments_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let history = JsonlHistory::new(&dir.path().to_string_lossy(), "convo");
+
+        history
+            .save_history(&[input("one"), input("two")])
+            .await
+            .unwrap();
+        history.save_persona("reviewer").await.unwrap();
+        history.save_persona("reviewer").await.unwrap();
+        history.save_history(&[input("three")]).await.unwrap();
+
+        let latest: Vec<String> = history
+            .get_history(2)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|segment| segment.content)
+            .collect();
+        assert_eq!(vec!["two", "three"], latest);
+        assert_eq!(
+            Some("reviewer".to_string()),
+            history.get_persona().await.unwrap()
+        );
+
+        let script = history.load().await.unwrap();
+        assert_eq!(3, script.dialogue.len());
+        assert_eq!(
+            vec!["convo"],
+            JsonlHistory::list(&dir.path().to_string_lossy()).unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn keeps_the_contract() {
+        let dir = tempfile::tempdir().unwrap();
+        check_history(&JsonlHistory::new(&dir.path().to_string_lossy(), "convo")).await;
+    }
+}
@@ -0,0 +1,456 @@
+use std::{error::Error, path::PathBuf, time::Duration};
+
+use async_trait::async_trait;
+use chrono::{TimeZone, Utc};
+use log::debug;
+use rusqlite::{params, Connection, Row};
+
+use crate::data::model::SourceType;
+
+use super::{
+    conversation::new_id,
+    embedder::Embedder,
+    search_index::{migrate, source},
+    LongMemEffect, LongMemOutput, LongMemQueryOpt, LongMemSaveInp,
+};
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS memories (
+        id TEXT PRIMARY KEY,
+        category TEXT NOT NULL,
+        author TEXT NOT NULL,
+        created_at INTEGER NOT NULL,
+        text TEXT NOT NULL,
+        embedder TEXT NOT NULL,
+        vector BLOB NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS memories_by_category ON memories (category, created_at);
+";
+
+/// Schema changes, applied in order to databases at an older `user_version`
+const MIGRATIONS: &[&str] = &[
+    "ALTER TABLE memories ADD COLUMN path TEXT;
+     ALTER TABLE memories ADD COLUMN offset INTEGER;",
+    // only ingested documents had a path
+    "ALTER TABLE memories ADD COLUMN source_type TEXT;
+     UPDATE memories SET source_type = CASE WHEN path IS NULL THEN 'chat' ELSE 'document' END;",
+];
+
+/// Long term memory in a sqlite database, searched by the cosine similarity of embeddings.
+/// Memories embedded by another model than the current one are not searched
+pub struct LocalMemory {
+    database: PathBuf,
+    embedder: Box<dyn Embedder>,
+    top_k: u32,
+}
+
+impl LocalMemory {
+    pub fn new(database: PathBuf, embedder: Box<dyn Embedder>, top_k: u32) -> Self {
+        Self {
+            database,
+            embedder,
+            top_k,
+        }
+    }
+
+    /// Runs the query on a blocking thread with a connection to the database
+    async fn with_connection<T, F>(&self, query: F) -> Result<T, Box<dyn Error>>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> Result<T, rusqlite::Error> + Send + 'static,
+    {
+        let database = self.database.clone();
+        let result =
+            tokio::task::spawn_blocking(move || -> Result<T, Box<dyn Error + Send + Sync>> {
+                if let Some(dir) = database.parent() {
+                    std::fs::create_dir_all(dir)?;
+                }
+                let mut connection = Connection::open(database)?;
+                connection.busy_timeout(Duration::from_secs(10))?;
+                connection.execute_batch(SCHEMA)?;
+                migrate(&mut connection, MIGRATIONS)?;
+                Ok(query(&mut connection)?)
+            })
+            .await?;
+        result.map_err(|e| -> Box<dyn Error> { e })
+    }
+}
+
+#[async_trait]
+impl LongMemEffect for LocalMemory {
+    async fn save(
+        &self,
+        input: &[LongMemSaveInp],
+        category: &str,
+    ) -> Result<Vec<String>, Box<dyn Error>> {
+        if input.is_empty() {
+            return Ok(vec![]);
+        }
+        let texts: Vec<String> = input.iter().map(|input| input.text.clone()).collect();
+        let vectors = self.embedder.embed(&texts).await?;
+        let rows: Vec<_> = input
+            .iter()
+            .zip(vectors)
+            .map(|(input, vector)| {
+                (
+                    new_id(),
+                    input.author.clone(),
+                    input.created_at.timestamp_millis(),
+                    input.text.clone(),
+                    to_bytes(&normalised(vector)),
+                    input.source.clone(),
+                    input.source_type.as_str(),
+                )
+            })
+            .collect();
+        let category = category.to_string();
+        let embedder = self.embedder.name();
+
+        self.with_connection(move |connection| {
+            let transaction = connection.transaction()?;
+            for (id, author, created_at, text, vector, source, source_type) in &rows {
+                transaction.execute(
+                    "INSERT INTO memories (id, category, author, created_at, text, embedder,
+                        vector, path, offset, source_type)
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+                    params![
+                        id,
+                        category,
+                        author,
+                        created_at,
+                        text,
+                        embedder,
+                        vector,
+                        source.as_ref().map(|source| &source.path),
+                        source.as_ref().map(|source| source.offset as i64),
+                        source_type
+                    ],
+                )?;
+            }
+            transaction.commit()?;
+            Ok(rows.into_iter().map(|row| row.0).collect())
+        })
+        .await
+    }
+
+    async fn query(
+        &self,
+        query: &str,
+        query_opts: &[LongMemQueryOpt],
+    ) -> Result<Vec<LongMemOutput>, Box<dyn Error>> {
+        let query = normalised(
+            self.embedder
+                .embed(&[query.to_string()])
+                .await?
+                .pop()
+                .unwrap_or_default(),
+        );
+        let filters: Vec<Filter> = query_opts.iter().map(filter).collect();
+        let embedder = self.embedder.name();
+
+        let mut scored = self
+            .with_connection(move |connection| {
+                let mut statement = connection.prepare(&format!(
+                    "SELECT {}, vector FROM memories WHERE {} AND embedder = ?6",
+                    MEMORY_COLUMNS, FILTER
+                ))?;
+                let mut scored = vec![];
+                for (category, min, max, author, source_type) in &filters {
+                    let rows = statement.query_map(
+                        params![category, min, max, author, source_type, embedder],
+                        |row| {
+                            let vector: Vec<u8> = row.get(8)?;
+                            let similarity = cosine(&query, &from_bytes(&vector));
+                            Ok((
+                                similarity,
+                                LongMemOutput {
+                                    // opposite meanings are as irrelevant as unrelated ones
+                                    score: similarity.max(0.0) as f64,
+                                    ..memory_row(row)?
+                                },
+                            ))
+                        },
+                    )?;
+                    for row in rows {
+                        scored.push(row?);
+                    }
+                }
+                Ok(scored)
+            })
+            .await?;
+
+        debug!("Scored {} local memories", scored.len());
+        scored.sort_by(|a, b| b.0.total_cmp(&a.0));
+        Ok(scored
+            .into_iter()
+            .take(self.top_k as usize)
+            .map(|(_, output)| output)
+            .collect())
+    }
+
+    async fn list(
+        &self,
+        query_opt: &LongMemQueryOpt,
+        limit: usize,
+    ) -> Result<Vec<LongMemOutput>, Box<dyn Error>> {
+        let (category, min, max, author, source_type) = filter(query_opt);
+        self.with_connection(move |connection| {
+            let mut statement = connection.prepare(&format!(
+                "SELECT {} FROM memories WHERE {}
+                 ORDER BY created_at DESC, rowid DESC LIMIT ?6",
+                MEMORY_COLUMNS, FILTER
+            ))?;
+            let memories = statement
+                .query_map(
+                    params![category, min, max, author, source_type, limit as i64],
+                    memory_row,
+                )?
+                .collect::<Result<Vec<_>, rusqlite::Error>>()?;
+            Ok(memories)
+        })
+        .await
+    }
+
+    async fn get(&self, id: &str) -> Result<Option<LongMemOutput>, Box<dyn Error>> {
+        let id = id.to_string();
+        self.with_connection(move |connection| {
+            let mut statement = connection.prepare(&format!(
+                "SELECT {} FROM memories WHERE id = ?1",
+                MEMORY_COLUMNS
+            ))?;
+            let mut memories = statement.query_map([id], memory_row)?;
+            memories.next().transpose()
+        })
+        .await
+    }
+
+    async fn delete(&self, id: &str) -> Result<(), Box<dyn Error>> {
+        let id = id.to_string();
+        self.with_connection(move |connection| {
+            connection.execute("DELETE FROM memories WHERE id = ?1", [id])?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn delete_category(&self, category: &str) -> Result<(), Box<dyn Error>> {
+        let category = category.to_string();
+        self.with_connection(move |connection| {
+            connection.execute("DELETE FROM memories WHERE category = ?1", [category])?;
+            Ok(())
+        })
+        .await
+    }
+}
+
+const MEMORY_COLUMNS: &str = "id, category, author, created_at, text, path, offset, source_type";
+
+/// Conditions on the memories of a query option, with its values as the first parameters
+const FILTER: &str = "category = ?1 AND created_at >= ?2 AND created_at <= ?3
+    AND (?4 IS NULL OR author = ?4) AND (?5 IS NULL OR source_type = ?5)";
+
+/// Category, window, author and source type of a query option
+type Filter = (String, i64, i64, Option<String>, Option<&'static str>);
+
+fn filter(query_opt: &LongMemQueryOpt) -> Filter {
+    (
+        query_opt.category.clone(),
+        query_opt
+            .query_window
+            .min
+            .map_or(i64::MIN, |min| min.timestamp_millis()),
+        query_opt
+            .query_window
+            .max
+            .map_or(i64::MAX, |max| max.timestamp_millis()),
+        query_opt.author.clone(),
+        query_opt
+            .source_type
+            .map(|source_type| source_type.as_str()),
+    )
+}
+
+fn memory_row(row: &Row) -> Result<LongMemOutput, rusqlite::Error> {
+    Ok(LongMemOutput {
+        id: row.get(0)?,
+        category: row.get(1)?,
+        author: row.get(2)?,
+        created_at: Utc
+            .timestamp_millis_opt(row.get(3)?)
+            .single()
+            .unwrap_or_default(),
+        text: row.get(4)?,
+        score: 1.0,
+        source_type: SourceType::from_stored(row.get::<_, Option<String>>(7)?.as_deref()),
+        source: source(row.get(5)?, row.get(6)?),
+    })
+}
+
+fn normalised(vector: Vec<f32>) -> Vec<f32> {
+    let norm = vector.iter().map(|x| x * x).sum::<f32>().sqrt();
+    match norm > 0.0 {
+        true => vector.into_iter().map(|x| x / norm).collect(),
+        false => vector,
+    }
+}
+
+/// Cosine similarity of normalised vectors. Vectors of different lengths are not similar
+fn cosine(a: &[f32], b: &[f32]) -> f32 {
+    match a.len() == b.len() {
+        true => a.iter().zip(b).map(|(a, b)| a * b).sum(),
+        false => 0.0,
+    }
+}
+
+fn to_bytes(vector: &[f32]) -> Vec<u8> {
+    vector.iter().flat_map(|x| x.to_le_bytes()).collect()
+}
+
+fn from_bytes(bytes: &[u8]) -> Vec<f32> {
+    bytes
+        .chunks_exact(4)
+        .map(|chunk| f32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, Utc};
+    use tempfile::tempdir;
+
+    use rusqlite::{params, Connection};
+
+    use crate::{
+        data::model::SourceType,
+        effect::{
+            embedder::HashingEmbedder, search_index::migrate, LongMemEffect, LongMemQueryOpt,
+            LongMemSaveInp, QueryWindow,
+        },
+    };
+
+    use super::{LocalMemory, MIGRATIONS, SCHEMA};
+
+    #[tokio::test]
+    async fn finds_similar_memories_in_the_category_and_window() {
+        let dir = tempdir().unwrap();
+        let memory = LocalMemory::new(
+            dir.path().join("memory").join("vectors.sqlite3"),
+            Box::new(HashingEmbedder::new(256)),
+            2,
+        );
+        let now = Utc::now();
+        let save = |text: &str, days_ago: i64| LongMemSaveInp {
+            text: text.to_string(),
+            author: "user".to_string(),
+            created_at: now - Duration::days(days_ago),
+            source_type: SourceType::Chat,
+            source: None,
+        };
+        let ids = memory
+            .save(
+                &[
+                    save("the deploy script lives in ops", 3),
+                    save("lunch is at noon", 2),
+                    save("run the deploy script with sudo", 1),
+                ],
+                "work",
+            )
+            .await
+            .unwrap();
+        memory
+            .save(&[save("the deploy script is broken", 0)], "other")
+            .await
+            .unwrap();
+
+        let query = |min, category: &str| {
+            vec![LongMemQueryOpt {
+                category: category.to_string(),
+                query_window: QueryWindow { min, max: None },
+                author: None,
+                source_type: None,
+            }]
+        };
+        let texts = |outputs: Vec<crate::effect::LongMemOutput>| {
+            outputs
+                .into_iter()
+                .map(|output| output.text)
+                .collect::<Vec<String>>()
+        };
+
+        let found = memory
+            .query("is the deploy script in ops", &query(None, "work"))
+            .await
+            .unwrap();
+        assert_eq!(
+            vec![
+                "the deploy script lives in ops",
+                "run the deploy script with sudo"
+            ],
+            texts(found)
+        );
+        let recent = memory
+            .query(
+                "deploy script",
+                &query(Some(now - Duration::hours(36)), "work"),
+            )
+            .await
+            .unwrap();
+        assert_eq!(vec!["run the deploy script with sudo"], texts(recent));
+
+        let listed = memory.list(&query(None, "work")[0], 5).await.unwrap();
+        assert_eq!(
+            vec![
+                "run the deploy script with sudo",
+                "lunch is at noon",
+                "the deploy script lives in ops"
+            ],
+            texts(listed)
+        );
+        let shown = memory.get(&ids[1]).await.unwrap().unwrap();
+        assert_eq!(
+            ("lunch is at noon", "work"),
+            (shown.text.as_str(), shown.category.as_str())
+        );
+
+        memory.delete(&ids[0]).await.unwrap();
+        memory.delete_category("other").await.unwrap();
+        let mut categories = query(None, "work");
+        categories.extend(query(None, "other"));
+        let left = memory.query("deploy script", &categories).await.unwrap();
+        assert_eq!(
+            vec!["run the deploy script with sudo", "lunch is at noon"],
+            texts(left)
+        );
+    }
+
+    #[tokio::test]
+    async fn memories_saved_before_source_types_are_chats() {
+        let dir = tempdir().unwrap();
+        let database = dir.path().join("vectors.sqlite3");
+        let mut connection = Connection::open(&database).unwrap();
+        connection.execute_batch(SCHEMA).unwrap();
+        migrate(&mut connection, &MIGRATIONS[..1]).unwrap();
+        connection
+            .execute(
+                "INSERT INTO memories (id, category, author, created_at, text, embedder, vector)
+                 VALUES ('old', 'work', 'user', ?1, 'an old memory', 'hashing-256', ?2)",
+                params![Utc::now().timestamp_millis(), vec![0u8; 4]],
+            )
+            .unwrap();
+        drop(connection);
+
+        let memory = LocalMemory::new(database, Box::new(HashingEmbedder::new(256)), 2);
+        let old = memory.get("old").await.unwrap().unwrap();
+        assert_eq!(SourceType::Chat, old.source_type);
+        let chats = LongMemQueryOpt {
+            category: "work".to_string(),
+            query_window: QueryWindow {
+                min: None,
+                max: None,
+            },
+            author: None,
+            source_type: Some(SourceType::Chat),
+        };
+        assert_eq!(1, memory.list(&chats, 5).await.unwrap().len());
+    }
+}
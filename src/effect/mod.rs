@@ -1,21 +1,49 @@
-mod conversation;
+pub mod bm25_memory;
+pub mod conversation;
+pub mod embedder;
+mod encrypted_history;
+pub mod encryption;
 mod gpt_context;
 mod gpt_request;
+mod indexed_history;
+mod jsonl_history;
+mod local_memory;
+pub mod outbox;
 mod output;
+mod rerank;
+pub mod retention;
+pub mod search_index;
+mod shell;
+mod sqlite_history;
 mod user;
 
-use std::{error::Error, pin::Pin};
+use std::{error::Error, path::Path, pin::Pin};
 
 use async_trait::async_trait;
 use chrono::{DateTime, Utc};
 use futures::Stream;
 use reqwest::Client;
 
-use crate::model::Model;
+use crate::model::{
+    Algo, HistoryBackend, MemoryFilter, MemoryStore, Model, RerankConfig, SourceType,
+};
 
 use self::{
-    conversation::YamlHistory, gpt_context::LongTermGptMemory, gpt_request::GptRequest,
-    output::Output, user::User,
+    bm25_memory::Bm25Memory,
+    conversation::{HistoryError, Script, Summary, TokenUsage, TurnMeta, YamlHistory},
+    encrypted_history::EncryptedHistory,
+    encryption::Cipher,
+    gpt_context::LongTermGptMemory,
+    gpt_request::GptRequest,
+    indexed_history::IndexedHistory,
+    jsonl_history::JsonlHistory,
+    local_memory::LocalMemory,
+    output::Output,
+    rerank::RerankedMemory,
+    search_index::SearchIndex,
+    shell::Shell,
+    sqlite_history::SqliteHistory,
+    user::User,
 };
 
 //--- Ai Requests ---//
@@ -31,6 +59,14 @@ pub struct ChatRequestInput {
     pub content: String,
 }
 
+/// Details of a chat response that are not part of its text
+#[derive(Debug, Clone, Default)]
+pub struct ChatResponseMeta {
+    pub provider: String,
+    pub finish_reason: Option<String>,
+    pub usage: Option<TokenUsage>,
+}
+
 #[async_trait]
 pub trait AiRequestEffect: Sync + Send {
     async fn chat_request_stream(
@@ -38,10 +74,20 @@ pub trait AiRequestEffect: Sync + Send {
         request: &[ChatRequestInput],
     ) -> Result<Pin<Box<dyn Stream<Item = Vec<String>> + Send + 'static>>, Box<dyn Error>>;
 
+    /// Chat request using different settings to those the requester was created with
+    async fn chat_request_stream_with_algo(
+        &self,
+        request: &[ChatRequestInput],
+        algo: &Algo,
+    ) -> Result<Pin<Box<dyn Stream<Item = Vec<String>> + Send + 'static>>, Box<dyn Error>>;
+
     async fn edit_request_stream(
         &self,
         request: EditRequestInput,
     ) -> Result<Pin<Box<dyn Stream<Item = Vec<String>> + Send + 'static>>, Box<dyn Error>>;
+
+    /// Details of the last chat response, complete once its stream has been consumed
+    fn response_meta(&self) -> ChatResponseMeta;
 }
 
 //--- Display Output ---//
@@ -63,11 +109,32 @@ pub enum UserCycleResponse {
     NextLeft,
 }
 
+pub enum UserCommandResponse {
+    Run,
+    Edit,
+    Copy,
+    Cancel,
+}
+
 pub trait InteractionEffect: Send + Sync {
     fn elicit_cycle_response(&self, user_prompt: &str) -> UserCycleResponse;
+    fn elicit_command_response(&self, user_prompt: &str) -> UserCommandResponse;
     fn edit_data_gen_prompt(&self, initial_prompt: &str) -> Result<String, Box<dyn Error>>;
 }
 
+//--- Shell ---//
+#[derive(Debug)]
+pub struct ShellOutput {
+    pub success: bool,
+    pub output: String,
+}
+
+pub trait ShellEffect: Send + Sync {
+    /// Runs the command, returning its output only when `capture`
+    fn run(&self, command: &str, capture: bool) -> Result<ShellOutput, Box<dyn Error>>;
+    fn copy_to_clipboard(&self, text: &str) -> Result<(), Box<dyn Error>>;
+}
+
 //--- Memory ---//
 #[derive(Debug)]
 pub struct QueryWindow {
@@ -75,24 +142,78 @@ pub struct QueryWindow {
     pub max: Option<DateTime<Utc>>,
 }
 
+/// Where in a file an ingested memory comes from
+#[derive(Debug, Clone, PartialEq)]
+pub struct MemorySource {
+    pub path: String,
+    /// Byte offset of the text in the file
+    pub offset: usize,
+}
+
+impl MemorySource {
+    /// `path#offset`, for stores that only keep a url
+    pub fn to_url(&self) -> String {
+        format!("{}#{}", self.path, self.offset)
+    }
+
+    pub fn from_url(url: &str) -> Option<Self> {
+        let (path, offset) = url.rsplit_once('#')?;
+        Some(Self {
+            path: path.to_string(),
+            offset: offset.parse().ok()?,
+        })
+    }
+}
+
 #[derive(Debug)]
 pub struct LongMemSaveInp {
     pub text: String,
     pub author: String,
+    pub created_at: DateTime<Utc>,
+    pub source_type: SourceType,
+    pub source: Option<MemorySource>,
 }
 
 #[derive(Debug)]
 pub struct LongMemQueryOpt {
     pub category: String,
     pub query_window: QueryWindow,
+    pub author: Option<String>,
+    pub source_type: Option<SourceType>,
+}
+
+impl LongMemQueryOpt {
+    /// Options for the category within the window, narrowed by the filter
+    pub fn filtered(category: &str, query_window: QueryWindow, filter: &MemoryFilter) -> Self {
+        let max = match (query_window.max, filter.until) {
+            (Some(max), Some(until)) => Some(max.min(until)),
+            (max, until) => max.or(until),
+        };
+        let min = match (query_window.min, filter.since) {
+            (Some(min), Some(since)) => Some(min.max(since)),
+            (min, since) => min.or(since),
+        };
+        Self {
+            category: category.to_string(),
+            query_window: QueryWindow { min, max },
+            author: filter.author.clone(),
+            source_type: filter.source_type,
+        }
+    }
 }
 
 #[derive(Debug)]
 pub struct LongMemOutput {
+    /// Id of the saved document, as returned by `save`
+    pub id: String,
     pub text: String,
     pub created_at: DateTime<Utc>,
     pub author: String,
     pub category: String,
+    /// How well the memory matches the query, from 0 to 1
+    pub score: f64,
+    pub source_type: SourceType,
+    pub source: Option<MemorySource>,
 }
 
 #[async_trait]
@@ -107,25 +228,187 @@ pub trait LongMemEffect: Sync + Send {
         query: &str,
         query_opts: &[LongMemQueryOpt],
     ) -> Result<Vec<LongMemOutput>, Box<dyn Error>>;
+    /// The newest `limit` memories of the category that pass the query options' filters
+    async fn list(
+        &self,
+        query_opt: &LongMemQueryOpt,
+        limit: usize,
+    ) -> Result<Vec<LongMemOutput>, Box<dyn Error>>;
+    /// The memory with the id returned by `save`
+    async fn get(&self, id: &str) -> Result<Option<LongMemOutput>, Box<dyn Error>>;
     async fn delete(&self, id: &str) -> Result<(), Box<dyn Error>>;
+    /// Deletes every memory saved under the category
+    async fn delete_category(&self, category: &str) -> Result<(), Box<dyn Error>>;
+}
+
+/// The long term memory store, re-ranking the memories it finds when `rerank` is given
+pub fn context(
+    store: &MemoryStore,
+    top_k: u32,
+    rerank: Option<&RerankConfig>,
+) -> Box<dyn LongMemEffect> {
+    let candidates = match rerank {
+        Some(rerank) => rerank.candidates.unwrap_or(top_k * 4).max(top_k),
+        None => top_k,
+    };
+    let memory: Box<dyn LongMemEffect> = match store {
+        MemoryStore::RetrievalPlugin { url, bearer } => Box::new(LongTermGptMemory::new(
+            Client::new(),
+            bearer.clone(),
+            candidates,
+            url.clone(),
+        )),
+        MemoryStore::Local {
+            path,
+            embedder,
+            api_key,
+        } => Box::new(LocalMemory::new(
+            path.into(),
+            embedder::embedder(embedder, api_key.clone()),
+            candidates,
+        )),
+        MemoryStore::Bm25 { path } => {
+            Box::new(Bm25Memory::new(SearchIndex::new(path.into()), candidates))
+        }
+    };
+    match rerank {
+        Some(rerank) => Box::new(RerankedMemory::new(memory, rerank.clone(), top_k)),
+        None => memory,
+    }
 }
 
 pub struct ShortMemInput {
+    /// Id to give the segment, a new one when none
+    pub id: Option<String>,
     pub author: String,
     pub content: String,
+    pub meta: Option<TurnMeta>,
+    /// Id of the long term memory saved for the segment
+    pub memory_id: Option<String>,
 }
 
 #[derive(Debug)]
 pub struct ShortMemOutput {
+    pub id: String,
     pub author: String,
     pub created_at: DateTime<Utc>,
     pub content: String,
+    /// Output of the command suggested by the segment, when it was run with --feed-output
+    pub command_output: Option<String>,
 }
 
+/// A change to a whole conversation, made while no other process can write to it
+pub type ScriptEdit = Box<dyn FnOnce(Script) -> Result<Script, HistoryError> + Send>;
+
 #[async_trait]
 pub trait ShortMemEffect: Sync + Send {
     async fn save_history(&self, input: &[ShortMemInput]) -> Result<(), Box<dyn Error>>;
+    /// The last `len` segments of the active branch
     async fn get_history(&self, len: usize) -> Result<Vec<ShortMemOutput>, Box<dyn Error>>;
+    /// Moves the branch pointer so the next turn follows segment `id`, or starts from the
+    /// beginning of the conversation when none
+    async fn checkout(&self, id: Option<&str>) -> Result<(), Box<dyn Error>>;
+    /// Name of the persona last used in the conversation
+    async fn get_persona(&self) -> Result<Option<String>, Box<dyn Error>>;
+    async fn save_persona(&self, persona: &str) -> Result<(), Box<dyn Error>>;
+    async fn get_summary(&self) -> Result<Option<Summary>, Box<dyn Error>>;
+    async fn save_summary(&self, summary: &Summary) -> Result<(), Box<dyn Error>>;
+    /// The whole conversation, an empty one if it does not exist
+    async fn load(&self) -> Result<Script, Box<dyn Error>>;
+    async fn replace(&self, script: Script) -> Result<(), Box<dyn Error>>;
+    /// Makes the change to the conversation as it is when locked, returning the conversation
+    /// from before it
+    async fn edit(&self, edit: ScriptEdit) -> Result<Script, Box<dyn Error>>;
+    async fn exists(&self) -> Result<bool, Box<dyn Error>>;
+    async fn delete(&self) -> Result<(), Box<dyn Error>>;
+
+    /// Number of segments in the active branch
+    async fn len(&self) -> Result<usize, Box<dyn Error>> {
+        Ok(self.load().await?.branch().len())
+    }
+
+    /// Segments of the active branch from index `from` up to but not including `to`
+    async fn get_segments(
+        &self,
+        from: usize,
+        to: usize,
+    ) -> Result<Vec<ShortMemOutput>, Box<dyn Error>> {
+        let script = self.load().await?;
+        let branch = script.branch();
+        let to = to.min(branch.len());
+        Ok(branch[from.min(to)..to]
+            .iter()
+            .map(|segment| conversation::output(segment))
+            .collect())
+    }
+}
+
+/// History of a conversation stored by the backend, encrypted when there is a cipher. Only
+/// conversations stored in plain text are added to the search index
+pub fn history(
+    backend: &HistoryBackend,
+    convo_dir: &str,
+    convo: &str,
+    cipher: Option<&Cipher>,
+) -> Box<dyn ShortMemEffect> {
+    let history: Box<dyn ShortMemEffect> = match backend {
+        HistoryBackend::Yaml => Box::new(YamlHistory::new(
+            &Path::new(convo_dir).join(convo).to_string_lossy(),
+        )),
+        HistoryBackend::Jsonl => Box::new(JsonlHistory::new(convo_dir, convo)),
+        HistoryBackend::Sqlite => Box::new(SqliteHistory::new(convo_dir, convo)),
+    };
+    let history = Box::new(EncryptedHistory::new(history, cipher.cloned(), convo));
+    match cipher {
+        Some(_) => history,
+        None => Box::new(IndexedHistory::new(
+            history,
+            SearchIndex::for_conversations(convo_dir),
+            convo,
+        )),
+    }
+}
+
+/// Names of the conversations stored by the backend
+pub fn conversations(
+    backend: &HistoryBackend,
+    convo_dir: &str,
+) -> Result<Vec<String>, Box<dyn Error>> {
+    Ok(match backend {
+        HistoryBackend::Yaml => YamlHistory::list(convo_dir)?,
+        HistoryBackend::Jsonl => JsonlHistory::list(convo_dir)?,
+        HistoryBackend::Sqlite => SqliteHistory::list(convo_dir)?,
+    })
+}
+
+/// The search index of the conversations in the directory, first indexing any conversations
+/// that are missing from it, or all of them when `rebuild`
+pub async fn search_index(
+    backend: &HistoryBackend,
+    convo_dir: &str,
+    rebuild: bool,
+) -> Result<SearchIndex, Box<dyn Error>> {
+    let index = SearchIndex::for_conversations(convo_dir);
+    let names = conversations(backend, convo_dir)?;
+    let indexed = index.collections().await?;
+    for name in &indexed {
+        if rebuild || !names.contains(name) {
+            index.remove(name).await?;
+        }
+    }
+    for name in names {
+        if rebuild || !indexed.contains(&name) {
+            match history(backend, convo_dir, &name, None).load().await {
+                Ok(script) => {
+                    index
+                        .replace(&name, indexed_history::documents(&script))
+                        .await?
+                }
+                Err(e) => eprintln!("Skipping '{}': {}", name, e),
+            }
+        }
+    }
+    Ok(index)
 }
 
 pub struct Effects {
@@ -134,6 +417,7 @@ pub struct Effects {
     pub user: Box<dyn InteractionEffect>,
     pub context: Box<dyn LongMemEffect>,
     pub history: Box<dyn ShortMemEffect>,
+    pub shell: Box<dyn ShellEffect>,
 }
 
 impl Effects {
@@ -148,13 +432,18 @@ impl Effects {
         let user_displayer = Box::new(Output);
         let user = Box::new(User(user_displayer));
 
-        let context = Box::new(LongTermGptMemory::new(
-            Client::new(),
-            model.context_token.clone(),
+        let context = context(
+            &model.memory.store,
             model.memory.top_k,
-            model.config.context_url.to_string(),
-        ));
-        let history = Box::new(YamlHistory::new(&model.memory.convo_path));
+            model.memory.rerank.as_ref(),
+        );
+        let history = history(
+            &model.memory.history_backend,
+            &model.memory.convo_dir,
+            &model.memory.convo,
+            model.memory.cipher.as_ref(),
+        );
+        let shell = Box::new(Shell::new(&model.config.shell));
 
         Self {
             requester,
@@ -162,6 +451,7 @@ impl Effects {
             user,
             context,
             history,
+            shell,
         }
     }
 }
@@ -0,0 +1,554 @@
+use std::{collections::HashMap, error::Error, path::PathBuf, time::Duration};
+
+use chrono::{DateTime, Utc};
+use log::debug;
+use rusqlite::{params, Connection, TransactionBehavior};
+use serde::{Deserialize, Serialize};
+
+use crate::data::model::SourceType;
+
+use super::{LongMemEffect, LongMemSaveInp, MemorySource, ShortMemEffect};
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS outbox (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        category TEXT NOT NULL,
+        memories TEXT NOT NULL,
+        queued_at INTEGER NOT NULL,
+        attempts INTEGER NOT NULL DEFAULT 0,
+        last_attempt INTEGER,
+        last_error TEXT,
+        claimed_until INTEGER
+    );
+";
+
+/// Schema changes, applied in order to databases at an older `user_version`
+const MIGRATIONS: &[&str] = &["ALTER TABLE outbox ADD COLUMN convo TEXT;"];
+
+const MINUTE_MS: i64 = 60 * 1000;
+/// How long a flush may hold entries before other runs may take them over
+const CLAIM_MS: i64 = 10 * MINUTE_MS;
+/// Longest wait between automatic retries of an entry
+const MAX_BACKOFF_MS: i64 = 24 * 60 * MINUTE_MS;
+
+/// Memories waiting to be saved to long term memory, kept in a sqlite database so that they
+/// survive the run that could not save them. Entries are retried with an exponential backoff
+pub struct Outbox {
+    database: PathBuf,
+}
+
+/// A memory as it is queued
+#[derive(Serialize, Deserialize)]
+struct QueuedMemory {
+    text: String,
+    author: String,
+    created_at: DateTime<Utc>,
+    source_type: SourceType,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    path: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    offset: Option<usize>,
+    /// Id of the conversation segment whose memory id is set once the memory is saved
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    segment_id: Option<String>,
+}
+
+struct Entry {
+    id: i64,
+    category: String,
+    memories: String,
+    convo: Option<String>,
+}
+
+/// The conversation segments that queued memories were saved for, in the order of the memories
+pub struct Segments<'a> {
+    pub convo: &'a str,
+    pub ids: &'a [String],
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Flushed {
+    /// Entries saved and removed from the outbox
+    pub saved: usize,
+    /// Memories in the saved entries
+    pub memories: usize,
+    /// Entries that could not be saved this time
+    pub failed: Vec<String>,
+    /// Entries left in the outbox
+    pub waiting: usize,
+    /// Conversations whose segments could not be given the ids of their saved memories
+    pub unlinked: Vec<String>,
+}
+
+impl Outbox {
+    pub fn new(database: PathBuf) -> Self {
+        Self { database }
+    }
+
+    /// Runs the query on a blocking thread with a connection to the database
+    async fn with_connection<T, F>(&self, query: F) -> Result<T, Box<dyn Error>>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> Result<T, rusqlite::Error> + Send + 'static,
+    {
+        let database = self.database.clone();
+        let result =
+            tokio::task::spawn_blocking(move || -> Result<T, Box<dyn Error + Send + Sync>> {
+                if let Some(dir) = database.parent() {
+                    std::fs::create_dir_all(dir)?;
+                }
+                let mut connection = Connection::open(database)?;
+                connection.busy_timeout(Duration::from_secs(10))?;
+                connection.execute_batch(SCHEMA)?;
+                migrate(&mut connection)?;
+                Ok(query(&mut connection)?)
+            })
+            .await?;
+        result.map_err(|e| -> Box<dyn Error> { e })
+    }
+
+    /// Queues memories that could not be saved to the category, with the error that stopped them
+    /// and the conversation segments they were saved for, if any
+    pub async fn queue(
+        &self,
+        category: &str,
+        input: &[LongMemSaveInp],
+        segments: Option<Segments<'_>>,
+        error: &str,
+    ) -> Result<(), Box<dyn Error>> {
+        let segment_ids = segments
+            .as_ref()
+            .map(|segments| segments.ids)
+            .unwrap_or_default();
+        let memories: Vec<QueuedMemory> = input
+            .iter()
+            .enumerate()
+            .map(|(i, input)| QueuedMemory {
+                text: input.text.clone(),
+                author: input.author.clone(),
+                created_at: input.created_at,
+                source_type: input.source_type,
+                path: input.source.as_ref().map(|source| source.path.clone()),
+                offset: input.source.as_ref().map(|source| source.offset),
+                segment_id: segment_ids.get(i).cloned(),
+            })
+            .collect();
+        let memories = serde_json::to_string(&memories)?;
+        let category = category.to_string();
+        let convo = segments.map(|segments| segments.convo.to_string());
+        let error = error.to_string();
+        let now = Utc::now().timestamp_millis();
+        self.with_connection(move |connection| {
+            // the failed save counts as the first attempt
+            connection.execute(
+                "INSERT INTO outbox (category, memories, convo, queued_at, attempts, last_attempt,
+                    last_error)
+                 VALUES (?1, ?2, ?3, ?4, 1, ?4, ?5)",
+                params![category, memories, convo, now, error],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Number of entries waiting to be saved
+    pub async fn pending(&self) -> Result<usize, Box<dyn Error>> {
+        if !self.database.exists() {
+            return Ok(0);
+        }
+        self.with_connection(|connection| {
+            connection.query_row("SELECT COUNT(*) FROM outbox", [], |row| {
+                row.get::<_, i64>(0).map(|count| count as usize)
+            })
+        })
+        .await
+    }
+
+    /// Saves the queued entries to the memory, oldest first, removing those that were saved and
+    /// setting the memory ids of their segments in the conversation given by `history`. Unless
+    /// `all`, only entries whose backoff has passed are tried and the flush stops at the first
+    /// failure, as the store is likely still down
+    pub async fn flush(
+        &self,
+        context: &dyn LongMemEffect,
+        history: &(dyn Fn(&str) -> Box<dyn ShortMemEffect> + Sync),
+        all: bool,
+    ) -> Result<Flushed, Box<dyn Error>> {
+        if !self.database.exists() {
+            return Ok(Flushed::default());
+        }
+        let entries = self.claim(all).await?;
+        debug!("Flushing {} queued memory writes", entries.len());
+
+        let mut flushed = Flushed::default();
+        let mut unclaimed = vec![];
+        for entry in entries {
+            if !all && !flushed.failed.is_empty() {
+                unclaimed.push(entry.id);
+                continue;
+            }
+            let memories: Vec<QueuedMemory> = serde_json::from_str(&entry.memories)?;
+            let segment_ids: Vec<Option<String>> = memories
+                .iter()
+                .map(|memory| memory.segment_id.clone())
+                .collect();
+            let input: Vec<LongMemSaveInp> = memories
+                .into_iter()
+                .map(|memory| LongMemSaveInp {
+                    text: memory.text,
+                    author: memory.author,
+                    created_at: memory.created_at,
+                    source_type: memory.source_type,
+                    source: memory.path.map(|path| MemorySource {
+                        path,
+                        offset: memory.offset.unwrap_or_default(),
+                    }),
+                })
+                .collect();
+            // the error is not Send, so only its message is kept across the next await
+            let saved = context
+                .save(&input, &entry.category)
+                .await
+                .map_err(|e| e.to_string());
+            let id = entry.id;
+            match saved {
+                Ok(memory_ids) => {
+                    self.with_connection(move |connection| {
+                        connection.execute("DELETE FROM outbox WHERE id = ?1", [id])?;
+                        Ok(())
+                    })
+                    .await?;
+                    flushed.saved += 1;
+                    flushed.memories += input.len();
+
+                    if let Some(convo) = &entry.convo {
+                        // ids are returned in the order the memories were saved
+                        let linked: HashMap<String, String> = segment_ids
+                            .into_iter()
+                            .zip(memory_ids)
+                            .filter_map(|(segment_id, memory_id)| Some((segment_id?, memory_id)))
+                            .collect();
+                        let unlinked = link(history(convo).as_ref(), linked)
+                            .await
+                            .err()
+                            .map(|e| format!("'{}': {}", convo, e));
+                        flushed.unlinked.extend(unlinked);
+                    }
+                }
+                Err(error) => {
+                    flushed
+                        .failed
+                        .push(format!("'{}': {}", entry.category, error));
+                    let now = Utc::now().timestamp_millis();
+                    self.with_connection(move |connection| {
+                        connection.execute(
+                            "UPDATE outbox SET attempts = attempts + 1, last_attempt = ?2,
+                                last_error = ?3, claimed_until = NULL
+                             WHERE id = ?1",
+                            params![id, now, error],
+                        )?;
+                        Ok(())
+                    })
+                    .await?;
+                }
+            }
+        }
+        self.with_connection(move |connection| {
+            for id in unclaimed {
+                connection.execute("UPDATE outbox SET claimed_until = NULL WHERE id = ?1", [id])?;
+            }
+            Ok(())
+        })
+        .await?;
+
+        flushed.waiting = self.pending().await?;
+        Ok(flushed)
+    }
+
+    /// Takes the entries to flush, so that runs flushing at the same time do not save them twice
+    async fn claim(&self, all: bool) -> Result<Vec<Entry>, Box<dyn Error>> {
+        let now = Utc::now().timestamp_millis();
+        self.with_connection(move |connection| {
+            let transaction =
+                connection.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            let entries = {
+                let mut statement = transaction.prepare(
+                    "SELECT id, category, memories, convo, attempts, last_attempt FROM outbox
+                     WHERE claimed_until IS NULL OR claimed_until < ?1
+                     ORDER BY id",
+                )?;
+                let rows = statement.query_map([now], |row| {
+                    let entry = Entry {
+                        id: row.get(0)?,
+                        category: row.get(1)?,
+                        memories: row.get(2)?,
+                        convo: row.get(3)?,
+                    };
+                    let attempts: u32 = row.get(4)?;
+                    let last_attempt: Option<i64> = row.get(5)?;
+                    Ok((entry, retry_at(attempts, last_attempt)))
+                })?;
+                rows.collect::<Result<Vec<_>, _>>()?
+                    .into_iter()
+                    .filter(|(_, retry_at)| all || *retry_at <= now)
+                    .map(|(entry, _)| entry)
+                    .collect::<Vec<Entry>>()
+            };
+            for entry in &entries {
+                transaction.execute(
+                    "UPDATE outbox SET claimed_until = ?2 WHERE id = ?1",
+                    params![entry.id, now + CLAIM_MS],
+                )?;
+            }
+            transaction.commit()?;
+            Ok(entries)
+        })
+        .await
+    }
+}
+
+fn migrate(connection: &mut Connection) -> Result<(), rusqlite::Error> {
+    let version = |connection: &Connection| -> Result<usize, rusqlite::Error> {
+        let version: i64 = connection.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+        Ok(version as usize)
+    };
+    if version(connection)? >= MIGRATIONS.len() {
+        return Ok(());
+    }
+
+    // immediate so that only one process migrates, the others wait and see the new version
+    let transaction = connection.transaction_with_behavior(TransactionBehavior::Immediate)?;
+    let from = version(&transaction)?;
+    for migration in MIGRATIONS.iter().skip(from) {
+        transaction.execute_batch(migration)?;
+    }
+    transaction.pragma_update(None, "user_version", MIGRATIONS.len() as i64)?;
+    transaction.commit()
+}
+
+/// Sets the memory ids of the segments, unless the conversation has since been deleted
+async fn link(
+    history: &dyn ShortMemEffect,
+    memory_ids: HashMap<String, String>,
+) -> Result<(), Box<dyn Error>> {
+    if memory_ids.is_empty() || !history.exists().await? {
+        return Ok(());
+    }
+    history
+        .edit(Box::new(move |mut script| {
+            for segment in &mut script.dialogue {
+                if let Some(memory_id) = memory_ids.get(&segment.id) {
+                    segment.memory_id = Some(memory_id.clone());
+                }
+            }
+            Ok(script)
+        }))
+        .await?;
+    Ok(())
+}
+
+/// When an entry may be retried automatically: a minute after its first attempt, doubling with
+/// each attempt after that up to a day
+fn retry_at(attempts: u32, last_attempt: Option<i64>) -> i64 {
+    let doublings = attempts.saturating_sub(1).min(20);
+    let backoff = (MINUTE_MS << doublings).min(MAX_BACKOFF_MS);
+    last_attempt.unwrap_or_default() + backoff
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{error::Error, path::Path};
+
+    use async_trait::async_trait;
+    use chrono::Utc;
+    use tempfile::tempdir;
+
+    use crate::{
+        data::model::SourceType,
+        effect::{
+            bm25_memory::Bm25Memory, conversation::YamlHistory, search_index::SearchIndex,
+            LongMemEffect, LongMemOutput, LongMemQueryOpt, LongMemSaveInp, MemorySource,
+            ShortMemEffect, ShortMemInput,
+        },
+    };
+
+    use super::{retry_at, Outbox, Segments};
+
+    fn history(dir: &Path) -> impl Fn(&str) -> Box<dyn ShortMemEffect> + Sync + '_ {
+        move |name| Box::new(YamlHistory::new(&dir.join(name).to_string_lossy()))
+    }
+
+    /// A store that cannot be reached
+    struct Down;
+
+    #[async_trait]
+    impl LongMemEffect for Down {
+        async fn save(&self, _: &[LongMemSaveInp], _: &str) -> Result<Vec<String>, Box<dyn Error>> {
+            Err("connection refused".into())
+        }
+
+        async fn query(
+            &self,
+            _: &str,
+            _: &[LongMemQueryOpt],
+        ) -> Result<Vec<LongMemOutput>, Box<dyn Error>> {
+            Err("connection refused".into())
+        }
+
+        async fn list(
+            &self,
+            _: &LongMemQueryOpt,
+            _: usize,
+        ) -> Result<Vec<LongMemOutput>, Box<dyn Error>> {
+            Err("connection refused".into())
+        }
+
+        async fn get(&self, _: &str) -> Result<Option<LongMemOutput>, Box<dyn Error>> {
+            Err("connection refused".into())
+        }
+
+        async fn delete(&self, _: &str) -> Result<(), Box<dyn Error>> {
+            Err("connection refused".into())
+        }
+
+        async fn delete_category(&self, _: &str) -> Result<(), Box<dyn Error>> {
+            Err("connection refused".into())
+        }
+    }
+
+    #[tokio::test]
+    async fn queued_memories_are_saved_once_the_store_is_back() {
+        let dir = tempdir().unwrap();
+        let outbox = Outbox::new(dir.path().join("outbox.sqlite3"));
+        let memory = |text: &str, source| LongMemSaveInp {
+            text: text.to_string(),
+            author: "user".to_string(),
+            created_at: Utc::now(),
+            source_type: SourceType::Chat,
+            source,
+        };
+        assert_eq!(0, outbox.pending().await.unwrap());
+        outbox
+            .queue(
+                "work",
+                &[
+                    memory("the vpn needs a token", None),
+                    memory(
+                        "vpn setup",
+                        Some(MemorySource {
+                            path: "/docs/vpn.md".to_string(),
+                            offset: 40,
+                        }),
+                    ),
+                ],
+                None,
+                "connection refused",
+            )
+            .await
+            .unwrap();
+        assert_eq!(1, outbox.pending().await.unwrap());
+
+        // it was just attempted, so is not retried automatically yet
+        let waiting = outbox
+            .flush(&Down, &history(dir.path()), false)
+            .await
+            .unwrap();
+        assert_eq!((0, 1), (waiting.saved, waiting.waiting));
+        assert!(waiting.failed.is_empty());
+        let failed = outbox
+            .flush(&Down, &history(dir.path()), true)
+            .await
+            .unwrap();
+        assert_eq!(vec!["'work': connection refused"], failed.failed);
+        assert_eq!(1, failed.waiting);
+
+        let store = Bm25Memory::new(SearchIndex::new(dir.path().join("bm25.sqlite3")), 5);
+        let flushed = outbox
+            .flush(&store, &history(dir.path()), true)
+            .await
+            .unwrap();
+        assert_eq!(
+            (1, 2, 0),
+            (flushed.saved, flushed.memories, flushed.waiting)
+        );
+        let found = store
+            .query(
+                "vpn",
+                &[LongMemQueryOpt {
+                    category: "work".to_string(),
+                    query_window: crate::effect::QueryWindow {
+                        min: None,
+                        max: None,
+                    },
+                    author: None,
+                    source_type: None,
+                }],
+            )
+            .await
+            .unwrap();
+        assert_eq!(2, found.len());
+        assert!(found.iter().any(|output| output
+            .source
+            .as_ref()
+            .is_some_and(|source| source.offset == 40)));
+    }
+
+    #[tokio::test]
+    async fn saved_memories_are_recorded_with_their_turn() {
+        let dir = tempdir().unwrap();
+        let outbox = Outbox::new(dir.path().join("outbox.sqlite3"));
+        let convo = history(dir.path())("convo");
+        let input = |id: &str, author: &str, content: &str| ShortMemInput {
+            id: Some(id.to_string()),
+            author: author.to_string(),
+            content: content.to_string(),
+            meta: None,
+            memory_id: None,
+        };
+        convo
+            .save_history(&[input("q", "user", "hello"), input("a", "assistant", "hi")])
+            .await
+            .unwrap();
+        let memory = |author: &str, text: &str| LongMemSaveInp {
+            text: text.to_string(),
+            author: author.to_string(),
+            created_at: Utc::now(),
+            source_type: SourceType::Chat,
+            source: None,
+        };
+        outbox
+            .queue(
+                "convo",
+                &[memory("user", "hello"), memory("assistant", "hi")],
+                Some(Segments {
+                    convo: "convo",
+                    ids: &["q".to_string(), "a".to_string()],
+                }),
+                "connection refused",
+            )
+            .await
+            .unwrap();
+
+        let store = Bm25Memory::new(SearchIndex::new(dir.path().join("bm25.sqlite3")), 5);
+        let flushed = outbox
+            .flush(&store, &history(dir.path()), true)
+            .await
+            .unwrap();
+        assert_eq!(1, flushed.saved);
+        assert!(flushed.unlinked.is_empty());
+
+        for segment in convo.load().await.unwrap().dialogue {
+            let memory_id = segment.memory_id.unwrap();
+            let saved = store.get(&memory_id).await.unwrap().unwrap();
+            assert_eq!(segment.content, saved.text);
+        }
+    }
+
+    #[test]
+    fn retries_back_off_up_to_a_day() {
+        let minute = 60 * 1000;
+        assert_eq!(minute, retry_at(1, Some(0)));
+        assert_eq!(4 * minute, retry_at(3, Some(0)));
+        assert_eq!(24 * 60 * minute, retry_at(40, Some(0)));
+    }
+}
@@ -0,0 +1,304 @@
+use std::{
+    collections::{HashMap, HashSet},
+    error::Error,
+};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use log::debug;
+
+use crate::data::model::RerankConfig;
+
+use super::{
+    search_index::{terms, B, K1},
+    LongMemEffect, LongMemOutput, LongMemQueryOpt, LongMemSaveInp,
+};
+
+const LEXICAL_WEIGHT: f64 = 0.3;
+const MMR_LAMBDA: f64 = 0.7;
+
+/// Re-ranks the memories found by another store. Its score is fused with how well each memory
+/// matches the query word for word, decayed with age, and memories below the threshold are
+/// dropped. The rest are picked by maximal marginal relevance, so that near duplicates do not
+/// crowd out everything else
+pub struct RerankedMemory {
+    memory: Box<dyn LongMemEffect>,
+    config: RerankConfig,
+    top_k: usize,
+}
+
+impl RerankedMemory {
+    pub fn new(memory: Box<dyn LongMemEffect>, config: RerankConfig, top_k: u32) -> Self {
+        Self {
+            memory,
+            config,
+            top_k: top_k as usize,
+        }
+    }
+}
+
+#[async_trait]
+impl LongMemEffect for RerankedMemory {
+    async fn save(
+        &self,
+        input: &[LongMemSaveInp],
+        category: &str,
+    ) -> Result<Vec<String>, Box<dyn Error>> {
+        self.memory.save(input, category).await
+    }
+
+    async fn query(
+        &self,
+        query: &str,
+        query_opts: &[LongMemQueryOpt],
+    ) -> Result<Vec<LongMemOutput>, Box<dyn Error>> {
+        let candidates = self.memory.query(query, query_opts).await?;
+        Ok(rerank(
+            query,
+            candidates,
+            &self.config,
+            self.top_k,
+            Utc::now(),
+        ))
+    }
+
+    async fn list(
+        &self,
+        query_opt: &LongMemQueryOpt,
+        limit: usize,
+    ) -> Result<Vec<LongMemOutput>, Box<dyn Error>> {
+        self.memory.list(query_opt, limit).await
+    }
+
+    async fn get(&self, id: &str) -> Result<Option<LongMemOutput>, Box<dyn Error>> {
+        self.memory.get(id).await
+    }
+
+    async fn delete(&self, id: &str) -> Result<(), Box<dyn Error>> {
+        self.memory.delete(id).await
+    }
+
+    async fn delete_category(&self, category: &str) -> Result<(), Box<dyn Error>> {
+        self.memory.delete_category(category).await
+    }
+}
+
+/// The `top_k` candidates to use, with their re-ranked scores, in the order they were picked
+pub fn rerank(
+    query: &str,
+    candidates: Vec<LongMemOutput>,
+    config: &RerankConfig,
+    top_k: usize,
+    now: DateTime<Utc>,
+) -> Vec<LongMemOutput> {
+    let mut seen = HashSet::new();
+    let candidates: Vec<LongMemOutput> = candidates
+        .into_iter()
+        .filter(|candidate| seen.insert(candidate.id.clone()))
+        .collect();
+    debug!("Re-ranking {} memories", candidates.len());
+
+    let weight = config
+        .lexical_weight
+        .unwrap_or(LEXICAL_WEIGHT)
+        .clamp(0.0, 1.0);
+    let lexical = lexical_scores(query, &candidates);
+    let mut scored: Vec<LongMemOutput> = candidates
+        .into_iter()
+        .zip(lexical)
+        .map(|(candidate, lexical)| {
+            let fused = weight * lexical + (1.0 - weight) * candidate.score;
+            let age = (now - candidate.created_at).num_seconds().max(0) as f64 / 86400.0;
+            let decay = config
+                .half_life_days
+                .filter(|half_life| *half_life > 0.0)
+                .map_or(1.0, |half_life| 0.5_f64.powf(age / half_life));
+            let score = fused * decay;
+            debug!(
+                "Memory {}: store {:.3}, lexical {:.3}, fused {:.3}, {:.1} days old x{:.3} = {:.3}",
+                candidate.id, candidate.score, lexical, fused, age, decay, score
+            );
+            LongMemOutput { score, ..candidate }
+        })
+        .collect();
+
+    let min_score = config.min_score.unwrap_or(0.0);
+    scored.retain(|candidate| {
+        let kept = candidate.score >= min_score;
+        if !kept {
+            debug!(
+                "Memory {} dropped, {:.3} is below {:.3}",
+                candidate.id, candidate.score, min_score
+            );
+        }
+        kept
+    });
+
+    let lambda = config.mmr_lambda.unwrap_or(MMR_LAMBDA).clamp(0.0, 1.0);
+    let frequencies: Vec<HashMap<String, f64>> = scored
+        .iter()
+        .map(|candidate| term_frequencies(&candidate.text))
+        .collect();
+    let mut remaining: Vec<usize> = (0..scored.len()).collect();
+    let mut picked: Vec<usize> = vec![];
+    while picked.len() < top_k && !remaining.is_empty() {
+        let (position, mmr, redundancy) = remaining
+            .iter()
+            .enumerate()
+            .map(|(position, &candidate)| {
+                let redundancy = picked
+                    .iter()
+                    .map(|&other| cosine(&frequencies[candidate], &frequencies[other]))
+                    .fold(0.0, f64::max);
+                let mmr = lambda * scored[candidate].score - (1.0 - lambda) * redundancy;
+                (position, mmr, redundancy)
+            })
+            .max_by(|a, b| a.1.total_cmp(&b.1))
+            .expect("remaining is not empty");
+        let candidate = remaining.remove(position);
+        debug!(
+            "Memory {} picked {}, {:.3} similar to those before it, mmr {:.3}",
+            scored[candidate].id,
+            picked.len() + 1,
+            redundancy,
+            mmr
+        );
+        picked.push(candidate);
+    }
+
+    let mut scored: Vec<Option<LongMemOutput>> = scored.into_iter().map(Some).collect();
+    picked
+        .into_iter()
+        .filter_map(|candidate| scored[candidate].take())
+        .collect()
+}
+
+/// BM25 of each text for the query, among the texts, over the most any of them could score
+fn lexical_scores(query: &str, candidates: &[LongMemOutput]) -> Vec<f64> {
+    let query: HashSet<String> = terms(query).into_iter().collect();
+    let documents: Vec<Vec<String>> = candidates
+        .iter()
+        .map(|candidate| terms(&candidate.text))
+        .collect();
+    let count = documents.len() as f64;
+    let average_length = documents.iter().map(Vec::len).sum::<usize>() as f64 / count.max(1.0);
+
+    let mut best = 0.0;
+    let mut scores = vec![0.0; documents.len()];
+    for term in &query {
+        let frequencies: Vec<f64> = documents
+            .iter()
+            .map(|document| document.iter().filter(|word| *word == term).count() as f64)
+            .collect();
+        let containing = frequencies.iter().filter(|tf| **tf > 0.0).count() as f64;
+        if containing == 0.0 {
+            continue;
+        }
+        let idf = (1.0 + (count - containing + 0.5) / (containing + 0.5)).ln();
+        best += idf * (K1 + 1.0);
+        for (score, (tf, document)) in scores.iter_mut().zip(frequencies.iter().zip(&documents)) {
+            let norm = 1.0 - B + B * document.len() as f64 / average_length.max(1.0);
+            *score += idf * tf * (K1 + 1.0) / (tf + K1 * norm);
+        }
+    }
+    match best > 0.0 {
+        true => scores.into_iter().map(|score| score / best).collect(),
+        false => scores,
+    }
+}
+
+fn term_frequencies(text: &str) -> HashMap<String, f64> {
+    let mut frequencies = HashMap::new();
+    for term in terms(text) {
+        *frequencies.entry(term).or_default() += 1.0;
+    }
+    frequencies
+}
+
+fn cosine(a: &HashMap<String, f64>, b: &HashMap<String, f64>) -> f64 {
+    let dot: f64 = a
+        .iter()
+        .filter_map(|(term, x)| b.get(term).map(|y| x * y))
+        .sum();
+    let norm = |v: &HashMap<String, f64>| v.values().map(|x| x * x).sum::<f64>().sqrt();
+    match norm(a) * norm(b) {
+        product if product > 0.0 => dot / product,
+        _ => 0.0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, TimeZone, Utc};
+
+    use crate::{
+        data::model::{RerankConfig, SourceType},
+        effect::LongMemOutput,
+    };
+
+    use super::rerank;
+
+    fn memory(id: &str, text: &str, score: f64, days_old: i64) -> LongMemOutput {
+        LongMemOutput {
+            id: id.to_string(),
+            text: text.to_string(),
+            created_at: Utc.with_ymd_and_hms(2023, 6, 30, 0, 0, 0).unwrap()
+                - Duration::days(days_old),
+            author: "assistant".to_string(),
+            category: "ops".to_string(),
+            score,
+            source_type: SourceType::Chat,
+            source: None,
+        }
+    }
+
+    fn ids(memories: &[LongMemOutput]) -> Vec<&str> {
+        memories.iter().map(|memory| memory.id.as_str()).collect()
+    }
+
+    #[test]
+    fn near_duplicates_do_not_crowd_out_other_memories() {
+        let now = Utc.with_ymd_and_hms(2023, 6, 30, 0, 0, 0).unwrap();
+        let candidates = || {
+            vec![
+                memory("a", "restart nginx with systemctl restart nginx", 0.9, 1),
+                memory("b", "restart nginx with systemctl restart nginx", 0.89, 2),
+                memory("c", "nginx logs are in /var/log/nginx", 0.8, 1),
+                memory("a", "restart nginx with systemctl restart nginx", 0.9, 1),
+            ]
+        };
+        let config = RerankConfig {
+            lexical_weight: Some(0.0),
+            ..Default::default()
+        };
+        let reranked = rerank("nginx", candidates(), &config, 2, now);
+        assert_eq!(vec!["a", "c"], ids(&reranked));
+
+        let relevance_only = RerankConfig {
+            mmr_lambda: Some(1.0),
+            ..config
+        };
+        let reranked = rerank("nginx", candidates(), &relevance_only, 2, now);
+        assert_eq!(vec!["a", "b"], ids(&reranked));
+    }
+
+    #[test]
+    fn old_and_weak_memories_are_dropped() {
+        let now = Utc.with_ymd_and_hms(2023, 6, 30, 0, 0, 0).unwrap();
+        let candidates = vec![
+            memory("old", "the database password rotates monthly", 0.9, 60),
+            memory("new", "the database moved to a new host", 0.6, 0),
+            memory("weak", "lunch is at noon", 0.3, 0),
+        ];
+        let config = RerankConfig {
+            half_life_days: Some(30.0),
+            min_score: Some(0.3),
+            ..Default::default()
+        };
+        let reranked = rerank("database", candidates, &config, 3, now);
+
+        assert_eq!(vec!["new"], ids(&reranked));
+        // 0.3 of the lexical score and 0.7 of the store's
+        assert!(reranked[0].score > 0.6 * 0.7 && reranked[0].score < 0.6 * 0.7 + 0.3);
+    }
+}
@@ -0,0 +1,421 @@
+use std::{
+    collections::{HashMap, HashSet},
+    error::Error,
+};
+
+use chrono::{DateTime, Duration, Utc};
+
+use crate::data::model::RetentionPolicy;
+
+use super::{
+    conversation::{DialogueSegment, Script, Summary},
+    LongMemEffect, LongMemQueryOpt, QueryWindow, ShortMemEffect,
+};
+
+/// How far before its conversation segment a long term memory may have been saved
+const MEMORY_SAVED_BEFORE: i64 = 300;
+
+/// What was, or would be, removed from a conversation
+#[derive(Debug, Default)]
+pub struct Pruned {
+    pub turns: Vec<Vec<DialogueSegment>>,
+    pub bytes: usize,
+    /// Ids of the long term memories of the removed turns
+    pub memories: Vec<String>,
+}
+
+/// Turns of every branch, oldest first. A segment belongs to the turn of the user message it
+/// follows, so turns of different branches saved at the same time are kept apart
+fn all_turns(script: &Script) -> Vec<Vec<&DialogueSegment>> {
+    let by_id: HashMap<&str, &DialogueSegment> = script
+        .dialogue
+        .iter()
+        .map(|segment| (segment.id.as_str(), segment))
+        .collect();
+    let turn_start = |segment: &'_ DialogueSegment| -> String {
+        let mut start = segment;
+        // the limit guards against a cycle in a hand edited file
+        for _ in 0..script.dialogue.len() {
+            match start.parent.as_deref().and_then(|id| by_id.get(id)) {
+                Some(parent) if start.role != "user" => start = parent,
+                _ => break,
+            }
+        }
+        start.id.clone()
+    };
+
+    let mut segments: Vec<&DialogueSegment> = script.dialogue.iter().collect();
+    // segments are stored in the order they were saved, but a hand edited file may not be
+    segments.sort_by_key(|segment| segment.created_at);
+    let mut turns: Vec<Vec<&DialogueSegment>> = vec![];
+    let mut turn_index: HashMap<String, usize> = HashMap::new();
+    for segment in segments {
+        let index = *turn_index.entry(turn_start(segment)).or_insert_with(|| {
+            turns.push(vec![]);
+            turns.len() - 1
+        });
+        turns[index].push(segment);
+    }
+    turns
+}
+
+/// The oldest turns of the conversation, across every branch, that are past any limit of the
+/// policy
+pub fn expired<'a>(
+    script: &'a Script,
+    policy: &RetentionPolicy,
+    now: DateTime<Utc>,
+) -> Vec<Vec<&'a DialogueSegment>> {
+    let turns = all_turns(script);
+
+    let size = |turn: &Vec<&DialogueSegment>| -> usize {
+        turn.iter().map(|segment| segment.content.len()).sum()
+    };
+    let mut bytes: usize = turns.iter().map(size).sum();
+    let mut kept = turns.len();
+    let cutoff = policy
+        .max_age_days
+        .map(|days| now - Duration::days(days as i64));
+
+    turns
+        .into_iter()
+        .take_while(|turn| {
+            let too_old = cutoff.is_some_and(|cutoff| turn[0].created_at < cutoff);
+            let too_many = policy.max_turns.is_some_and(|max| kept > max);
+            let too_big = policy.max_bytes.is_some_and(|max| bytes > max);
+            if too_old || too_many || too_big {
+                kept -= 1;
+                bytes -= size(turn);
+                true
+            } else {
+                false
+            }
+        })
+        .collect()
+}
+
+/// The script without the segments. Segments that followed a removed one start their branch,
+/// and the summary, which still describes the removed turns, covers fewer segments
+pub fn without(script: &Script, ids: &HashSet<&str>) -> Script {
+    let removed_from_branch = script
+        .branch()
+        .iter()
+        .filter(|segment| ids.contains(segment.id.as_str()))
+        .count();
+    let dialogue = script
+        .dialogue
+        .iter()
+        .filter(|segment| !ids.contains(segment.id.as_str()))
+        .map(|segment| DialogueSegment {
+            parent: segment
+                .parent
+                .clone()
+                .filter(|parent| !ids.contains(parent.as_str())),
+            ..segment.clone()
+        })
+        .collect();
+    let summary = script.summary.as_ref().map(|summary| {
+        let covered = summary.covered.saturating_sub(removed_from_branch);
+        Summary {
+            text: summary.text.clone(),
+            covered,
+            through: summary.through.clone().filter(|_| covered > 0),
+        }
+    });
+    let head = script
+        .head
+        .clone()
+        .filter(|head| !ids.contains(head.as_str()));
+
+    Script {
+        dialogue,
+        summary,
+        head,
+        ..script.clone()
+    }
+}
+
+/// The expired turns with only the segments in `ids`
+fn still_expired<'a>(
+    script: &'a Script,
+    policy: &RetentionPolicy,
+    now: DateTime<Utc>,
+    ids: &HashSet<String>,
+) -> Vec<Vec<&'a DialogueSegment>> {
+    expired(script, policy, now)
+        .into_iter()
+        .map(|turn| {
+            turn.into_iter()
+                .filter(|segment| ids.contains(&segment.id))
+                .collect::<Vec<&DialogueSegment>>()
+        })
+        .filter(|turn| !turn.is_empty())
+        .collect()
+}
+
+/// Ids of the long term memories saved for the segment in the conversation's category
+async fn segment_memories(
+    memory: &dyn LongMemEffect,
+    convo: &str,
+    segment: &DialogueSegment,
+) -> Result<Vec<String>, Box<dyn Error>> {
+    if let Some(id) = &segment.memory_id {
+        return Ok(vec![id.clone()]);
+    }
+    // segments saved before their memory's id was kept are matched by text and time
+    let window = QueryWindow {
+        min: Some(segment.created_at - Duration::seconds(MEMORY_SAVED_BEFORE)),
+        max: Some(segment.created_at + Duration::seconds(1)),
+    };
+    let options = [LongMemQueryOpt {
+        category: convo.to_string(),
+        query_window: window,
+        author: None,
+        source_type: None,
+    }];
+    Ok(memory
+        .query(&segment.content, &options)
+        .await?
+        .into_iter()
+        .filter(|output| output.text == segment.content && output.author == segment.role)
+        .map(|output| output.id)
+        .collect())
+}
+
+/// Applies the policy to a conversation, and deletes the long term memories of the removed
+/// turns from the conversation's category when `memory` is given. Nothing is changed when
+/// `dry_run`
+pub async fn prune(
+    history: &dyn ShortMemEffect,
+    memory: Option<&dyn LongMemEffect>,
+    convo: &str,
+    policy: &RetentionPolicy,
+    dry_run: bool,
+) -> Result<Pruned, Box<dyn Error>> {
+    let now = Utc::now();
+    let script = history.load().await?;
+    let expired = expired(&script, policy, now);
+    if expired.is_empty() {
+        return Ok(Pruned::default());
+    }
+
+    let mut memories: Vec<(&str, String)> = vec![];
+    if let Some(memory) = memory {
+        for segment in expired.iter().flatten() {
+            for id in segment_memories(memory, convo, segment).await? {
+                memories.push((segment.id.as_str(), id));
+            }
+        }
+    }
+
+    let turns: Vec<Vec<DialogueSegment>> = if dry_run {
+        expired
+            .iter()
+            .map(|turn| turn.iter().map(|segment| (*segment).clone()).collect())
+            .collect()
+    } else {
+        // turns are removed under the backend's lock, and only those still past the limits, so
+        // turns saved by another process since loading are kept
+        let ids: HashSet<String> = expired
+            .iter()
+            .flatten()
+            .map(|segment| segment.id.clone())
+            .collect();
+        let (limits, candidates) = (policy.clone(), ids.clone());
+        let before = history
+            .edit(Box::new(move |script: Script| {
+                let removed = still_expired(&script, &limits, now, &candidates);
+                let ids: HashSet<&str> = removed
+                    .iter()
+                    .flatten()
+                    .map(|segment| segment.id.as_str())
+                    .collect();
+                Ok(without(&script, &ids))
+            }))
+            .await?;
+        still_expired(&before, policy, now, &ids)
+            .iter()
+            .map(|turn| turn.iter().map(|segment| (*segment).clone()).collect())
+            .collect()
+    };
+
+    let removed: HashSet<&str> = turns
+        .iter()
+        .flatten()
+        .map(|segment| segment.id.as_str())
+        .collect();
+    let mut memories: Vec<String> = memories
+        .into_iter()
+        .filter(|(segment, _)| removed.contains(segment))
+        .map(|(_, id)| id)
+        .collect();
+    memories.sort();
+    memories.dedup();
+
+    if !dry_run {
+        if let Some(memory) = memory {
+            for id in &memories {
+                memory.delete(id).await?;
+            }
+        }
+    }
+    Ok(Pruned {
+        bytes: turns
+            .iter()
+            .flatten()
+            .map(|segment| segment.content.len())
+            .sum(),
+        turns,
+        memories,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use chrono::{Duration, TimeZone, Utc};
+
+    use crate::{
+        data::model::RetentionPolicy,
+        effect::{
+            conversation::{DialogueSegment, Script, Summary, YamlHistory},
+            ShortMemEffect,
+        },
+    };
+
+    use super::{expired, prune, without};
+
+    fn script() -> Script {
+        let start = Utc.with_ymd_and_hms(2023, 1, 1, 0, 0, 0).unwrap();
+        let segment = |id: &str, parent: Option<&str>, role: &str, day: i64| DialogueSegment {
+            id: id.to_string(),
+            parent: parent.map(str::to_string),
+            role: role.to_string(),
+            content: format!("{} message", id),
+            created_at: start + Duration::days(day),
+            meta: None,
+            memory_id: None,
+        };
+        Script {
+            dialogue: vec![
+                segment("q1", None, "user", 0),
+                segment("a1", Some("q1"), "assistant", 0),
+                segment("q2", Some("a1"), "user", 10),
+                segment("a2", Some("q2"), "assistant", 10),
+                // another branch from the first turn
+                segment("q3", Some("a1"), "user", 20),
+                segment("a3", Some("q3"), "assistant", 20),
+            ],
+            summary: Some(Summary {
+                text: "The user said hello".to_string(),
+                covered: 2,
+                through: Some("a1".to_string()),
+            }),
+            ..Default::default()
+        }
+    }
+
+    fn ids(turns: Vec<Vec<&DialogueSegment>>) -> Vec<&str> {
+        turns
+            .into_iter()
+            .flatten()
+            .map(|segment| segment.id.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn oldest_turns_past_any_limit_expire() {
+        let script = script();
+        let now = Utc.with_ymd_and_hms(2023, 1, 31, 0, 0, 0).unwrap();
+        let policy = |max_age_days, max_turns, max_bytes| RetentionPolicy {
+            max_age_days,
+            max_turns,
+            max_bytes,
+        };
+
+        assert!(expired(&script, &policy(None, None, None), now).is_empty());
+        assert_eq!(
+            vec!["q1", "a1", "q2", "a2"],
+            ids(expired(&script, &policy(Some(15), None, None), now))
+        );
+        assert_eq!(
+            vec!["q1", "a1"],
+            ids(expired(&script, &policy(Some(60), Some(2), None), now))
+        );
+        // each turn is 20 bytes
+        assert_eq!(
+            vec!["q1", "a1", "q2", "a2"],
+            ids(expired(&script, &policy(None, Some(5), Some(25)), now))
+        );
+    }
+
+    #[test]
+    fn turns_of_branches_saved_at_the_same_time_are_kept_apart() {
+        let mut script = script();
+        // the question of the other branch is asked before the second turn and answered after
+        script.dialogue[4].created_at = script.dialogue[0].created_at + Duration::days(5);
+        script.dialogue[5].created_at = script.dialogue[0].created_at + Duration::days(12);
+        let now = Utc.with_ymd_and_hms(2023, 1, 31, 0, 0, 0).unwrap();
+        let policy = RetentionPolicy {
+            max_turns: Some(1),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            vec!["q1", "a1", "q3", "a3"],
+            ids(expired(&script, &policy, now))
+        );
+    }
+
+    #[tokio::test]
+    async fn pruning_removes_expired_turns_from_the_stored_conversation() {
+        let dir = tempfile::tempdir().unwrap();
+        let history = YamlHistory::new(&dir.path().join("convo").to_string_lossy());
+        history.replace(script()).await.unwrap();
+        let policy = RetentionPolicy {
+            max_turns: Some(2),
+            ..Default::default()
+        };
+
+        let pruned = prune(&history, None, "convo", &policy, true).await.unwrap();
+        assert_eq!(1, pruned.turns.len());
+        assert_eq!(6, history.load().await.unwrap().dialogue.len());
+
+        let pruned = prune(&history, None, "convo", &policy, false)
+            .await
+            .unwrap();
+        assert_eq!(1, pruned.turns.len());
+        assert_eq!(20, pruned.bytes);
+        let ids: Vec<String> = history
+            .load()
+            .await
+            .unwrap()
+            .dialogue
+            .into_iter()
+            .map(|segment| segment.id)
+            .collect();
+        assert_eq!(vec!["q2", "a2", "q3", "a3"], ids);
+    }
+
+    #[test]
+    fn removing_turns_keeps_the_branches_after_them() {
+        let script = script();
+        let removed: HashSet<&str> = ["q1", "a1"].into();
+        let pruned = without(&script, &removed);
+
+        assert_eq!(4, pruned.dialogue.len());
+        assert_eq!(
+            vec!["q3", "a3"],
+            pruned
+                .branch()
+                .iter()
+                .map(|segment| segment.id.as_str())
+                .collect::<Vec<&str>>()
+        );
+        assert_eq!(None, pruned.dialogue[0].parent);
+        let summary = pruned.summary.unwrap();
+        assert_eq!((0, None), (summary.covered, summary.through));
+        assert_eq!("The user said hello", summary.text);
+    }
+}
@@ -0,0 +1,546 @@
+use std::{
+    collections::{HashMap, HashSet},
+    error::Error,
+    fmt::Display,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use chrono::{DateTime, TimeZone, Utc};
+use rusqlite::{params, params_from_iter, types::Value, Connection, Row, TransactionBehavior};
+
+use crate::data::model::SourceType;
+
+use super::MemorySource;
+
+const INDEX: &str = ".search.sqlite3";
+
+const SCHEMA: &str = "
+    PRAGMA foreign_keys = ON;
+    CREATE TABLE IF NOT EXISTS collections (
+        name TEXT PRIMARY KEY
+    );
+    CREATE TABLE IF NOT EXISTS documents (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        collection TEXT NOT NULL REFERENCES collections (name) ON DELETE CASCADE,
+        key TEXT NOT NULL,
+        author TEXT NOT NULL,
+        created_at INTEGER NOT NULL,
+        text TEXT NOT NULL,
+        length INTEGER NOT NULL,
+        UNIQUE (collection, key)
+    );
+    CREATE TABLE IF NOT EXISTS postings (
+        term TEXT NOT NULL,
+        document INTEGER NOT NULL REFERENCES documents (id) ON DELETE CASCADE,
+        frequency INTEGER NOT NULL,
+        PRIMARY KEY (term, document)
+    ) WITHOUT ROWID;
+    CREATE INDEX IF NOT EXISTS postings_by_document ON postings (document);
+";
+
+/// Schema changes, applied in order to databases at an older `user_version`
+const MIGRATIONS: &[&str] = &[
+    "ALTER TABLE documents ADD COLUMN path TEXT;
+     ALTER TABLE documents ADD COLUMN offset INTEGER;",
+    // only ingested documents had a path
+    "ALTER TABLE documents ADD COLUMN source_type TEXT;
+     UPDATE documents SET source_type = CASE WHEN path IS NULL THEN 'chat' ELSE 'document' END;",
+];
+
+/// BM25 term frequency saturation and length normalisation
+pub const K1: f64 = 1.2;
+pub const B: f64 = 0.75;
+
+#[derive(Debug)]
+pub struct IndexError(pub String);
+
+impl Display for IndexError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+impl Error for IndexError {}
+
+impl From<rusqlite::Error> for IndexError {
+    fn from(e: rusqlite::Error) -> Self {
+        IndexError(e.to_string())
+    }
+}
+
+/// Text stored under a key that is unique within its collection
+#[derive(Debug, Clone)]
+pub struct Document {
+    pub key: String,
+    pub author: String,
+    pub created_at: DateTime<Utc>,
+    pub text: String,
+    pub source_type: SourceType,
+    pub source: Option<MemorySource>,
+}
+
+#[derive(Debug, Default)]
+pub struct SearchFilter {
+    /// Only search these collections, all of them when empty
+    pub collections: Vec<String>,
+    pub author: Option<String>,
+    pub source_type: Option<SourceType>,
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug)]
+pub struct Hit {
+    pub collection: String,
+    pub document: Document,
+    pub score: f64,
+    /// The score over the most any document could score for the query, from 0 to 1
+    pub relevance: f64,
+}
+
+/// Inverted index of documents grouped into collections, ranked with BM25. Kept in a sqlite
+/// database so it can be updated a few documents at a time
+pub struct SearchIndex {
+    database: PathBuf,
+}
+
+impl SearchIndex {
+    pub fn new(database: PathBuf) -> Self {
+        Self { database }
+    }
+
+    /// The index of the conversations in a directory
+    pub fn for_conversations(convo_dir: &str) -> Self {
+        Self::new(Path::new(convo_dir).join(INDEX))
+    }
+
+    /// Adds the documents to the collection, replacing those with the same keys
+    pub async fn add(&self, collection: &str, documents: Vec<Document>) -> Result<(), IndexError> {
+        let collection = collection.to_string();
+        self.with_connection(move |connection| {
+            let transaction = connection.transaction()?;
+            transaction.execute(
+                "INSERT OR IGNORE INTO collections (name) VALUES (?1)",
+                [&collection],
+            )?;
+            insert(&transaction, &collection, &documents)?;
+            transaction.commit()?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Replaces everything in the collection with the documents
+    pub async fn replace(
+        &self,
+        collection: &str,
+        documents: Vec<Document>,
+    ) -> Result<(), IndexError> {
+        let collection = collection.to_string();
+        self.with_connection(move |connection| {
+            let transaction = connection.transaction()?;
+            transaction.execute("DELETE FROM collections WHERE name = ?1", [&collection])?;
+            transaction.execute("INSERT INTO collections (name) VALUES (?1)", [&collection])?;
+            insert(&transaction, &collection, &documents)?;
+            transaction.commit()?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Removes the documents with the key from every collection
+    pub async fn remove_document(&self, key: &str) -> Result<(), IndexError> {
+        let key = key.to_string();
+        self.with_connection(move |connection| {
+            connection.execute("DELETE FROM documents WHERE key = ?1", [&key])?;
+            Ok(())
+        })
+        .await
+    }
+
+    pub async fn remove(&self, collection: &str) -> Result<(), IndexError> {
+        let collection = collection.to_string();
+        self.with_connection(move |connection| {
+            connection.execute("DELETE FROM collections WHERE name = ?1", [&collection])?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// The newest `limit` documents that pass the filter, with their collections
+    pub async fn documents(
+        &self,
+        filter: SearchFilter,
+        limit: usize,
+    ) -> Result<Vec<(String, Document)>, IndexError> {
+        self.with_connection(move |connection| {
+            let mut conditions = vec!["1 = 1".to_string()];
+            let mut values: Vec<Value> = vec![];
+            filter_conditions(&filter, &mut conditions, &mut values);
+            values.push(Value::Integer(limit as i64));
+            let mut statement = connection.prepare(&format!(
+                "SELECT {} FROM documents WHERE {} ORDER BY created_at DESC, id DESC LIMIT ?",
+                DOCUMENT_COLUMNS,
+                conditions.join(" AND ")
+            ))?;
+            let documents = statement
+                .query_map(params_from_iter(values.iter()), document_row)?
+                .collect::<Result<Vec<_>, rusqlite::Error>>()?;
+            Ok(documents)
+        })
+        .await
+    }
+
+    /// The document with the key, in any collection
+    pub async fn document(&self, key: &str) -> Result<Option<(String, Document)>, IndexError> {
+        let key = key.to_string();
+        self.with_connection(move |connection| {
+            let mut statement = connection.prepare(&format!(
+                "SELECT {} FROM documents WHERE key = ?1 LIMIT 1",
+                DOCUMENT_COLUMNS
+            ))?;
+            let mut documents = statement.query_map([key], document_row)?;
+            Ok(documents.next().transpose()?)
+        })
+        .await
+    }
+
+    /// Names of the collections that have been indexed, including empty ones
+    pub async fn collections(&self) -> Result<Vec<String>, IndexError> {
+        self.with_connection(|connection| {
+            let mut statement = connection.prepare("SELECT name FROM collections ORDER BY name")?;
+            let names = statement
+                .query_map([], |row| row.get(0))?
+                .collect::<Result<Vec<String>, rusqlite::Error>>()?;
+            Ok(names)
+        })
+        .await
+    }
+
+    /// The `limit` documents that best match the query, best first
+    pub async fn search(
+        &self,
+        query: &str,
+        filter: SearchFilter,
+        limit: usize,
+    ) -> Result<Vec<Hit>, IndexError> {
+        let terms: HashSet<String> = terms(query).into_iter().collect();
+        self.with_connection(move |connection| search(connection, terms, &filter, limit))
+            .await
+    }
+
+    /// Runs the query on a blocking thread with a connection to the index
+    async fn with_connection<T, F>(&self, query: F) -> Result<T, IndexError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> Result<T, IndexError> + Send + 'static,
+    {
+        let database = self.database.clone();
+        tokio::task::spawn_blocking(move || {
+            if let Some(dir) = database.parent() {
+                std::fs::create_dir_all(dir).map_err(|e| IndexError(e.to_string()))?;
+            }
+            let mut connection = Connection::open(database)?;
+            connection.busy_timeout(Duration::from_secs(10))?;
+            connection.execute_batch(SCHEMA)?;
+            migrate(&mut connection, MIGRATIONS)?;
+            query(&mut connection)
+        })
+        .await
+        .map_err(|e| IndexError(e.to_string()))?
+    }
+}
+
+/// Applies the migrations a database has not had yet, tracked by its `user_version`
+pub fn migrate(connection: &mut Connection, migrations: &[&str]) -> Result<(), rusqlite::Error> {
+    let version = |connection: &Connection| -> Result<usize, rusqlite::Error> {
+        let version: i64 = connection.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+        Ok(version as usize)
+    };
+    if version(connection)? >= migrations.len() {
+        return Ok(());
+    }
+
+    // immediate so that only one process migrates, the others wait and see the new version
+    let transaction = connection.transaction_with_behavior(TransactionBehavior::Immediate)?;
+    for migration in migrations.iter().skip(version(&transaction)?) {
+        transaction.execute_batch(migration)?;
+    }
+    transaction.pragma_update(None, "user_version", migrations.len() as i64)?;
+    transaction.commit()
+}
+
+/// Lowercased words of the text. Underscores are kept so identifiers are matched whole
+pub fn terms(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric() && c != '_')
+        .filter(|word| !word.is_empty() && word.chars().count() <= 64)
+        .map(str::to_lowercase)
+        .collect()
+}
+
+fn insert(
+    connection: &Connection,
+    collection: &str,
+    documents: &[Document],
+) -> Result<(), IndexError> {
+    for document in documents {
+        connection.execute(
+            "DELETE FROM documents WHERE collection = ?1 AND key = ?2",
+            params![collection, document.key],
+        )?;
+        let terms = terms(&document.text);
+        connection.execute(
+            "INSERT INTO documents
+                (collection, key, author, created_at, text, length, path, offset, source_type)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+            params![
+                collection,
+                document.key,
+                document.author,
+                document.created_at.timestamp_millis(),
+                document.text,
+                terms.len(),
+                document.source.as_ref().map(|source| &source.path),
+                document.source.as_ref().map(|source| source.offset as i64),
+                document.source_type.as_str()
+            ],
+        )?;
+        let id = connection.last_insert_rowid();
+
+        let mut frequencies: HashMap<&str, u32> = HashMap::new();
+        for term in &terms {
+            *frequencies.entry(term).or_default() += 1;
+        }
+        let mut statement = connection.prepare_cached(
+            "INSERT INTO postings (term, document, frequency) VALUES (?1, ?2, ?3)",
+        )?;
+        for (term, frequency) in frequencies {
+            statement.execute(params![term, id, frequency])?;
+        }
+    }
+    Ok(())
+}
+
+fn search(
+    connection: &Connection,
+    terms: HashSet<String>,
+    filter: &SearchFilter,
+    limit: usize,
+) -> Result<Vec<Hit>, IndexError> {
+    let (count, average_length): (f64, f64) = connection.query_row(
+        "SELECT COUNT(*), COALESCE(AVG(length), 0) FROM documents",
+        [],
+        |row| Ok((row.get::<_, i64>(0)? as f64, row.get(1)?)),
+    )?;
+
+    let mut conditions = vec!["postings.term = ?".to_string()];
+    let mut values: Vec<Value> = vec![Value::Null];
+    filter_conditions(filter, &mut conditions, &mut values);
+    let mut postings = connection.prepare(&format!(
+        "SELECT postings.document, postings.frequency, documents.length
+         FROM postings JOIN documents ON documents.id = postings.document
+         WHERE {}",
+        conditions.join(" AND ")
+    ))?;
+    let mut frequency = connection.prepare("SELECT COUNT(*) FROM postings WHERE term = ?1")?;
+
+    let mut scores: HashMap<i64, f64> = HashMap::new();
+    // term frequency saturates at K1 + 1
+    let mut best = 0.0;
+    for term in terms {
+        let documents: i64 = frequency.query_row([&term], |row| row.get(0))?;
+        if documents == 0 {
+            continue;
+        }
+        let documents = documents as f64;
+        let idf = (1.0 + (count - documents + 0.5) / (documents + 0.5)).ln();
+        best += idf * (K1 + 1.0);
+
+        values[0] = Value::Text(term);
+        let mut rows = postings.query(params_from_iter(values.iter()))?;
+        while let Some(row) = rows.next()? {
+            let id: i64 = row.get(0)?;
+            let tf: f64 = row.get::<_, i64>(1)? as f64;
+            let length: f64 = row.get::<_, i64>(2)? as f64;
+            let norm = 1.0 - B + B * length / average_length.max(1.0);
+            *scores.entry(id).or_default() += idf * tf * (K1 + 1.0) / (tf + K1 * norm);
+        }
+    }
+
+    let mut ranked: Vec<(i64, f64)> = scores.into_iter().collect();
+    // newer documents first when scores tie
+    ranked.sort_by(|a, b| b.1.total_cmp(&a.1).then(b.0.cmp(&a.0)));
+    ranked.truncate(limit);
+
+    let mut document = connection.prepare(&format!(
+        "SELECT {} FROM documents WHERE id = ?1",
+        DOCUMENT_COLUMNS
+    ))?;
+    ranked
+        .into_iter()
+        .map(|(id, score)| {
+            let (collection, document) = document.query_row([id], document_row)?;
+            Ok(Hit {
+                collection,
+                document,
+                score,
+                relevance: score / best,
+            })
+        })
+        .collect()
+}
+
+const DOCUMENT_COLUMNS: &str =
+    "collection, key, author, created_at, text, path, offset, source_type";
+
+fn document_row(row: &Row) -> Result<(String, Document), rusqlite::Error> {
+    Ok((
+        row.get(0)?,
+        Document {
+            key: row.get(1)?,
+            author: row.get(2)?,
+            created_at: Utc
+                .timestamp_millis_opt(row.get(3)?)
+                .single()
+                .unwrap_or_default(),
+            text: row.get(4)?,
+            source: source(row.get(5)?, row.get(6)?),
+            source_type: SourceType::from_stored(row.get::<_, Option<String>>(7)?.as_deref()),
+        },
+    ))
+}
+
+/// Conditions on the documents table for the filter, with their values
+fn filter_conditions(filter: &SearchFilter, conditions: &mut Vec<String>, values: &mut Vec<Value>) {
+    if !filter.collections.is_empty() {
+        conditions.push(format!(
+            "documents.collection IN ({})",
+            vec!["?"; filter.collections.len()].join(", ")
+        ));
+        values.extend(filter.collections.iter().cloned().map(Value::Text));
+    }
+    if let Some(author) = &filter.author {
+        conditions.push("documents.author = ?".to_string());
+        values.push(Value::Text(author.clone()));
+    }
+    if let Some(source_type) = filter.source_type {
+        conditions.push("documents.source_type = ?".to_string());
+        values.push(Value::Text(source_type.as_str().to_string()));
+    }
+    if let Some(since) = filter.since {
+        conditions.push("documents.created_at >= ?".to_string());
+        values.push(Value::Integer(since.timestamp_millis()));
+    }
+    if let Some(until) = filter.until {
+        conditions.push("documents.created_at < ?".to_string());
+        values.push(Value::Integer(until.timestamp_millis()));
+    }
+}
+
+pub fn source(path: Option<String>, offset: Option<i64>) -> Option<MemorySource> {
+    Some(MemorySource {
+        path: path?,
+        offset: offset? as usize,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+    use tempfile::tempdir;
+
+    use crate::data::model::SourceType;
+
+    use super::{Document, SearchFilter, SearchIndex};
+
+    fn document(key: &str, author: &str, day: u32, text: &str) -> Document {
+        Document {
+            key: key.to_string(),
+            author: author.to_string(),
+            created_at: Utc.with_ymd_and_hms(2023, 6, day, 9, 0, 0).unwrap(),
+            text: text.to_string(),
+            source_type: SourceType::Chat,
+            source: None,
+        }
+    }
+
+    fn keys(hits: &[super::Hit]) -> Vec<&str> {
+        hits.iter().map(|hit| hit.document.key.as_str()).collect()
+    }
+
+    #[tokio::test]
+    async fn ranks_documents_matching_rarer_terms_first() {
+        let dir = tempdir().unwrap();
+        let index = SearchIndex::for_conversations(&dir.path().to_string_lossy());
+        index
+            .add(
+                "async",
+                vec![
+                    document("0", "user", 1, "How does tokio handle cancellation?"),
+                    document("1", "assistant", 1, "Dropping a future cancels it in tokio"),
+                    document("2", "user", 2, "And how does rayon work?"),
+                ],
+            )
+            .await
+            .unwrap();
+        index
+            .add(
+                "shell",
+                vec![document("0", "user", 3, "How do I list files?")],
+            )
+            .await
+            .unwrap();
+
+        let hits = index
+            .search("tokio cancellation", SearchFilter::default(), 10)
+            .await
+            .unwrap();
+        assert_eq!(vec!["0", "1"], keys(&hits));
+        assert!(hits[0].score > hits[1].score);
+        assert!(hits[0].relevance < 1.0 && hits[1].relevance > 0.0);
+
+        let filter = SearchFilter {
+            author: Some("assistant".to_string()),
+            ..Default::default()
+        };
+        let hits = index.search("tokio", filter, 10).await.unwrap();
+        assert_eq!(vec!["1"], keys(&hits));
+
+        let filter = SearchFilter {
+            collections: vec!["shell".to_string()],
+            since: Some(Utc.with_ymd_and_hms(2023, 6, 2, 0, 0, 0).unwrap()),
+            ..Default::default()
+        };
+        let hits = index.search("how", filter, 10).await.unwrap();
+        assert_eq!(vec!["0"], keys(&hits));
+        assert_eq!("shell", hits[0].collection);
+    }
+
+    #[tokio::test]
+    async fn replacing_a_collection_removes_its_old_documents() {
+        let dir = tempdir().unwrap();
+        let index = SearchIndex::for_conversations(&dir.path().to_string_lossy());
+        index
+            .add(
+                "notes",
+                vec![document("0", "user", 1, "an old lifetime question")],
+            )
+            .await
+            .unwrap();
+        index
+            .replace(
+                "notes",
+                vec![document("a", "user", 1, "a new borrow question")],
+            )
+            .await
+            .unwrap();
+
+        let search = |query: &'static str| index.search(query, SearchFilter::default(), 10);
+        assert!(search("lifetime").await.unwrap().is_empty());
+        assert_eq!(vec!["a"], keys(&search("borrow").await.unwrap()));
+
+        index.remove("notes").await.unwrap();
+        assert!(search("borrow").await.unwrap().is_empty());
+        assert!(index.collections().await.unwrap().is_empty());
+    }
+}
@@ -0,0 +1,134 @@
+use std::{
+    error::Error,
+    io::{self, Read, Write},
+    process::{Command, Stdio},
+    thread,
+};
+
+use log::debug;
+
+use super::{ShellEffect, ShellOutput};
+
+const CLIPBOARD_COMMANDS: [(&str, &[&str]); 4] = [
+    ("pbcopy", &[]),
+    ("wl-copy", &[]),
+    ("xclip", &["-selection", "clipboard"]),
+    ("xsel", &["--clipboard", "--input"]),
+];
+
+pub struct Shell {
+    shell: String,
+}
+
+impl Shell {
+    pub fn new(shell: &str) -> Self {
+        Self {
+            shell: shell.to_string(),
+        }
+    }
+}
+
+/// Copies the reader to the writer as it is read, returning what was read
+fn tee(mut reader: impl Read, mut writer: impl Write) -> io::Result<Vec<u8>> {
+    let mut read = vec![];
+    let mut buffer = [0; 8192];
+    loop {
+        let count = reader.read(&mut buffer)?;
+        if count == 0 {
+            return Ok(read);
+        }
+        writer.write_all(&buffer[..count])?;
+        writer.flush()?;
+        read.extend_from_slice(&buffer[..count]);
+    }
+}
+
+impl ShellEffect for Shell {
+    /// Runs the command with the user's shell, attached to the terminal. When `capture`, its
+    /// output is also returned so that it can be stored, otherwise the output is empty
+    fn run(&self, command: &str, capture: bool) -> Result<ShellOutput, Box<dyn Error>> {
+        debug!("Running '{}' with {}", command, self.shell);
+        let mut shell = Command::new(&self.shell);
+        shell.arg("-c").arg(command).stdin(Stdio::inherit());
+        if !capture {
+            let status = shell.status()?;
+            return Ok(ShellOutput {
+                success: status.success(),
+                output: String::new(),
+            });
+        }
+
+        let mut child = shell
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        let stdout = child.stdout.take().expect("stdout is piped");
+        let stderr = child.stderr.take().expect("stderr is piped");
+        // both are read at once so that neither fills its pipe and blocks the command
+        let stderr = thread::spawn(move || tee(stderr, io::stderr()));
+        let stdout = tee(stdout, io::stdout())?;
+        let stderr = stderr
+            .join()
+            .map_err(|_| io::Error::other("Could not read the command's stderr"))??;
+        let status = child.wait()?;
+
+        Ok(ShellOutput {
+            success: status.success(),
+            output: format!(
+                "{}{}",
+                String::from_utf8_lossy(&stdout),
+                String::from_utf8_lossy(&stderr)
+            ),
+        })
+    }
+
+    fn copy_to_clipboard(&self, text: &str) -> Result<(), Box<dyn Error>> {
+        for (program, args) in CLIPBOARD_COMMANDS {
+            let child = Command::new(program)
+                .args(args)
+                .stdin(Stdio::piped())
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .spawn();
+
+            if let Ok(mut child) = child {
+                debug!("Copying to clipboard with {}", program);
+                // stdin is dropped at the end of this block so the program sees EOF
+                if let Some(mut stdin) = child.stdin.take() {
+                    stdin.write_all(text.as_bytes())?;
+                }
+                if child.wait()?.success() {
+                    return Ok(());
+                }
+            }
+        }
+
+        Err(Box::new(std::io::Error::other(format!(
+            "Could not copy to clipboard, tried: {}",
+            CLIPBOARD_COMMANDS
+                .iter()
+                .map(|(program, _)| *program)
+                .collect::<Vec<&str>>()
+                .join(", ")
+        ))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::effect::ShellEffect;
+
+    use super::Shell;
+
+    #[test]
+    fn output_is_only_kept_when_captured() {
+        let shell = Shell::new("/bin/sh");
+        let captured = shell.run("echo out; echo err >&2; exit 3", true).unwrap();
+        assert!(!captured.success);
+        assert_eq!("out\nerr\n", captured.output);
+
+        let shown = shell.run("echo out", false).unwrap();
+        assert!(shown.success);
+        assert!(shown.output.is_empty());
+    }
+}
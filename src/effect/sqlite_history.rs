@@ -0,0 +1,561 @@
+use std::{
+    error::Error,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use rusqlite::{params, Connection, OptionalExtension, Row, Transaction, TransactionBehavior};
+
+use super::{
+    conversation::{
+        blocking, output, segments, DialogueSegment, HistoryError, Script, Summary, ROOT,
+    },
+    ScriptEdit, ShortMemEffect, ShortMemInput, ShortMemOutput,
+};
+
+const DATABASE: &str = ".history.sqlite3";
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS conversations (
+        name TEXT PRIMARY KEY,
+        persona TEXT
+    );
+    CREATE TABLE IF NOT EXISTS segments (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        conversation TEXT NOT NULL,
+        segment TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS segments_by_conversation ON segments (conversation, id);
+";
+
+/// Schema changes, applied in order to databases at an older `user_version`
+const MIGRATIONS: &[&str] = &[
+    "ALTER TABLE conversations ADD COLUMN summary TEXT;",
+    "ALTER TABLE conversations ADD COLUMN head TEXT;
+     ALTER TABLE segments ADD COLUMN segment_id TEXT;
+     ALTER TABLE segments ADD COLUMN parent TEXT;
+     UPDATE segments SET
+        segment_id = (SELECT COUNT(*) FROM segments AS earlier
+            WHERE earlier.conversation = segments.conversation AND earlier.id < segments.id),
+        parent = (SELECT COUNT(*) - 1 FROM segments AS earlier
+            WHERE earlier.conversation = segments.conversation AND earlier.id < segments.id);
+     UPDATE segments SET parent = NULL WHERE parent = '-1';
+     CREATE INDEX IF NOT EXISTS segments_by_id ON segments (conversation, segment_id);",
+];
+
+/// Walks the active branch back from the head, `?2` segments at most
+const BRANCH: &str = "
+    WITH RECURSIVE branch (segment_id, parent, segment, depth) AS (
+        SELECT segment_id, parent, segment, 1 FROM segments
+        WHERE conversation = ?1 AND segment_id = (SELECT COALESCE(
+            (SELECT head FROM conversations WHERE name = ?1),
+            (SELECT segment_id FROM segments WHERE conversation = ?1 ORDER BY id DESC LIMIT 1)
+        ))
+        UNION ALL
+        SELECT segments.segment_id, segments.parent, segments.segment, branch.depth + 1
+        FROM segments JOIN branch ON segments.segment_id = branch.parent
+        WHERE segments.conversation = ?1 AND branch.depth < ?2
+    )
+    SELECT segment, segment_id, parent FROM branch ORDER BY depth DESC
+";
+
+impl From<rusqlite::Error> for HistoryError {
+    fn from(e: rusqlite::Error) -> Self {
+        HistoryError(e.to_string())
+    }
+}
+
+/// Conversation history stored in a sqlite database shared by every conversation in the
+/// directory. Segments are stored as json so new fields do not need a migration
+pub struct SqliteHistory {
+    database: PathBuf,
+    convo: String,
+}
+
+impl SqliteHistory {
+    pub fn new(convo_dir: &str, convo: &str) -> Self {
+        Self {
+            database: Path::new(convo_dir).join(DATABASE),
+            convo: convo.to_string(),
+        }
+    }
+
+    /// Names of the conversations in the directory's database
+    pub fn list(convo_dir: &str) -> Result<Vec<String>, HistoryError> {
+        let database = Path::new(convo_dir).join(DATABASE);
+        if !database.exists() {
+            return Ok(vec![]);
+        }
+        let connection = open(&database)?;
+        let mut statement = connection.prepare("SELECT name FROM conversations ORDER BY name")?;
+        let names = statement
+            .query_map([], |row| row.get(0))?
+            .collect::<Result<Vec<String>, rusqlite::Error>>()?;
+        Ok(names)
+    }
+
+    /// Runs the query on a blocking thread with a connection to the database
+    async fn with_connection<T, F>(&self, query: F) -> Result<T, HistoryError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection, &str) -> Result<T, HistoryError> + Send + 'static,
+    {
+        let database = self.database.clone();
+        let convo = self.convo.clone();
+        blocking(move || {
+            let mut connection = open(&database)?;
+            query(&mut connection, &convo)
+        })
+        .await
+    }
+}
+
+fn open(database: &Path) -> Result<Connection, HistoryError> {
+    let mut connection = Connection::open(database)?;
+    connection.busy_timeout(Duration::from_secs(10))?;
+    connection.execute_batch(SCHEMA)?;
+    migrate(&mut connection)?;
+    Ok(connection)
+}
+
+fn migrate(connection: &mut Connection) -> Result<(), HistoryError> {
+    let version = |connection: &Connection| -> Result<usize, HistoryError> {
+        let version: i64 = connection.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+        Ok(version as usize)
+    };
+    if version(connection)? >= MIGRATIONS.len() {
+        return Ok(());
+    }
+
+    // immediate so that only one process migrates, the others wait and see the new version
+    let transaction = connection.transaction_with_behavior(TransactionBehavior::Immediate)?;
+    let from = version(&transaction)?;
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(from) {
+        match transaction.execute_batch(migration) {
+            // databases from before versioning may already have the summary column
+            Err(e) if i == 0 && e.to_string().contains("duplicate column") => (),
+            result => result?,
+        }
+    }
+    transaction.pragma_update(None, "user_version", MIGRATIONS.len() as i64)?;
+    transaction.commit()?;
+    Ok(())
+}
+
+/// The segment the next turn follows
+fn head(connection: &Connection, convo: &str) -> Result<Option<String>, HistoryError> {
+    let head: Option<String> = connection
+        .query_row(
+            "SELECT head FROM conversations WHERE name = ?1",
+            params![convo],
+            |row| row.get(0),
+        )
+        .optional()?
+        .flatten();
+    let head = match head {
+        Some(head) => Some(head),
+        None => connection
+            .query_row(
+                "SELECT segment_id FROM segments WHERE conversation = ?1 ORDER BY id DESC LIMIT 1",
+                params![convo],
+                |row| row.get(0),
+            )
+            .optional()?,
+    };
+    Ok(head.filter(|head| head != ROOT))
+}
+
+fn insert_segments(
+    connection: &Connection,
+    convo: &str,
+    dialogue: &[DialogueSegment],
+) -> Result<(), HistoryError> {
+    connection.execute(
+        "INSERT OR IGNORE INTO conversations (name) VALUES (?1)",
+        params![convo],
+    )?;
+    let mut statement = connection.prepare(
+        "INSERT INTO segments (conversation, segment, segment_id, parent) VALUES (?1, ?2, ?3, ?4)",
+    )?;
+    for segment in dialogue {
+        statement.execute(params![
+            convo,
+            serde_json::to_string(segment)?,
+            segment.id,
+            segment.parent
+        ])?;
+    }
+    Ok(())
+}
+
+/// Segment json with its id and parent, which are kept in their own columns
+type SegmentRow = (String, String, Option<String>);
+
+fn segment_row(row: &Row) -> Result<SegmentRow, rusqlite::Error> {
+    Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+}
+
+fn parse_segments(segments: Vec<SegmentRow>) -> Result<Vec<DialogueSegment>, HistoryError> {
+    segments
+        .into_iter()
+        .map(|(segment, id, parent)| {
+            Ok(DialogueSegment {
+                id,
+                parent,
+                ..serde_json::from_str(&segment)?
+            })
+        })
+        .collect()
+}
+
+fn read_script(connection: &Connection, convo: &str) -> Result<Script, HistoryError> {
+    let mut statement = connection.prepare(
+        "SELECT segment, segment_id, parent FROM segments WHERE conversation = ?1 ORDER BY id",
+    )?;
+    let segments = statement
+        .query_map(params![convo], segment_row)?
+        .collect::<Result<Vec<SegmentRow>, rusqlite::Error>>()?;
+    let (persona, summary, head): (Option<String>, Option<String>, Option<String>) = connection
+        .query_row(
+            "SELECT persona, summary, head FROM conversations WHERE name = ?1",
+            params![convo],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        )
+        .optional()?
+        .unwrap_or_default();
+
+    Ok(Script {
+        dialogue: parse_segments(segments)?,
+        persona,
+        summary: summary
+            .map(|summary| serde_json::from_str(&summary))
+            .transpose()?,
+        head,
+    })
+}
+
+/// Replaces the conversation with the script, the caller commits the transaction
+fn write_script(
+    transaction: &Transaction,
+    convo: &str,
+    script: &Script,
+) -> Result<(), HistoryError> {
+    transaction.execute(
+        "DELETE FROM segments WHERE conversation = ?1",
+        params![convo],
+    )?;
+    let summary = script
+        .summary
+        .as_ref()
+        .map(serde_json::to_string)
+        .transpose()?;
+    transaction.execute(
+        "INSERT INTO conversations (name, persona, summary, head) VALUES (?1, ?2, ?3, ?4)
+         ON CONFLICT (name) DO UPDATE
+         SET persona = excluded.persona, summary = excluded.summary, head = excluded.head",
+        params![convo, script.persona, summary, script.head],
+    )?;
+    insert_segments(transaction, convo, &script.dialogue)
+}
+
+#[async_trait]
+impl ShortMemEffect for SqliteHistory {
+    async fn save_history(&self, input: &[ShortMemInput]) -> Result<(), Box<dyn Error>> {
+        let dialogue = segments(input);
+        self.with_connection(move |connection, convo| {
+            let transaction =
+                connection.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            let mut parent = head(&transaction, convo)?;
+            let dialogue: Vec<DialogueSegment> = dialogue
+                .into_iter()
+                .map(|segment| {
+                    let id = segment.id.clone();
+                    DialogueSegment {
+                        parent: parent.replace(id),
+                        ..segment
+                    }
+                })
+                .collect();
+            insert_segments(&transaction, convo, &dialogue)?;
+            transaction.execute(
+                "UPDATE conversations SET head = NULL WHERE name = ?1",
+                params![convo],
+            )?;
+            transaction.commit()?;
+            Ok(())
+        })
+        .await?;
+        Ok(())
+    }
+
+    async fn get_history(&self, len: usize) -> Result<Vec<ShortMemOutput>, Box<dyn Error>> {
+        if len == 0 {
+            return Ok(vec![]);
+        }
+        let segments = self
+            .with_connection(move |connection, convo| {
+                let mut statement = connection.prepare(BRANCH)?;
+                let segments = statement
+                    .query_map(params![convo, len as i64], segment_row)?
+                    .collect::<Result<Vec<SegmentRow>, rusqlite::Error>>()?;
+                Ok(segments)
+            })
+            .await?;
+
+        Ok(parse_segments(segments)?.iter().map(output).collect())
+    }
+
+    async fn checkout(&self, id: Option<&str>) -> Result<(), Box<dyn Error>> {
+        let id = id.map(|id| id.to_string());
+        self.with_connection(move |connection, convo| {
+            let transaction =
+                connection.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            if let Some(id) = &id {
+                transaction
+                    .query_row(
+                        "SELECT 1 FROM segments WHERE conversation = ?1 AND segment_id = ?2",
+                        params![convo, id],
+                        |_| Ok(()),
+                    )
+                    .optional()?
+                    .ok_or_else(|| HistoryError(format!("No segment with id '{}'", id)))?;
+            }
+            transaction.execute(
+                "INSERT INTO conversations (name, head) VALUES (?1, ?2)
+                 ON CONFLICT (name) DO UPDATE SET head = excluded.head",
+                params![convo, id.as_deref().unwrap_or(ROOT)],
+            )?;
+            transaction.commit()?;
+            Ok(())
+        })
+        .await?;
+        Ok(())
+    }
+
+    async fn get_persona(&self) -> Result<Option<String>, Box<dyn Error>> {
+        Ok(self
+            .with_connection(|connection, convo| {
+                Ok(connection
+                    .query_row(
+                        "SELECT persona FROM conversations WHERE name = ?1",
+                        params![convo],
+                        |row| row.get(0),
+                    )
+                    .optional()?
+                    .flatten())
+            })
+            .await?)
+    }
+
+    async fn save_persona(&self, persona: &str) -> Result<(), Box<dyn Error>> {
+        let persona = persona.to_string();
+        self.with_connection(move |connection, convo| {
+            connection.execute(
+                "INSERT INTO conversations (name, persona) VALUES (?1, ?2)
+                 ON CONFLICT (name) DO UPDATE SET persona = excluded.persona",
+                params![convo, persona],
+            )?;
+            Ok(())
+        })
+        .await?;
+        Ok(())
+    }
+
+    async fn get_summary(&self) -> Result<Option<Summary>, Box<dyn Error>> {
+        let summary: Option<String> = self
+            .with_connection(|connection, convo| {
+                Ok(connection
+                    .query_row(
+                        "SELECT summary FROM conversations WHERE name = ?1",
+                        params![convo],
+                        |row| row.get(0),
+                    )
+                    .optional()?
+                    .flatten())
+            })
+            .await?;
+        Ok(summary
+            .map(|summary| serde_json::from_str(&summary))
+            .transpose()?)
+    }
+
+    async fn save_summary(&self, summary: &Summary) -> Result<(), Box<dyn Error>> {
+        let summary = serde_json::to_string(summary)?;
+        self.with_connection(move |connection, convo| {
+            connection.execute(
+                "INSERT INTO conversations (name, summary) VALUES (?1, ?2)
+                 ON CONFLICT (name) DO UPDATE SET summary = excluded.summary",
+                params![convo, summary],
+            )?;
+            Ok(())
+        })
+        .await?;
+        Ok(())
+    }
+
+    async fn load(&self) -> Result<Script, Box<dyn Error>> {
+        Ok(self
+            .with_connection(|connection, convo| read_script(connection, convo))
+            .await?)
+    }
+
+    async fn replace(&self, script: Script) -> Result<(), Box<dyn Error>> {
+        self.with_connection(move |connection, convo| {
+            let transaction = connection.transaction()?;
+            write_script(&transaction, convo, &script)?;
+            transaction.commit()?;
+            Ok(())
+        })
+        .await?;
+        Ok(())
+    }
+
+    async fn edit(&self, edit: ScriptEdit) -> Result<Script, Box<dyn Error>> {
+        Ok(self
+            .with_connection(move |connection, convo| {
+                // immediate so no other process writes between reading and replacing
+                let transaction =
+                    connection.transaction_with_behavior(TransactionBehavior::Immediate)?;
+                let before = read_script(&transaction, convo)?;
+                write_script(&transaction, convo, &edit(before.clone())?)?;
+                transaction.commit()?;
+                Ok(before)
+            })
+            .await?)
+    }
+
+    async fn exists(&self) -> Result<bool, Box<dyn Error>> {
+        Ok(self
+            .with_connection(|connection, convo| {
+                Ok(connection
+                    .query_row(
+                        "SELECT 1 FROM conversations WHERE name = ?1",
+                        params![convo],
+                        |_| Ok(()),
+                    )
+                    .optional()?
+                    .is_some())
+            })
+            .await?)
+    }
+
+    async fn delete(&self) -> Result<(), Box<dyn Error>> {
+        self.with_connection(|connection, convo| {
+            let transaction = connection.transaction()?;
+            transaction.execute(
+                "DELETE FROM segments WHERE conversation = ?1",
+                params![convo],
+            )?;
+            transaction.execute("DELETE FROM conversations WHERE name = ?1", params![convo])?;
+            transaction.commit()?;
+            Ok(())
+        })
+        .await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::effect::{conversation::tests::check_history, ShortMemEffect, ShortMemInput};
+
+    use super::SqliteHistory;
+
+    fn input(content: &str) -> ShortMemInput {
+        ShortMemInput {
+            id: None,
+            author: "user".to_string(),
+            content: content.to_string(),
+            meta: None,
+            memory_id: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn conversations_are_kept_apart() {
+        let dir = tempfile::tempdir().unwrap();
+        let convo_dir = dir.path().to_string_lossy().to_string();
+        let first = SqliteHistory::new(&convo_dir, "first");
+        let second = SqliteHistory::new(&convo_dir, "second");
+
+        first
+            .save_history(&[input("one"), input("two"), input("three")])
+            .await
+            .unwrap();
+        second.save_history(&[input("other")]).await.unwrap();
+        first.save_persona("reviewer").await.unwrap();
+
+        let latest: Vec<String> = first
+            .get_history(2)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|segment| segment.content)
+            .collect();
+        assert_eq!(vec!["two", "three"], latest);
+        assert_eq!(
+            Some("reviewer".to_string()),
+            first.get_persona().await.unwrap()
+        );
+        assert_eq!(None, second.get_persona().await.unwrap());
+        assert_eq!(
+            vec!["first", "second"],
+            SqliteHistory::list(&convo_dir).unwrap()
+        );
+
+        second.delete().await.unwrap();
+        assert!(!second.exists().await.unwrap());
+        assert_eq!(3, first.load().await.unwrap().dialogue.len());
+    }
+
+    #[tokio::test]
+    async fn segments_saved_before_branches_form_one_branch() {
+        let dir = tempfile::tempdir().unwrap();
+        let database = dir.path().join(super::DATABASE);
+        let connection = rusqlite::Connection::open(&database).unwrap();
+        connection
+            .execute_batch(
+                r#"
+                CREATE TABLE conversations (name TEXT PRIMARY KEY, persona TEXT);
+                CREATE TABLE segments (
+                    id INTEGER PRIMARY KEY AUTOINCREMENT,
+                    conversation TEXT NOT NULL,
+                    segment TEXT NOT NULL
+                );
+                INSERT INTO conversations (name) VALUES ('convo');
+                INSERT INTO segments (conversation, segment) VALUES
+                    ('convo', '{"role":"user","content":"one","created_at":"2023-01-01T00:00:00Z"}'),
+                    ('convo', '{"role":"assistant","content":"two","created_at":"2023-01-01T00:00:00Z"}');
+                "#,
+            )
+            .unwrap();
+        drop(connection);
+
+        let history = SqliteHistory::new(&dir.path().to_string_lossy(), "convo");
+        history.save_history(&[input("three")]).await.unwrap();
+
+        let latest: Vec<(String, String)> = history
+            .get_history(5)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|segment| (segment.id, segment.content))
+            .collect();
+        assert_eq!("0", latest[0].0);
+        assert_eq!("1", latest[1].0);
+        assert_eq!(
+            vec!["one", "two", "three"],
+            latest
+                .iter()
+                .map(|(_, content)| content)
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[tokio::test]
+    async fn keeps_the_contract() {
+        let dir = tempfile::tempdir().unwrap();
+        check_history(&SqliteHistory::new(&dir.path().to_string_lossy(), "convo")).await;
+    }
+}
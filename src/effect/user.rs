@@ -10,7 +10,7 @@ use std::{
 use tempfile::NamedTempFile;
 use termion::{event::Key, input::TermRead};
 
-use super::{DisplayEffect, InteractionEffect, UserCycleResponse};
+use super::{DisplayEffect, InteractionEffect, UserCommandResponse, UserCycleResponse};
 
 pub struct User(pub Box<dyn DisplayEffect>);
 
@@ -36,6 +36,29 @@ impl InteractionEffect for User {
         }
     }
 
+    fn elicit_command_response(&self, user_prompt: &str) -> UserCommandResponse {
+        self.0.print(user_prompt);
+
+        let mut stdin = termion::async_stdin().keys();
+
+        loop {
+            let input = stdin.next();
+
+            if let Some(Ok(key)) = input {
+                match key {
+                    Key::Char('\n') | Key::Char('\r') | Key::Char('r') => {
+                        break UserCommandResponse::Run
+                    }
+                    Key::Char('e') => break UserCommandResponse::Edit,
+                    Key::Char('c') => break UserCommandResponse::Copy,
+                    Key::Char('q') | Key::Esc => break UserCommandResponse::Cancel,
+                    _ => (),
+                }
+            }
+            thread::sleep(time::Duration::from_millis(50));
+        }
+    }
+
     fn edit_data_gen_prompt(&self, initial_prompt: &str) -> Result<String, Box<dyn Error>> {
         let mut temp_file = NamedTempFile::new()?;
         temp_file.write_all(initial_prompt.as_bytes())?;
@@ -46,8 +69,7 @@ impl InteractionEffect for User {
         let status = Command::new("nvim").arg(temp_file_path).status()?;
 
         if !status.success() {
-            return Err(Box::new(std::io::Error::new(
-                std::io::ErrorKind::Other,
+            return Err(Box::new(std::io::Error::other(
                 "NeoVim did not exit successfully",
             )));
         }
@@ -8,8 +8,12 @@ use log::debug;
 use model::Model;
 use states::Action;
 
-use crate::{data::args::CliArgs, states::init::InitState};
+use crate::{
+    data::args::{CliArgs, CliCommand, ConfigArgs},
+    states::init::InitState,
+};
 
+mod commands;
 mod data;
 mod effect;
 mod output;
@@ -18,6 +22,7 @@ mod utils;
 
 pub const COMPLETION_URL: &str = "https://api.openai.com/v1/chat/completions";
 pub const EDIT_URL: &str = "https://api.openai.com/v1/edits";
+pub const OPENAI_URL: &str = "https://api.openai.com/v1";
 pub const DEFAULT_CONVO: &str = "a4c80afe-f225-11ed-a05b-0242ac120003";
 
 fn setup_logger(is_debug: bool) -> Result<(), fern::InitError> {
@@ -45,8 +50,42 @@ async fn main() -> Result<(), Box<dyn Error>> {
     let args = CliArgs::parse();
     setup_logger(args.debug)?;
 
+    if let Some(CliCommand::Templates { command }) = &args.command {
+        let config_args = ConfigArgs::load()?;
+        return commands::templates::run(command, &args.templates_dir(&config_args)?);
+    }
+    if let Some(CliCommand::Convo { command }) = &args.command {
+        let config_args = ConfigArgs::load()?;
+        return commands::convo::run(command, &args, &config_args).await;
+    }
+    if let Some(CliCommand::Memory { command }) = &args.command {
+        let config_args = ConfigArgs::load()?;
+        return commands::memory::run(command, &args, &config_args).await;
+    }
+    if let Some(CliCommand::Search(search)) = &args.command {
+        let config_args = ConfigArgs::load()?;
+        return commands::search::run(search, &args, &config_args).await;
+    }
+    if let Some(CliCommand::Ingest(ingest)) = &args.command {
+        let config_args = ConfigArgs::load()?;
+        return commands::ingest::run(ingest, &args, &config_args).await;
+    }
+    if let Some(CliCommand::Gc {
+        names,
+        dry_run,
+        memory,
+    }) = &args.command
+    {
+        let config_args = ConfigArgs::load()?;
+        return commands::gc::run(names, *dry_run, *memory, &args, &config_args).await;
+    }
+    if let Some(CliCommand::Personas) = &args.command {
+        let config_args = ConfigArgs::load()?;
+        return commands::personas::run(&config_args.personas.unwrap_or_default());
+    }
+
     debug!("Converting arguments to domain model");
-    let model = args.into_domain()?;
+    let model = args.into_domain().await?;
 
     debug!("composing effects");
     let effects = Effects::new(&model);
@@ -1,4 +1,3 @@
-#[allow(unused)]
 fn parse_output<'a>(delimeter: &str, input: &[&'a str]) -> Vec<&'a str> {
     input
         .iter()
@@ -11,9 +10,26 @@ fn parse_output<'a>(delimeter: &str, input: &[&'a str]) -> Vec<&'a str> {
         .collect()
 }
 
+/// Pulls a shell command out of a model response, removing any code fences, language tags or
+/// inline backticks the model added despite being asked not to
+pub fn extract_command(response: &str) -> String {
+    let command = match parse_output("```", &[response]).first() {
+        Some(block) => {
+            let block = block.trim_start_matches([' ', '\t']);
+            match block.split_once('\n') {
+                Some((tag, rest)) if !tag.trim().contains(' ') && !rest.trim().is_empty() => rest,
+                _ => block,
+            }
+        }
+        None => response,
+    };
+
+    command.trim().trim_matches('`').trim().to_string()
+}
+
 #[cfg(test)]
 mod tests {
-    use super::parse_output;
+    use super::{extract_command, parse_output};
 
     #[test]
     fn splits_basic_code_example() {
@@ -38,4 +54,28 @@ mod tests {
         let output = parse_output("```", &input);
         assert_eq!(vec!["\nExpected Output\n", "\nExpected Output 2\n"], output);
     }
+
+    #[test]
+    fn extracts_plain_command() {
+        let output = extract_command("find . -size +100M\n");
+        assert_eq!("find . -size +100M", output);
+    }
+
+    #[test]
+    fn extracts_command_from_tagged_code_block() {
+        let output = extract_command("```bash\nfind . -size +100M\n```");
+        assert_eq!("find . -size +100M", output);
+    }
+
+    #[test]
+    fn extracts_command_from_untagged_code_block() {
+        let output = extract_command("Here you go:\n```\nls -la\n```");
+        assert_eq!("ls -la", output);
+    }
+
+    #[test]
+    fn extracts_command_from_inline_code() {
+        let output = extract_command("`du -sh *`");
+        assert_eq!("du -sh *", output);
+    }
 }
@@ -0,0 +1,97 @@
+use std::{error::Error, process::exit};
+
+use async_trait::async_trait;
+use log::debug;
+
+use crate::{
+    effect::{ChatRequestInput, Effects, UserCommandResponse},
+    model::Model,
+};
+
+use super::{success::SuccessState, Action};
+
+pub struct CommandState {
+    pub effects: Effects,
+    pub command: String,
+    pub explain: bool,
+}
+
+fn explain_system_prompt(shell: &str) -> String {
+    format!(
+        "You are explaining {} commands in a computer terminal. \
+        Break the given command down into each of its parts and briefly explain what each does.",
+        shell
+    )
+}
+
+#[async_trait]
+impl Action for CommandState {
+    async fn execute(
+        self: Box<Self>,
+        model: Model,
+    ) -> Result<(Box<dyn Action>, Model), Box<dyn Error>> {
+        self.effects
+            .displayer
+            .print(&format!("\n{}\n", self.command));
+
+        if self.explain {
+            debug!("Requesting explanation for '{}'", self.command);
+            let request = [
+                ChatRequestInput {
+                    role: "system".to_string(),
+                    content: explain_system_prompt(&model.config.shell),
+                },
+                ChatRequestInput {
+                    role: "user".to_string(),
+                    content: self.command.clone(),
+                },
+            ];
+            let response = self.effects.requester.chat_request_stream(&request).await?;
+            self.effects.displayer.print_stream(response).await;
+        }
+
+        let user_response = self.effects.user.elicit_command_response(
+            "[Press enter or r to run; e to Edit; c to Copy; q to Cancel]",
+        );
+
+        match user_response {
+            UserCommandResponse::Run => {
+                let shell_output = self
+                    .effects
+                    .shell
+                    .run(&self.command, model.config.feed_command_output)?;
+                if !shell_output.success {
+                    self.effects
+                        .displayer
+                        .eprint("Command exited unsuccessfully");
+                }
+                Ok((
+                    Box::new(SuccessState(self.effects)),
+                    model.with_command_output(shell_output.output),
+                ))
+            }
+            UserCommandResponse::Edit => {
+                let command = self.effects.user.edit_data_gen_prompt(&self.command)?;
+                let command = command.trim().to_string();
+                Ok((
+                    Box::new(CommandState {
+                        effects: self.effects,
+                        command: command.clone(),
+                        explain: model.config.explain_command,
+                    }),
+                    model.with_chat_response(vec![command]),
+                ))
+            }
+            UserCommandResponse::Copy => {
+                self.effects.shell.copy_to_clipboard(&self.command)?;
+                self.effects.displayer.print("Copied to clipboard");
+                Ok((Box::new(SuccessState(self.effects)), model))
+            }
+            UserCommandResponse::Cancel => exit(0),
+        }
+    }
+
+    fn _type(&self) -> String {
+        String::from("Command")
+    }
+}
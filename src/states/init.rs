@@ -19,7 +19,8 @@ impl Action for InitState {
     ) -> Result<(Box<dyn Action>, Model), Box<dyn Error>> {
         match &model.mode {
             Mode::Chat(ChatData::DataFromPrompt(data_request_prompt))
-            | Mode::Edit(EditData::DataFromPrompt(data_request_prompt)) => Ok((
+            | Mode::Edit(EditData::DataFromPrompt(data_request_prompt))
+            | Mode::Shell(ChatData::DataFromPrompt(data_request_prompt)) => Ok((
                 Box::new(DataRequestState {
                     effects: (*self).0,
                     prompt: data_request_prompt.clone(),
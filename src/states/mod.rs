@@ -1,8 +1,11 @@
+mod command;
 mod data_request;
 pub mod init;
+mod pipeline;
 mod preview;
 mod request;
 mod success;
+mod summary;
 
 use std::error::Error;
 
@@ -0,0 +1,266 @@
+use std::{collections::HashMap, error::Error, path::PathBuf};
+
+use async_trait::async_trait;
+use chrono::Utc;
+use log::debug;
+
+use crate::{
+    data::{
+        inputs::input_vars,
+        pipeline::{split_list_items, Pipeline, PipelineError, Stage},
+        template::Template,
+    },
+    effect::{ChatRequestInput, Effects, UserCycleResponse},
+    model::{Algo, Mode, Model},
+    utils,
+};
+
+use super::{success::SuccessState, Action};
+
+/// Runs one stage of a pipeline, saving its prompt and output to the run directory
+pub struct StageState {
+    pub effects: Effects,
+    pub index: usize,
+    pub outputs: HashMap<String, String>,
+    pub run_dir: PathBuf,
+}
+
+impl StageState {
+    pub fn start(effects: Effects, model: &Model) -> Result<StageState, Box<dyn Error>> {
+        let name = match &model.mode {
+            Mode::Pipeline(pipeline, _) => pipeline.name.clone().unwrap_or_default(),
+            _ => return Err(Box::new(PipelineError("Not in pipeline mode".to_string()))),
+        };
+        let run_dir: PathBuf = [
+            model.config.runs_dir.clone(),
+            format!("{}-{}", name, Utc::now().format("%Y%m%dT%H%M%S")),
+        ]
+        .iter()
+        .collect();
+
+        std::fs::create_dir_all(&run_dir)?;
+        debug!("Saving pipeline run to {:?}", run_dir);
+
+        Ok(StageState {
+            effects,
+            index: 0,
+            outputs: HashMap::new(),
+            run_dir,
+        })
+    }
+
+    fn artifact_path(&self, stage: &Stage, suffix: &str) -> String {
+        self.run_dir
+            .join(format!("{:02}-{}{}", self.index + 1, stage.name, suffix))
+            .to_string_lossy()
+            .to_string()
+    }
+}
+
+fn current_pipeline(model: &Model) -> Result<&Pipeline, PipelineError> {
+    match &model.mode {
+        Mode::Pipeline(pipeline, _) => Ok(pipeline),
+        _ => Err(PipelineError("Not in pipeline mode".to_string())),
+    }
+}
+
+#[async_trait]
+impl Action for StageState {
+    async fn execute(
+        self: Box<Self>,
+        model: Model,
+    ) -> Result<(Box<dyn Action>, Model), Box<dyn Error>> {
+        let pipeline = current_pipeline(&model)?;
+        let stage = pipeline.stages[self.index].clone();
+
+        if self.index == 0 {
+            utils::write_to_async(
+                &self.run_dir.join("pipeline.yaml").to_string_lossy(),
+                pipeline,
+            )
+            .await?;
+        }
+
+        let vars = {
+            let mut vars = pipeline.vars.clone();
+            if let Mode::Pipeline(_, inputs) = &model.mode {
+                vars.extend(input_vars(inputs));
+            }
+            vars.extend(model.prompt.template_vars.clone());
+            vars.extend(self.outputs.clone());
+            vars
+        };
+
+        let items: Vec<Option<String>> = match &stage.for_each {
+            Some(for_each) => split_list_items(&self.outputs[for_each])
+                .into_iter()
+                .map(Some)
+                .collect(),
+            None => vec![None],
+        };
+
+        let algo = Algo {
+            chat_model: stage.model.clone().unwrap_or(model.algo.chat_model.clone()),
+            temp: stage.temp.unwrap_or(model.algo.temp),
+            ..model.algo.clone()
+        };
+        let template = Template {
+            name: stage.name.clone(),
+            body: stage.prompt.clone(),
+            ..Default::default()
+        };
+
+        let mut item_outputs: Vec<String> = vec![];
+        for (i, item) in items.iter().enumerate() {
+            let mut vars = vars.clone();
+            if let Some(item) = item {
+                vars.insert("item".to_string(), item.clone());
+                self.effects.displayer.eprint(&format!(
+                    "==> {} [{}/{}] <==",
+                    stage.name,
+                    i + 1,
+                    items.len()
+                ));
+            } else {
+                self.effects
+                    .displayer
+                    .eprint(&format!("==> {} <==", stage.name));
+            }
+
+            let prompt = template.render(&vars)?;
+            let request = [
+                ChatRequestInput {
+                    role: "system".to_string(),
+                    content: stage.act_as.clone().unwrap_or(model.prompt.act_as.clone()),
+                },
+                ChatRequestInput {
+                    role: "user".to_string(),
+                    content: prompt.clone(),
+                },
+            ];
+
+            debug!("Running stage '{}' with {:#?}", stage.name, algo);
+            let response = self
+                .effects
+                .requester
+                .chat_request_stream_with_algo(&request, &algo)
+                .await?;
+            let output = self.effects.displayer.print_stream(response).await;
+            let output = output.into_iter().next().unwrap_or_default();
+
+            let suffix = match item {
+                Some(_) => format!("-{:02}", i + 1),
+                None => String::new(),
+            };
+            tokio::fs::write(
+                self.artifact_path(&stage, &format!("{}.prompt.md", suffix)),
+                &prompt,
+            )
+            .await?;
+            if item.is_some() {
+                tokio::fs::write(
+                    self.artifact_path(&stage, &format!("{}.md", suffix)),
+                    &output,
+                )
+                .await?;
+            }
+            item_outputs.push(output);
+        }
+
+        let output = item_outputs.join("\n\n");
+        tokio::fs::write(self.artifact_path(&stage, ".md"), &output).await?;
+
+        if stage.preview {
+            Ok((
+                Box::new(StagePreviewState {
+                    stage: *self,
+                    output,
+                }),
+                model,
+            ))
+        } else {
+            next_stage(*self, stage.name, output, model)
+        }
+    }
+
+    fn _type(&self) -> String {
+        String::from("Pipeline Stage")
+    }
+}
+
+fn next_stage(
+    state: StageState,
+    name: String,
+    output: String,
+    model: Model,
+) -> Result<(Box<dyn Action>, Model), Box<dyn Error>> {
+    let mut outputs = state.outputs;
+    outputs.insert(name, output);
+
+    if state.index + 1 < current_pipeline(&model)?.stages.len() {
+        Ok((
+            Box::new(StageState {
+                index: state.index + 1,
+                outputs,
+                ..state
+            }),
+            model,
+        ))
+    } else {
+        state.effects.displayer.eprint(&format!(
+            "Saved pipeline run to {}",
+            state.run_dir.to_string_lossy()
+        ));
+        Ok((Box::new(SuccessState(state.effects)), model))
+    }
+}
+
+/// Lets the user accept a stage's output, or edit its prompt and run it again
+pub struct StagePreviewState {
+    stage: StageState,
+    output: String,
+}
+
+#[async_trait]
+impl Action for StagePreviewState {
+    async fn execute(
+        self: Box<Self>,
+        model: Model,
+    ) -> Result<(Box<dyn Action>, Model), Box<dyn Error>> {
+        let user_response = self
+            .stage
+            .effects
+            .user
+            .elicit_cycle_response("\n[Press enter to continue; e to Edit the stage prompt]");
+
+        match user_response {
+            UserCycleResponse::Edit => {
+                let index = self.stage.index;
+                let mut pipeline = current_pipeline(&model)?.clone();
+                let new_prompt = self
+                    .stage
+                    .effects
+                    .user
+                    .edit_data_gen_prompt(&pipeline.stages[index].prompt)?;
+                pipeline.stages[index].prompt = new_prompt;
+
+                let mode = match model.mode {
+                    Mode::Pipeline(_, inputs) => Mode::Pipeline(pipeline, inputs),
+                    mode => mode,
+                };
+                Ok((Box::new(self.stage), Model { mode, ..model }))
+            }
+            UserCycleResponse::Accept => {
+                let name = current_pipeline(&model)?.stages[self.stage.index]
+                    .name
+                    .clone();
+                next_stage(self.stage, name, self.output, model)
+            }
+            UserCycleResponse::NextLeft | UserCycleResponse::NextRight => Ok((self, model)),
+        }
+    }
+
+    fn _type(&self) -> String {
+        String::from("Preview Pipeline Stage")
+    }
+}
@@ -1,15 +1,26 @@
-use std::{error::Error, fmt::Display, ops::Sub};
+use std::{error::Error, fmt::Display, ops::Sub, time::Instant};
 
 use async_trait::async_trait;
 use chrono::Duration;
+use futures::StreamExt;
 use log::debug;
 
 use crate::{
-    effect::{ChatRequestInput, EditRequestInput, Effects, LongMemQueryOpt, QueryWindow},
-    model::{ChatData, EditData, Mode, Model},
+    data::inputs::{format_inputs, input_meta},
+    effect::{
+        conversation::{self, TurnMeta},
+        ChatRequestInput, EditRequestInput, Effects, LongMemOutput, LongMemQueryOpt, QueryWindow,
+        ShortMemOutput,
+    },
+    model::{ChatData, EditData, Memory, Mode, Model},
+    output::extract_command,
+    utils::{format_time, snippet},
 };
 
-use super::{success::SuccessState, Action};
+use super::{
+    command::CommandState, pipeline::StageState, success::SuccessState, summary::current_summary,
+    Action,
+};
 
 pub struct RequestState(pub Effects);
 
@@ -19,10 +30,24 @@ impl Action for RequestState {
         self: Box<Self>,
         model: Model,
     ) -> Result<(Box<dyn Action>, Model), Box<dyn Error>> {
+        // recorded before rendering, which removes inputs used by the template
+        let inputs = match &model.mode {
+            Mode::Chat(ChatData::DataFromInputs(inputs))
+            | Mode::Shell(ChatData::DataFromInputs(inputs)) => input_meta(inputs),
+            _ => vec![],
+        };
+        let model = model.with_rendered_prompt()?.with_turn_meta(TurnMeta {
+            inputs,
+            ..Default::default()
+        });
+        debug!("Rendered prompt: {}", model.prompt.prompt);
+
         let mode = &model.mode;
         match mode {
             Mode::Chat(_) => Ok((Box::new(ChatState { effects: self.0 }), model)),
             Mode::Edit(_) => Ok((Box::new(EditState { effects: self.0 }), model)),
+            Mode::Shell(_) => Ok((Box::new(ShellState { effects: self.0 }), model)),
+            Mode::Pipeline(..) => Ok((Box::new(StageState::start(self.0, &model)?), model)),
         }
     }
 
@@ -33,22 +58,165 @@ impl Action for RequestState {
 
 struct PotentialPrompt<'a> {
     prompt: &'a String,
-    data_prompt_result: &'a Option<String>,
-    std_in: Option<&'a String>,
+    data_prompt_result: Option<String>,
+    inputs: Option<String>,
 }
 
-fn get_potential_prompts(model: &Model) -> PotentialPrompt {
+fn get_potential_prompts(model: &Model) -> PotentialPrompt<'_> {
     let prompt = &model.prompt.prompt;
-    let data_prompt_result = &model.prompt.generated_data;
-    let std_in = match &model.mode {
-        Mode::Chat(ChatData::DataFromStdIn(data)) => Some(data),
-        Mode::Edit(EditData::DataFromStdIn(data)) => Some(data),
+    let data_prompt_result = model.prompt.generated_data.clone();
+    let inputs = match &model.mode {
+        Mode::Chat(ChatData::DataFromInputs(inputs))
+        | Mode::Shell(ChatData::DataFromInputs(inputs))
+        | Mode::Edit(EditData::DataFromInputs(inputs)) => Some(format_inputs(inputs)),
         _ => None,
     };
     PotentialPrompt {
         prompt,
         data_prompt_result,
-        std_in,
+        inputs,
+    }
+}
+
+/// Settings of a chat or shell request along with the details of its response, once the response
+/// has been consumed
+fn turn_meta(
+    effects: &Effects,
+    model: &Model,
+    memories: &[LongMemOutput],
+    started: Instant,
+) -> TurnMeta {
+    let response = effects.requester.response_meta();
+    TurnMeta {
+        provider: response.provider,
+        model: model.algo.chat_model.clone(),
+        temperature: model.algo.temp,
+        max_tokens: model.algo.max_tokens,
+        usage: response.usage,
+        latency_ms: Some(started.elapsed().as_millis() as u64),
+        finish_reason: response.finish_reason,
+        persona: model.prompt.persona.clone(),
+        memories: memories.iter().map(|memory| memory.id.clone()).collect(),
+        ..model.output.turn_meta.clone().unwrap_or_default()
+    }
+}
+
+/// The memories added to a request, numbered as they are for citations
+fn context_listing(memories: &[LongMemOutput]) -> String {
+    if memories.is_empty() {
+        return "No memories were added to the request".to_string();
+    }
+    memories
+        .iter()
+        .enumerate()
+        .map(|(i, memory)| {
+            format!(
+                "[m{}] {:.3} {} {} {}",
+                i + 1,
+                memory.score,
+                memory.category,
+                format_time(&memory.created_at),
+                snippet(&memory.text)
+            )
+        })
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+/// Numbers of the memories cited in the answer, as [m3] or [m1, m3], in the order they are first
+/// cited
+fn citations(answer: &str) -> Vec<usize> {
+    let mut cited = vec![];
+    for group in answer.split('[').skip(1) {
+        let Some((inner, _)) = group.split_once(']') else {
+            continue;
+        };
+        for number in inner.split(',').filter_map(|citation| {
+            citation
+                .trim()
+                .strip_prefix('m')
+                .and_then(|number| number.parse::<usize>().ok())
+        }) {
+            if !cited.contains(&number) {
+                cited.push(number);
+            }
+        }
+    }
+    cited
+}
+
+/// Where each memory cited in the answer comes from
+fn cited_sources(answer: &str, memories: &[LongMemOutput]) -> Vec<String> {
+    citations(answer)
+        .into_iter()
+        .map(
+            |number| match number.checked_sub(1).and_then(|i| memories.get(i)) {
+                Some(memory) => {
+                    let source = match &memory.source {
+                        Some(source) => source.to_url(),
+                        None => format!("{} memory {}", memory.source_type.as_str(), memory.id),
+                    };
+                    format!(
+                        "[m{}] {}, '{}' {}",
+                        number,
+                        source,
+                        memory.category,
+                        format_time(&memory.created_at)
+                    )
+                }
+                None => format!("[m{}] is not one of the memories of the request", number),
+            },
+        )
+        .collect()
+}
+
+/// The messages of the conversation window. The output of a command run with --feed-output
+/// follows the answer that suggested it
+fn history_messages(convo_history: &[ShortMemOutput]) -> Vec<ChatRequestInput> {
+    let mut messages = vec![];
+    for segment in convo_history {
+        messages.push(ChatRequestInput {
+            role: segment.author.to_string(),
+            content: segment.content.to_string(),
+        });
+        if let Some(output) = &segment.command_output {
+            messages.push(ChatRequestInput {
+                role: "user".to_string(),
+                content: format!("Output of running `{}`:\n{}", segment.content, output),
+            });
+        }
+    }
+    messages
+}
+
+/// The conversation window before the new turn, along with the number of segments of the branch
+/// up to the end of the window when summarising. When editing a turn the window ends before that
+/// turn
+async fn convo_history(
+    effects: &Effects,
+    memory: &Memory,
+) -> Result<(Vec<ShortMemOutput>, usize), Box<dyn Error>> {
+    match memory.edit_turn {
+        Some(turn) => {
+            let script = effects.history.load().await?;
+            let parent = script.turn_parent(turn)?;
+            let branch = script.branch_to(parent.as_deref());
+            let window = branch[branch.len().saturating_sub(memory.convo_len)..]
+                .iter()
+                .map(|segment| conversation::output(segment))
+                .collect();
+            Ok((window, branch.len()))
+        }
+        None if memory.convo_len > 0 => {
+            let window = effects.history.get_history(memory.convo_len).await?;
+            let branch_len = if memory.summarise {
+                effects.history.len().await?
+            } else {
+                0
+            };
+            Ok((window, branch_len))
+        }
+        None => Ok((vec![], 0)),
     }
 }
 
@@ -75,7 +243,7 @@ impl Action for EditState {
         match get_potential_prompts(&model) {
             PotentialPrompt {
                 data_prompt_result: None,
-                std_in: None,
+                inputs: None,
                 ..
             } => Err(Box::new(EditStateError(String::from(
                 "No input data given to edit request",
@@ -87,7 +255,7 @@ impl Action for EditState {
             }
             | PotentialPrompt {
                 prompt,
-                std_in: Some(input),
+                inputs: Some(input),
                 ..
             } => {
                 let request_input = EditRequestInput {
@@ -101,11 +269,8 @@ impl Action for EditState {
                     .edit_request_stream(request_input)
                     .await?;
 
-                let data = self.effects.displayer.print_stream(response).await;
-                Ok((
-                    Box::new(SuccessState(self.effects)),
-                    model.with_edit_response(data),
-                ))
+                self.effects.displayer.print_stream(response).await;
+                Ok((Box::new(SuccessState(self.effects)), model))
             }
         }
     }
@@ -133,22 +298,14 @@ impl Action for ChatState {
             }
             | PotentialPrompt {
                 prompt,
-                std_in: Some(data),
+                inputs: Some(data),
                 ..
             } if !data.is_empty() => format!("{}. Use this input: {}.", prompt, data),
             PotentialPrompt { prompt, .. } => prompt.to_string(),
         };
 
         debug!("Retrieving chat history: ");
-        let convo_history = if model.memory.convo_len > 0 {
-            // history file should be here as check done in argument parsing
-            self.effects
-                .history
-                .get_history(model.memory.convo_len)
-                .await?
-        } else {
-            Default::default()
-        };
+        let (convo_history, branch_len) = convo_history(&self.effects, &model.memory).await?;
 
         debug!("Retrieved convo history: {:#?}", convo_history);
 
@@ -163,46 +320,88 @@ impl Action for ChatState {
                         .map(|dialogue| dialogue.created_at.sub(Duration::seconds(1))),
                 };
 
-                let convo_query_input = LongMemQueryOpt {
-                    category: model.memory.convo.to_string(),
-                    query_window: convo_query_window,
-                };
+                let convo_query_input = LongMemQueryOpt::filtered(
+                    &model.memory.convo,
+                    convo_query_window,
+                    &model.memory.filter,
+                );
 
                 let mut mem_query_input: Vec<LongMemQueryOpt> = model
                     .memory
                     .memories
                     .iter()
-                    .map(|memory| LongMemQueryOpt {
-                        category: memory.to_string(),
-                        query_window: QueryWindow {
-                            max: None,
-                            min: None,
-                        },
+                    .map(|memory| {
+                        LongMemQueryOpt::filtered(
+                            memory,
+                            QueryWindow {
+                                max: None,
+                                min: None,
+                            },
+                            &model.memory.filter,
+                        )
                     })
                     .collect();
 
                 mem_query_input.push(convo_query_input);
 
                 debug!("Retrieving memories from db with the following input: query - {:#?}; query options - {:#?}", chat_request, mem_query_input);
-                self.effects
+                // a store that is down should not cost the answer
+                let found = self
+                    .effects
                     .context
                     .query(&chat_request, &mem_query_input)
-                    .await?
+                    .await
+                    .map_err(|e| e.to_string());
+                found.unwrap_or_else(|e| {
+                    self.effects.displayer.eprint(&format!(
+                        "Could not retrieve long term memories, answering without them: {}",
+                        e
+                    ));
+                    vec![]
+                })
             } else {
                 Default::default()
             }
         };
 
         debug!("Found {} memories. {:#?}", memories.len(), memories);
+        if model.memory.enabled && model.memory.show_context {
+            self.effects.displayer.eprint(&context_listing(&memories));
+        }
+        for memory in &memories {
+            debug!(
+                "Memory {} in '{}' scored {:.3}",
+                memory.id, memory.category, memory.score
+            );
+        }
+
+        let summary = if model.memory.summarise {
+            // an edited turn may come before the end of the summary
+            current_summary(self.effects.history.as_ref())
+                .await?
+                .filter(|summary| summary.covered <= branch_len)
+        } else {
+            None
+        };
 
         debug!("Constructing system message");
         let mut system_msg = {
             let memory_string = memories
                 .iter()
-                .map(|mem| {
+                .enumerate()
+                .map(|(i, mem)| {
+                    let number = match model.memory.cite {
+                        true => format!("m{}, ", i + 1),
+                        false => "".to_string(),
+                    };
+                    let source = mem
+                        .source
+                        .as_ref()
+                        .map(|source| format!(", source: {}", source.to_url()))
+                        .unwrap_or_default();
                     format!(
-                        "[author: {}, category: {}, created_at: {}] {}\n",
-                        mem.author, mem.category, mem.created_at, mem.text
+                        "[{}author: {}, category: {}, created_at: {}{}] {}\n",
+                        number, mem.author, mem.category, mem.created_at, source, mem.text
                     )
                 })
                 .fold(String::from(""), |mut acc, i| {
@@ -211,12 +410,26 @@ impl Action for ChatState {
                 });
 
             let memory_msg = if !memory_string.is_empty() {
-                format!("Below is a list of text related to the current query, it has metadata prepended between the square braces: {}", memory_string)
+                let cite_msg = match model.memory.cite {
+                    true => ". When your answer uses one of them, cite it by the number at the start of its metadata, such as [m1]. The list",
+                    false => "",
+                };
+                format!("Below is a list of text related to the current query, it has metadata prepended between the square braces{}: {}", cite_msg, memory_string)
             } else {
                 "".to_string()
             };
 
-            let msg = format!("{}. {}", model.prompt.act_as, memory_msg);
+            let summary_msg = summary
+                .filter(|summary| !summary.text.is_empty())
+                .map(|summary| {
+                    format!(
+                        "Summary of the conversation before the messages below: {}\n",
+                        summary.text
+                    )
+                })
+                .unwrap_or_default();
+
+            let msg = format!("{}. {}{}", model.prompt.act_as, summary_msg, memory_msg);
             vec![ChatRequestInput {
                 role: "system".to_string(),
                 content: msg,
@@ -224,13 +437,7 @@ impl Action for ChatState {
         };
 
         debug!("Constructing conversation message");
-        let mut conversation: Vec<ChatRequestInput> = convo_history
-            .iter()
-            .map(|x| ChatRequestInput {
-                role: x.author.to_string(),
-                content: x.content.to_string(),
-            })
-            .collect();
+        let mut conversation = history_messages(&convo_history);
 
         debug!("Constructing current query");
         let mut current = vec![ChatRequestInput {
@@ -247,14 +454,28 @@ impl Action for ChatState {
         };
 
         debug!("Sending query");
+        let started = Instant::now();
         let response_stream = self.effects.requester.chat_request_stream(&request).await?;
 
         let result = self.effects.displayer.print_stream(response_stream).await;
+        if model.memory.cite {
+            let sources = result
+                .first()
+                .map(|answer| cited_sources(answer, &memories))
+                .unwrap_or_default();
+            if !sources.is_empty() {
+                self.effects
+                    .displayer
+                    .eprint(&format!("Sources:\n{}", sources.join("\n")));
+            }
+        }
+        let turn_meta = turn_meta(&self.effects, &model, &memories, started);
         Ok((
             Box::new(SuccessState(self.effects)),
             model
                 .with_chat_response(result)
-                .with_chat_prompt(chat_request.to_string()),
+                .with_chat_prompt(chat_request.to_string())
+                .with_turn_meta(turn_meta),
         ))
     }
 
@@ -263,9 +484,154 @@ impl Action for ChatState {
     }
 }
 
+fn shell_system_prompt(shell: &str) -> String {
+    format!(
+        "You translate requests into a single command for the {} shell on {}. \
+        Respond with only the command: no explanation, no markdown and no code fences. \
+        If several steps are needed chain them into one line.",
+        shell,
+        std::env::consts::OS
+    )
+}
+
+pub struct ShellState {
+    effects: Effects,
+}
+
+#[async_trait]
+impl Action for ShellState {
+    async fn execute(
+        self: Box<Self>,
+        model: Model,
+    ) -> Result<(Box<dyn Action>, Model), Box<dyn Error>> {
+        let shell_request = match get_potential_prompts(&model) {
+            PotentialPrompt {
+                prompt,
+                data_prompt_result: Some(data),
+                ..
+            }
+            | PotentialPrompt {
+                prompt,
+                inputs: Some(data),
+                ..
+            } if !data.is_empty() => format!("{}. Use this input: {}.", prompt, data),
+            PotentialPrompt { prompt, .. } => prompt.to_string(),
+        };
+
+        debug!("Retrieving chat history: ");
+        let (convo_history, _) = convo_history(&self.effects, &model.memory).await?;
+
+        let mut request = vec![ChatRequestInput {
+            role: "system".to_string(),
+            content: shell_system_prompt(&model.config.shell),
+        }];
+        request.extend(history_messages(&convo_history));
+        request.push(ChatRequestInput {
+            role: "user".to_string(),
+            content: shell_request.to_string(),
+        });
+
+        debug!("Sending shell command query");
+        let started = Instant::now();
+        let response_stream = self.effects.requester.chat_request_stream(&request).await?;
+        let response = response_stream
+            .collect::<Vec<Vec<String>>>()
+            .await
+            .concat()
+            .concat();
+        let turn_meta = turn_meta(&self.effects, &model, &[], started);
+
+        let command = extract_command(&response);
+        debug!("Extracted command '{}' from '{}'", command, response);
+
+        Ok((
+            Box::new(CommandState {
+                effects: self.effects,
+                command: command.clone(),
+                explain: model.config.explain_command,
+            }),
+            model
+                .with_chat_response(vec![command])
+                .with_chat_prompt(shell_request)
+                .with_turn_meta(turn_meta),
+        ))
+    }
+
+    fn _type(&self) -> String {
+        String::from("Shell")
+    }
+}
+
 #[cfg(test)]
 mod tests {
+    use chrono::{TimeZone, Utc};
+
+    use crate::{
+        data::model::SourceType,
+        effect::{LongMemOutput, MemorySource, ShortMemOutput},
+    };
+
+    use super::{citations, cited_sources, history_messages};
 
     #[test]
     fn hello_world() {}
+
+    #[test]
+    fn command_output_follows_the_answer_that_suggested_it() {
+        let segment = |author: &str, content: &str, command_output: Option<&str>| ShortMemOutput {
+            id: content.to_string(),
+            author: author.to_string(),
+            created_at: Utc::now(),
+            content: content.to_string(),
+            command_output: command_output.map(str::to_string),
+        };
+        let messages = history_messages(&[
+            segment("user", "list files", None),
+            segment("assistant", "ls", Some("a.txt\n")),
+        ]);
+        let messages: Vec<(&str, &str)> = messages
+            .iter()
+            .map(|message| (message.role.as_str(), message.content.as_str()))
+            .collect();
+        assert_eq!(
+            vec![
+                ("user", "list files"),
+                ("assistant", "ls"),
+                ("user", "Output of running `ls`:\na.txt\n")
+            ],
+            messages
+        );
+    }
+
+    #[test]
+    fn citations_are_resolved_to_their_memories() {
+        let answer = "Deploy with make [m2]. It needs sudo [m1, m2] [see above] [m3]";
+        assert_eq!(vec![2, 1, 3], citations(answer));
+
+        let memory = |id: &str, source| LongMemOutput {
+            id: id.to_string(),
+            text: "text".to_string(),
+            created_at: Utc.with_ymd_and_hms(2023, 6, 30, 12, 0, 0).unwrap(),
+            author: "user".to_string(),
+            category: "ops".to_string(),
+            score: 0.5,
+            source_type: SourceType::Chat,
+            source,
+        };
+        let memories = [
+            memory("abc", None),
+            memory(
+                "def",
+                Some(MemorySource {
+                    path: "/docs/deploy.md".to_string(),
+                    offset: 120,
+                }),
+            ),
+        ];
+        let sources = cited_sources(answer, &memories);
+        assert_eq!(3, sources.len());
+        assert!(sources[0].starts_with("[m2] /docs/deploy.md#120, 'ops' "));
+        assert!(sources[1].starts_with("[m1] chat memory abc, 'ops' "));
+        assert_eq!("[m3] is not one of the memories of the request", sources[2]);
+    }
 }
@@ -1,14 +1,20 @@
 use std::{error::Error, process::exit};
 
 use async_trait::async_trait;
+use chrono::Utc;
 use log::debug;
 
 use crate::{
-    effect::{Effects, LongMemSaveInp, ShortMemInput},
-    model::{Mode, Model},
+    effect::{
+        self,
+        conversation::new_id,
+        outbox::{Outbox, Segments},
+        retention, Effects, LongMemSaveInp, ShortMemInput,
+    },
+    model::{Mode, Model, SourceType},
 };
 
-use super::Action;
+use super::{summary::SummaryState, Action};
 
 pub struct SuccessState(pub Effects);
 
@@ -20,7 +26,7 @@ impl Action for SuccessState {
     ) -> Result<(Box<dyn Action>, Model), Box<dyn Error>> {
         // save memory if in chat mode
         match &model.mode {
-            Mode::Chat(_) => {
+            Mode::Chat(_) | Mode::Shell(_) => {
                 let has_chat_output = model
                     .output
                     .chat_results
@@ -30,48 +36,172 @@ impl Action for SuccessState {
 
                 if has_chat_output {
                     // save chat history to long term storage
-                    let prompt = model.prompt.final_chat_prompt.unwrap_or("".to_string());
+                    let prompt = model
+                        .prompt
+                        .final_chat_prompt
+                        .clone()
+                        .unwrap_or("".to_string());
                     let response = model
                         .output
                         .chat_results
+                        .as_ref()
                         .map(|results| results[0].clone()) // multiple responses not yet implementated
                         .unwrap_or("".to_string());
 
+                    // the turn's ids are chosen here so that memories queued for it can find it
+                    let segment_ids = [new_id(), new_id()];
+                    let mut memory_ids = vec![];
                     if model.memory.enabled {
                         debug!("Saving prompt and response to database");
                         let user_input = LongMemSaveInp {
                             text: prompt.clone(),
                             author: "user".to_string(),
+                            created_at: Utc::now(),
+                            source_type: SourceType::Chat,
+                            source: None,
                         };
 
                         let assistant_response = LongMemSaveInp {
                             text: response.clone(),
                             author: "assistant".to_string(),
+                            created_at: Utc::now(),
+                            source_type: SourceType::Chat,
+                            source: None,
+                        };
+
+                        let outbox = Outbox::new(model.memory.outbox.clone().into());
+                        let history = |name: &str| {
+                            effect::history(
+                                &model.memory.history_backend,
+                                &model.memory.convo_dir,
+                                name,
+                                model.memory.cipher.as_ref(),
+                            )
                         };
+                        // memories queued by earlier runs go first
+                        match outbox.flush(self.0.context.as_ref(), &history, false).await {
+                            Ok(flushed) => {
+                                if flushed.saved > 0 {
+                                    self.0.displayer.eprint(&format!(
+                                        "Saved {} memory writes queued by earlier runs, {} still queued",
+                                        flushed.saved, flushed.waiting
+                                    ));
+                                }
+                                for unlinked in &flushed.unlinked {
+                                    self.0.displayer.eprint(&format!(
+                                        "Could not record the saved memories in conversation {}",
+                                        unlinked
+                                    ));
+                                }
+                            }
+                            Err(e) => self.0.displayer.eprint(&format!(
+                                "Could not save the memory writes queued by earlier runs: {}",
+                                e
+                            )),
+                        }
 
-                        self.0
-                            .context
-                            .save(&[user_input, assistant_response], &model.memory.convo)
-                            .await?;
+                        // the answer has been shown, so it is queued rather than lost when it
+                        // cannot be saved
+                        let input = [user_input, assistant_response];
+                        let error = match self.0.context.save(&input, &model.memory.convo).await {
+                            Ok(ids) => {
+                                memory_ids = ids;
+                                None
+                            }
+                            Err(e) => Some(e.to_string()),
+                        };
+                        if let Some(error) = error {
+                            let warning = match outbox
+                                .queue(
+                                    &model.memory.convo,
+                                    &input,
+                                    Some(Segments {
+                                        convo: &model.memory.convo,
+                                        ids: &segment_ids,
+                                    }),
+                                    &error,
+                                )
+                                .await
+                            {
+                                Ok(()) => format!(
+                                    "Could not save to long term memory, queued to be saved later: {}",
+                                    error
+                                ),
+                                Err(e) => format!(
+                                    "Could not save to long term memory: {}. Nor queue it: {}",
+                                    error, e
+                                ),
+                            };
+                            self.0.displayer.eprint(&warning);
+                        }
                     }
 
                     // save chat history to short term storage
+                    // ids are returned in the order the memories were saved
+                    let mut memory_ids = memory_ids.into_iter();
                     let user_input = ShortMemInput {
+                        id: Some(segment_ids[0].clone()),
                         author: "user".to_string(),
                         content: prompt,
+                        meta: None,
+                        memory_id: memory_ids.next(),
                     };
 
+                    // the output is kept with the answer so that turns stay a question and
+                    // its answer
+                    let mut meta = model.output.turn_meta.clone();
+                    if model.config.feed_command_output {
+                        if let Some(command_output) = &model.output.command_output {
+                            debug!("Saving command output to conversation history");
+                            meta.get_or_insert_with(Default::default).command_output =
+                                Some(command_output.clone());
+                        }
+                    }
                     let assistant_response = ShortMemInput {
+                        id: Some(segment_ids[1].clone()),
                         author: "assistant".to_string(),
-                        content: response,
+                        content: response.clone(),
+                        meta,
+                        memory_id: memory_ids.next(),
                     };
-                    self.0
-                        .history
-                        .save_history(&[user_input, assistant_response])
-                        .await?;
+
+                    let history = vec![user_input, assistant_response];
+
+                    if let Some(turn) = model.memory.edit_turn {
+                        // the edited turn branches from the one before it
+                        let parent = self.0.history.load().await?.turn_parent(turn)?;
+                        self.0.history.checkout(parent.as_deref()).await?;
+                    }
+                    self.0.history.save_history(&history).await?;
+
+                    if let Some(persona) = &model.prompt.persona {
+                        self.0.history.save_persona(persona).await?;
+                    }
+
+                    if let Some(policy) = &model.memory.retention {
+                        let memory = model.memory.enabled.then_some(self.0.context.as_ref());
+                        // the response has been saved, so pruning can wait for the next run
+                        if let Err(e) = retention::prune(
+                            self.0.history.as_ref(),
+                            memory,
+                            &model.memory.convo,
+                            policy,
+                            false,
+                        )
+                        .await
+                        {
+                            self.0
+                                .displayer
+                                .eprint(&format!("Could not remove old turns: {}", e));
+                        }
+                    }
+
+                    if model.memory.summarise {
+                        return Ok((Box::new(SummaryState(self.0)), model));
+                    }
                 };
             }
-            Mode::Edit(_) => (),
+            Mode::Edit(_) | Mode::Pipeline(..) => (),
         }
         exit(0);
     }
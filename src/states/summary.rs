@@ -0,0 +1,159 @@
+use std::{error::Error, process::exit};
+
+use async_trait::async_trait;
+use futures::StreamExt;
+use log::debug;
+
+use crate::{
+    effect::{conversation::Summary, ChatRequestInput, Effects, ShortMemEffect, ShortMemOutput},
+    model::{Algo, Model},
+};
+
+use super::Action;
+
+/// The running summary, unless the active branch no longer starts with the segments it covers
+pub async fn current_summary(
+    history: &dyn ShortMemEffect,
+) -> Result<Option<Summary>, Box<dyn Error>> {
+    let summary = match history.get_summary().await? {
+        Some(summary) if summary.covered > 0 => summary,
+        summary => return Ok(summary),
+    };
+    let last = history
+        .get_segments(summary.covered - 1, summary.covered)
+        .await?;
+    let valid = match (last.first(), &summary.through) {
+        (Some(last), Some(through)) => last.id == *through,
+        // summaries from before branches were added cover a linear conversation
+        (Some(_), None) => true,
+        (None, _) => false,
+    };
+    Ok(Some(summary).filter(|_| valid))
+}
+
+/// Folds segments that have fallen out of the conversation window into the running summary
+pub struct SummaryState(pub Effects);
+
+fn summary_request(
+    summary: Option<&Summary>,
+    segments: &[ShortMemOutput],
+) -> Vec<ChatRequestInput> {
+    let transcript = segments
+        .iter()
+        .map(|segment| format!("{}: {}", segment.author, segment.content))
+        .collect::<Vec<String>>()
+        .join("\n\n");
+    let current = summary
+        .map(|summary| summary.text.as_str())
+        .filter(|text| !text.is_empty())
+        .unwrap_or("There is no summary yet.");
+
+    vec![
+        ChatRequestInput {
+            role: "system".to_string(),
+            content: "You maintain a concise running summary of a conversation between a user and \
+                an assistant. Keep facts, decisions, names and open questions that later turns may \
+                refer to. Respond with only the updated summary."
+                .to_string(),
+        },
+        ChatRequestInput {
+            role: "user".to_string(),
+            content: format!(
+                "Current summary:\n{}\n\nLater messages to fold into the summary:\n{}",
+                current, transcript
+            ),
+        },
+    ]
+}
+
+impl SummaryState {
+    async fn update_summary(&self, model: &Model) -> Result<(), Box<dyn Error>> {
+        let history = &self.0.history;
+        let summary = current_summary(history.as_ref()).await?;
+        let covered = summary.as_ref().map(|summary| summary.covered).unwrap_or(0);
+        let fold_to = history.len().await?.saturating_sub(model.memory.convo_len);
+
+        if fold_to <= covered {
+            debug!("No turns have left the conversation window since the last summary");
+            return Ok(());
+        }
+
+        let segments = history.get_segments(covered, fold_to).await?;
+        debug!("Summarising {} segments", segments.len());
+
+        let algo = Algo {
+            chat_model: model.memory.summary_model.clone(),
+            temp: 0.2,
+            ..model.algo.clone()
+        };
+        let response = self
+            .0
+            .requester
+            .chat_request_stream_with_algo(&summary_request(summary.as_ref(), &segments), &algo)
+            .await?;
+        let text = response
+            .collect::<Vec<Vec<String>>>()
+            .await
+            .concat()
+            .concat();
+
+        history
+            .save_summary(&Summary {
+                text: text.trim().to_string(),
+                covered: fold_to,
+                through: segments.last().map(|segment| segment.id.clone()),
+            })
+            .await
+    }
+}
+
+#[async_trait]
+impl Action for SummaryState {
+    async fn execute(
+        self: Box<Self>,
+        model: Model,
+    ) -> Result<(Box<dyn Action>, Model), Box<dyn Error>> {
+        // the response has already been saved, so a failure here only delays the summary
+        if let Err(e) = self.update_summary(&model).await {
+            self.0
+                .displayer
+                .eprint(&format!("Could not update the conversation summary: {}", e));
+        }
+        exit(0);
+    }
+
+    fn _type(&self) -> String {
+        String::from("Summary")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+
+    use crate::effect::{conversation::Summary, ShortMemOutput};
+
+    use super::summary_request;
+
+    #[test]
+    fn request_includes_current_summary_and_new_segments() {
+        let summary = Summary {
+            text: "The user is writing a parser.".to_string(),
+            covered: 2,
+            through: None,
+        };
+        let segments = vec![ShortMemOutput {
+            id: "a1".to_string(),
+            author: "user".to_string(),
+            content: "It should handle comments".to_string(),
+            created_at: Utc::now(),
+            command_output: None,
+        }];
+
+        let request = summary_request(Some(&summary), &segments);
+        assert!(request[1].content.contains("The user is writing a parser."));
+        assert!(request[1]
+            .content
+            .contains("user: It should handle comments"));
+    }
+}
@@ -1,20 +1,12 @@
-use std::{error::Error, io::Read};
-
-use serde::{de::DeserializeOwned, Serialize};
-use tokio::{
-    fs::{self, File},
-    io::{AsyncReadExt, AsyncWriteExt},
+use std::{
+    error::Error,
+    io::{Read, Write},
+    path::Path,
 };
 
-pub async fn deserialise_from_file_async<T: DeserializeOwned>(
-    file_path: &str,
-) -> Result<T, Box<dyn Error>> {
-    let mut file = File::open(file_path).await?;
-    let mut contents = String::new();
-    file.read_to_string(&mut contents).await?;
-    let t = serde_yaml::from_str(&contents)?;
-    Ok(t)
-}
+use chrono::{DateTime, Local, NaiveDate, Utc};
+use serde::{de::DeserializeOwned, Serialize};
+use tokio::{fs::File, io::AsyncWriteExt};
 
 pub async fn write_to_async<T: Serialize>(file_path: &str, data: &T) -> Result<(), Box<dyn Error>> {
     let contents = serde_yaml::to_string(data)?;
@@ -23,6 +15,53 @@ pub async fn write_to_async<T: Serialize>(file_path: &str, data: &T) -> Result<(
     Ok(())
 }
 
+/// Writes to a temporary file in the same directory then renames it over the target, so the
+/// target is never left partially written
+pub fn write_atomic(file_path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    let dir = match file_path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let mut file = tempfile::NamedTempFile::new_in(dir)?;
+    file.write_all(contents)?;
+    file.as_file().sync_all()?;
+    file.persist(file_path)?;
+    Ok(())
+}
+
+/// Checks a name given to a file or directory keeps it inside its parent: it is not empty, has no
+/// path separators and does not start with a dot
+pub fn check_name(kind: &str, name: &str) -> Result<(), String> {
+    match name.is_empty() || name.contains(['/', '\\']) || name.starts_with('.') {
+        true => Err(format!("Invalid {} name '{}'", kind, name)),
+        false => Ok(()),
+    }
+}
+
+/// The start of the day in the local timezone
+pub fn start_of(date: NaiveDate) -> Option<DateTime<Utc>> {
+    date.and_hms_opt(0, 0, 0)?
+        .and_local_timezone(Local)
+        .earliest()
+        .map(|time| time.with_timezone(&Utc))
+}
+
+/// The time in the local timezone, to the second
+pub fn format_time(time: &DateTime<Utc>) -> String {
+    time.with_timezone(&Local)
+        .format("%Y-%m-%d %H:%M:%S")
+        .to_string()
+}
+
+/// First line of a message, shortened to fit a listing
+pub fn snippet(content: &str) -> String {
+    let line = content.lines().next().unwrap_or_default();
+    match line.char_indices().nth(60) {
+        Some((end, _)) => format!("{}...", &line[..end]),
+        None => line.to_string(),
+    }
+}
+
 pub fn deserialise_from_file<T: DeserializeOwned>(file_path: &str) -> Result<T, Box<dyn Error>> {
     let mut file = std::fs::File::open(file_path)?;
     let mut contents = String::new();
@@ -31,10 +70,6 @@ pub fn deserialise_from_file<T: DeserializeOwned>(file_path: &str) -> Result<T,
     Ok(t)
 }
 
-pub async fn file_exists_async(file_path: &str) -> bool {
-    fs::metadata(file_path).await.is_ok()
-}
-
 pub fn file_exists(file_path: &str) -> bool {
     std::fs::metadata(file_path).is_ok()
 }
@@ -69,7 +104,17 @@ impl<T: Default> Transpose<T> for Vec<Vec<T>> {
 
 #[cfg(test)]
 mod tests {
-    use super::Transpose;
+    use super::{check_name, Transpose};
+
+    #[test]
+    fn names_cannot_leave_their_directory() {
+        assert!(check_name("conversation", "default").is_ok());
+        assert!(check_name("conversation", "my_repo-1a2b3c4d").is_ok());
+        for name in ["", "../../.bashrc", "a/b", "a\\b", ".hidden", ".."] {
+            assert!(check_name("conversation", name).is_err(), "{}", name);
+        }
+    }
+
     #[test]
     fn transpose_tranposes_simple_matrix() {
         let input = vec![vec![1, 2, 3], vec![4, 5, 6], vec![7, 8, 9]];